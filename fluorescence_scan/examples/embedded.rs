@@ -0,0 +1,29 @@
+//! Mounts the fluorescence scan subgraph under `/fluorescence` within a host application
+//!
+//! Run with `DATABASE_URL` set to the ISPyB instance to read from, then browse to `http://localhost:8080/fluorescence`.
+
+use axum::{routing::get, Router};
+use fluorescence_scan::FluorescenceScanService;
+use sea_orm::Database;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() {
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let database = Database::connect(database_url).await.unwrap();
+
+    let fluorescence_scan = FluorescenceScanService::builder(database)
+        .graphql_endpoint("/fluorescence")
+        .build();
+    let app = Router::new()
+        .route("/", get(|| async { "Host application" }))
+        .nest("/fluorescence", fluorescence_scan.router());
+
+    tokio::spawn({
+        let fluorescence_scan = fluorescence_scan.clone();
+        async move { fluorescence_scan.start(true, false).await }
+    });
+
+    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
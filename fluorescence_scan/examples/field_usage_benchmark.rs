@@ -0,0 +1,144 @@
+//! Compares the latency of a representative session listing with field usage metrics disabled, enabled for every request, and sampled
+//!
+//! By default the listing is of 500 scans served from memory, so that only the cost of execution is measured. Run with `DATABASE_URL` set to an ISPyB instance and `SESSION_ID` set to a session with around 500 scans to include the database, optionally setting `ITERATIONS`.
+
+use async_graphql::{Request, Variables};
+use chrono::{DateTime, NaiveDate};
+use fluorescence_scan::FluorescenceScanService;
+use models::xfe_fluorescence_spectrum;
+use sea_orm::{
+    Database, DatabaseBackend, DatabaseConnection, DbErr, IdenStatic, Iterable, ModelTrait,
+    ProxyDatabaseTrait, ProxyExecResult, ProxyRow, Statement,
+};
+use serde_json::json;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The number of scans in the session served from memory
+const FAKE_SCANS: u32 = 500;
+
+/// Lists every scalar field of every scan in the session
+const QUERY: &str = r#"
+query ($representations: [_Any!]!) {
+    _entities(representations: $representations) {
+        ... on Session {
+            fluorescenceScan {
+                id sessionId startTime endTime filename exposureTime axisPosition
+                beamTransmission energy beamSizeVertical beamSizeHorizontal
+            }
+        }
+    }
+}
+"#;
+
+/// A database answering every query with the same scans
+#[derive(Debug)]
+struct FakeScans(Vec<ProxyRow>);
+
+impl FakeScans {
+    /// Creates a database holding the number of scans, each with every column recorded, in the session
+    fn new(count: u32, session_id: u32) -> Self {
+        let start = NaiveDate::from_ymd_opt(2024, 3, 5)
+            .unwrap()
+            .and_hms_opt(9, 30, 0)
+            .unwrap();
+        Self(
+            (1..=count)
+                .map(|id| {
+                    let scan = xfe_fluorescence_spectrum::Model {
+                        xfe_fluorescence_spectrum_id: id,
+                        session_id,
+                        jpeg_scan_file_full_path: Some(format!(
+                            "/dls/i18/data/2024/cm1-1/{id}.jpg"
+                        )),
+                        start_time: Some(start + chrono::Duration::minutes(id.into())),
+                        end_time: Some(
+                            start
+                                + chrono::Duration::minutes(id.into())
+                                + chrono::Duration::seconds(90),
+                        ),
+                        filename: Some(format!("{id}.dat")),
+                        exposure_time: Some(0.5),
+                        axis_position: Some(id as f32 / 4.0),
+                        beam_transmission: Some(0.75),
+                        energy: Some(13500.0),
+                        beam_size_vertical: Some(20.0),
+                        beam_size_horizontal: Some(80.0),
+                        scan_file_full_path: Some(format!("/dls/i18/data/2024/cm1-1/{id}.dat")),
+                        record_time_stamp: DateTime::UNIX_EPOCH,
+                    };
+                    ProxyRow::new(
+                        xfe_fluorescence_spectrum::Column::iter()
+                            .map(|column| (column.as_str().to_string(), scan.get(column)))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Connects to the database
+    async fn connect(self) -> DatabaseConnection {
+        Database::connect_proxy(DatabaseBackend::MySql, Arc::new(Mutex::new(Box::new(self))))
+            .await
+            .unwrap()
+    }
+}
+
+impl ProxyDatabaseTrait for FakeScans {
+    fn query(&self, _statement: Statement) -> Result<Vec<ProxyRow>, DbErr> {
+        Ok(self.0.clone())
+    }
+
+    fn execute(&self, _statement: Statement) -> Result<ProxyExecResult, DbErr> {
+        Ok(ProxyExecResult::new(0, 0))
+    }
+}
+
+/// Executes the query repeatedly against the service, returning the mean latency
+async fn measure(service: FluorescenceScanService, session_id: u32, iterations: u32) -> Duration {
+    let variables = Variables::from_json(json!({
+        "representations": [{ "__typename": "Session", "id": session_id }],
+    }));
+    let request = || Request::new(QUERY).variables(variables.clone());
+    let warm_up = service.schema().execute(request()).await;
+    assert!(warm_up.errors.is_empty(), "{:?}", warm_up.errors);
+    let started = Instant::now();
+    for _ in 0..iterations {
+        service.schema().execute(request()).await;
+    }
+    started.elapsed() / iterations
+}
+
+#[tokio::main]
+async fn main() {
+    let iterations = std::env::var("ITERATIONS")
+        .map(|iterations| iterations.parse().expect("ITERATIONS must be an integer"))
+        .unwrap_or(50);
+    let (database, session_id) = match std::env::var("DATABASE_URL") {
+        Ok(database_url) => (
+            Database::connect(database_url).await.unwrap(),
+            std::env::var("SESSION_ID")
+                .expect("SESSION_ID must be set with DATABASE_URL")
+                .parse()
+                .expect("SESSION_ID must be an integer"),
+        ),
+        Err(_) => (FakeScans::new(FAKE_SCANS, 1).connect().await, 1),
+    };
+
+    for (name, sample_every) in [
+        ("disabled", None),
+        ("enabled", Some(1)),
+        ("sampled 1 in 10", Some(10)),
+    ] {
+        let mut builder = FluorescenceScanService::builder(database.clone());
+        if let Some(sample_every) = sample_every {
+            builder = builder.field_usage_metrics(sample_every);
+        }
+        let service = builder.build();
+        let latency = measure(service, session_id, iterations).await;
+        println!("{name}: {latency:?} per request over {iterations} requests");
+    }
+}
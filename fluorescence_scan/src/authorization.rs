@@ -0,0 +1,506 @@
+use crate::token_verifier::TokenVerifier;
+use async_graphql::{async_trait::async_trait, ErrorExtensions};
+use axum_extra::headers::{authorization::Bearer, Authorization};
+use models::{bl_session, person, session_has_person};
+use sea_orm::{
+    sea_query::Query, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, PaginatorTrait,
+    QueryFilter, QuerySelect,
+};
+use serde_json::Value;
+use std::fmt::Debug;
+use tracing::debug;
+
+/// Marks requests issued by the service itself, such as the warm-up, which are not subject to the authorization policy
+#[derive(Debug, Clone, Copy)]
+pub struct InternalRequest;
+
+/// The claims of the bearer token presented with a request
+///
+/// Claims are only read from tokens whose signature, issuer, audience and expiry have been verified, so that they may be trusted by policies. Requests without such a token have no claims.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Claims {
+    /// The subject of the token, the login of the user for tokens issued at Diamond
+    pub subject: Option<String>,
+    /// The groups of which the subject is a member
+    pub groups: Vec<String>,
+}
+
+impl Claims {
+    /// Verifies the bearer token, producing its claims if it is genuine, otherwise or if there is no token or verifier producing no claims
+    pub async fn verified(
+        verifier: Option<&TokenVerifier>,
+        token: Option<&Authorization<Bearer>>,
+    ) -> Self {
+        let (Some(verifier), Some(token)) = (verifier, token) else {
+            return Self::default();
+        };
+        match verifier.verify(token.token()).await {
+            Ok(claims) => claims,
+            Err(err) => {
+                debug!("Bearer token rejected: {err}");
+                Self::default()
+            }
+        }
+    }
+
+    /// Reads the claims from the verified payload of a token
+    pub(crate) fn from_payload(payload: &Value) -> Self {
+        Self {
+            subject: payload["sub"].as_str().map(String::from),
+            groups: payload["groups"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|group| group.as_str())
+                .map(|group| group.trim_start_matches('/').to_string())
+                .collect(),
+        }
+    }
+
+    /// Whether the subject is a member of the group, ignoring case
+    fn in_group(&self, group: &str) -> bool {
+        self.groups
+            .iter()
+            .any(|member_of| member_of.eq_ignore_ascii_case(group))
+    }
+}
+
+/// An action, and the identifiers of its target, for which access is decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Reading a session and the listing of its scans
+    SessionRead {
+        /// The session to be read
+        session_id: u32,
+    },
+    /// Reading the files of a scan
+    ScanRead {
+        /// The session to which the scan belongs
+        session_id: u32,
+        /// The scan to be read
+        scan_id: u32,
+    },
+    /// Reading a field restricted to administrators
+    RestrictedField {
+        /// The name of the field
+        field: &'static str,
+    },
+    /// Executing a mutation
+    Mutation {
+        /// The name of the mutation
+        name: &'static str,
+    },
+    /// Exporting the scans of a session
+    Export {
+        /// The session to be exported
+        session_id: u32,
+    },
+}
+
+/// The outcome of an authorization decision
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// The action is permitted
+    Allow,
+    /// The action is forbidden, for the reason given
+    Deny(String),
+}
+
+impl Decision {
+    /// Converts a denial into a GraphQL error with the `FORBIDDEN` code
+    pub fn into_result(self) -> async_graphql::Result<()> {
+        match self {
+            Self::Allow => Ok(()),
+            Self::Deny(reason) => Err(async_graphql::Error::new(reason)
+                .extend_with(|_, extensions| extensions.set("code", "FORBIDDEN"))),
+        }
+    }
+}
+
+/// Decides whether the holder of the claims may perform an action, encapsulating the rules of a facility
+#[async_trait]
+pub trait AuthorizationPolicy: Debug + Send + Sync {
+    /// Decides whether the holder of the claims may perform the action
+    async fn decide(&self, claims: &Claims, action: Action) -> Result<Decision, DbErr>;
+}
+
+/// Permits every read, for deployments which are not exposed to untrusted clients, but no mutation, as it cannot tell administrators from anonymous clients
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+#[async_trait]
+impl AuthorizationPolicy for AllowAll {
+    async fn decide(&self, _claims: &Claims, action: Action) -> Result<Decision, DbErr> {
+        Ok(match action {
+            Action::Mutation { .. } => Decision::Deny(String::from(
+                "Mutations require an authorization policy other than allow-all",
+            )),
+            _ => Decision::Allow,
+        })
+    }
+}
+
+/// Permits access to the sessions of which the subject is recorded as a member in ISPyB, and restricted actions to members of the administrator group
+#[derive(Debug, Clone)]
+pub struct IspybMembership {
+    /// The connection to the ISPyB database
+    database: DatabaseConnection,
+    /// The group whose members may perform restricted actions
+    admin_group: String,
+}
+
+impl IspybMembership {
+    /// Creates a policy consulting the supplied database
+    pub fn new(database: DatabaseConnection, admin_group: impl Into<String>) -> Self {
+        Self {
+            database,
+            admin_group: admin_group.into(),
+        }
+    }
+
+    /// Decides whether the subject is a member of the session
+    async fn session_member(&self, claims: &Claims, session_id: u32) -> Result<Decision, DbErr> {
+        let Some(subject) = &claims.subject else {
+            return Ok(Decision::Deny(String::from("No subject was presented")));
+        };
+        let memberships = session_has_person::Entity::find()
+            .filter(session_has_person::Column::SessionId.eq(session_id))
+            .filter(
+                session_has_person::Column::PersonId.in_subquery(
+                    Query::select()
+                        .column(person::Column::PersonId)
+                        .from(person::Entity)
+                        .and_where(person::Column::Login.eq(subject.as_str()))
+                        .to_owned(),
+                ),
+            )
+            .limit(1)
+            .count(&self.database)
+            .await?;
+        Ok(if memberships > 0 || claims.in_group(&self.admin_group) {
+            Decision::Allow
+        } else {
+            Decision::Deny(format!("{subject} is not a member of session {session_id}"))
+        })
+    }
+}
+
+#[async_trait]
+impl AuthorizationPolicy for IspybMembership {
+    async fn decide(&self, claims: &Claims, action: Action) -> Result<Decision, DbErr> {
+        match action {
+            Action::SessionRead { session_id }
+            | Action::ScanRead { session_id, .. }
+            | Action::Export { session_id } => self.session_member(claims, session_id).await,
+            Action::RestrictedField { .. } | Action::Mutation { .. } => {
+                Ok(admin_only(claims, &self.admin_group))
+            }
+        }
+    }
+}
+
+/// Permits access to the sessions of the beamlines whose groups the subject is a member of according to the token claims, and restricted actions to members of the administrator group
+#[derive(Debug, Clone)]
+pub struct BeamlineClaims {
+    /// The connection to the ISPyB database, used to find the beamline of a session
+    database: DatabaseConnection,
+    /// The group whose members may perform restricted actions
+    admin_group: String,
+}
+
+impl BeamlineClaims {
+    /// Creates a policy finding the beamlines of sessions in the supplied database
+    pub fn new(database: DatabaseConnection, admin_group: impl Into<String>) -> Self {
+        Self {
+            database,
+            admin_group: admin_group.into(),
+        }
+    }
+
+    /// Decides whether the subject is a member of the group of the beamline of the session
+    async fn beamline_member(&self, claims: &Claims, session_id: u32) -> Result<Decision, DbErr> {
+        if claims.in_group(&self.admin_group) {
+            return Ok(Decision::Allow);
+        }
+        let beamline = bl_session::Entity::find_by_id(session_id)
+            .one(&self.database)
+            .await?
+            .and_then(|session| session.beam_line_name);
+        Ok(match beamline {
+            Some(beamline) if claims.in_group(&beamline) => Decision::Allow,
+            Some(beamline) => Decision::Deny(format!("Not a member of the {beamline} group")),
+            None => Decision::Deny(format!("Session {session_id} has no beamline")),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthorizationPolicy for BeamlineClaims {
+    async fn decide(&self, claims: &Claims, action: Action) -> Result<Decision, DbErr> {
+        match action {
+            Action::SessionRead { session_id }
+            | Action::ScanRead { session_id, .. }
+            | Action::Export { session_id } => self.beamline_member(claims, session_id).await,
+            Action::RestrictedField { .. } | Action::Mutation { .. } => {
+                Ok(admin_only(claims, &self.admin_group))
+            }
+        }
+    }
+}
+
+/// Permits only members of the administrator group
+fn admin_only(claims: &Claims, admin_group: &str) -> Decision {
+    if claims.in_group(admin_group) {
+        Decision::Allow
+    } else {
+        Decision::Deny(format!("Restricted to members of the {admin_group} group"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Action, AllowAll, AuthorizationPolicy, BeamlineClaims, Claims, Decision, IspybMembership,
+    };
+    use crate::fake_database::{model_row, row, FakeDatabase};
+    use models::bl_session;
+    use sea_orm::{DatabaseConnection, Value};
+
+    /// The claims of a user who is not an administrator
+    fn user() -> Claims {
+        Claims {
+            subject: Some(String::from("abc12345")),
+            groups: vec![String::from("i18")],
+        }
+    }
+
+    /// The claims of a member of the administrator group
+    fn admin() -> Claims {
+        Claims {
+            subject: Some(String::from("xyz98765")),
+            groups: vec![String::from("Admin")],
+        }
+    }
+
+    /// A database answering membership counts with each of the supplied counts in turn
+    async fn memberships(counts: &[i32]) -> DatabaseConnection {
+        FakeDatabase::with_results(
+            counts
+                .iter()
+                .map(|count| vec![row([("num_items", Value::Int(Some(*count)))])]),
+        )
+        .connect()
+        .await
+    }
+
+    /// A database holding a session of the beamline
+    async fn session_of(beamline: Option<&str>) -> DatabaseConnection {
+        FakeDatabase::with_results([vec![model_row(&bl_session::Model {
+            session_id: 42,
+            beam_line_name: beamline.map(String::from),
+            proposal_id: 7,
+            visit_number: Some(1),
+        })]])
+        .connect()
+        .await
+    }
+
+    /// Every action, targeting session 42
+    const ACTIONS: &[Action] = &[
+        Action::SessionRead { session_id: 42 },
+        Action::ScanRead {
+            session_id: 42,
+            scan_id: 3,
+        },
+        Action::RestrictedField {
+            field: "downloadDiagnostics",
+        },
+        Action::Mutation {
+            name: "backfillJpegPaths",
+        },
+        Action::Export { session_id: 42 },
+    ];
+
+    #[tokio::test]
+    async fn allow_all_allows_anonymous_reads() {
+        for action in [
+            Action::SessionRead { session_id: 42 },
+            Action::ScanRead {
+                session_id: 42,
+                scan_id: 3,
+            },
+            Action::RestrictedField {
+                field: "downloadDiagnostics",
+            },
+            Action::Export { session_id: 42 },
+        ] {
+            assert_eq!(
+                AllowAll.decide(&Claims::default(), action).await.unwrap(),
+                Decision::Allow
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn allow_all_denies_mutations_even_to_admins() {
+        for claims in [Claims::default(), admin()] {
+            assert_eq!(
+                AllowAll
+                    .decide(
+                        &claims,
+                        Action::Mutation {
+                            name: "backfillJpegPaths",
+                        },
+                    )
+                    .await
+                    .unwrap(),
+                Decision::Deny(String::from(
+                    "Mutations require an authorization policy other than allow-all"
+                ))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ispyb_membership_allows_members() {
+        let policy = IspybMembership::new(memberships(&[1, 1, 1]).await, "admin");
+        for action in [
+            Action::SessionRead { session_id: 42 },
+            Action::ScanRead {
+                session_id: 42,
+                scan_id: 3,
+            },
+            Action::Export { session_id: 42 },
+        ] {
+            assert_eq!(
+                policy.decide(&user(), action).await.unwrap(),
+                Decision::Allow
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ispyb_membership_denies_non_members() {
+        let policy = IspybMembership::new(memberships(&[0]).await, "admin");
+        assert_eq!(
+            policy
+                .decide(&user(), Action::SessionRead { session_id: 42 })
+                .await
+                .unwrap(),
+            Decision::Deny(String::from("abc12345 is not a member of session 42"))
+        );
+    }
+
+    #[tokio::test]
+    async fn ispyb_membership_denies_anonymous_without_querying() {
+        let database = FakeDatabase::with_results([]);
+        let policy = IspybMembership::new(database.connect().await, "admin");
+        for action in ACTIONS {
+            assert!(matches!(
+                policy.decide(&Claims::default(), *action).await.unwrap(),
+                Decision::Deny(_)
+            ));
+        }
+        assert!(database.queries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ispyb_membership_denies_restricted_actions_to_users() {
+        let policy = IspybMembership::new(memberships(&[]).await, "admin");
+        for action in [
+            Action::RestrictedField {
+                field: "downloadDiagnostics",
+            },
+            Action::Mutation {
+                name: "backfillJpegPaths",
+            },
+        ] {
+            assert_eq!(
+                policy.decide(&user(), action).await.unwrap(),
+                Decision::Deny(String::from("Restricted to members of the admin group"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ispyb_membership_allows_admins_everything() {
+        let policy = IspybMembership::new(memberships(&[0, 0, 0]).await, "admin");
+        for action in ACTIONS {
+            assert_eq!(
+                policy.decide(&admin(), *action).await.unwrap(),
+                Decision::Allow
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn beamline_claims_allows_members_of_the_beamline_group() {
+        let policy = BeamlineClaims::new(session_of(Some("I18")).await, "admin");
+        assert_eq!(
+            policy
+                .decide(&user(), Action::SessionRead { session_id: 42 })
+                .await
+                .unwrap(),
+            Decision::Allow
+        );
+    }
+
+    #[tokio::test]
+    async fn beamline_claims_denies_members_of_other_beamline_groups() {
+        let policy = BeamlineClaims::new(session_of(Some("i20")).await, "admin");
+        assert_eq!(
+            policy
+                .decide(
+                    &user(),
+                    Action::ScanRead {
+                        session_id: 42,
+                        scan_id: 3
+                    }
+                )
+                .await
+                .unwrap(),
+            Decision::Deny(String::from("Not a member of the i20 group"))
+        );
+    }
+
+    #[tokio::test]
+    async fn beamline_claims_denies_sessions_without_a_beamline() {
+        let policy = BeamlineClaims::new(session_of(None).await, "admin");
+        assert_eq!(
+            policy
+                .decide(&user(), Action::Export { session_id: 42 })
+                .await
+                .unwrap(),
+            Decision::Deny(String::from("Session 42 has no beamline"))
+        );
+    }
+
+    #[tokio::test]
+    async fn beamline_claims_denies_restricted_actions_to_users() {
+        let policy = BeamlineClaims::new(session_of(Some("i18")).await, "admin");
+        for action in [
+            Action::RestrictedField {
+                field: "downloadDiagnostics",
+            },
+            Action::Mutation {
+                name: "backfillJpegPaths",
+            },
+        ] {
+            assert!(matches!(
+                policy.decide(&user(), action).await.unwrap(),
+                Decision::Deny(_)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn beamline_claims_allows_admins_everything_without_querying() {
+        let policy = BeamlineClaims::new(FakeDatabase::with_results([]).connect().await, "admin");
+        for action in ACTIONS {
+            assert_eq!(
+                policy.decide(&admin(), *action).await.unwrap(),
+                Decision::Allow
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    graphql::DeprecationUsage, negative_cache::NegativeCache, store::ScanFileStore,
+    warmup::WarmupReport,
+};
+
+/// Statistics describing the running service, served by the debug endpoints
+#[derive(Debug, Clone)]
+pub struct DebugStats {
+    /// The outcome of the startup warm-up, if one has completed
+    warmup: Arc<Mutex<Option<WarmupReport>>>,
+    /// The cache of objects known to be missing from S3
+    negative_cache: Arc<NegativeCache>,
+    /// The number of requests resolving each deprecated field
+    deprecation_usage: DeprecationUsage,
+    /// The store from which scan files are read, if available
+    store: Option<Arc<dyn ScanFileStore>>,
+}
+
+impl DebugStats {
+    /// Creates statistics reporting on the supplied components
+    pub fn new(
+        negative_cache: Arc<NegativeCache>,
+        deprecation_usage: DeprecationUsage,
+        store: Option<Arc<dyn ScanFileStore>>,
+    ) -> Self {
+        Self {
+            warmup: Arc::default(),
+            negative_cache,
+            deprecation_usage,
+            store,
+        }
+    }
+
+    /// Records the outcome of the startup warm-up
+    pub fn record_warmup(&self, report: WarmupReport) {
+        *self.warmup.lock().unwrap() = Some(report);
+    }
+
+    /// Renders the statistics as JSON
+    pub fn to_json(&self) -> Value {
+        json!({
+            "warmup": self.warmup.lock().unwrap().as_ref().map(WarmupReport::to_json),
+            "negativeCache": self.negative_cache.to_json(),
+            "deprecatedFieldUsage": self.deprecation_usage.to_json(),
+            "storage": self.store.as_ref().and_then(|store| store.status()),
+        })
+    }
+}
@@ -0,0 +1,139 @@
+use models::xfe_fluorescence_spectrum;
+use sea_orm::{
+    Database, DatabaseBackend, DatabaseConnection, DbErr, EntityTrait, IdenStatic, Iterable,
+    ModelTrait, ProxyDatabaseTrait, ProxyExecResult, ProxyRow, Statement, Value,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Debug, Formatter},
+    sync::{Arc, Mutex},
+};
+
+/// Answers a query with its rows, or an error
+type Responder = dyn Fn(&Statement) -> Result<Vec<ProxyRow>, DbErr> + Send + Sync;
+
+/// A database answering queries with canned rows and recording every statement it receives, in place of sea-orm's mock database, which cannot be cloned
+///
+/// Proxy connections cannot begin transactions, so writes made within one are tested against an in-memory SQLite database instead.
+#[derive(Clone)]
+pub struct FakeDatabase {
+    /// Answers each query
+    responder: Arc<Responder>,
+    /// The statements received, in order, with `BEGIN`, `COMMIT` and `ROLLBACK` marking transactions
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Debug for FakeDatabase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FakeDatabase")
+            .field("log", &self.log)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FakeDatabase {
+    /// Creates a database answering every query with the responder
+    pub fn new(
+        responder: impl Fn(&Statement) -> Result<Vec<ProxyRow>, DbErr> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            responder: Arc::new(responder),
+            log: Arc::default(),
+        }
+    }
+
+    /// Creates a database answering queries with each of the results in turn, and with no rows once they are exhausted
+    pub fn with_results(results: impl IntoIterator<Item = Vec<ProxyRow>>) -> Self {
+        let results = Mutex::new(results.into_iter().collect::<VecDeque<_>>());
+        Self::new(move |_| Ok(results.lock().unwrap().pop_front().unwrap_or_default()))
+    }
+
+    /// Connects to the database
+    pub async fn connect(&self) -> DatabaseConnection {
+        Database::connect_proxy(
+            DatabaseBackend::MySql,
+            Arc::new(Mutex::new(Box::new(self.clone()))),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// The SQL of the queries received so far, excluding transaction boundaries
+    pub fn queries(&self) -> Vec<String> {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|statement| !matches!(statement.as_str(), "BEGIN" | "COMMIT" | "ROLLBACK"))
+            .cloned()
+            .collect()
+    }
+}
+
+impl ProxyDatabaseTrait for FakeDatabase {
+    fn query(&self, statement: Statement) -> Result<Vec<ProxyRow>, DbErr> {
+        self.log.lock().unwrap().push(statement.to_string());
+        (self.responder)(&statement)
+    }
+
+    fn execute(&self, statement: Statement) -> Result<ProxyExecResult, DbErr> {
+        self.log.lock().unwrap().push(statement.to_string());
+        (self.responder)(&statement).map(|rows| ProxyExecResult::new(0, rows.len() as u64))
+    }
+
+    fn begin(&self) {
+        self.log.lock().unwrap().push(String::from("BEGIN"));
+    }
+
+    fn commit(&self) {
+        self.log.lock().unwrap().push(String::from("COMMIT"));
+    }
+
+    fn rollback(&self) {
+        self.log.lock().unwrap().push(String::from("ROLLBACK"));
+    }
+}
+
+/// A row holding the columns of the model, as selected by its entity
+pub fn model_row<M: ModelTrait>(model: &M) -> ProxyRow {
+    ProxyRow::new(
+        <M::Entity as EntityTrait>::Column::iter()
+            .map(|column| (column.as_str().to_string(), model.get(column)))
+            .collect(),
+    )
+}
+
+/// A row holding the supplied columns
+pub fn row<'a>(columns: impl IntoIterator<Item = (&'a str, Value)>) -> ProxyRow {
+    ProxyRow::new(
+        columns
+            .into_iter()
+            .map(|(column, value)| (column.to_string(), value))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+/// A scan of the session with the scan file and jpeg paths and no other values recorded
+pub fn scan(
+    id: u32,
+    session_id: u32,
+    scan_file: Option<&str>,
+    jpeg: Option<&str>,
+) -> xfe_fluorescence_spectrum::Model {
+    xfe_fluorescence_spectrum::Model {
+        xfe_fluorescence_spectrum_id: id,
+        session_id,
+        jpeg_scan_file_full_path: jpeg.map(String::from),
+        start_time: None,
+        end_time: None,
+        filename: None,
+        exposure_time: None,
+        axis_position: None,
+        beam_transmission: None,
+        energy: None,
+        beam_size_vertical: None,
+        beam_size_horizontal: None,
+        scan_file_full_path: scan_file.map(String::from),
+        record_time_stamp: chrono::DateTime::UNIX_EPOCH,
+    }
+}
@@ -0,0 +1,156 @@
+use crate::object_key::url_path;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The route, relative to the GraphQL endpoint, beneath which objects are proxied
+pub const FILE_PROXY_ROUTE: &str = "/files";
+
+/// Signs and verifies expiring URLs under which objects are served by the service itself, for stores which cannot presign
+#[derive(Clone)]
+pub struct FileProxy {
+    /// The path, as seen by the browser, beneath which objects are served
+    endpoint: String,
+    /// The key with which URLs are signed
+    secret: Arc<[u8]>,
+}
+
+impl std::fmt::Debug for FileProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileProxy")
+            .field("endpoint", &self.endpoint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileProxy {
+    /// Creates a proxy serving objects beneath the endpoint, signing URLs with the secret
+    pub fn new(endpoint: impl Into<String>, secret: impl Into<Arc<[u8]>>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            secret: secret.into(),
+        }
+    }
+
+    /// Computes the signature of the key for the expiry time
+    fn signature(&self, key: &str, expires: u64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(format!("{expires}\n{key}").as_bytes());
+        mac
+    }
+
+    /// Produces a URL granting read access to the object for the supplied duration
+    pub fn url(&self, key: &str, expiry: Duration) -> String {
+        let expires = (SystemTime::now() + expiry)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let signature =
+            URL_SAFE_NO_PAD.encode(self.signature(key, expires).finalize().into_bytes());
+        format!(
+            "{}{FILE_PROXY_ROUTE}/{}?expires={expires}&signature={signature}",
+            self.endpoint.trim_end_matches('/'),
+            url_path(key)
+        )
+    }
+
+    /// Checks that the signature was produced for the key and has not expired
+    pub fn verify(&self, key: &str, expires: u64, signature: &str) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+            return false;
+        };
+        expires >= now
+            && self
+                .signature(key, expires)
+                .verify_slice(&signature)
+                .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileProxy, FILE_PROXY_ROUTE};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use hmac::Mac;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// A proxy beneath the endpoint of the service, signing with a fixed secret
+    fn proxy(secret: &[u8]) -> FileProxy {
+        FileProxy::new("https://example.invalid/graphql/", secret.to_vec())
+    }
+
+    /// The key, expiry time and signature of a URL produced by the proxy
+    fn parts(url: &str) -> (String, u64, String) {
+        let path = url
+            .strip_prefix(&format!(
+                "https://example.invalid/graphql{FILE_PROXY_ROUTE}/"
+            ))
+            .unwrap();
+        let (key, query) = path.split_once('?').unwrap();
+        let (expires, signature) = query
+            .strip_prefix("expires=")
+            .unwrap()
+            .split_once("&signature=")
+            .unwrap();
+        (
+            percent_encoding::percent_decode_str(key)
+                .decode_utf8()
+                .unwrap()
+                .to_string(),
+            expires.parse().unwrap(),
+            signature.to_string(),
+        )
+    }
+
+    /// The current time, in seconds since the epoch
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn signed_urls_are_verified() {
+        let proxy = proxy(b"secret");
+        let (key, expires, signature) =
+            parts(&proxy.url("/i18/a scan ß.dat", Duration::from_secs(600)));
+        assert_eq!(key, "/i18/a scan ß.dat");
+        assert!((now() + 590..=now() + 600).contains(&expires));
+        assert!(proxy.verify(&key, expires, &signature));
+    }
+
+    #[test]
+    fn tampered_urls_are_rejected() {
+        let proxy = proxy(b"secret");
+        let (key, expires, signature) =
+            parts(&proxy.url("/i18/scan.dat", Duration::from_secs(600)));
+        assert!(!proxy.verify("/i18/other.dat", expires, &signature));
+        assert!(!proxy.verify(&key, expires + 1, &signature));
+        assert!(!proxy.verify(&key, expires, "not base64!"));
+        assert!(!proxy.verify(&key, expires, ""));
+        assert!(!super::tests::proxy(b"another secret").verify(&key, expires, &signature));
+    }
+
+    #[test]
+    fn expired_urls_are_rejected() {
+        let proxy = proxy(b"secret");
+        let expired = now() - 1;
+        let url = proxy.url("/i18/scan.dat", Duration::ZERO);
+        let (key, _, _) = parts(&url);
+        let signature =
+            URL_SAFE_NO_PAD.encode(proxy.signature(&key, expired).finalize().into_bytes());
+        assert!(!proxy.verify(&key, expired, &signature));
+        let (key, expires, signature) = parts(&url);
+        assert!(proxy.verify(&key, expires, &signature));
+    }
+}
@@ -0,0 +1,588 @@
+use async_graphql::{
+    resolver_utils::enum_value, value, Context, Enum, ErrorExtensions, SimpleObject, Value,
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use models::xfe_fluorescence_spectrum::{Column, Entity};
+use sea_orm::{
+    sea_query::{Alias, Expr, Func},
+    ColumnTrait, Condition, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+    QueryTrait, TransactionTrait, UpdateMany,
+};
+
+use super::{
+    entities::FluorescenceScan,
+    fetch_scans, object_exists,
+    snapshots::{SnapshotVariants, MAX_CONCURRENT_PROBES},
+};
+use crate::{object_key::ObjectKey, read_only::ReadOnlyMode, store::ScanFiles};
+
+/// The maximum number of scans backfilled by a single invocation, unless configured otherwise
+pub const DEFAULT_BACKFILL_LIMIT: u64 = 500;
+
+/// The number of scans whose rows are updated within a single transaction
+const BATCH_SIZE: usize = 50;
+
+/// The maximum number of scans backfilled by a single invocation
+#[derive(Debug, Clone, Copy)]
+pub struct BackfillLimit(pub u64);
+
+/// The outcome of backfilling the jpeg path of a scan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum BackfillAction {
+    /// The jpeg was found and the row would be updated, were this not a dry run
+    WouldUpdate,
+    /// The jpeg was found and the row updated
+    Updated,
+    /// The row was given a jpeg path by another writer before it could be updated
+    Skipped,
+    /// No jpeg was found under any conventional key
+    NotFound,
+    /// No key could be derived from the path of the scan file
+    Unresolvable,
+}
+
+/// The outcome of backfilling the jpeg path of a single scan
+#[derive(Debug, Clone, SimpleObject)]
+pub struct BackfillResult {
+    /// An opaque unique identifier for the XFEFluorescenceSpectrum
+    scan_id: u32,
+    /// The outcome for the scan
+    action: BackfillAction,
+    /// The key of the jpeg found, or of the conventional jpeg if none was found
+    key: Option<String>,
+}
+
+/// The outcome of probing for the conventional jpeg of a scan
+enum Probe {
+    /// The jpeg exists, to be recorded under the path
+    Found {
+        /// The path to record for the jpeg
+        path: String,
+        /// The key under which the jpeg is stored
+        key: ObjectKey,
+    },
+    /// No jpeg exists under any conventional key, the first of which is included
+    NotFound(ObjectKey),
+    /// No key could be derived from the path of the scan file
+    Unresolvable,
+}
+
+/// Matches rows in which the column is `NULL`, empty or only spaces, which the entities treat as absent
+fn null_or_blank(column: Column) -> Condition {
+    Condition::any()
+        .add(column.is_null())
+        .add(Expr::expr(Func::cust(Alias::new("TRIM")).arg(Expr::col((Entity, column)))).eq(""))
+}
+
+/// Records the jpeg path of the scan, unless another writer has recorded one since it was selected
+fn record_jpeg(scan_id: u32, path: String) -> UpdateMany<Entity> {
+    Entity::update_many()
+        .col_expr(Column::JpegScanFileFullPath, Expr::value(path))
+        .filter(Column::XfeFluorescenceSpectrumId.eq(scan_id))
+        .filter(null_or_blank(Column::JpegScanFileFullPath))
+}
+
+/// Probes the conventional jpeg paths of the scan file, in the order the snapshot variants are configured, for the first which exists
+async fn probe(ctx: &Context<'_>, scan_file: Option<String>) -> async_graphql::Result<Probe> {
+    let Some(scan_file) = scan_file else {
+        return Ok(Probe::Unresolvable);
+    };
+    let key_rules = &ctx.data::<ScanFiles>()?.key_rules;
+    let mut conventional = None;
+    for path in ctx.data::<SnapshotVariants>()?.jpeg_paths(&scan_file) {
+        let Ok(key) = ObjectKey::scan_jpeg(key_rules, &path) else {
+            continue;
+        };
+        if object_exists(ctx, &key).await? {
+            return Ok(Probe::Found { path, key });
+        }
+        conventional.get_or_insert(key);
+    }
+    Ok(conventional.map_or(Probe::Unresolvable, Probe::NotFound))
+}
+
+/// Records the conventional jpeg path of each scan in the session with a scan file but no jpeg, where the jpeg exists, up to the configured limit, considering only scans after `after_scan_id` if given
+///
+/// Rows are updated in batches, each within a transaction, so batches committed before a failure remain updated, and their results are reported in the `completed` extension of the error. Scans whose jpeg was not found or whose key could not be derived keep no jpeg path, so are selected again by every invocation; passing the last reported scan as `after_scan_id` continues past them.
+pub async fn backfill_jpeg_paths(
+    ctx: &Context<'_>,
+    session_id: u32,
+    after_scan_id: Option<u32>,
+    dry_run: bool,
+) -> async_graphql::Result<Vec<BackfillResult>> {
+    let BackfillLimit(limit) = *ctx.data::<BackfillLimit>()?;
+    let scans = fetch_scans(
+        ctx,
+        Entity::find()
+            .filter(Column::SessionId.eq(session_id))
+            .apply_if(after_scan_id, |select, after_scan_id| {
+                select.filter(Column::XfeFluorescenceSpectrumId.gt(after_scan_id))
+            })
+            .filter(null_or_blank(Column::JpegScanFileFullPath))
+            .filter(null_or_blank(Column::ScanFileFullPath).not())
+            .order_by_asc(Column::XfeFluorescenceSpectrumId)
+            .limit(limit),
+    )
+    .await?
+    .into_iter()
+    .map(FluorescenceScan::from)
+    .collect::<Vec<_>>();
+    let mut results = Vec::with_capacity(scans.len());
+    for batch in scans.chunks(BATCH_SIZE) {
+        match backfill_batch(ctx, batch, dry_run).await {
+            Ok(batch) => results.extend(batch),
+            Err(err) => return Err(with_completed(err, &results)),
+        }
+    }
+    Ok(results)
+}
+
+/// Probes for the jpeg of each scan in the batch and, unless this is a dry run, records those found within a single transaction
+async fn backfill_batch(
+    ctx: &Context<'_>,
+    batch: &[FluorescenceScan],
+    dry_run: bool,
+) -> async_graphql::Result<Vec<BackfillResult>> {
+    let probes: Vec<_> = stream::iter(
+        batch
+            .iter()
+            .map(|scan| scan.scan_file_full_path.clone())
+            .collect::<Vec<_>>(),
+    )
+    .map(|scan_file| probe(ctx, scan_file))
+    .buffered(MAX_CONCURRENT_PROBES)
+    .try_collect()
+    .await?;
+    let read_only = ctx.data::<ReadOnlyMode>()?;
+    let write_error = |err| read_only.write_error(err);
+    let transaction = if dry_run {
+        None
+    } else {
+        Some(
+            ctx.data::<DatabaseConnection>()?
+                .begin()
+                .await
+                .map_err(write_error)?,
+        )
+    };
+    let mut results = Vec::with_capacity(batch.len());
+    for (scan, probe) in batch.iter().zip(probes) {
+        let (action, key) = match probe {
+            Probe::Found { path, key } => match &transaction {
+                Some(transaction) => {
+                    let updated = record_jpeg(scan.id, path)
+                        .exec(transaction)
+                        .await
+                        .map_err(write_error)?;
+                    if updated.rows_affected > 0 {
+                        (BackfillAction::Updated, Some(key))
+                    } else {
+                        (BackfillAction::Skipped, Some(key))
+                    }
+                }
+                None => (BackfillAction::WouldUpdate, Some(key)),
+            },
+            Probe::NotFound(key) => (BackfillAction::NotFound, Some(key)),
+            Probe::Unresolvable => (BackfillAction::Unresolvable, None),
+        };
+        results.push(BackfillResult {
+            scan_id: scan.id,
+            action,
+            key: key.map(String::from),
+        });
+    }
+    if let Some(transaction) = transaction {
+        transaction.commit().await.map_err(write_error)?;
+    }
+    Ok(results)
+}
+
+/// Attaches the results of the batches completed before the error to it, as the `completed` extension, if there are any
+fn with_completed(err: async_graphql::Error, completed: &[BackfillResult]) -> async_graphql::Error {
+    if completed.is_empty() {
+        return err;
+    }
+    let completed = Value::List(
+        completed
+            .iter()
+            .map(|result| {
+                value!({
+                    "scanId": result.scan_id,
+                    "action": enum_value(result.action),
+                    "key": result.key.clone(),
+                })
+            })
+            .collect(),
+    );
+    err.extend_with(|_, extensions| extensions.set("completed", completed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_jpeg, BATCH_SIZE};
+    use crate::{
+        authorization::{Claims, IspybMembership},
+        fake_database::{model_row, scan, FakeDatabase},
+        object_key::ObjectKeyRules,
+        store::testing::FakeStore,
+        FluorescenceScanService,
+    };
+    use async_graphql::Request;
+    use models::xfe_fluorescence_spectrum::{ActiveModel, Column, Entity, Model};
+    use sea_orm::{
+        ColumnTrait, ConnectOptions, ConnectionTrait, Database, DatabaseConnection, DbBackend,
+        EntityTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait, Schema,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    /// The claims of a member of the administrator group
+    fn admin() -> Claims {
+        Claims {
+            subject: Some(String::from("xyz98765")),
+            groups: vec![String::from("admin")],
+        }
+    }
+
+    /// A service reading from the database and the store, with a policy under which administrators may mutate
+    async fn service(database: &FakeDatabase, store: Arc<FakeStore>) -> FluorescenceScanService {
+        writing_service(database.connect().await, store)
+    }
+
+    /// A service writing to the database and reading from the store, with a policy under which administrators may mutate
+    fn writing_service(
+        database: DatabaseConnection,
+        store: Arc<FakeStore>,
+    ) -> FluorescenceScanService {
+        FluorescenceScanService::builder(database.clone())
+            .scan_file_store(store, ObjectKeyRules::default())
+            .authorization_policy(Arc::new(IspybMembership::new(database, "admin")))
+            .build()
+    }
+
+    /// An in-memory SQLite database holding the scans, on a single connection so that every statement reaches the same database
+    ///
+    /// Proxy connections cannot begin transactions, and sea-orm's mock connections cannot be cloned, so writes are tested against SQLite.
+    async fn sqlite(scans: impl IntoIterator<Item = Model>) -> DatabaseConnection {
+        let mut options = ConnectOptions::new("sqlite::memory:");
+        options.max_connections(1).min_connections(1);
+        let database = Database::connect(options).await.unwrap();
+        let backend = database.get_database_backend();
+        database
+            .execute(backend.build(&Schema::new(backend).create_table_from_entity(Entity)))
+            .await
+            .unwrap();
+        Entity::insert_many(scans.into_iter().map(ActiveModel::from))
+            .exec(&database)
+            .await
+            .unwrap();
+        database
+    }
+
+    /// Scans in session 42 with the ids, each with a scan file but no jpeg path
+    fn unrecorded_scans(ids: impl IntoIterator<Item = u32>) -> impl Iterator<Item = Model> {
+        ids.into_iter().map(|id| {
+            scan(
+                id,
+                42,
+                Some(&format!("/dls/i18/data/2024/cm1-1/{id}.dat")),
+                None,
+            )
+        })
+    }
+
+    /// A store holding the conventional jpegs of the scans with the ids
+    fn jpegs(ids: impl IntoIterator<Item = u32>) -> Arc<FakeStore> {
+        let keys = ids
+            .into_iter()
+            .map(|id| format!("/dls/i18/data/2024/cm1-1/{id}.jpg"))
+            .collect::<Vec<_>>();
+        FakeStore::new(keys.iter().map(|key| (key.as_str(), &b""[..])))
+    }
+
+    /// The ids of the scans whose jpeg paths are recorded in the database
+    async fn recorded(database: &DatabaseConnection) -> Vec<u32> {
+        Entity::find()
+            .select_only()
+            .column(Column::XfeFluorescenceSpectrumId)
+            .filter(Column::JpegScanFileFullPath.is_not_null())
+            .order_by_asc(Column::XfeFluorescenceSpectrumId)
+            .into_tuple()
+            .all(database)
+            .await
+            .unwrap()
+    }
+
+    /// The ids and actions of the results in the list
+    fn actions(results: &Value) -> Vec<(u64, String)> {
+        results
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|result| {
+                (
+                    result["scanId"].as_u64().unwrap(),
+                    result["action"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// Backfills the scans of session 42, optionally after a scan, as an administrator
+    const BACKFILL: &str = "mutation ($after: Int) { backfillJpegPaths(sessionId: 42, afterScanId: $after, dryRun: false) { scanId action key } }";
+
+    /// Executes the backfill of session 42 after the scan, if any, as an administrator
+    async fn backfill(service: &FluorescenceScanService, after: Option<u32>) -> Value {
+        serde_json::to_value(
+            service
+                .schema()
+                .execute(
+                    Request::new(BACKFILL)
+                        .variables(async_graphql::Variables::from_json(
+                            json!({ "after": after }),
+                        ))
+                        .data(admin()),
+                )
+                .await,
+        )
+        .unwrap()
+    }
+
+    /// Executes the query as the holder of the claims, producing the response as JSON
+    async fn execute(service: &FluorescenceScanService, query: &str, claims: Claims) -> Value {
+        serde_json::to_value(
+            service
+                .schema()
+                .execute(Request::new(query).data(claims))
+                .await,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn allow_all_refuses_anonymous_backfills() {
+        let database = FakeDatabase::with_results([]);
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        let response = execute(
+            &service,
+            "mutation { backfillJpegPaths(sessionId: 42, dryRun: false) { scanId } }",
+            Claims::default(),
+        )
+        .await;
+        assert_eq!(response["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        assert!(database.queries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_reports_found_jpegs_without_writing() {
+        let database = FakeDatabase::with_results([vec![
+            model_row(&scan(
+                7,
+                42,
+                Some("/dls/i18/data/2024/cm1-1/scan.dat"),
+                None,
+            )),
+            model_row(&scan(
+                9,
+                42,
+                Some("/dls/i18/data/2024/cm1-1/other.dat"),
+                None,
+            )),
+        ]]);
+        let store = FakeStore::new([("/dls/i18/data/2024/cm1-1/scan.jpg", &b""[..])]);
+        let service = service(&database, store.clone()).await;
+        let response = execute(
+            &service,
+            "mutation { backfillJpegPaths(sessionId: 42, dryRun: true) { scanId action key } }",
+            admin(),
+        )
+        .await;
+        assert_eq!(
+            response["data"]["backfillJpegPaths"],
+            json!([
+                {
+                    "scanId": 7,
+                    "action": "WOULD_UPDATE",
+                    "key": "/dls/i18/data/2024/cm1-1/scan.jpg",
+                },
+                {
+                    "scanId": 9,
+                    "action": "NOT_FOUND",
+                    "key": "/dls/i18/data/2024/cm1-1/other.jpg",
+                },
+            ])
+        );
+        assert_eq!(store.heads(), 2);
+        assert_eq!(database.queries().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn backfill_selects_scans_with_blank_jpegs_after_the_cursor() {
+        let database = FakeDatabase::with_results([]);
+        let service = service(&database, FakeStore::new([])).await;
+        let response = execute(
+            &service,
+            "mutation { backfillJpegPaths(sessionId: 42, afterScanId: 9, dryRun: true) { scanId } }",
+            admin(),
+        )
+        .await;
+        assert_eq!(response["data"]["backfillJpegPaths"], json!([]));
+        let queries = database.queries();
+        assert_eq!(queries.len(), 1);
+        assert!(queries[0].contains("`xfeFluorescenceSpectrumId` > 9"));
+        assert!(queries[0].contains(
+            "(`XFEFluorescenceSpectrum`.`jpegScanFileFullPath` IS NULL OR TRIM(`XFEFluorescenceSpectrum`.`jpegScanFileFullPath`) = '')"
+        ));
+        assert!(queries[0].contains(
+            "(NOT (`XFEFluorescenceSpectrum`.`scanFileFullPath` IS NULL OR TRIM(`XFEFluorescenceSpectrum`.`scanFileFullPath`) = ''))"
+        ));
+    }
+
+    #[test]
+    fn recording_a_jpeg_leaves_rows_given_one_by_another_writer() {
+        assert_eq!(
+            record_jpeg(7, String::from("/dls/i18/data/2024/cm1-1/scan.jpg"))
+                .build(DbBackend::MySql)
+                .to_string(),
+            "UPDATE `XFEFluorescenceSpectrum` SET `jpegScanFileFullPath` = '/dls/i18/data/2024/cm1-1/scan.jpg' \
+             WHERE `XFEFluorescenceSpectrum`.`xfeFluorescenceSpectrumId` = 7 \
+             AND (`XFEFluorescenceSpectrum`.`jpegScanFileFullPath` IS NULL OR TRIM(`XFEFluorescenceSpectrum`.`jpegScanFileFullPath`) = '')"
+        );
+    }
+
+    #[tokio::test]
+    async fn found_jpegs_are_recorded_and_others_reported() {
+        let database = sqlite(unrecorded_scans(1..=3)).await;
+        let service = writing_service(database.clone(), jpegs([1, 3]));
+        let response = backfill(&service, None).await;
+        assert_eq!(
+            response["data"]["backfillJpegPaths"],
+            json!([
+                { "scanId": 1, "action": "UPDATED", "key": "/dls/i18/data/2024/cm1-1/1.jpg" },
+                { "scanId": 2, "action": "NOT_FOUND", "key": "/dls/i18/data/2024/cm1-1/2.jpg" },
+                { "scanId": 3, "action": "UPDATED", "key": "/dls/i18/data/2024/cm1-1/3.jpg" },
+            ]),
+            "{response}"
+        );
+        assert_eq!(recorded(&database).await, [1, 3]);
+        assert_eq!(
+            Entity::find_by_id(3_u32)
+                .one(&database)
+                .await
+                .unwrap()
+                .unwrap()
+                .jpeg_scan_file_full_path
+                .as_deref(),
+            Some("/dls/i18/data/2024/cm1-1/3.jpg")
+        );
+    }
+
+    #[tokio::test]
+    async fn rows_recorded_by_another_writer_are_skipped() {
+        let database = sqlite(unrecorded_scans(1..=3)).await;
+        // Ignoring the update of a row leaves it unaffected, as if another writer had recorded its jpeg first
+        database
+            .execute_unprepared(
+                "CREATE TRIGGER other_writer BEFORE UPDATE ON XFEFluorescenceSpectrum \
+                 WHEN OLD.xfeFluorescenceSpectrumId = 2 BEGIN SELECT RAISE(IGNORE); END",
+            )
+            .await
+            .unwrap();
+        let service = writing_service(database.clone(), jpegs(1..=3));
+        let response = backfill(&service, None).await;
+        assert_eq!(
+            actions(&response["data"]["backfillJpegPaths"]),
+            [
+                (1, String::from("UPDATED")),
+                (2, String::from("SKIPPED")),
+                (3, String::from("UPDATED")),
+            ],
+            "{response}"
+        );
+        assert_eq!(recorded(&database).await, [1, 3]);
+    }
+
+    #[tokio::test]
+    async fn each_batch_is_committed_separately() {
+        let count = 2 * BATCH_SIZE as u32 + 20;
+        let failing = 2 * BATCH_SIZE as u32 + 10;
+        let database = sqlite(unrecorded_scans(1..=count)).await;
+        database
+            .execute_unprepared(&format!(
+                "CREATE TRIGGER refuse BEFORE UPDATE ON XFEFluorescenceSpectrum \
+                 WHEN OLD.xfeFluorescenceSpectrumId = {failing} BEGIN SELECT RAISE(ABORT, 'Refused'); END"
+            ))
+            .await
+            .unwrap();
+        let service = writing_service(database.clone(), jpegs(1..=count));
+        let response = backfill(&service, None).await;
+        assert_eq!(response["data"], Value::Null);
+        assert!(
+            response["errors"][0]["message"]
+                .as_str()
+                .unwrap()
+                .contains("Refused"),
+            "{response}"
+        );
+        let completed = actions(&response["errors"][0]["extensions"]["completed"]);
+        assert_eq!(
+            completed,
+            (1..=2 * BATCH_SIZE as u64)
+                .map(|id| (id, String::from("UPDATED")))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            recorded(&database).await,
+            (1..=2 * BATCH_SIZE as u32).collect::<Vec<_>>()
+        );
+
+        database
+            .execute_unprepared("DROP TRIGGER refuse")
+            .await
+            .unwrap();
+        let continued = backfill(&service, Some(2 * BATCH_SIZE as u32)).await;
+        assert_eq!(
+            actions(&continued["data"]["backfillJpegPaths"]).len(),
+            20,
+            "{continued}"
+        );
+        assert_eq!(recorded(&database).await, (1..=count).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn failures_before_any_batch_is_committed_report_no_results() {
+        let database = sqlite(unrecorded_scans(1..=3)).await;
+        database
+            .execute_unprepared(
+                "CREATE TRIGGER refuse BEFORE UPDATE ON XFEFluorescenceSpectrum \
+                 BEGIN SELECT RAISE(ABORT, 'Refused'); END",
+            )
+            .await
+            .unwrap();
+        let service = writing_service(database.clone(), jpegs(1..=3));
+        let response = backfill(&service, None).await;
+        assert!(
+            response["errors"][0]["extensions"]["completed"].is_null(),
+            "{response}"
+        );
+        assert!(recorded(&database).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invocations_backfill_at_most_the_limit() {
+        let database = sqlite(unrecorded_scans(1..=501)).await;
+        let service = writing_service(database.clone(), jpegs(1..=501));
+        let response = backfill(&service, None).await;
+        let results = actions(&response["data"]["backfillJpegPaths"]);
+        assert_eq!(results.len(), 500, "{response}");
+        assert_eq!(results.last(), Some(&(500, String::from("UPDATED"))));
+        assert_eq!(recorded(&database).await.len(), 500);
+
+        let continued = backfill(&service, Some(500)).await;
+        assert_eq!(
+            actions(&continued["data"]["backfillJpegPaths"]),
+            [(501, String::from("UPDATED"))]
+        );
+        assert_eq!(recorded(&database).await.len(), 501);
+    }
+}
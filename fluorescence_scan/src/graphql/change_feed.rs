@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use derive_more::{Display, Error};
+use models::xfe_fluorescence_spectrum;
+use sea_orm::{sea_query::Expr, ColumnTrait, Condition};
+use std::time::Duration;
+
+/// The version tag of the current cursor encoding
+const CURSOR_VERSION: &str = "v1";
+
+/// The settle interval used when none is configured
+pub const DEFAULT_SETTLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The period for which rows remain unsettled after being recorded
+///
+/// Rows are only returned once their timestamp is older than this, so that a row recorded by a transaction which commits after a cursor has been issued is not ordered before it. Each row is therefore delivered at least once only if the transaction recording it commits within the interval of its timestamp; rows committed later than that may be skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct SettleInterval(pub Duration);
+
+/// An error produced when a change feed cursor cannot be decoded
+#[derive(Debug, Display, Error)]
+pub enum CursorError {
+    /// The cursor was produced by an incompatible encoding
+    #[display(fmt = "Cursor version is not supported, restart the feed without a cursor")]
+    UnsupportedVersion,
+    /// The cursor was not produced by this service
+    #[display(fmt = "Cursor is malformed")]
+    Malformed,
+}
+
+/// A position within the change feed of a session, ordered by record timestamp then id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangeCursor {
+    /// The record timestamp of the last returned row
+    timestamp: DateTime<Utc>,
+    /// The id of the last returned row
+    id: u32,
+}
+
+impl ChangeCursor {
+    /// The position preceding every row
+    pub fn start() -> Self {
+        Self {
+            timestamp: DateTime::UNIX_EPOCH,
+            id: 0,
+        }
+    }
+
+    /// The position immediately following the supplied row
+    pub fn after(row: &xfe_fluorescence_spectrum::Model) -> Self {
+        Self {
+            timestamp: row.record_time_stamp,
+            id: row.xfe_fluorescence_spectrum_id,
+        }
+    }
+
+    /// Decodes a cursor previously produced by [`ChangeCursor::encode`]
+    pub fn decode(cursor: &str) -> Result<Self, CursorError> {
+        let mut parts = cursor.split(':');
+        match parts.next() {
+            Some(CURSOR_VERSION) => {}
+            Some(version) if is_version_tag(version) => {
+                return Err(CursorError::UnsupportedVersion)
+            }
+            _ => return Err(CursorError::Malformed),
+        }
+        let (Some(timestamp), Some(id), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(CursorError::Malformed);
+        };
+        let timestamp = timestamp
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or(CursorError::Malformed)?;
+        let id = id.parse().map_err(|_| CursorError::Malformed)?;
+        Ok(Self { timestamp, id })
+    }
+
+    /// Encodes the cursor in the current, versioned, format
+    pub fn encode(&self) -> String {
+        format!(
+            "{CURSOR_VERSION}:{}:{}",
+            self.timestamp.timestamp_micros(),
+            self.id
+        )
+    }
+
+    /// A condition selecting the rows which follow this position and have been recorded for longer than the settle interval
+    pub fn following(&self, SettleInterval(settle_interval): SettleInterval) -> Condition {
+        Condition::all()
+            .add(
+                Condition::any()
+                    .add(xfe_fluorescence_spectrum::Column::RecordTimeStamp.gt(self.timestamp))
+                    .add(
+                        Condition::all()
+                            .add(
+                                xfe_fluorescence_spectrum::Column::RecordTimeStamp
+                                    .eq(self.timestamp),
+                            )
+                            .add(
+                                xfe_fluorescence_spectrum::Column::XfeFluorescenceSpectrumId
+                                    .gt(self.id),
+                            ),
+                    ),
+            )
+            .add(
+                Expr::col(xfe_fluorescence_spectrum::Column::RecordTimeStamp).lt(Expr::cust(
+                    format!(
+                        "CURRENT_TIMESTAMP - INTERVAL {} SECOND",
+                        settle_interval.as_secs()
+                    ),
+                )),
+            )
+    }
+}
+
+/// Whether the cursor segment has the form of a version tag, a `v` followed by digits
+fn is_version_tag(segment: &str) -> bool {
+    segment.strip_prefix('v').is_some_and(|number| {
+        !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChangeCursor, CursorError, SettleInterval};
+    use crate::{
+        fake_database::{model_row, scan, FakeDatabase},
+        FluorescenceScanService,
+    };
+    use chrono::DateTime;
+    use models::xfe_fluorescence_spectrum::Entity;
+    use sea_orm::{DbBackend, EntityTrait, QueryFilter, QueryTrait};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    /// A scan of session 42 recorded at the number of seconds after the epoch
+    fn recorded(id: u32, seconds: i64) -> models::xfe_fluorescence_spectrum::Model {
+        let mut scan = scan(id, 42, None, None);
+        scan.record_time_stamp = DateTime::from_timestamp(seconds, 0).unwrap();
+        scan
+    }
+
+    /// Fetches a page of the change feed of session 42 from the cursor
+    async fn changes(database: &FakeDatabase, cursor: Option<&str>, limit: u64) -> Value {
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        let query = format!(
+            "{{ fluorescenceScanChanges(sessionId: 42, cursor: {}, limit: {limit}) {{ changes {{ id }} nextCursor hasMore }} }}",
+            serde_json::to_string(&cursor).unwrap()
+        );
+        let response = serde_json::to_value(service.schema().execute(query).await).unwrap();
+        response["data"]["fluorescenceScanChanges"].clone()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = ChangeCursor::after(&recorded(7, 1_700_000_000));
+        assert_eq!(ChangeCursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert_eq!(cursor.encode(), "v1:1700000000000000:7");
+    }
+
+    #[test]
+    fn empty_and_garbled_cursors_are_malformed() {
+        for cursor in [
+            "", ":", "garbage", "v1", "v1:", "v1:abc:7", "v1:0:-1", "v1:0:7:8", "v:0:7",
+        ] {
+            assert!(
+                matches!(ChangeCursor::decode(cursor), Err(CursorError::Malformed)),
+                "{cursor:?} was not malformed"
+            );
+        }
+    }
+
+    #[test]
+    fn cursors_of_other_versions_are_unsupported() {
+        assert!(matches!(
+            ChangeCursor::decode("v2:1700000000000000:7"),
+            Err(CursorError::UnsupportedVersion)
+        ));
+    }
+
+    #[test]
+    fn rows_sharing_a_timestamp_follow_by_id() {
+        let sql = Entity::find()
+            .filter(
+                ChangeCursor::after(&recorded(7, 1_700_000_000))
+                    .following(SettleInterval(Duration::from_secs(5))),
+            )
+            .build(DbBackend::MySql)
+            .to_string();
+        assert!(sql.contains(
+            "(`XFEFluorescenceSpectrum`.`recordTimeStamp` > '2023-11-14 22:13:20 +00:00' \
+             OR (`XFEFluorescenceSpectrum`.`recordTimeStamp` = '2023-11-14 22:13:20 +00:00' \
+             AND `XFEFluorescenceSpectrum`.`xfeFluorescenceSpectrumId` > 7))"
+        ));
+        assert!(sql.contains("`recordTimeStamp` < (CURRENT_TIMESTAMP - INTERVAL 5 SECOND)"));
+    }
+
+    #[tokio::test]
+    async fn a_page_ending_within_a_timestamp_resumes_after_its_last_row() {
+        let database = FakeDatabase::with_results([vec![
+            model_row(&recorded(7, 1_700_000_000)),
+            model_row(&recorded(9, 1_700_000_000)),
+        ]]);
+        let page = changes(&database, None, 1).await;
+        assert_eq!(
+            page,
+            json!({
+                "changes": [{ "id": 7 }],
+                "nextCursor": "v1:1700000000000000:7",
+                "hasMore": true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn an_empty_page_keeps_the_cursor() {
+        let database = FakeDatabase::with_results([]);
+        let page = changes(&database, Some("v1:1700000000000000:7"), 10).await;
+        assert_eq!(
+            page,
+            json!({
+                "changes": [],
+                "nextCursor": "v1:1700000000000000:7",
+                "hasMore": false,
+            })
+        );
+        let page = changes(&database, None, 10).await;
+        assert_eq!(page["nextCursor"], ChangeCursor::start().encode());
+    }
+}
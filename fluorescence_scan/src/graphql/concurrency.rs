@@ -0,0 +1,236 @@
+use async_graphql::ErrorExtensions;
+use opentelemetry::{
+    metrics::{Counter, MeterProvider, UpDownCounter},
+    KeyValue,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::built_info;
+
+/// The number of expensive resolvers which may run concurrently for one client, unless configured otherwise
+pub const DEFAULT_PER_CLIENT_CONCURRENCY: usize = 4;
+
+/// The period for which excess work waits for a running resolver to finish before being rejected
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Identifies a websocket connection, so that the subscriptions of unauthenticated clients can be limited per connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Allocates an identifier distinct from that of every other connection served by this process
+    pub fn next() -> Self {
+        /// The identifier of the next connection
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The client on whose behalf an expensive resolver runs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    /// Work on behalf of the authenticated principal with the subject, whichever sessions it concerns
+    Principal(String),
+    /// Work on behalf of an unauthenticated client over a websocket connection
+    Connection(ConnectionId),
+    /// Work concerning a session, used in the absence of an authenticated principal
+    Session(u32),
+}
+
+impl ClientKey {
+    /// The class of the key, used to label metrics without exposing individual clients
+    fn class(&self) -> &'static str {
+        match self {
+            Self::Principal(_) => "principal",
+            Self::Connection(_) => "connection",
+            Self::Session(_) => "session",
+        }
+    }
+}
+
+/// The semaphores of the clients with work running or queued
+type Semaphores = Arc<Mutex<HashMap<ClientKey, Arc<Semaphore>>>>;
+
+/// Limits the number of expensive resolvers which may run concurrently on behalf of the same client, so that one client cannot occupy every database connection
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    /// The number of resolvers which may run concurrently for each client
+    limit: usize,
+    /// The semaphores of the clients with work running or queued
+    semaphores: Semaphores,
+    /// The number of resolvers waiting for a permit, by key class
+    queued: UpDownCounter<i64>,
+    /// The count of resolvers rejected after waiting, by key class
+    rejected: Counter<u64>,
+}
+
+impl ConcurrencyLimiter {
+    /// Creates a limiter permitting `limit` concurrent resolvers per client, recording metrics using the supplied meter provider
+    pub fn new(limit: usize, meter_provider: &impl MeterProvider) -> Self {
+        let meter = meter_provider.meter(built_info::PKG_NAME);
+        Self {
+            limit,
+            semaphores: Arc::default(),
+            queued: meter
+                .i64_up_down_counter("graphql.concurrency.queued")
+                .with_description(
+                    "Expensive resolvers waiting for another of the same client to finish",
+                )
+                .init(),
+            rejected: meter
+                .u64_counter("graphql.concurrency.rejected")
+                .with_description("Expensive resolvers rejected as the client had too many running")
+                .init(),
+        }
+    }
+
+    /// Waits briefly for a permit to run an expensive resolver for the client, producing a `RATE_LIMITED` error if none becomes available
+    pub async fn acquire(&self, key: ClientKey) -> async_graphql::Result<ConcurrencyPermit> {
+        let attributes = [KeyValue::new("key_class", key.class())];
+        // Declared before the semaphore is cloned, so that it is dropped last and collects the entry of the client however the wait ends, including by cancellation
+        let mut permit = ConcurrencyPermit {
+            key,
+            permit: None,
+            semaphores: self.semaphores.clone(),
+        };
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(permit.key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        permit.permit = match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                let waiting = tokio::time::timeout(QUEUE_TIMEOUT, semaphore.acquire_owned());
+                let _queued = Queued::new(&self.queued, &attributes);
+                waiting.await.ok().and_then(Result::ok)
+            }
+        };
+        if permit.permit.is_none() {
+            self.rejected.add(1, &attributes);
+            return Err(
+                async_graphql::Error::new("Too many concurrent requests for this client")
+                    .extend_with(|_, extensions| extensions.set("code", "RATE_LIMITED")),
+            );
+        }
+        Ok(permit)
+    }
+}
+
+/// A resolver counted as queued until dropped, whether it stops waiting by acquiring a permit, timing out or being cancelled
+struct Queued<'a> {
+    /// The number of resolvers waiting for a permit, by key class
+    queued: &'a UpDownCounter<i64>,
+    /// The key class of the client of the resolver
+    attributes: &'a [KeyValue],
+}
+
+impl<'a> Queued<'a> {
+    /// Counts a resolver of the key class as queued
+    fn new(queued: &'a UpDownCounter<i64>, attributes: &'a [KeyValue]) -> Self {
+        queued.add(1, attributes);
+        Self { queued, attributes }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.add(-1, self.attributes);
+    }
+}
+
+/// Permission to run an expensive resolver, released when dropped
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    /// The client on whose behalf the resolver runs
+    key: ClientKey,
+    /// The permit held from the semaphore of the client, if one was acquired
+    permit: Option<OwnedSemaphorePermit>,
+    /// The semaphores of the clients with work running or queued
+    semaphores: Semaphores,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.permit.take();
+        let mut semaphores = self.semaphores.lock().unwrap();
+        // Semaphores are only cloned whilst locked, so one held solely by the map has no work running or queued
+        if semaphores
+            .get(&self.key)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            semaphores.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientKey, ConcurrencyLimiter};
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+
+    #[tokio::test]
+    async fn principal_is_limited_across_sessions() {
+        let limiter = ConcurrencyLimiter::new(1, &NoopMeterProvider::new());
+        let principal = ClientKey::Principal(String::from("abc12345"));
+        let _permit = limiter.acquire(principal.clone()).await.unwrap();
+        let rejected = limiter.acquire(principal).await.unwrap_err();
+        assert_eq!(
+            rejected.extensions.unwrap().get("code"),
+            Some(&async_graphql::Value::from("RATE_LIMITED"))
+        );
+    }
+
+    #[tokio::test]
+    async fn clients_are_limited_independently() {
+        let limiter = ConcurrencyLimiter::new(1, &NoopMeterProvider::new());
+        let _first = limiter
+            .acquire(ClientKey::Principal(String::from("abc12345")))
+            .await
+            .unwrap();
+        let _second = limiter
+            .acquire(ClientKey::Principal(String::from("def67890")))
+            .await
+            .unwrap();
+        let _session = limiter.acquire(ClientKey::Session(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn released_permits_are_reusable() {
+        let limiter = ConcurrencyLimiter::new(1, &NoopMeterProvider::new());
+        drop(limiter.acquire(ClientKey::Session(1)).await.unwrap());
+        let _permit = limiter.acquire(ClientKey::Session(1)).await.unwrap();
+        assert_eq!(limiter.semaphores.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waits_release_the_entry_of_the_client() {
+        let limiter = ConcurrencyLimiter::new(1, &NoopMeterProvider::new());
+        let running = limiter.acquire(ClientKey::Session(1)).await.unwrap();
+        let mut waiting = Box::pin(limiter.acquire(ClientKey::Session(1)));
+        assert!(futures::poll!(waiting.as_mut()).is_pending());
+        drop(running);
+        assert_eq!(limiter.semaphores.lock().unwrap().len(), 1);
+        drop(waiting);
+        assert!(limiter.semaphores.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejected_waits_release_the_entry_of_the_client() {
+        let limiter = ConcurrencyLimiter::new(1, &NoopMeterProvider::new());
+        let running = limiter.acquire(ClientKey::Session(1)).await.unwrap();
+        assert!(limiter.acquire(ClientKey::Session(1)).await.is_err());
+        drop(running);
+        assert!(limiter.semaphores.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use async_graphql::{
+    parser::types::Field,
+    registry::{MetaType, Registry},
+    ContainerType, Context, ContextSelectionSet, ObjectType, OutputType, Positioned, Response,
+    ServerResult, SubscriptionType, Value,
+};
+use futures::Stream;
+use std::{borrow::Cow, cell::RefCell, pin::Pin};
+
+thread_local! {
+    /// The contract to which the schema being built on this thread is restricted, if any
+    static BUILDING_CONTRACT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Restores the contract which was being built on this thread when dropped
+struct ContractGuard(Option<String>);
+
+impl Drop for ContractGuard {
+    fn drop(&mut self) {
+        BUILDING_CONTRACT.with(|contract| *contract.borrow_mut() = self.0.take());
+    }
+}
+
+/// Builds a schema whose root types restrict it to the fields tagged for the contract, or to every field if there is no contract
+///
+/// The registry of a schema is only populated whilst [`async_graphql::Schema::build`] runs, so the contract is made available to the root types for the duration of the build.
+pub fn with_contract<T>(contract: Option<&str>, build: impl FnOnce() -> T) -> T {
+    let _guard = ContractGuard(
+        BUILDING_CONTRACT.with(|building| building.replace(contract.map(String::from))),
+    );
+    build()
+}
+
+/// The contract to which the schema being built on this thread is restricted
+fn building_contract() -> Option<String> {
+    BUILDING_CONTRACT.with(|contract| contract.borrow().clone())
+}
+
+/// Checks whether the field belongs to the contract, which every introspection and federation field does
+fn in_contract(name: &str, tags: &[String], contract: &str) -> bool {
+    name.starts_with('_') || tags.iter().any(|tag| tag == contract)
+}
+
+/// Checks whether a root type would have no fields once restricted to the contract being built, if any
+fn excluded_by_contract(type_name: &str, create_type_info: fn(&mut Registry) -> String) -> bool {
+    let Some(contract) = building_contract() else {
+        return false;
+    };
+    let mut registry = Registry::default();
+    create_type_info(&mut registry);
+    !matches!(
+        registry.types.get(type_name),
+        Some(MetaType::Object { fields, .. })
+            if fields.iter().any(|(name, field)| in_contract(name, &field.tags, &contract))
+    )
+}
+
+/// Removes every field not tagged for the contract being built, if any, from the object types registered so far
+///
+/// Types reachable only through removed fields are subsequently dropped from the registry, so they can neither be introspected nor selected.
+fn restrict(registry: &mut Registry) {
+    let Some(contract) = building_contract() else {
+        return;
+    };
+    for ty in registry.types.values_mut() {
+        if let MetaType::Object { name, fields, .. } = ty {
+            if !name.starts_with('_') {
+                fields.retain(|name, field| in_contract(name, &field.tags, &contract));
+            }
+        }
+    }
+}
+
+/// A root type of the schema, exposing only the fields of the contract the schema was built with
+///
+/// Fields outside the contract are absent from the registry, so selecting one fails validation as an unknown field rather than merely being hidden from introspection. Subscription fields cannot be tagged, so a contract excludes every subscription.
+#[derive(Debug, Clone, Default)]
+pub struct ContractRoot<T>(pub T);
+
+impl<T: OutputType> OutputType for ContractRoot<T> {
+    fn type_name() -> Cow<'static, str> {
+        T::type_name()
+    }
+
+    fn create_type_info(registry: &mut Registry) -> String {
+        let type_name = T::create_type_info(registry);
+        restrict(registry);
+        type_name
+    }
+
+    async fn resolve(
+        &self,
+        ctx: &ContextSelectionSet<'_>,
+        field: &Positioned<Field>,
+    ) -> ServerResult<Value> {
+        self.0.resolve(ctx, field).await
+    }
+}
+
+impl<T: ContainerType> ContainerType for ContractRoot<T> {
+    fn is_empty() -> bool {
+        T::is_empty() || excluded_by_contract(&T::type_name(), T::create_type_info)
+    }
+
+    async fn resolve_field(&self, ctx: &Context<'_>) -> ServerResult<Option<Value>> {
+        self.0.resolve_field(ctx).await
+    }
+
+    async fn find_entity(&self, ctx: &Context<'_>, params: &Value) -> ServerResult<Option<Value>> {
+        self.0.find_entity(ctx, params).await
+    }
+}
+
+impl<T: ObjectType> ObjectType for ContractRoot<T> {}
+
+impl<T: SubscriptionType> SubscriptionType for ContractRoot<T> {
+    fn type_name() -> Cow<'static, str> {
+        T::type_name()
+    }
+
+    fn create_type_info(registry: &mut Registry) -> String {
+        let type_name = T::create_type_info(registry);
+        restrict(registry);
+        type_name
+    }
+
+    fn is_empty() -> bool {
+        T::is_empty() || excluded_by_contract(&T::type_name(), T::create_type_info)
+    }
+
+    fn create_field_stream<'a>(
+        &'a self,
+        ctx: &'a Context<'_>,
+    ) -> Option<Pin<Box<dyn Stream<Item = Response> + Send + 'a>>> {
+        self.0.create_field_stream(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::graphql::root_schema_builder;
+
+    /// A query selecting a field of the public contract through one outside of it
+    const DIAGNOSTICS_QUERY: &str =
+        "{ fluorescenceScansBySession(sessionIds: [1]) { sessionId scans { id downloadDiagnostics { family } } } }";
+
+    #[test]
+    fn contract_removes_untagged_fields_and_their_types() {
+        let full = root_schema_builder(None).finish().sdl();
+        let public = root_schema_builder(Some("public")).finish().sdl();
+        for untagged in [
+            "fluorescenceScanPage",
+            "downloadDiagnostics",
+            "proposalCode",
+            "type FluorescenceScanPage",
+            "backfillJpegPaths",
+            "type Mutation",
+            "liveSpectrum",
+            "type Subscription",
+        ] {
+            assert!(
+                full.contains(untagged),
+                "{untagged} missing from full schema"
+            );
+            assert!(
+                !public.contains(untagged),
+                "{untagged} present in public schema"
+            );
+        }
+        for tagged in [
+            "fluorescenceScanChanges",
+            "fluorescenceScansBySession",
+            "jpegScanUrl",
+            "scanFileUrl",
+            "snapshots",
+            "_entities",
+        ] {
+            assert!(full.contains(tagged), "{tagged} missing from full schema");
+            assert!(
+                public.contains(tagged),
+                "{tagged} missing from public schema"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fields_outside_the_contract_cannot_be_executed() {
+        let public = root_schema_builder(Some("public")).finish();
+        let response = public.execute(DIAGNOSTICS_QUERY).await;
+        assert_eq!(response.errors.len(), 1);
+        assert!(response.errors[0]
+            .message
+            .starts_with(r#"Unknown field "downloadDiagnostics""#));
+        let response = public
+            .execute("mutation { backfillJpegPaths(sessionId: 1, dryRun: true) { scanId } }")
+            .await;
+        assert!(!response.errors.is_empty());
+        let full = root_schema_builder(None).finish();
+        let response = full.execute(DIAGNOSTICS_QUERY).await;
+        assert!(response
+            .errors
+            .iter()
+            .all(|error| !error.message.starts_with("Unknown field")));
+    }
+}
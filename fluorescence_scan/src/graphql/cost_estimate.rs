@@ -0,0 +1,190 @@
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextExecute, NextPrepareRequest,
+        NextRequest, NextValidation,
+    },
+    value, ObjectType, Request, Response, SchemaBuilder, ServerError, ServerResult,
+    SubscriptionType, ValidationResult, Value,
+};
+use clap::Parser;
+use std::sync::{Arc, Mutex};
+
+use super::selection_limits::SelectionLimits;
+
+/// The request extension field which requests an estimate in place of execution
+pub const ESTIMATE_COST_EXTENSION: &str = "estimateCost";
+
+/// The maximum page size used when none is configured
+const DEFAULT_MAX_PAGE_SIZE: u64 = 1000;
+
+/// The maximum number of items returned by a single page of a paginated field
+#[derive(Debug, Clone, Copy)]
+pub struct MaxPageSize(pub u64);
+
+/// Limits on the cost of the queries accepted by the service
+#[derive(Debug, Clone, Copy, Parser)]
+pub struct QueryLimits {
+    /// The maximum depth of an accepted query
+    #[arg(long, env)]
+    query_depth_limit: Option<usize>,
+    /// The maximum complexity of an accepted query
+    #[arg(long, env)]
+    query_complexity_limit: Option<usize>,
+    /// The maximum number of items returned by a single page of a paginated field
+    #[arg(long, env, default_value_t = DEFAULT_MAX_PAGE_SIZE)]
+    max_page_size: u64,
+    /// Limits on the aliases, repeated fields, directives and selections of an accepted operation
+    #[command(flatten)]
+    selection_limits: SelectionLimits,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            query_depth_limit: None,
+            query_complexity_limit: None,
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            selection_limits: SelectionLimits::default(),
+        }
+    }
+}
+
+impl QueryLimits {
+    /// Creates limits with the supplied maximum depth, complexity and page size
+    pub fn new(
+        query_depth_limit: Option<usize>,
+        query_complexity_limit: Option<usize>,
+        max_page_size: u64,
+    ) -> Self {
+        Self {
+            query_depth_limit,
+            query_complexity_limit,
+            max_page_size,
+            selection_limits: SelectionLimits::default(),
+        }
+    }
+
+    /// Replaces the limits on the aliases, repeated fields, directives and selections of an operation
+    pub fn selection_limits(mut self, selection_limits: SelectionLimits) -> Self {
+        self.selection_limits = selection_limits;
+        self
+    }
+
+    /// Enforces the limits on the schema and allows clients to request estimates against them
+    pub fn apply<Query, Mutation, Subscription>(
+        self,
+        mut builder: SchemaBuilder<Query, Mutation, Subscription>,
+    ) -> SchemaBuilder<Query, Mutation, Subscription>
+    where
+        Query: ObjectType + 'static,
+        Mutation: ObjectType + 'static,
+        Subscription: SubscriptionType + 'static,
+    {
+        if let Some(depth) = self.query_depth_limit {
+            builder = builder.limit_depth(depth);
+        }
+        if let Some(complexity) = self.query_complexity_limit {
+            builder = builder.limit_complexity(complexity);
+        }
+        builder
+            .data(MaxPageSize(self.max_page_size))
+            .extension(self.selection_limits)
+            .extension(CostEstimate(self))
+    }
+}
+
+/// An extension which, when the request sets the `estimateCost` extension field, reports the depth and complexity of the query and the applicable limits without executing any resolver
+///
+/// Paginated fields are costed assuming the requested page size is returned in full.
+#[derive(Debug)]
+struct CostEstimate(QueryLimits);
+
+impl ExtensionFactory for CostEstimate {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(CostEstimateExtension {
+            limits: self.0,
+            estimate_only: Mutex::default(),
+            validation_result: Mutex::default(),
+        })
+    }
+}
+
+/// The per-request state of the [`CostEstimate`] extension
+#[derive(Debug)]
+struct CostEstimateExtension {
+    /// The limits enforced by the schema
+    limits: QueryLimits,
+    /// Whether the request asked for an estimate in place of execution
+    estimate_only: Mutex<bool>,
+    /// The depth and complexity computed during validation
+    validation_result: Mutex<Option<ValidationResult>>,
+}
+
+#[async_trait]
+impl Extension for CostEstimateExtension {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        let response = next.run(ctx).await;
+        let estimate_only = *self.estimate_only.lock().unwrap();
+        let validation_result = self.validation_result.lock().unwrap().take();
+        match validation_result {
+            Some(validation_result) if estimate_only => response.extension(
+                "cost",
+                value!({
+                    "depth": validation_result.depth,
+                    "complexity": validation_result.complexity,
+                    "limits": {
+                        "depth": self.limits.query_depth_limit,
+                        "complexity": self.limits.query_complexity_limit,
+                    },
+                }),
+            ),
+            _ => response,
+        }
+    }
+
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        *self.estimate_only.lock().unwrap() = matches!(
+            request.extensions.get(ESTIMATE_COST_EXTENSION),
+            Some(Value::Boolean(true))
+        );
+        next.run(ctx, request).await
+    }
+
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let validation_result = next.run(ctx).await?;
+        *self.validation_result.lock().unwrap() = Some(validation_result);
+        if *self.estimate_only.lock().unwrap() {
+            // Report a zero cost so that queries exceeding the limits are still estimated rather than rejected
+            Ok(ValidationResult {
+                complexity: 0,
+                depth: 0,
+                ..validation_result
+            })
+        } else {
+            Ok(validation_result)
+        }
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        if *self.estimate_only.lock().unwrap() {
+            Response::new(Value::Null)
+        } else {
+            next.run(ctx, operation_name).await
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery},
+    parser::types::{
+        DocumentOperations, ExecutableDocument, OperationType, Selection, SelectionSet,
+    },
+    registry::{MetaType, MetaTypeName, Registry},
+    Response, ServerResult, Value, Variables,
+};
+use opentelemetry::{
+    metrics::{Counter, MeterProvider},
+    KeyValue,
+};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+use crate::built_info;
+
+/// The header with which clients identify themselves, as used by Apollo clients and gateways
+pub const CLIENT_NAME_HEADER: &str = "apollographql-client-name";
+
+/// The name with which the client identified itself, if it did so
+#[derive(Debug, Clone)]
+pub struct ClientName(pub String);
+
+/// Counts the requests which use each deprecated field or enum value, so that unused ones can be identified for removal
+///
+/// The executed operation is walked once against the schema, so fields are counted when requested, whether or not they are resolved, and enum values when supplied as arguments, whether inline or in variables. Usages are labelled by their schema coordinate, of which there are only as many as there are deprecations in the schema. Arguments themselves cannot be deprecated in the schema registry.
+#[derive(Debug, Clone)]
+pub struct DeprecationUsage {
+    /// The number of requests using each deprecated field or enum value, keyed by coordinate
+    usage: Arc<Mutex<BTreeMap<String, u64>>>,
+    /// The count of requests using deprecated fields or enum values
+    requests: Counter<u64>,
+}
+
+impl DeprecationUsage {
+    /// Creates empty usage counts, recording metrics using the supplied meter provider
+    pub fn new(meter_provider: &impl MeterProvider) -> Self {
+        Self {
+            usage: Arc::default(),
+            requests: meter_provider
+                .meter(built_info::PKG_NAME)
+                .u64_counter("graphql.deprecated_field_usage")
+                .with_description("Requests using a deprecated field or enum value")
+                .init(),
+        }
+    }
+
+    /// Records that a request used the deprecated field or enum value
+    fn record(&self, coordinate: String, client_name: Option<&ClientName>) {
+        if let Some(ClientName(client_name)) = client_name {
+            info!(field = coordinate, client_name, "Deprecated field used");
+        }
+        self.requests
+            .add(1, &[KeyValue::new("field", coordinate.clone())]);
+        *self.usage.lock().unwrap().entry(coordinate).or_default() += 1;
+    }
+
+    /// Renders the usage counts as JSON
+    pub fn to_json(&self) -> serde_json::Value {
+        json!(*self.usage.lock().unwrap())
+    }
+}
+
+impl ExtensionFactory for DeprecationUsage {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(DeprecationUsageExtension {
+            usage: self.clone(),
+            parsed: Mutex::default(),
+        })
+    }
+}
+
+/// The per-request state of the [`DeprecationUsage`] extension
+#[derive(Debug)]
+struct DeprecationUsageExtension {
+    /// The usage counts shared between requests
+    usage: DeprecationUsage,
+    /// The parsed document and variables of the request, held until the operation is executed
+    parsed: Mutex<Option<(ExecutableDocument, Variables)>>,
+}
+
+#[async_trait]
+impl Extension for DeprecationUsageExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await?;
+        *self.parsed.lock().unwrap() = Some((document.clone(), variables.clone()));
+        Ok(document)
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        let parsed = self.parsed.lock().unwrap().take();
+        if let Some((document, variables)) = parsed {
+            let deprecations = Deprecations::find(
+                &ctx.schema_env.registry,
+                &document,
+                &variables,
+                operation_name,
+            );
+            for coordinate in deprecations {
+                self.usage.record(coordinate, ctx.data_opt::<ClientName>());
+            }
+        }
+        next.run(ctx, operation_name).await
+    }
+}
+
+/// A walk of an operation, collecting the coordinates of the deprecated fields and enum values it uses
+struct Deprecations<'a> {
+    /// The types of the schema
+    registry: &'a Registry,
+    /// The document containing the operation and its fragments
+    document: &'a ExecutableDocument,
+    /// The variables supplied with the operation
+    variables: &'a Variables,
+    /// The coordinates found so far, each once however often it is used
+    found: BTreeSet<String>,
+}
+
+impl<'a> Deprecations<'a> {
+    /// Walks the named operation, or the only operation if no name is given, producing the coordinates of the deprecations it uses
+    fn find(
+        registry: &'a Registry,
+        document: &'a ExecutableDocument,
+        variables: &'a Variables,
+        operation_name: Option<&str>,
+    ) -> BTreeSet<String> {
+        let mut walk = Self {
+            registry,
+            document,
+            variables,
+            found: BTreeSet::new(),
+        };
+        let operation = match (&document.operations, operation_name) {
+            (DocumentOperations::Single(operation), _) => Some(operation),
+            (DocumentOperations::Multiple(operations), Some(name)) => operations.get(name),
+            (DocumentOperations::Multiple(operations), None) if operations.len() == 1 => {
+                operations.values().next()
+            }
+            (DocumentOperations::Multiple(_), None) => None,
+        };
+        if let Some(operation) = operation {
+            let root = match operation.node.ty {
+                OperationType::Query => Some(registry.query_type.as_str()),
+                OperationType::Mutation => registry.mutation_type.as_deref(),
+                OperationType::Subscription => registry.subscription_type.as_deref(),
+            };
+            if let Some(root) = root {
+                walk.selection_set(root, &operation.node.selection_set.node);
+            }
+        }
+        walk.found
+    }
+
+    /// Walks the selections made on the named type
+    fn selection_set(&mut self, parent_type: &str, selection_set: &'a SelectionSet) {
+        for selection in &selection_set.items {
+            match &selection.node {
+                Selection::Field(field) => {
+                    let field = &field.node;
+                    let name = field.name.node.as_str();
+                    // Introspection is not described by the deprecations of the schema
+                    if name.starts_with("__") {
+                        continue;
+                    }
+                    let Some(meta) = self
+                        .registry
+                        .types
+                        .get(parent_type)
+                        .and_then(|ty| ty.field_by_name(name))
+                    else {
+                        continue;
+                    };
+                    if meta.deprecation.is_deprecated() {
+                        self.found.insert(format!("{parent_type}.{name}"));
+                    }
+                    for (argument, value) in &field.arguments {
+                        let Some(input) = meta.args.get(argument.node.as_str()) else {
+                            continue;
+                        };
+                        if let Ok(value) = value.node.clone().into_const_with(|variable| {
+                            self.variables.get(&variable).cloned().ok_or(())
+                        }) {
+                            self.input_value(&input.ty, &value);
+                        }
+                    }
+                    self.selection_set(
+                        MetaTypeName::concrete_typename(&meta.ty),
+                        &field.selection_set.node,
+                    );
+                }
+                Selection::InlineFragment(fragment) => {
+                    let fragment = &fragment.node;
+                    let ty = fragment
+                        .type_condition
+                        .as_ref()
+                        .map_or(parent_type, |condition| condition.node.on.node.as_str());
+                    self.selection_set(ty, &fragment.selection_set.node);
+                }
+                Selection::FragmentSpread(spread) => {
+                    // Documents are validated before execution, so spreads are acyclic
+                    if let Some(fragment) =
+                        self.document.fragments.get(&spread.node.fragment_name.node)
+                    {
+                        self.selection_set(
+                            fragment.node.type_condition.node.on.node.as_str(),
+                            &fragment.node.selection_set.node,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Walks a value supplied for an input of the type, finding the deprecated enum values within it
+    fn input_value(&mut self, ty: &str, value: &Value) {
+        let ty = MetaTypeName::concrete_typename(ty);
+        match (self.registry.types.get(ty), value) {
+            (_, Value::List(items)) => {
+                for item in items {
+                    self.input_value(ty, item);
+                }
+            }
+            (Some(MetaType::Enum { enum_values, .. }), value) => {
+                // Enum values are strings when supplied in variables
+                let name = match value {
+                    Value::Enum(name) => name.as_str(),
+                    Value::String(name) => name.as_str(),
+                    _ => return,
+                };
+                if enum_values
+                    .get(name)
+                    .is_some_and(|meta| meta.deprecation.is_deprecated())
+                {
+                    self.found.insert(format!("{ty}.{name}"));
+                }
+            }
+            (Some(MetaType::InputObject { input_fields, .. }), Value::Object(fields)) => {
+                for (name, value) in fields {
+                    if let Some(input) = input_fields.get(name.as_str()) {
+                        self.input_value(&input.ty, value);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeprecationUsage;
+    use async_graphql::{
+        EmptyMutation, EmptySubscription, Enum, InputObject, Object, Request, Schema, SimpleObject,
+        Variables,
+    };
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use serde_json::json;
+
+    /// An axis along which a scan may be taken, one of which is deprecated
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+    enum Axis {
+        /// The current name of the horizontal axis
+        Horizontal,
+        /// The former name of the horizontal axis
+        #[graphql(deprecation = "Use HORIZONTAL")]
+        X,
+    }
+
+    /// A filter on the axes of scans
+    #[derive(Debug, InputObject)]
+    struct AxisFilter {
+        /// The axes of the scans included
+        axes: Vec<Axis>,
+    }
+
+    /// A scan, with a deprecated field
+    #[derive(Debug, SimpleObject)]
+    struct Scan {
+        /// The current name of the field
+        energy: f64,
+        /// The former name of the field
+        #[graphql(deprecation = "Use energy")]
+        beam_energy: f64,
+    }
+
+    /// The root of a schema with deprecated fields and enum values
+    struct Query;
+
+    #[Object]
+    impl Query {
+        /// A scan along the axis
+        async fn scan(&self, _axis: Option<Axis>) -> Scan {
+            Scan {
+                energy: 1.0,
+                beam_energy: 1.0,
+            }
+        }
+
+        /// Scans matching the filter
+        async fn scans(&self, _filter: AxisFilter) -> Vec<Scan> {
+            Vec::new()
+        }
+
+        /// A scan which is never found
+        async fn missing(&self) -> Option<Scan> {
+            None
+        }
+
+        /// The former name of the scan
+        #[graphql(deprecation = "Use scan")]
+        async fn spectrum(&self) -> Scan {
+            Scan {
+                energy: 1.0,
+                beam_energy: 1.0,
+            }
+        }
+    }
+
+    /// Executes the request against the schema, recording usage, and produces the usage counts
+    async fn usage(requests: impl IntoIterator<Item = Request>) -> serde_json::Value {
+        let usage = DeprecationUsage::new(&NoopMeterProvider::new());
+        let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(usage.clone())
+            .finish();
+        for request in requests {
+            schema.execute(request).await;
+        }
+        usage.to_json()
+    }
+
+    #[tokio::test]
+    async fn deprecated_fields_are_counted_once_per_request() {
+        assert_eq!(
+            usage([
+                Request::new("{ scan { energy } }"),
+                Request::new("{ scan { beamEnergy old: beamEnergy } spectrum { energy } }"),
+                Request::new("{ spectrum { ...Old } } fragment Old on Scan { beamEnergy }"),
+            ])
+            .await,
+            json!({ "Query.spectrum": 2, "Scan.beamEnergy": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn deprecated_fields_are_counted_whether_or_not_they_are_resolved() {
+        assert_eq!(
+            usage([
+                Request::new("{ missing { beamEnergy } }"),
+                Request::new("{ scans(filter: { axes: [] }) { ... on Scan { beamEnergy } } }"),
+            ])
+            .await,
+            json!({ "Scan.beamEnergy": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn deprecated_enum_values_are_counted_in_arguments_and_variables() {
+        assert_eq!(
+            usage([
+                Request::new("{ scan(axis: X) { energy } }"),
+                Request::new("{ scan(axis: HORIZONTAL) { energy } }"),
+                Request::new("query ($axis: Axis) { scan(axis: $axis) { energy } }")
+                    .variables(Variables::from_json(json!({ "axis": "X" }))),
+                Request::new("query ($filter: AxisFilter!) { scans(filter: $filter) { energy } }")
+                    .variables(Variables::from_json(
+                        json!({ "filter": { "axes": ["HORIZONTAL", "X"] } })
+                    )),
+            ])
+            .await,
+            json!({ "Axis.X": 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_executed_operation_of_a_valid_request_is_counted() {
+        assert_eq!(
+            usage([
+                Request::new("query Old { spectrum { energy } } query New { scan { energy } }")
+                    .operation_name("New"),
+                Request::new("{ spectrum { unknown } }"),
+                Request::new(
+                    "{ __type(name: \"Scan\") { fields(includeDeprecated: true) { name } } }"
+                ),
+            ])
+            .await,
+            json!({})
+        );
+    }
+}
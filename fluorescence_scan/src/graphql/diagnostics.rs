@@ -0,0 +1,310 @@
+use async_graphql::{Context, SimpleObject};
+use std::sync::Arc;
+
+use crate::{
+    negative_cache::NegativeCache,
+    object_key::{KeyFamily, ObjectKey},
+    store::ScanFiles,
+};
+
+/// The presence of this in the schema data enables the `downloadDiagnostics` field
+#[derive(Debug, Clone, Copy)]
+pub struct DownloadDiagnosticsEnabled;
+
+/// A description of how a scan file is located in the store, for diagnosing failed downloads
+#[derive(Debug, Clone, SimpleObject)]
+#[graphql(tag = "internal")]
+pub struct ObjectDiagnostics {
+    /// The family of the file, which determines how its key is derived
+    family: &'static str,
+    /// The path recorded in ISPyB, if any
+    path: Option<String>,
+    /// The store in which the file is looked up
+    store: String,
+    /// The configured prefix which was stripped from the path, if any
+    matched_prefix: Option<String>,
+    /// The derived key of the file, if one could be derived
+    key: Option<String>,
+    /// Why no key could be derived from the path, if it could not
+    key_error: Option<String>,
+    /// Whether the object exists, if the lookup succeeded
+    exists: Option<bool>,
+    /// The size of the object in bytes, if it exists
+    size: Option<u64>,
+    /// Why the lookup failed, if it did
+    lookup_error: Option<String>,
+    /// The period, in seconds, for which the object remains cached as missing, if it is
+    cached_missing_secs: Option<u64>,
+    /// The most recent error with which a lookup of the object failed before this one, such as when resolving a verified URL, if one is cached
+    cached_error: Option<String>,
+    /// How long ago, in seconds, the cached error occurred, if one is cached
+    cached_error_age_secs: Option<u64>,
+}
+
+/// Describes how the file recorded at the path is located, looking up the object directly rather than through the cache
+pub async fn diagnose(
+    ctx: &Context<'_>,
+    family: KeyFamily,
+    path: Option<&str>,
+) -> async_graphql::Result<ObjectDiagnostics> {
+    if ctx.data_opt::<DownloadDiagnosticsEnabled>().is_none() {
+        return Err("Download diagnostics are disabled".into());
+    }
+    let files = ctx.data::<ScanFiles>()?;
+    let mut diagnostics = ObjectDiagnostics {
+        family: family.name(),
+        path: path.map(String::from),
+        store: files.store.location(),
+        matched_prefix: path
+            .and_then(|path| files.key_rules.matched_prefix(path))
+            .map(String::from),
+        key: None,
+        key_error: None,
+        exists: None,
+        size: None,
+        lookup_error: None,
+        cached_missing_secs: None,
+        cached_error: None,
+        cached_error_age_secs: None,
+    };
+    let Some(path) = path else {
+        return Ok(diagnostics);
+    };
+    let key = match ObjectKey::from_path(family, &files.key_rules, path) {
+        Ok(key) => key,
+        Err(err) => {
+            diagnostics.key_error = Some(err.to_string());
+            return Ok(diagnostics);
+        }
+    };
+    let negative_cache = ctx.data::<Arc<NegativeCache>>()?;
+    diagnostics.cached_missing_secs = negative_cache
+        .remaining(&key)
+        .map(|remaining| remaining.as_secs());
+    if let Some((error, age)) = negative_cache.last_error(&key) {
+        diagnostics.cached_error = Some(error);
+        diagnostics.cached_error_age_secs = Some(age.as_secs());
+    }
+    match files.store.head(&key).await {
+        Ok(info) => {
+            diagnostics.exists = Some(info.is_some());
+            diagnostics.size = info.map(|info| info.size);
+        }
+        Err(err) => {
+            negative_cache.record_error(&key, &err);
+            diagnostics.lookup_error = Some(err.to_string());
+        }
+    }
+    diagnostics.key = Some(key.into());
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        authorization::{Claims, IspybMembership},
+        fake_database::{model_row, row, scan, FakeDatabase},
+        object_key::ObjectKeyRules,
+        store::testing::FakeStore,
+        FluorescenceScanService,
+    };
+    use async_graphql::Request;
+    use sea_orm::Value;
+    use serde_json::{json, Value as Json};
+    use std::sync::Arc;
+
+    /// Describes the files of the scans of session 42
+    const DIAGNOSTICS: &str = r#"{
+        fluorescenceScansBySession(sessionIds: [42]) {
+            scans {
+                downloadDiagnostics {
+                    family path store matchedPrefix key keyError exists size lookupError
+                    cachedMissingSecs cachedError cachedErrorAgeSecs
+                }
+            }
+            error { code }
+        }
+    }"#;
+
+    /// Verifies the jpeg of the scans of session 42
+    const VERIFIED_JPEG: &str = r#"{
+        fluorescenceScansBySession(sessionIds: [42]) { scans { jpegScanUrl(verify: true) } }
+    }"#;
+
+    /// The claims of a member of every session who is not an administrator
+    fn member() -> Claims {
+        Claims {
+            subject: Some(String::from("abc12345")),
+            groups: Vec::new(),
+        }
+    }
+
+    /// The claims of a member of every session who is an administrator
+    fn admin() -> Claims {
+        Claims {
+            groups: vec![String::from("admin")],
+            ..member()
+        }
+    }
+
+    /// A service with debug endpoints enabled or not, holding the scan in session 42 whose jpeg was recorded at the path and whose data file was not recorded, under which every subject is a member of every session and administrators may read restricted fields
+    async fn service(store: Arc<FakeStore>, jpeg: &str, debug: bool) -> FluorescenceScanService {
+        let jpeg = jpeg.to_string();
+        let database = FakeDatabase::new(move |statement| {
+            Ok(vec![if statement.to_string().contains("COUNT(*)") {
+                row([("num_items", Value::from(1))])
+            } else {
+                model_row(&scan(7, 42, None, Some(&jpeg)))
+            }])
+        })
+        .connect()
+        .await;
+        FluorescenceScanService::builder(database.clone())
+            .scan_file_store(store, ObjectKeyRules::new(vec![String::from("/dls/")]))
+            .authorization_policy(Arc::new(IspybMembership::new(database, "admin")))
+            .debug_endpoints(debug)
+            .build()
+    }
+
+    /// Executes the query as the holder of the claims, producing the response as JSON
+    async fn execute(service: &FluorescenceScanService, query: &str, claims: Claims) -> Json {
+        serde_json::to_value(
+            service
+                .schema()
+                .execute(Request::new(query).data(claims))
+                .await,
+        )
+        .unwrap()
+    }
+
+    /// The diagnostics of the jpeg and the data file of the scan in the response
+    fn diagnostics(response: &Json) -> (&Json, &Json) {
+        let diagnostics =
+            &response["data"]["fluorescenceScansBySession"][0]["scans"][0]["downloadDiagnostics"];
+        (&diagnostics[0], &diagnostics[1])
+    }
+
+    #[tokio::test]
+    async fn diagnostics_are_refused_unless_debug_endpoints_are_enabled() {
+        let store = FakeStore::new([]);
+        let service = service(store.clone(), "/dls/i18/scan.jpg", false).await;
+        let response = execute(&service, DIAGNOSTICS, admin()).await;
+        assert_eq!(
+            response["errors"][0]["message"],
+            "Download diagnostics are disabled"
+        );
+        assert_eq!(store.heads(), 0);
+    }
+
+    #[tokio::test]
+    async fn diagnostics_are_refused_to_those_who_are_not_administrators() {
+        let store = FakeStore::new([]);
+        let service = service(store.clone(), "/dls/i18/scan.jpg", true).await;
+        let response = execute(&service, DIAGNOSTICS, member()).await;
+        assert_eq!(response["errors"][0]["extensions"]["code"], "FORBIDDEN");
+        assert_eq!(
+            response["errors"][0]["message"],
+            "Restricted to members of the admin group"
+        );
+        assert_eq!(store.heads(), 0);
+    }
+
+    #[tokio::test]
+    async fn administrators_are_shown_how_files_are_located() {
+        let store = FakeStore::new([("i18/scan.jpg", &b"jpeg"[..])]);
+        let service = service(store, "/dls/i18/scan.jpg", true).await;
+        let response = execute(&service, DIAGNOSTICS, admin()).await;
+        assert_eq!(response["errors"], Json::Null, "{response}");
+        assert_eq!(
+            diagnostics(&response),
+            (
+                &json!({
+                    "family": "scan-jpeg",
+                    "path": "/dls/i18/scan.jpg",
+                    "store": "memory://fake",
+                    "matchedPrefix": "/dls/",
+                    "key": "i18/scan.jpg",
+                    "keyError": null,
+                    "exists": true,
+                    "size": 4,
+                    "lookupError": null,
+                    "cachedMissingSecs": null,
+                    "cachedError": null,
+                    "cachedErrorAgeSecs": null,
+                }),
+                &json!({
+                    "family": "scan-data",
+                    "path": null,
+                    "store": "memory://fake",
+                    "matchedPrefix": null,
+                    "key": null,
+                    "keyError": null,
+                    "exists": null,
+                    "size": null,
+                    "lookupError": null,
+                    "cachedMissingSecs": null,
+                    "cachedError": null,
+                    "cachedErrorAgeSecs": null,
+                }),
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn paths_from_which_no_key_can_be_derived_are_explained() {
+        let store = FakeStore::new([]);
+        let service = service(store.clone(), "/dls/i18/../../etc/passwd", true).await;
+        let response = execute(&service, DIAGNOSTICS, admin()).await;
+        let (jpeg, _) = diagnostics(&response);
+        assert_eq!(jpeg["matchedPrefix"], "/dls/");
+        assert_eq!(jpeg["key"], Json::Null);
+        assert_eq!(
+            jpeg["keyError"],
+            "Path contains a parent directory traversal"
+        );
+        assert_eq!(jpeg["exists"], Json::Null);
+        assert_eq!(store.heads(), 0);
+    }
+
+    #[tokio::test]
+    async fn missing_objects_are_looked_up_despite_being_cached_as_missing() {
+        let store = FakeStore::new([]);
+        let service = service(store.clone(), "/dls/i18/scan.jpg", true).await;
+        let verified = execute(&service, VERIFIED_JPEG, member()).await;
+        assert_eq!(
+            verified["data"]["fluorescenceScansBySession"][0]["scans"][0]["jpegScanUrl"],
+            Json::Null
+        );
+        let response = execute(&service, DIAGNOSTICS, admin()).await;
+        let (jpeg, _) = diagnostics(&response);
+        assert_eq!(jpeg["key"], "i18/scan.jpg");
+        assert_eq!(jpeg["exists"], false);
+        assert_eq!(jpeg["size"], Json::Null);
+        assert_eq!(jpeg["lookupError"], Json::Null);
+        assert!(jpeg["cachedMissingSecs"].as_u64().unwrap() > 0);
+        assert_eq!(store.heads(), 2);
+    }
+
+    #[tokio::test]
+    async fn lookup_errors_and_cached_errors_are_reported() {
+        let store = FakeStore::new([("i18/scan.jpg", &b"jpeg"[..])]);
+        store.fail(Some("Connection refused"));
+        let service = service(store.clone(), "/dls/i18/scan.jpg", true).await;
+        let verified = execute(&service, VERIFIED_JPEG, member()).await;
+        assert_eq!(verified["errors"][0]["message"], "Connection refused");
+        let response = execute(&service, DIAGNOSTICS, admin()).await;
+        let (jpeg, _) = diagnostics(&response);
+        assert_eq!(jpeg["exists"], Json::Null);
+        assert_eq!(jpeg["lookupError"], "Connection refused");
+        assert_eq!(jpeg["cachedMissingSecs"], Json::Null);
+        assert_eq!(jpeg["cachedError"], "Connection refused");
+        assert_eq!(jpeg["cachedErrorAgeSecs"], 0);
+
+        store.fail(None);
+        let recovered = execute(&service, DIAGNOSTICS, admin()).await;
+        let (jpeg, _) = diagnostics(&recovered);
+        assert_eq!(jpeg["exists"], true);
+        assert_eq!(jpeg["lookupError"], Json::Null);
+        assert_eq!(jpeg["cachedError"], "Connection refused");
+    }
+}
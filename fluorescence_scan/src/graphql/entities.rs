@@ -1,44 +1,85 @@
 use async_graphql::SimpleObject;
 use chrono::{DateTime, Utc};
 use models::xfe_fluorescence_spectrum;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use std::sync::OnceLock;
+
+use crate::built_info;
+
+/// Counts the empty or whitespace-only values found in columns which are treated as NULL
+fn blank_values() -> &'static Counter<u64> {
+    /// The lazily initialised counter
+    static BLANK_VALUES: OnceLock<Counter<u64>> = OnceLock::new();
+    BLANK_VALUES.get_or_init(|| {
+        global::meter(built_info::PKG_NAME)
+            .u64_counter("database.blank_values")
+            .with_description("Empty or whitespace-only values treated as NULL")
+            .init()
+    })
+}
+
+/// Treats empty and whitespace-only values as absent, counting each occurrence against the column
+fn non_blank(value: Option<String>, column: &'static str) -> Option<String> {
+    match value {
+        Some(value) if value.trim().is_empty() => {
+            blank_values().add(1, &[KeyValue::new("column", column)]);
+            None
+        }
+        value => value,
+    }
+}
 
 /// Combines autoproc integration, autoproc program, autoproc and autoproc scaling
 #[derive(Debug, Clone, SimpleObject)]
 #[graphql(name = "Session", complex)]
 pub struct Session {
     /// An opaque unique identifier for session
+    #[graphql(tag = "public")]
     pub id: u32,
 }
 
 /// Represents XFEFluorescenceSpectrum table from the ISPyB database
 #[derive(Debug, Clone, SimpleObject)]
-#[graphql(name = "FluorescenceScan", unresolvable)]
+#[graphql(name = "FluorescenceScan", complex)]
 pub struct FluorescenceScan {
     /// An opaque unique identifier for the XFEFluorescenceSpectrum
+    #[graphql(tag = "public")]
     pub id: u32,
     /// An opaque unique identifier for a session
+    #[graphql(tag = "public")]
     pub session_id: u32,
     /// Full path of the scan file in jpeg format
+    #[graphql(tag = "public")]
     pub jpeg_scan_file_full_path: Option<String>,
     /// Start time of the scan
+    #[graphql(tag = "public")]
     pub start_time: Option<DateTime<Utc>>,
     /// End time of the scan
+    #[graphql(tag = "public")]
     pub end_time: Option<DateTime<Utc>>,
     /// Scan file name
+    #[graphql(tag = "public")]
     pub filename: Option<String>,
     /// Beam exposure time
+    #[graphql(tag = "public")]
     pub exposure_time: Option<f32>,
     /// Beam axis position
+    #[graphql(tag = "public")]
     pub axis_position: Option<f32>,
     /// Amount of beam transmission
+    #[graphql(tag = "public")]
     pub beam_transmission: Option<f32>,
     /// Full path of the scan file
+    #[graphql(tag = "public")]
     pub scan_file_full_path: Option<String>,
     /// Amount of energy from the beam
+    #[graphql(tag = "public")]
     pub energy: Option<f32>,
     /// Beam verticial size
+    #[graphql(tag = "public")]
     pub beam_size_vertical: Option<f32>,
     /// Beam horizontal size
+    #[graphql(tag = "public")]
     pub beam_size_horizontal: Option<f32>,
 }
 
@@ -47,17 +88,155 @@ impl From<xfe_fluorescence_spectrum::Model> for FluorescenceScan {
         Self {
             id: value.xfe_fluorescence_spectrum_id,
             session_id: value.session_id,
-            jpeg_scan_file_full_path: value.jpeg_scan_file_full_path,
+            jpeg_scan_file_full_path: non_blank(
+                value.jpeg_scan_file_full_path,
+                "jpegScanFileFullPath",
+            ),
             start_time: value.start_time.map(|time| time.and_utc()),
             end_time: value.end_time.map(|time| time.and_utc()),
-            filename: value.filename,
+            filename: non_blank(value.filename, "filename"),
             exposure_time: value.exposure_time,
             axis_position: value.axis_position,
             beam_transmission: value.beam_transmission,
-            scan_file_full_path: value.scan_file_full_path,
+            scan_file_full_path: non_blank(value.scan_file_full_path, "scanFileFullPath"),
             energy: value.energy,
             beam_size_vertical: value.beam_size_vertical,
             beam_size_horizontal: value.beam_size_horizontal,
         }
     }
 }
+
+/// A page of the change feed of the fluorescence scans of a session
+#[derive(Debug, Clone, SimpleObject)]
+pub struct FluorescenceScanChanges {
+    /// The scans recorded since the supplied cursor, ordered by time of recording
+    #[graphql(tag = "public")]
+    pub changes: Vec<FluorescenceScan>,
+    /// The cursor from which the feed should be resumed
+    #[graphql(tag = "public")]
+    pub next_cursor: String,
+    /// Whether further changes are available immediately
+    #[graphql(tag = "public")]
+    pub has_more: bool,
+}
+
+/// A page of the fluorescence scans of a session, selected by page number
+#[derive(Debug, Clone, SimpleObject)]
+pub struct FluorescenceScanPage {
+    /// The scans on the requested page
+    pub items: Vec<FluorescenceScan>,
+    /// The total number of pages of the requested size
+    pub total_pages: u64,
+    /// The total number of scans across all pages
+    pub total_count: u64,
+}
+
+/// The fluorescence scans of one session of a batch, or the reason they could not be read
+#[derive(Debug, Clone, SimpleObject)]
+pub struct SessionScansResult {
+    /// An opaque unique identifier for the session, as requested
+    #[graphql(tag = "public")]
+    pub session_id: u32,
+    /// The scans of the session, or null if they could not be read
+    #[graphql(tag = "public")]
+    pub scans: Option<Vec<FluorescenceScan>>,
+    /// The reason the scans of the session could not be read, or null if they were read
+    #[graphql(tag = "public")]
+    pub error: Option<ScanQueryError>,
+}
+
+/// The reason the scans of one session of a batch could not be read
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ScanQueryError {
+    /// The code which would be given in the extensions of an equivalent GraphQL error, such as FORBIDDEN or RATE_LIMITED
+    #[graphql(tag = "public")]
+    pub code: String,
+    /// A description of the error
+    #[graphql(tag = "public")]
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{non_blank, FluorescenceScan};
+    use crate::fake_database::scan;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use models::xfe_fluorescence_spectrum::Model;
+
+    /// The scan converted from the model after the text columns are set to the value
+    fn with_text(value: Option<&str>) -> FluorescenceScan {
+        FluorescenceScan::from(Model {
+            filename: value.map(String::from),
+            ..scan(7, 42, value, value)
+        })
+    }
+
+    #[test]
+    fn non_blank_keeps_only_values_with_content() {
+        assert_eq!(non_blank(None, "filename"), None);
+        assert_eq!(non_blank(Some(String::new()), "filename"), None);
+        assert_eq!(non_blank(Some(String::from(" \t\n")), "filename"), None);
+        assert_eq!(
+            non_blank(Some(String::from("scan.dat")), "filename").as_deref(),
+            Some("scan.dat")
+        );
+        assert_eq!(
+            non_blank(Some(String::from(" scan.dat ")), "filename").as_deref(),
+            Some(" scan.dat ")
+        );
+    }
+
+    #[test]
+    fn absent_and_blank_text_columns_are_null() {
+        for value in [None, Some(""), Some("   "), Some("\t\r\n")] {
+            let scan = with_text(value);
+            assert_eq!(scan.jpeg_scan_file_full_path, None, "{value:?}");
+            assert_eq!(scan.scan_file_full_path, None, "{value:?}");
+            assert_eq!(scan.filename, None, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn recorded_text_columns_are_kept() {
+        let scan = with_text(Some("/dls/i18/data/2024/cm1-1/scan.dat"));
+        for value in [
+            scan.jpeg_scan_file_full_path,
+            scan.scan_file_full_path,
+            scan.filename,
+        ] {
+            assert_eq!(value.as_deref(), Some("/dls/i18/data/2024/cm1-1/scan.dat"));
+        }
+    }
+
+    #[test]
+    fn other_columns_are_converted_unchanged() {
+        let start = NaiveDate::from_ymd_opt(2024, 3, 5)
+            .unwrap()
+            .and_hms_opt(9, 30, 0)
+            .unwrap();
+        let converted = FluorescenceScan::from(Model {
+            start_time: Some(start),
+            end_time: None,
+            exposure_time: Some(0.5),
+            axis_position: Some(-12.25),
+            beam_transmission: Some(0.0),
+            energy: Some(13500.0),
+            beam_size_vertical: None,
+            beam_size_horizontal: Some(80.0),
+            ..scan(7, 42, None, None)
+        });
+        assert_eq!(converted.id, 7);
+        assert_eq!(converted.session_id, 42);
+        assert_eq!(
+            converted.start_time,
+            Some(Utc.with_ymd_and_hms(2024, 3, 5, 9, 30, 0).unwrap())
+        );
+        assert_eq!(converted.end_time, None);
+        assert_eq!(converted.exposure_time, Some(0.5));
+        assert_eq!(converted.axis_position, Some(-12.25));
+        assert_eq!(converted.beam_transmission, Some(0.0));
+        assert_eq!(converted.energy, Some(13500.0));
+        assert_eq!(converted.beam_size_vertical, None);
+        assert_eq!(converted.beam_size_horizontal, Some(80.0));
+    }
+}
@@ -0,0 +1,217 @@
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextResolve, ResolveInfo},
+    parser::{
+        parse_schema,
+        types::{TypeKind, TypeSystemDefinition},
+    },
+    ServerResult, Value,
+};
+use opentelemetry::{
+    metrics::{Counter, MeterProvider},
+    KeyValue,
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use crate::built_info;
+
+/// The fields of the schema, fixed once the schema has been built
+#[derive(Debug)]
+struct Coordinates {
+    /// The index of each field, keyed by the name of its parent type then by its own name
+    index: HashMap<String, HashMap<String, usize>>,
+    /// The schema coordinate of each field
+    names: Vec<String>,
+    /// The label with which each field is recorded
+    labels: Vec<[KeyValue; 1]>,
+    /// The number of resolutions of each field by sampled requests
+    counts: Vec<AtomicU64>,
+}
+
+impl Coordinates {
+    /// Lists the fields of each object and interface type in the SDL, other than those of introspection and federation
+    fn from_sdl(sdl: &str) -> Self {
+        let mut index = HashMap::<String, HashMap<String, usize>>::new();
+        let mut names = Vec::new();
+        for definition in parse_schema(sdl)
+            .map(|document| document.definitions)
+            .unwrap_or_default()
+        {
+            let TypeSystemDefinition::Type(ty) = definition else {
+                continue;
+            };
+            let fields = match &ty.node.kind {
+                TypeKind::Object(object) => &object.fields,
+                TypeKind::Interface(interface) => &interface.fields,
+                _ => continue,
+            };
+            let type_name = ty.node.name.node.as_str();
+            if type_name.starts_with('_') {
+                continue;
+            }
+            for field in fields {
+                let field_name = field.node.name.node.as_str();
+                if field_name.starts_with('_') {
+                    continue;
+                }
+                index
+                    .entry(type_name.to_string())
+                    .or_default()
+                    .insert(field_name.to_string(), names.len());
+                names.push(format!("{type_name}.{field_name}"));
+            }
+        }
+        Self {
+            index,
+            labels: names
+                .iter()
+                .map(|name| [KeyValue::new("field", name.clone())])
+                .collect(),
+            counts: names.iter().map(|_| AtomicU64::default()).collect(),
+            names,
+        }
+    }
+
+    /// The index of the field, if it belongs to the schema
+    fn find(&self, parent_type: &str, name: &str) -> Option<usize> {
+        self.index.get(parent_type)?.get(name).copied()
+    }
+}
+
+/// Counts the resolutions of each field of the schema by every Nth request, so that unused fields can be identified
+///
+/// Fields are labelled by their schema coordinate, drawn from the fields of the schema once it is built, so resolutions of anything else are not recorded.
+#[derive(Debug, Clone)]
+pub struct FieldUsage {
+    /// The fields of the schema, once it has been built
+    coordinates: Arc<OnceLock<Coordinates>>,
+    /// The number of requests received, sampled or not
+    requests: Arc<AtomicU64>,
+    /// The number of requests sampled
+    sampled: Arc<AtomicU64>,
+    /// The interval at which requests are sampled
+    sample_every: u64,
+    /// The count of field resolutions by sampled requests
+    resolutions: Counter<u64>,
+}
+
+impl FieldUsage {
+    /// Creates empty usage counts, sampling every `sample_every`th request and recording metrics using the supplied meter provider
+    pub fn new(sample_every: u64, meter_provider: &impl MeterProvider) -> Self {
+        Self {
+            coordinates: Arc::default(),
+            requests: Arc::default(),
+            sampled: Arc::default(),
+            sample_every: sample_every.max(1),
+            resolutions: meter_provider
+                .meter(built_info::PKG_NAME)
+                .u64_counter("graphql.field_usage")
+                .with_description("Resolutions of each field by sampled requests")
+                .init(),
+        }
+    }
+
+    /// Fixes the fields counted to those of the built schema, given as SDL, before which no requests are sampled
+    pub fn register_schema(&self, sdl: &str) {
+        self.coordinates.get_or_init(|| Coordinates::from_sdl(sdl));
+    }
+
+    /// Renders the usage counts as JSON, with the most resolved fields first
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut fields = self
+            .coordinates
+            .get()
+            .map(|coordinates| {
+                coordinates
+                    .names
+                    .iter()
+                    .zip(&coordinates.counts)
+                    .map(|(name, count)| (name.as_str(), count.load(Ordering::Relaxed)))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        fields.sort_by(|(a_name, a_count), (b_name, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_name.cmp(b_name))
+        });
+        json!({
+            "sampleEvery": self.sample_every,
+            "sampledRequests": self.sampled.load(Ordering::Relaxed),
+            "fields": fields
+                .into_iter()
+                .map(|(field, count)| json!({ "field": field, "count": count }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl ExtensionFactory for FieldUsage {
+    fn create(&self) -> Arc<dyn Extension> {
+        let position = self.requests.fetch_add(1, Ordering::Relaxed) % self.sample_every;
+        match self.coordinates.get() {
+            Some(coordinates) if position == 0 => {
+                self.sampled.fetch_add(1, Ordering::Relaxed);
+                Arc::new(FieldUsageExtension {
+                    usage: self.clone(),
+                    counts: Mutex::new(vec![0; coordinates.names.len()]),
+                })
+            }
+            _ => Arc::new(Unsampled),
+        }
+    }
+}
+
+/// The extension used for requests which are not sampled, which records nothing
+struct Unsampled;
+
+impl Extension for Unsampled {}
+
+/// The per-request state of the [`FieldUsage`] extension for sampled requests
+#[derive(Debug)]
+struct FieldUsageExtension {
+    /// The usage counts shared between requests
+    usage: FieldUsage,
+    /// The number of resolutions of each field by this request, accumulated locally so that the shared counts are updated once, when the request completes
+    counts: Mutex<Vec<u64>>,
+}
+
+impl Drop for FieldUsageExtension {
+    fn drop(&mut self) {
+        if let Some(coordinates) = self.usage.coordinates.get() {
+            for (index, count) in self.counts.get_mut().unwrap().iter().enumerate() {
+                if *count > 0 {
+                    coordinates.counts[index].fetch_add(*count, Ordering::Relaxed);
+                    self.usage
+                        .resolutions
+                        .add(*count, &coordinates.labels[index]);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Extension for FieldUsageExtension {
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        if let Some(index) = self
+            .usage
+            .coordinates
+            .get()
+            .and_then(|coordinates| coordinates.find(info.parent_type, info.name))
+        {
+            self.counts.lock().unwrap()[index] += 1;
+        }
+        next.run(ctx, info).await
+    }
+}
@@ -0,0 +1,315 @@
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest, NextRequest},
+    value, Context, Request, Response, ServerResult, Value,
+};
+use models::xfe_fluorescence_spectrum::{Column, Entity, Model};
+use opentelemetry::{
+    metrics::{Counter, MeterProvider},
+    KeyValue,
+};
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbErr, IdenStatic, QueryResult, QueryTrait, Select,
+    TryGetable,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::built_info;
+
+/// The minimum interval between warnings describing decode failures
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tolerates rows which cannot be fully decoded, nulling nullable fields which fail to decode and skipping rows whose required fields fail to decode
+///
+/// The presence of this in the schema data enables lenient decoding, otherwise any decode failure fails the query.
+#[derive(Debug, Clone)]
+pub struct LenientDecoding {
+    /// The time at which a decode failure was last logged
+    last_warning: Arc<Mutex<Option<Instant>>>,
+    /// The count of fields nulled or rows skipped due to decode failures
+    decode_failures: Counter<u64>,
+}
+
+impl LenientDecoding {
+    /// Creates a decoder recording failures using the supplied meter provider
+    pub fn new(meter_provider: &impl MeterProvider) -> Self {
+        Self {
+            last_warning: Arc::default(),
+            decode_failures: meter_provider
+                .meter(built_info::PKG_NAME)
+                .u64_counter("database.decode_failures")
+                .with_description(
+                    "Fields nulled or rows skipped as their columns could not be decoded",
+                )
+                .init(),
+        }
+    }
+
+    /// Records a decode failure, logging it if no warning has been logged recently
+    fn report(&self, column: Column, outcome: &'static str, err: &DbErr) {
+        self.decode_failures.add(
+            1,
+            &[
+                KeyValue::new("column", column.as_str().to_string()),
+                KeyValue::new("outcome", outcome),
+            ],
+        );
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if !last_warning.is_some_and(|last_warning| last_warning.elapsed() < WARNING_INTERVAL) {
+            *last_warning = Some(Instant::now());
+            warn!(
+                column = column.as_str(),
+                outcome, "Failed to decode column: {err}"
+            );
+        }
+    }
+
+    /// Decodes a nullable column, producing [`None`] if it cannot be decoded
+    fn nullable<T: TryGetable>(&self, row: &QueryResult, column: Column) -> Option<T> {
+        row.try_get::<Option<T>>("", column.as_str())
+            .unwrap_or_else(|err| {
+                self.report(column, "nulled", &err);
+                None
+            })
+    }
+
+    /// Decodes a required column, producing an entry describing the skipped row if it cannot be decoded
+    fn required<T: TryGetable>(&self, row: &QueryResult, column: Column) -> Result<T, SkippedRow> {
+        row.try_get::<T>("", column.as_str()).map_err(|err| {
+            self.report(column, "skipped", &err);
+            SkippedRow {
+                column: column.as_str().to_string(),
+                message: err.to_string(),
+            }
+        })
+    }
+
+    /// Decodes a row, tolerating failures in nullable columns
+    fn decode(&self, row: &QueryResult) -> Result<Model, SkippedRow> {
+        Ok(Model {
+            xfe_fluorescence_spectrum_id: self.required(row, Column::XfeFluorescenceSpectrumId)?,
+            session_id: self.required(row, Column::SessionId)?,
+            record_time_stamp: self.required(row, Column::RecordTimeStamp)?,
+            jpeg_scan_file_full_path: self.nullable(row, Column::JpegScanFileFullPath),
+            start_time: self.nullable(row, Column::StartTime),
+            end_time: self.nullable(row, Column::EndTime),
+            filename: self.nullable(row, Column::Filename),
+            exposure_time: self.nullable(row, Column::ExposureTime),
+            axis_position: self.nullable(row, Column::AxisPosition),
+            beam_transmission: self.nullable(row, Column::BeamTransmission),
+            energy: self.nullable(row, Column::Energy),
+            beam_size_vertical: self.nullable(row, Column::BeamSizeVertical),
+            beam_size_horizontal: self.nullable(row, Column::BeamSizeHorizontal),
+            scan_file_full_path: self.nullable(row, Column::ScanFileFullPath),
+        })
+    }
+}
+
+/// A row omitted from a response as one of its required columns could not be decoded
+#[derive(Debug, Clone)]
+pub struct SkippedRow {
+    /// The name of the column which could not be decoded
+    column: String,
+    /// A description of the decode failure
+    message: String,
+}
+
+/// The rows skipped whilst executing a request
+#[derive(Debug, Clone, Default)]
+struct SkippedRows(Arc<Mutex<Vec<SkippedRow>>>);
+
+/// Reads the scans selected by the query, decoding leniently if a decoder is supplied, along with the rows skipped by it
+pub async fn decode_scans(
+    database: &DatabaseConnection,
+    lenient_decoding: Option<&LenientDecoding>,
+    select: Select<Entity>,
+) -> Result<(Vec<Model>, Vec<SkippedRow>), DbErr> {
+    let Some(lenient_decoding) = lenient_decoding else {
+        return Ok((select.all(database).await?, Vec::new()));
+    };
+    let rows = database
+        .query_all(select.build(database.get_database_backend()))
+        .await?;
+    let mut skipped = Vec::new();
+    let models = rows
+        .iter()
+        .filter_map(|row| {
+            lenient_decoding
+                .decode(row)
+                .map_err(|skipped_row| skipped.push(skipped_row))
+                .ok()
+        })
+        .collect();
+    Ok((models, skipped))
+}
+
+/// Fetches the scans selected by the query, decoding leniently if enabled
+pub async fn fetch_scans(
+    ctx: &Context<'_>,
+    select: Select<Entity>,
+) -> async_graphql::Result<Vec<Model>> {
+    let (models, skipped) = decode_scans(
+        ctx.data::<DatabaseConnection>()?,
+        ctx.data_opt::<LenientDecoding>(),
+        select,
+    )
+    .await?;
+    if let Some(skipped_rows) = ctx.data_opt::<SkippedRows>() {
+        skipped_rows.0.lock().unwrap().extend(skipped);
+    }
+    Ok(models)
+}
+
+/// An extension listing the rows skipped due to decode failures in the `skippedRows` response extension
+#[derive(Debug)]
+pub struct SkippedRowsReport;
+
+impl ExtensionFactory for SkippedRowsReport {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(SkippedRowsReportExtension::default())
+    }
+}
+
+/// The per-request state of the [`SkippedRowsReport`] extension
+#[derive(Debug, Default)]
+struct SkippedRowsReportExtension(SkippedRows);
+
+#[async_trait]
+impl Extension for SkippedRowsReportExtension {
+    async fn request(&self, ctx: &ExtensionContext<'_>, next: NextRequest<'_>) -> Response {
+        let response = next.run(ctx).await;
+        let skipped_rows = std::mem::take(&mut *self.0 .0.lock().unwrap());
+        if skipped_rows.is_empty() {
+            return response;
+        }
+        response.extension(
+            "skippedRows",
+            Value::List(
+                skipped_rows
+                    .into_iter()
+                    .map(|skipped_row| {
+                        value!({
+                            "column": skipped_row.column,
+                            "message": skipped_row.message,
+                        })
+                    })
+                    .collect(),
+            ),
+        )
+    }
+
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        next.run(ctx, request.data(self.0.clone())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_scans, LenientDecoding};
+    use crate::{
+        fake_database::{model_row, scan, FakeDatabase},
+        FluorescenceScanService,
+    };
+    use async_graphql::Request;
+    use models::xfe_fluorescence_spectrum::{Column, Entity, Model};
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use sea_orm::{EntityTrait, IdenStatic, ProxyRow, Value};
+    use serde_json::json;
+
+    /// The row of the scan with the column replaced by a value of the wrong type
+    fn mismatched(scan: &Model, column: Column, value: Value) -> ProxyRow {
+        let mut row = model_row(scan);
+        row.values.insert(column.as_str().to_string(), value);
+        row
+    }
+
+    /// A database holding a well formed scan, a scan with a mistyped nullable column and a scan with a mistyped required column
+    fn database() -> FakeDatabase {
+        let recorded = |id| Model {
+            energy: Some(13500.0),
+            filename: Some(format!("{id}.dat")),
+            ..scan(id, 42, None, None)
+        };
+        FakeDatabase::with_results([vec![
+            model_row(&recorded(3)),
+            mismatched(
+                &recorded(5),
+                Column::Energy,
+                Value::String(Some(Box::new(String::from("high")))),
+            ),
+            mismatched(
+                &recorded(8),
+                Column::SessionId,
+                Value::String(Some(Box::new(String::from("forty-two")))),
+            ),
+        ]])
+    }
+
+    #[tokio::test]
+    async fn mistyped_nullable_columns_are_nulled_and_mistyped_required_columns_skipped() {
+        let database = database();
+        let lenient_decoding = LenientDecoding::new(&NoopMeterProvider::new());
+        let (scans, skipped) = decode_scans(
+            &database.connect().await,
+            Some(&lenient_decoding),
+            Entity::find(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            scans
+                .iter()
+                .map(|scan| (scan.xfe_fluorescence_spectrum_id, scan.energy))
+                .collect::<Vec<_>>(),
+            [(3, Some(13500.0)), (5, None)]
+        );
+        assert_eq!(scans[1].filename.as_deref(), Some("5.dat"));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].column, "sessionId");
+    }
+
+    #[tokio::test]
+    async fn mistyped_columns_fail_strict_decoding() {
+        let database = database();
+        assert!(
+            decode_scans(&database.connect().await, None, Entity::find())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn skipped_rows_are_reported_in_the_response() {
+        let database = database();
+        let service = FluorescenceScanService::builder(database.connect().await)
+            .lenient_decoding(true)
+            .build();
+        let response = service
+            .schema()
+            .execute(Request::new(
+                "{ fluorescenceScansBySession(sessionIds: [42]) { scans { id energy } error { code } } }",
+            ))
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(
+            response["data"]["fluorescenceScansBySession"],
+            json!([{
+                "scans": [{ "id": 3, "energy": 13500.0 }, { "id": 5, "energy": null }],
+                "error": null,
+            }])
+        );
+        let skipped = response["extensions"]["skippedRows"].as_array().unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0]["column"], "sessionId");
+    }
+}
@@ -0,0 +1,450 @@
+use async_graphql::{ErrorExtensions, SimpleObject};
+use futures::{stream, Stream};
+use models::xfe_fluorescence_spectrum;
+use sea_orm::{DatabaseConnection, EntityTrait};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use super::concurrency::ClientKey;
+use crate::{object_key::ObjectKey, store::ScanFileStore};
+
+/// The number of live spectra which may be watched concurrently by one principal, unless configured otherwise
+pub const DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL: usize = 4;
+
+/// The interval between polls of the data file when none is requested, in milliseconds
+pub const DEFAULT_POLL_INTERVAL_MS: u64 = 2000;
+
+/// A point of a fluorescence spectrum
+#[derive(Debug, Clone, Copy, SimpleObject)]
+pub struct SpectrumPoint {
+    /// The energy of the channel
+    energy: f64,
+    /// The counts recorded in the channel
+    counts: f64,
+}
+
+/// The points appended to the data file of a scan since the previous batch
+#[derive(Debug, Clone, SimpleObject)]
+pub struct SpectrumBatch {
+    /// The points read in this batch
+    points: Vec<SpectrumPoint>,
+    /// Whether the data file was rewritten, such that previously delivered points should be discarded
+    restarted: bool,
+    /// The number of bytes of the data file read so far
+    offset: u64,
+}
+
+/// Reads the points from the complete lines of the data, each of which holds the energy then the counts separated by whitespace, returning the number of bytes consumed
+///
+/// Blank lines, comments beginning with `#` and lines which do not begin with two numbers are skipped. A trailing line without a newline is only consumed when the data is final, as it may still be being written.
+fn parse_points(data: &[u8], last: bool, points: &mut Vec<SpectrumPoint>) -> usize {
+    let consumed = if last {
+        data.len()
+    } else {
+        data.iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |newline| newline + 1)
+    };
+    for line in String::from_utf8_lossy(&data[..consumed]).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut columns = line.split_whitespace().map(str::parse::<f64>);
+        if let (Some(Ok(energy)), Some(Ok(counts))) = (columns.next(), columns.next()) {
+            points.push(SpectrumPoint { energy, counts });
+        }
+    }
+    consumed
+}
+
+/// The number of live spectra being watched by each client
+type Watchers = Arc<Mutex<HashMap<ClientKey, usize>>>;
+
+/// Limits the number of live spectra which may be watched concurrently by the same principal
+///
+/// Clients presenting no subject are limited per websocket connection, so that anonymous clients do not share a single allowance.
+#[derive(Debug)]
+pub struct LiveSpectrumLimiter {
+    /// The number of live spectra which may be watched concurrently by each principal
+    limit: usize,
+    /// The number of live spectra being watched by each client
+    watchers: Watchers,
+}
+
+impl LiveSpectrumLimiter {
+    /// Creates a limiter permitting `limit` live spectra per principal
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            watchers: Arc::default(),
+        }
+    }
+
+    /// Takes a permit to watch a live spectrum for the client, producing a `RATE_LIMITED` error if they are already watching as many as permitted
+    fn acquire(&self, client: ClientKey) -> async_graphql::Result<LiveSpectrumPermit> {
+        let mut watchers = self.watchers.lock().unwrap();
+        let watching = watchers.entry(client.clone()).or_default();
+        if *watching >= self.limit {
+            return Err(
+                async_graphql::Error::new("Too many live spectra are being watched")
+                    .extend_with(|_, extensions| extensions.set("code", "RATE_LIMITED")),
+            );
+        }
+        *watching += 1;
+        Ok(LiveSpectrumPermit {
+            client,
+            watchers: self.watchers.clone(),
+        })
+    }
+}
+
+/// Permission to watch a live spectrum, released when dropped
+#[derive(Debug)]
+struct LiveSpectrumPermit {
+    /// The client watching the spectrum
+    client: ClientKey,
+    /// The number of live spectra being watched by each client
+    watchers: Watchers,
+}
+
+impl Drop for LiveSpectrumPermit {
+    fn drop(&mut self) {
+        let mut watchers = self.watchers.lock().unwrap();
+        if let Some(watching) = watchers.get_mut(&self.client) {
+            *watching -= 1;
+            if *watching == 0 {
+                watchers.remove(&self.client);
+            }
+        }
+    }
+}
+
+/// The progress of watching the data file of an in-progress scan
+#[derive(Debug)]
+struct Watch {
+    /// The store holding the data file
+    store: Arc<dyn ScanFileStore>,
+    /// The connection to the ISPyB database, used to find whether the scan has ended
+    database: DatabaseConnection,
+    /// The key of the data file
+    key: ObjectKey,
+    /// The scan whose data file is watched
+    scan_id: u32,
+    /// The interval between polls
+    interval: Duration,
+    /// The number of bytes of the data file consumed so far
+    offset: u64,
+    /// Whether the data file has been polled before
+    polled: bool,
+    /// Whether the scan has ended and the data file has been read to its end
+    ended: bool,
+    /// The permit held for as long as the spectrum is watched
+    _permit: LiveSpectrumPermit,
+}
+
+impl Watch {
+    /// Polls until points have been appended or the data file rewritten, or [`None`] once the scan has ended and every point has been delivered
+    async fn next_batch(&mut self) -> async_graphql::Result<Option<SpectrumBatch>> {
+        loop {
+            if self.ended {
+                return Ok(None);
+            }
+            if self.polled {
+                tokio::time::sleep(self.interval).await;
+            }
+            self.polled = true;
+            // Checked before reading, so that every point written before the scan ended is read
+            self.ended = !matches!(
+                xfe_fluorescence_spectrum::Entity::find_by_id(self.scan_id)
+                    .one(&self.database)
+                    .await?,
+                Some(scan) if scan.end_time.is_none()
+            );
+            let size = self.store.head(&self.key).await?.map(|info| info.size);
+            // Files replaced atomically with shorter contents are read again from the start
+            let restarted = size.is_some_and(|size| size < self.offset);
+            if restarted {
+                self.offset = 0;
+            }
+            let mut points = Vec::new();
+            if size.is_some_and(|size| size > self.offset) {
+                if let Some(tail) = self.store.get_from(&self.key, self.offset).await? {
+                    self.offset += parse_points(&tail, self.ended, &mut points) as u64;
+                }
+            }
+            if restarted || !points.is_empty() {
+                return Ok(Some(SpectrumBatch {
+                    points,
+                    restarted,
+                    offset: self.offset,
+                }));
+            }
+        }
+    }
+}
+
+/// Streams the points appended to the data file of the scan to the client, at the interval, until the scan has ended
+///
+/// No task is spawned, polling happens only whilst the stream is consumed, so watching stops as soon as the client unsubscribes or disconnects.
+pub fn watch_spectrum(
+    limiter: &LiveSpectrumLimiter,
+    client: ClientKey,
+    store: Arc<dyn ScanFileStore>,
+    database: DatabaseConnection,
+    key: ObjectKey,
+    scan_id: u32,
+    interval: Duration,
+) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<SpectrumBatch>>> {
+    let watch = Watch {
+        store,
+        database,
+        key,
+        scan_id,
+        interval,
+        offset: 0,
+        polled: false,
+        ended: false,
+        _permit: limiter.acquire(client)?,
+    };
+    Ok(stream::unfold(Some(watch), |watch| async move {
+        let mut watch = watch?;
+        match watch.next_batch().await {
+            Ok(Some(batch)) => Some((Ok(batch), Some(watch))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None)),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_points, watch_spectrum, LiveSpectrumLimiter, SpectrumBatch, SpectrumPoint};
+    use crate::{
+        authorization::{Claims, IspybMembership},
+        fake_database::{model_row, row, scan, FakeDatabase},
+        graphql::concurrency::{ClientKey, ConnectionId},
+        object_key::{ObjectKey, ObjectKeyRules},
+        store::testing::FakeStore,
+        FluorescenceScanService,
+    };
+    use async_graphql::Request;
+    use chrono::NaiveDate;
+    use futures::{Stream, StreamExt};
+    use sea_orm::Value;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// The energy and counts of each point parsed from the data, with the number of bytes consumed
+    fn parse(data: &str, last: bool) -> (Vec<(f64, f64)>, usize) {
+        let mut points = Vec::new();
+        let consumed = parse_points(data.as_bytes(), last, &mut points);
+        (
+            points
+                .into_iter()
+                .map(|SpectrumPoint { energy, counts }| (energy, counts))
+                .collect(),
+            consumed,
+        )
+    }
+
+    #[test]
+    fn complete_lines_are_parsed() {
+        assert_eq!(
+            parse("1.5 10\n2.5\t20\r\n  3e1   3.5  extra\n", false),
+            (vec![(1.5, 10.0), (2.5, 20.0), (30.0, 3.5)], 34)
+        );
+    }
+
+    #[test]
+    fn trailing_line_is_consumed_only_when_final() {
+        assert_eq!(parse("1 10\n2 2", false), (vec![(1.0, 10.0)], 5));
+        assert_eq!(parse("1 10\n2 2", true), (vec![(1.0, 10.0), (2.0, 2.0)], 8));
+        assert_eq!(parse("1 1", false), (vec![], 0));
+    }
+
+    #[test]
+    fn blank_comment_and_malformed_lines_are_skipped() {
+        assert_eq!(
+            parse(
+                "# energy counts\n\n   \n  # indented\n1\nenergy 2\n4 counts\n5 50\n",
+                false
+            ),
+            (vec![(5.0, 50.0)], 59)
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_does_not_hide_later_points() {
+        let mut points = Vec::new();
+        let consumed = parse_points(b"\xff\xfe 1\n6 60\n", false, &mut points);
+        assert_eq!(consumed, 10);
+        assert_eq!(points.len(), 1);
+        assert_eq!((points[0].energy, points[0].counts), (6.0, 60.0));
+    }
+
+    #[test]
+    fn anonymous_connections_are_limited_separately() {
+        let limiter = LiveSpectrumLimiter::new(1);
+        let first = ClientKey::Connection(ConnectionId::next());
+        let second = ClientKey::Connection(ConnectionId::next());
+        let _permit = limiter.acquire(first.clone()).unwrap();
+        assert!(limiter.acquire(first).is_err());
+        let _other = limiter.acquire(second).unwrap();
+    }
+
+    #[test]
+    fn released_permits_are_reusable() {
+        let limiter = LiveSpectrumLimiter::new(1);
+        let principal = ClientKey::Principal(String::from("abc12345"));
+        drop(limiter.acquire(principal.clone()).unwrap());
+        let _permit = limiter.acquire(principal).unwrap();
+    }
+
+    /// The first response to a subscription to the live spectrum of scan 3, by a user who is a member of no session
+    async fn subscribe(database: FakeDatabase) -> async_graphql::Response {
+        let connection = database.connect().await;
+        let service = FluorescenceScanService::builder(connection.clone())
+            .authorization_policy(Arc::new(IspybMembership::new(connection, "admin")))
+            .build();
+        let claims = Claims {
+            subject: Some(String::from("abc12345")),
+            groups: Vec::new(),
+        };
+        service
+            .schema()
+            .execute_stream(
+                Request::new("subscription { liveSpectrum(scanId: 3) { offset } }").data(claims),
+            )
+            .next()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn unknown_scans_are_refused_as_unreadable_scans_are() {
+        let unreadable = subscribe(FakeDatabase::with_results([
+            vec![model_row(&scan(3, 42, Some("/dls/i18/scan.dat"), None))],
+            vec![row([("num_items", Value::Int(Some(0)))])],
+        ]))
+        .await;
+        let unknown = subscribe(FakeDatabase::with_results([vec![]])).await;
+        assert_eq!(unreadable.errors.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&unreadable.errors).unwrap(),
+            serde_json::to_value(&unknown.errors).unwrap()
+        );
+        assert_eq!(
+            unknown.errors[0].extensions.as_ref().unwrap().get("code"),
+            Some(&async_graphql::Value::from("FORBIDDEN"))
+        );
+    }
+
+    /// The key of the data file of the watched scan
+    const DATA_FILE: &str = "i18/scan.dat";
+
+    /// A database holding scan 3, which has ended once the flag is set
+    fn scan_database(ended: Arc<AtomicBool>) -> FakeDatabase {
+        FakeDatabase::new(move |_| {
+            let end_time = ended.load(Ordering::SeqCst).then(|| {
+                NaiveDate::from_ymd_opt(2024, 3, 5)
+                    .unwrap()
+                    .and_hms_opt(9, 31, 30)
+                    .unwrap()
+            });
+            Ok(vec![model_row(&models::xfe_fluorescence_spectrum::Model {
+                end_time,
+                ..scan(3, 42, Some(DATA_FILE), None)
+            })])
+        })
+    }
+
+    /// Watches the data file of scan 3 in the store, polling every few milliseconds
+    async fn watch(
+        store: Arc<FakeStore>,
+        ended: Arc<AtomicBool>,
+    ) -> impl Stream<Item = async_graphql::Result<SpectrumBatch>> {
+        watch_spectrum(
+            &LiveSpectrumLimiter::new(1),
+            ClientKey::Principal(String::from("abc12345")),
+            store,
+            scan_database(ended).connect().await,
+            ObjectKey::scan_data(&ObjectKeyRules::default(), DATA_FILE).unwrap(),
+            3,
+            Duration::from_millis(5),
+        )
+        .unwrap()
+    }
+
+    /// The energy and counts of each point of the batch, whether it restarted and its offset
+    fn summary(batch: SpectrumBatch) -> (Vec<(f64, f64)>, bool, u64) {
+        (
+            batch
+                .points
+                .into_iter()
+                .map(|SpectrumPoint { energy, counts }| (energy, counts))
+                .collect(),
+            batch.restarted,
+            batch.offset,
+        )
+    }
+
+    /// The next batch of the stream, which must not fail
+    async fn next(
+        stream: &mut (impl Stream<Item = async_graphql::Result<SpectrumBatch>> + Unpin),
+    ) -> (Vec<(f64, f64)>, bool, u64) {
+        summary(stream.next().await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn the_offset_advances_across_polls() {
+        let store = FakeStore::new([(DATA_FILE, &b"1 10\n"[..])]);
+        let mut stream = Box::pin(watch(store.clone(), Arc::default()).await);
+        assert_eq!(next(&mut stream).await, (vec![(1.0, 10.0)], false, 5));
+        store.put(DATA_FILE, b"1 10\n2 20\n3 30\n");
+        assert_eq!(
+            next(&mut stream).await,
+            (vec![(2.0, 20.0), (3.0, 30.0)], false, 15)
+        );
+    }
+
+    #[tokio::test]
+    async fn partial_trailing_lines_are_held_back() {
+        let store = FakeStore::new([(DATA_FILE, &b"1 10\n2 2"[..])]);
+        let mut stream = Box::pin(watch(store.clone(), Arc::default()).await);
+        assert_eq!(next(&mut stream).await, (vec![(1.0, 10.0)], false, 5));
+        store.put(DATA_FILE, b"1 10\n2 20\n");
+        assert_eq!(next(&mut stream).await, (vec![(2.0, 20.0)], false, 10));
+    }
+
+    #[tokio::test]
+    async fn shrunk_files_are_read_again_from_the_start() {
+        let store = FakeStore::new([(DATA_FILE, &b"1 10\n2 20\n"[..])]);
+        let mut stream = Box::pin(watch(store.clone(), Arc::default()).await);
+        assert_eq!(
+            next(&mut stream).await,
+            (vec![(1.0, 10.0), (2.0, 20.0)], false, 10)
+        );
+        store.put(DATA_FILE, b"3 30\n");
+        assert_eq!(next(&mut stream).await, (vec![(3.0, 30.0)], true, 5));
+    }
+
+    #[tokio::test]
+    async fn the_stream_completes_once_the_scan_has_ended() {
+        let store = FakeStore::new([(DATA_FILE, &b"1 10\n2 2"[..])]);
+        let ended = Arc::new(AtomicBool::new(false));
+        let mut stream = Box::pin(watch(store.clone(), ended.clone()).await);
+        assert_eq!(next(&mut stream).await, (vec![(1.0, 10.0)], false, 5));
+        ended.store(true, Ordering::SeqCst);
+        assert_eq!(next(&mut stream).await, (vec![(2.0, 2.0)], false, 8));
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextPrepareRequest},
+    Context, Request, ServerResult,
+};
+use std::{
+    any::Any,
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+/// Identifies a resolver call by the resolver name, the session it concerns and a hash of all of its arguments
+type MemoKey = (&'static str, u32, u64);
+
+/// The results of the idempotent resolvers executed within a single request
+///
+/// Unlike a cross-request cache this holds no stale data, as it is dropped when the request completes.
+#[derive(Debug, Clone, Default)]
+struct RequestMemo(Arc<Mutex<HashMap<MemoKey, Arc<dyn Any + Send + Sync>>>>);
+
+/// Resolves a session level field once per request for each distinct set of arguments, sharing the result between repeated selections
+///
+/// Failures are not memoised, so a repeated selection retries.
+pub async fn memoised<A, T, F>(
+    ctx: &Context<'_>,
+    resolver: &'static str,
+    session_id: u32,
+    arguments: &A,
+    resolve: F,
+) -> async_graphql::Result<T>
+where
+    A: Hash,
+    T: Clone + Send + Sync + 'static,
+    F: Future<Output = async_graphql::Result<T>>,
+{
+    let Some(memo) = ctx.data_opt::<RequestMemo>() else {
+        return resolve.await;
+    };
+    let mut hasher = DefaultHasher::new();
+    arguments.hash(&mut hasher);
+    let key = (resolver, session_id, hasher.finish());
+    let cell = memo
+        .0
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(OnceCell::<T>::new()))
+        .clone()
+        .downcast::<OnceCell<T>>()
+        .expect("Each resolver memoises a single type");
+    cell.get_or_try_init(|| resolve).await.cloned()
+}
+
+/// An extension providing each request with a [`RequestMemo`]
+#[derive(Debug)]
+pub struct RequestMemoisation;
+
+impl ExtensionFactory for RequestMemoisation {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(RequestMemoisationExtension)
+    }
+}
+
+/// The per-request state of the [`RequestMemoisation`] extension
+#[derive(Debug)]
+struct RequestMemoisationExtension;
+
+#[async_trait]
+impl Extension for RequestMemoisationExtension {
+    async fn prepare_request(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        request: Request,
+        next: NextPrepareRequest<'_>,
+    ) -> ServerResult<Request> {
+        next.run(ctx, request.data(RequestMemo::default())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fake_database::{model_row, scan, FakeDatabase},
+        FluorescenceScanService,
+    };
+    use serde_json::json;
+
+    /// Executes the query against the session entities, failing on any error
+    async fn execute(service: &FluorescenceScanService, query: &str, session_ids: &[u32]) {
+        let representations = session_ids
+            .iter()
+            .map(|id| json!({ "__typename": "Session", "id": id }))
+            .collect::<Vec<_>>();
+        let response = service
+            .schema()
+            .execute(async_graphql::Request::new(query).variables(
+                async_graphql::Variables::from_json(json!({ "representations": representations })),
+            ))
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+    }
+
+    /// Selections of the scans of each session through duplicated fragments and aliases
+    const DUPLICATED_FRAGMENTS: &str = r#"
+        query ($representations: [_Any!]!) {
+            _entities(representations: $representations) {
+                ... on Session { ...Ids ...Files first: fluorescenceScan { id } }
+            }
+        }
+        fragment Ids on Session { fluorescenceScan { id } again: fluorescenceScan { id } }
+        fragment Files on Session { fluorescenceScan { sessionId } }
+    "#;
+
+    #[tokio::test]
+    async fn duplicated_fragments_query_the_database_once() {
+        let database = FakeDatabase::new(|_| Ok(vec![model_row(&scan(3, 1, None, None))]));
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        execute(&service, DUPLICATED_FRAGMENTS, &[1, 1]).await;
+        assert_eq!(database.queries().len(), 1, "{:?}", database.queries());
+    }
+
+    #[tokio::test]
+    async fn memo_is_scoped_to_the_session_and_request() {
+        let database = FakeDatabase::new(|_| Ok(vec![model_row(&scan(3, 1, None, None))]));
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        execute(&service, DUPLICATED_FRAGMENTS, &[1, 2, 1]).await;
+        assert_eq!(database.queries().len(), 2, "{:?}", database.queries());
+        execute(&service, DUPLICATED_FRAGMENTS, &[1]).await;
+        assert_eq!(database.queries().len(), 3, "{:?}", database.queries());
+    }
+
+    #[tokio::test]
+    async fn memo_distinguishes_arguments() {
+        let database = FakeDatabase::new(|_| Ok(Vec::new()));
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        execute(
+            &service,
+            r#"
+                query ($representations: [_Any!]!) {
+                    _entities(representations: $representations) {
+                        ... on Session {
+                            first: fluorescenceScanPage(page: 0, pageSize: 2) { totalCount }
+                            repeated: fluorescenceScanPage(page: 0, pageSize: 2) { totalCount }
+                            second: fluorescenceScanPage(page: 1, pageSize: 2) { totalCount }
+                            larger: fluorescenceScanPage(page: 0, pageSize: 3) { totalCount }
+                        }
+                    }
+                }
+            "#,
+            &[1],
+        )
+        .await;
+        let queries = database.queries();
+        assert_eq!(queries.len(), 6, "{queries:?}");
+        assert_eq!(
+            queries
+                .iter()
+                .filter(|query| query.contains("COUNT"))
+                .count(),
+            3,
+            "{queries:?}"
+        );
+    }
+}
@@ -1,40 +1,444 @@
+/// Recording of the jpeg paths of older scans from the objects stored alongside them
+mod backfill;
+/// Cursors over the changes to the fluorescence scans of a session
+mod change_feed;
+/// Fair sharing of expensive resolvers between clients
+mod concurrency;
+/// Restriction of the schema to the fields tagged for a contract
+mod contract;
+/// Estimation of query cost against the configured limits
+mod cost_estimate;
+/// Counting of the requests which use deprecated fields
+mod deprecation_usage;
+/// Descriptions of how scan files are located, for diagnosing failed downloads
+mod diagnostics;
 /// Collection of graphql entities
 mod entities;
+/// Counting of the resolutions of each field of the schema
+mod field_usage;
+/// Decoding of rows which do not match the generated models
+mod lenient_decoding;
+/// Streaming of the points appended to the data files of in-progress scans
+mod live_spectrum;
+/// Sharing of resolver results between repeated selections within a request
+mod memo;
+/// Batched loading of scans by id, for federated entity requests
+mod scan_loader;
+/// Limits on the aliases, repeated fields, directives and selections of operations
+mod selection_limits;
+/// Discovery of the snapshot variants stored alongside a scan
+mod snapshots;
+/// Reading of the proposals and visits of sessions
+mod visit;
 use async_graphql::{
-    ComplexObject, Context, EmptyMutation, EmptySubscription, Object, Schema, SchemaBuilder,
+    dataloader::DataLoader, ComplexObject, Context, ErrorExtensions, Object, Schema, SchemaBuilder,
+    Subscription,
 };
-use entities::{FluorescenceScan, Session};
+pub use backfill::{BackfillLimit, DEFAULT_BACKFILL_LIMIT};
+pub use concurrency::{ConcurrencyLimiter, ConnectionId, DEFAULT_PER_CLIENT_CONCURRENCY};
+pub use cost_estimate::{QueryLimits, ESTIMATE_COST_EXTENSION};
+pub use deprecation_usage::{ClientName, DeprecationUsage, CLIENT_NAME_HEADER};
+pub use diagnostics::DownloadDiagnosticsEnabled;
+pub use field_usage::FieldUsage;
+
+use cost_estimate::MaxPageSize;
+pub use lenient_decoding::{LenientDecoding, SkippedRowsReport};
+pub use live_spectrum::{LiveSpectrumLimiter, DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL};
+pub use scan_loader::ScanLoader;
+pub use selection_limits::SelectionLimits;
+pub use snapshots::{SnapshotVariant, SnapshotVariants, DEFAULT_SNAPSHOT_VARIANTS};
+pub use visit::{ProposalAccess, VisitLoader};
+
+use backfill::{backfill_jpeg_paths, BackfillResult};
+use change_feed::ChangeCursor;
+pub use change_feed::{SettleInterval, DEFAULT_SETTLE_INTERVAL};
+use concurrency::ClientKey;
+use contract::{with_contract, ContractRoot};
+use diagnostics::{diagnose, ObjectDiagnostics};
+use entities::{
+    FluorescenceScan, FluorescenceScanChanges, FluorescenceScanPage, ScanQueryError, Session,
+    SessionScansResult,
+};
+use futures::Stream;
+use lenient_decoding::fetch_scans;
+use live_spectrum::{watch_spectrum, SpectrumBatch, DEFAULT_POLL_INTERVAL_MS};
+use memo::{memoised, RequestMemoisation};
 use models::xfe_fluorescence_spectrum;
+use percent_encoding::utf8_percent_encode;
+use snapshots::{find_snapshots, Snapshot};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{instrument, Span};
+use visit::{session_visit, SessionVisit};
+
+use crate::{
+    authorization::{Action, AuthorizationPolicy, Claims, Decision, InternalRequest},
+    file_proxy::FileProxy,
+    negative_cache::NegativeCache,
+    object_key::{url_path, KeyFamily, ObjectKey, SEGMENT_ENCODE_SET},
+    redaction::PathRedaction,
+    store::ScanFiles,
+};
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+
+/// The duration for which presigned URLs remain valid
+const PRESIGNED_URL_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// The host of deterministic URLs, under the reserved `.invalid` top level domain so that they can never resolve
+const DETERMINISTIC_URL_HOST: &str = "deterministic-urls.invalid";
 
-use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+/// The presence of this in the schema data replaces presigned URLs with deterministic fake URLs, for contract tests requiring stable responses
+///
+/// Every object is also taken to exist without consulting the store, so that verified URLs and snapshots do not depend upon its contents.
+#[derive(Debug, Clone, Copy)]
+pub struct DeterministicUrls;
 
 /// The GraphQL schema exposed by the service
-pub type RootSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+pub type RootSchema =
+    Schema<ContractRoot<Query>, ContractRoot<Mutation>, ContractRoot<Subscription>>;
 
-/// A schema builder for the service
-pub fn root_schema_builder() -> SchemaBuilder<Query, EmptyMutation, EmptySubscription> {
-    Schema::build(Query, EmptyMutation, EmptySubscription).enable_federation()
+/// A schema builder for the service, exposing only the fields tagged for the contract if one is supplied
+pub fn root_schema_builder(
+    contract: Option<&str>,
+) -> SchemaBuilder<ContractRoot<Query>, ContractRoot<Mutation>, ContractRoot<Subscription>> {
+    with_contract(contract, || {
+        Schema::build(
+            ContractRoot(Query),
+            ContractRoot(Mutation),
+            ContractRoot(Subscription),
+        )
+    })
+    .enable_federation()
+    .enable_subscription_in_federation()
+    .extension(RequestMemoisation)
 }
 
 /// The root query of the service
 #[derive(Debug, Clone, Default)]
 pub struct Query;
 
+/// The root mutation of the service
+#[derive(Debug, Clone, Default)]
+pub struct Mutation;
+
+/// The root subscription of the service
+#[derive(Debug, Clone, Default)]
+pub struct Subscription;
+
 #[ComplexObject]
 impl Session {
     /// Fetched all flourescence scans and generates s3 URLs
+    #[graphql(tag = "public")]
     async fn fluorescence_scan(
         &self,
         ctx: &Context<'_>,
     ) -> async_graphql::Result<Vec<FluorescenceScan>> {
-        let database = ctx.data::<DatabaseConnection>()?;
-        Ok(xfe_fluorescence_spectrum::Entity::find()
-            .filter(xfe_fluorescence_spectrum::Column::SessionId.eq(self.id))
-            .all(database)
+        authorize(
+            ctx,
+            Action::SessionRead {
+                session_id: self.id,
+            },
+        )
+        .await?;
+        memoised(ctx, "fluorescenceScan", self.id, &(), async {
+            let _permit = ctx
+                .data::<ConcurrencyLimiter>()?
+                .acquire(client_key(ctx, self.id))
+                .await?;
+            Ok(fetch_scans(
+                ctx,
+                xfe_fluorescence_spectrum::Entity::find()
+                    .filter(xfe_fluorescence_spectrum::Column::SessionId.eq(self.id)),
+            )
             .await?
             .into_iter()
             .map(FluorescenceScan::from)
             .collect())
+        })
+        .await
+    }
+
+    /// Fetches a page of the flourescence scans, ordered by id, for clients which paginate by page number
+    #[graphql(
+        deprecation = "Use cursor based pagination",
+        complexity = "page_complexity(page_size, child_complexity)"
+    )]
+    async fn fluorescence_scan_page(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "The zero-based index of the page")] page: u64,
+        #[graphql(validator(minimum = 1))] page_size: u64,
+    ) -> async_graphql::Result<FluorescenceScanPage> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let MaxPageSize(max_page_size) = *ctx.data::<MaxPageSize>()?;
+        if page_size > max_page_size {
+            return Err(async_graphql::Error::new(format!(
+                "pageSize must not exceed {max_page_size}"
+            ))
+            .extend_with(|_, extensions| extensions.set("code", "PAGE_SIZE_EXCEEDED")));
+        }
+        authorize(
+            ctx,
+            Action::SessionRead {
+                session_id: self.id,
+            },
+        )
+        .await?;
+        memoised(
+            ctx,
+            "fluorescenceScanPage",
+            self.id,
+            &(page, page_size),
+            async {
+                let _permit = ctx
+                    .data::<ConcurrencyLimiter>()?
+                    .acquire(client_key(ctx, self.id))
+                    .await?;
+                let select = xfe_fluorescence_spectrum::Entity::find()
+                    .filter(xfe_fluorescence_spectrum::Column::SessionId.eq(self.id))
+                    .order_by_asc(xfe_fluorescence_spectrum::Column::XfeFluorescenceSpectrumId);
+                let totals = select
+                    .clone()
+                    .paginate(database, page_size)
+                    .num_items_and_pages()
+                    .await?;
+                let items = fetch_scans(
+                    ctx,
+                    select
+                        .offset(page.saturating_mul(page_size))
+                        .limit(page_size),
+                )
+                .await?;
+                Ok(FluorescenceScanPage {
+                    items: items.into_iter().map(FluorescenceScan::from).collect(),
+                    total_pages: totals.number_of_pages,
+                    total_count: totals.number_of_items,
+                })
+            },
+        )
+        .await
+    }
+
+    /// The code of the proposal under which the session was allocated, such as mx, or null if it cannot be read
+    async fn proposal_code(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<String>> {
+        Ok(authorized_visit(ctx, self.id)
+            .await?
+            .and_then(|visit| visit.proposal_code))
+    }
+
+    /// The number of the proposal under which the session was allocated, or null if it cannot be read
+    async fn proposal_number(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<String>> {
+        Ok(authorized_visit(ctx, self.id)
+            .await?
+            .and_then(|visit| visit.proposal_number))
+    }
+
+    /// The number of the visit within the proposal, or null if it cannot be read
+    async fn visit_number(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<u32>> {
+        Ok(authorized_visit(ctx, self.id)
+            .await?
+            .and_then(|visit| visit.visit_number))
+    }
+
+    /// The conventional name of the visit, such as mx12345-6, or null if any part of it cannot be read
+    async fn visit(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<String>> {
+        Ok(authorized_visit(ctx, self.id)
+            .await?
+            .and_then(|visit| visit.visit()))
+    }
+}
+
+/// The complexity of a page of items of the child complexity, assuming the page is full
+///
+/// The complexity saturates well below the maximum of `usize`, as async-graphql sums the complexities of the fields of the query without checking for overflow.
+fn page_complexity(page_size: u64, child_complexity: usize) -> usize {
+    let cap = u32::MAX as usize;
+    usize::try_from(page_size)
+        .unwrap_or(cap)
+        .min(cap)
+        .saturating_mul(child_complexity)
+        .min(cap)
+}
+
+/// The proposal and visit of the session, if the client may read the session and they can be read
+async fn authorized_visit(
+    ctx: &Context<'_>,
+    session_id: u32,
+) -> async_graphql::Result<Option<SessionVisit>> {
+    authorize(ctx, Action::SessionRead { session_id }).await?;
+    session_visit(ctx, session_id).await
+}
+
+/// The subject of the authenticated principal making the request, if there is one
+fn principal(ctx: &Context<'_>) -> Option<String> {
+    ctx.data_opt::<Claims>()
+        .and_then(|claims| claims.subject.clone())
+}
+
+/// The client on whose behalf work concerning the session runs, which is the authenticated principal if there is one, otherwise the session
+fn client_key(ctx: &Context<'_>, session_id: u32) -> ClientKey {
+    principal(ctx).map_or(ClientKey::Session(session_id), ClientKey::Principal)
+}
+
+/// Asks the configured policy, once per request for each action, whether the client may perform the action, producing a `FORBIDDEN` error if not
+async fn authorize(ctx: &Context<'_>, action: Action) -> async_graphql::Result<()> {
+    decide(ctx, action).await?.into_result()
+}
+
+/// Asks the configured policy, once per request for each action, whether the client may perform the action, failing only if the policy could not decide
+async fn decide(ctx: &Context<'_>, action: Action) -> async_graphql::Result<Decision> {
+    if ctx.data_opt::<InternalRequest>().is_some() {
+        return Ok(Decision::Allow);
+    }
+    memoised(ctx, "authorize", 0, &action, async {
+        let policy = ctx.data::<Arc<dyn AuthorizationPolicy>>()?;
+        let claims = ctx.data_opt::<Claims>().cloned().unwrap_or_default();
+        Ok(policy.decide(&claims, action).await?)
+    })
+    .await
+}
+
+/// Generates a URL granting temporary read access to the object, presigned by the store or signed for the file proxy
+///
+/// If [`DeterministicUrls`] are enabled, a fake URL derived from only the store location and key is produced instead.
+#[instrument(skip_all, fields(object_key = tracing::field::Empty))]
+async fn presigned_url(ctx: &Context<'_>, key: &ObjectKey) -> async_graphql::Result<String> {
+    ctx.data::<PathRedaction>()?
+        .record(&Span::current(), "object_key", key);
+    let files = ctx.data::<ScanFiles>()?;
+    if ctx.data_opt::<DeterministicUrls>().is_some() {
+        return Ok(format!(
+            "https://{DETERMINISTIC_URL_HOST}/{}/{}",
+            utf8_percent_encode(&files.store.location(), SEGMENT_ENCODE_SET),
+            url_path(key)
+        ));
+    }
+    match files.store.presigned_url(key, PRESIGNED_URL_EXPIRY).await? {
+        Some(url) => Ok(url),
+        None => Ok(ctx.data::<FileProxy>()?.url(key, PRESIGNED_URL_EXPIRY)),
+    }
+}
+
+/// Checks whether the object exists, consulting and maintaining the cache of missing objects and recording any lookup error in it
+///
+/// If [`DeterministicUrls`] are enabled, every object exists.
+#[instrument(skip_all, fields(object_key = tracing::field::Empty))]
+async fn object_exists(ctx: &Context<'_>, key: &ObjectKey) -> async_graphql::Result<bool> {
+    ctx.data::<PathRedaction>()?
+        .record(&Span::current(), "object_key", key);
+    if ctx.data_opt::<DeterministicUrls>().is_some() {
+        return Ok(true);
+    }
+    let negative_cache = ctx.data::<Arc<NegativeCache>>()?;
+    if negative_cache.is_missing(key) {
+        return Ok(false);
+    }
+    let info = ctx
+        .data::<ScanFiles>()?
+        .store
+        .head(key)
+        .await
+        .inspect_err(|err| negative_cache.record_error(key, err))?;
+    if info.is_some() {
+        Ok(true)
+    } else {
+        negative_cache.insert(key.clone());
+        Ok(false)
+    }
+}
+
+/// Generates a presigned URL for the object, or if verification is requested and the object does not exist [`None`]
+async fn object_url(
+    ctx: &Context<'_>,
+    key: &ObjectKey,
+    verify: bool,
+) -> async_graphql::Result<Option<String>> {
+    if verify && !object_exists(ctx, key).await? {
+        return Ok(None);
+    }
+    Ok(Some(presigned_url(ctx, key).await?))
+}
+
+#[ComplexObject]
+impl FluorescenceScan {
+    /// A presigned URL from which the jpeg rendering of the scan can be downloaded
+    #[graphql(tag = "public")]
+    async fn jpeg_scan_url(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(
+            default = false,
+            desc = "Checks the object exists, producing null if it does not"
+        )]
+        verify: bool,
+    ) -> async_graphql::Result<Option<String>> {
+        let Some(path) = &self.jpeg_scan_file_full_path else {
+            return Ok(None);
+        };
+        let key = ObjectKey::scan_jpeg(&ctx.data::<ScanFiles>()?.key_rules, path)?;
+        object_url(ctx, &key, verify).await
+    }
+
+    /// Describes how the scan files are located in the store, available only when debug endpoints are enabled
+    #[graphql(tag = "internal")]
+    async fn download_diagnostics(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Vec<ObjectDiagnostics>> {
+        authorize(
+            ctx,
+            Action::RestrictedField {
+                field: "downloadDiagnostics",
+            },
+        )
+        .await?;
+        Ok(vec![
+            diagnose(
+                ctx,
+                KeyFamily::ScanJpeg,
+                self.jpeg_scan_file_full_path.as_deref(),
+            )
+            .await?,
+            diagnose(
+                ctx,
+                KeyFamily::ScanData,
+                self.scan_file_full_path.as_deref(),
+            )
+            .await?,
+        ])
+    }
+
+    /// The jpeg snapshots of the scan which exist in the bucket, including any annotated variants
+    #[graphql(tag = "public")]
+    async fn snapshots(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Snapshot>> {
+        match &self.jpeg_scan_file_full_path {
+            Some(path) => find_snapshots(ctx, path).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// A presigned URL from which the raw scan file can be downloaded
+    #[graphql(tag = "public")]
+    async fn scan_file_url(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(
+            default = false,
+            desc = "Checks the object exists, producing null if it does not"
+        )]
+        verify: bool,
+    ) -> async_graphql::Result<Option<String>> {
+        let Some(path) = &self.scan_file_full_path else {
+            return Ok(None);
+        };
+        let key = ObjectKey::scan_data(&ctx.data::<ScanFiles>()?.key_rules, path)?;
+        object_url(ctx, &key, verify).await
     }
 }
 
@@ -45,4 +449,600 @@ impl Query {
     async fn router_session(&self, id: u32) -> Session {
         Session { id }
     }
+
+    /// Reference fluorescence scans resolver for the router, producing null for scans which do not exist or may not be read
+    ///
+    /// An error would fail every representation of the request, so scans which cannot be produced are null in their own position instead.
+    #[graphql(entity)]
+    async fn router_fluorescence_scan(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+    ) -> async_graphql::Result<Option<FluorescenceScan>> {
+        let Some(scan) = ctx.data::<DataLoader<ScanLoader>>()?.load_one(id).await? else {
+            return Ok(None);
+        };
+        let decision = decide(
+            ctx,
+            Action::ScanRead {
+                session_id: scan.session_id,
+                scan_id: id,
+            },
+        )
+        .await?;
+        Ok(matches!(decision, Decision::Allow).then(|| FluorescenceScan::from(scan)))
+    }
+
+    /// Fetches the fluorescence scans of a session recorded since the cursor, in the order they were recorded
+    ///
+    /// ISPyB sets the record timestamp only when a scan is inserted, so later updates to a scan, such as its end time being recorded, are not reported. Scans become visible once recorded for longer than the configured settle interval, and each is delivered at least once provided the transaction recording it committed within that interval.
+    #[graphql(tag = "public")]
+    async fn fluorescence_scan_changes(
+        &self,
+        ctx: &Context<'_>,
+        session_id: u32,
+        cursor: Option<String>,
+        #[graphql(validator(minimum = 1, maximum = 1000))] limit: u64,
+    ) -> async_graphql::Result<FluorescenceScanChanges> {
+        let cursor = cursor
+            .as_deref()
+            .map(ChangeCursor::decode)
+            .transpose()?
+            .unwrap_or_else(ChangeCursor::start);
+        authorize(ctx, Action::SessionRead { session_id }).await?;
+        let _permit = ctx
+            .data::<ConcurrencyLimiter>()?
+            .acquire(client_key(ctx, session_id))
+            .await?;
+        let mut rows = fetch_scans(
+            ctx,
+            xfe_fluorescence_spectrum::Entity::find()
+                .filter(xfe_fluorescence_spectrum::Column::SessionId.eq(session_id))
+                .filter(cursor.following(*ctx.data::<SettleInterval>()?))
+                .order_by_asc(xfe_fluorescence_spectrum::Column::RecordTimeStamp)
+                .order_by_asc(xfe_fluorescence_spectrum::Column::XfeFluorescenceSpectrumId)
+                .limit(limit + 1),
+        )
+        .await?;
+        let has_more = rows.len() as u64 > limit;
+        rows.truncate(limit as usize);
+        let next_cursor = rows.last().map(ChangeCursor::after).unwrap_or(cursor);
+        Ok(FluorescenceScanChanges {
+            changes: rows.into_iter().map(FluorescenceScan::from).collect(),
+            next_cursor: next_cursor.encode(),
+            has_more,
+        })
+    }
+
+    /// Fetches the fluorescence scans of each of a batch of sessions, in the order requested, with an error in place of the scans of any session which cannot be read
+    ///
+    /// Failures affecting the whole batch, such as the database being unreachable, fail the query.
+    #[graphql(tag = "public", complexity = "session_ids.len() * child_complexity")]
+    async fn fluorescence_scans_by_session(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(validator(max_items = 100))] session_ids: Vec<u32>,
+    ) -> async_graphql::Result<Vec<SessionScansResult>> {
+        let limiter = ctx.data::<ConcurrencyLimiter>()?;
+        let principal = principal(ctx);
+        let mut readable = Vec::new();
+        let mut permits = Vec::new();
+        // The batch takes a single permit of the principal, as one for each session would exhaust the limit of the principal itself
+        if let Some(subject) = &principal {
+            permits.push(
+                limiter
+                    .acquire(ClientKey::Principal(subject.clone()))
+                    .await?,
+            );
+        }
+        let mut errors = HashMap::new();
+        for session_id in session_ids.iter().copied().collect::<HashSet<_>>() {
+            match decide(ctx, Action::SessionRead { session_id }).await? {
+                Decision::Allow if principal.is_some() => readable.push(session_id),
+                Decision::Allow => match limiter.acquire(ClientKey::Session(session_id)).await {
+                    Ok(permit) => {
+                        readable.push(session_id);
+                        permits.push(permit);
+                    }
+                    Err(err) => {
+                        errors.insert(
+                            session_id,
+                            ScanQueryError {
+                                code: "RATE_LIMITED".to_string(),
+                                message: err.message,
+                            },
+                        );
+                    }
+                },
+                Decision::Deny(reason) => {
+                    errors.insert(
+                        session_id,
+                        ScanQueryError {
+                            code: "FORBIDDEN".to_string(),
+                            message: reason,
+                        },
+                    );
+                }
+            }
+        }
+        let mut scans = HashMap::<u32, Vec<FluorescenceScan>>::new();
+        if !readable.is_empty() {
+            for scan in fetch_scans(
+                ctx,
+                xfe_fluorescence_spectrum::Entity::find()
+                    .filter(xfe_fluorescence_spectrum::Column::SessionId.is_in(readable))
+                    .order_by_asc(xfe_fluorescence_spectrum::Column::XfeFluorescenceSpectrumId),
+            )
+            .await?
+            {
+                scans
+                    .entry(scan.session_id)
+                    .or_default()
+                    .push(FluorescenceScan::from(scan));
+            }
+        }
+        drop(permits);
+        Ok(session_ids
+            .into_iter()
+            .map(|session_id| match errors.get(&session_id) {
+                Some(error) => SessionScansResult {
+                    session_id,
+                    scans: None,
+                    error: Some(error.clone()),
+                },
+                None => SessionScansResult {
+                    session_id,
+                    scans: Some(scans.get(&session_id).cloned().unwrap_or_default()),
+                    error: None,
+                },
+            })
+            .collect())
+    }
+}
+
+#[Object]
+impl Mutation {
+    /// Records the jpeg path of each scan in the session which has a scan file but no jpeg path, where a jpeg is stored alongside the scan file by convention
+    ///
+    /// Scans are updated in batches, each committed separately. Should a batch fail, the results of the batches committed before it are reported in the `completed` extension of the error.
+    async fn backfill_jpeg_paths(
+        &self,
+        ctx: &Context<'_>,
+        session_id: u32,
+        #[graphql(
+            desc = "Considers only scans with a greater scanId, so that an invocation which reached the limit can be continued from the last scanId it reported"
+        )]
+        after_scan_id: Option<u32>,
+        #[graphql(desc = "Reports the scans which would be updated without updating them")]
+        dry_run: bool,
+    ) -> async_graphql::Result<Vec<BackfillResult>> {
+        authorize(
+            ctx,
+            Action::Mutation {
+                name: "backfillJpegPaths",
+            },
+        )
+        .await?;
+        let _permit = ctx
+            .data::<ConcurrencyLimiter>()?
+            .acquire(client_key(ctx, session_id))
+            .await?;
+        backfill_jpeg_paths(ctx, session_id, after_scan_id, dry_run).await
+    }
+}
+
+#[Subscription]
+impl Subscription {
+    /// Streams the points appended to the data file of an in-progress scan, completing once the scan has ended
+    async fn live_spectrum(
+        &self,
+        ctx: &Context<'_>,
+        scan_id: u32,
+        #[graphql(
+            desc = "The interval between polls of the data file, in milliseconds, of at least 500",
+            validator(minimum = 500)
+        )]
+        interval_ms: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<SpectrumBatch>>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let scan = xfe_fluorescence_spectrum::Entity::find_by_id(scan_id)
+            .one(database)
+            .await?;
+        // Scans which do not exist are refused as those which may not be read are, so that their existence is not revealed
+        let decision = match &scan {
+            Some(scan) => {
+                decide(
+                    ctx,
+                    Action::ScanRead {
+                        session_id: scan.session_id,
+                        scan_id,
+                    },
+                )
+                .await?
+            }
+            None => Decision::Deny(String::new()),
+        };
+        let scan = match (scan, decision) {
+            (Some(scan), Decision::Allow) => scan,
+            _ => {
+                return Err(async_graphql::Error::new("Scan not found or not readable")
+                    .extend_with(|_, extensions| extensions.set("code", "FORBIDDEN")))
+            }
+        };
+        let client = match principal(ctx) {
+            Some(subject) => ClientKey::Principal(subject),
+            None => ctx
+                .data_opt::<ConnectionId>()
+                .map_or(ClientKey::Session(scan.session_id), |connection| {
+                    ClientKey::Connection(*connection)
+                }),
+        };
+        let files = ctx.data::<ScanFiles>()?;
+        let path = FluorescenceScan::from(scan)
+            .scan_file_full_path
+            .ok_or("Scan has no data file")?;
+        let key = ObjectKey::scan_data(&files.key_rules, &path)?;
+        watch_spectrum(
+            ctx.data::<LiveSpectrumLimiter>()?,
+            client,
+            files.store.clone(),
+            database.clone(),
+            key,
+            scan_id,
+            Duration::from_millis(interval_ms.unwrap_or(DEFAULT_POLL_INTERVAL_MS)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fake_database::{model_row, row, scan, FakeDatabase},
+        object_key::ObjectKeyRules,
+        store::testing::{every_store, FakeStore},
+        FluorescenceScanService, QueryLimits,
+    };
+    use async_graphql::{Request, Variables};
+    use axum::{
+        body::{to_bytes, Body},
+        http::StatusCode,
+    };
+    use chrono::NaiveDate;
+    use models::xfe_fluorescence_spectrum;
+    use sea_orm::Value;
+    use serde_json::{json, Value as Json};
+    use tower::ServiceExt;
+
+    /// A query of every field of the scans of a session, verifying the existence of the objects to which URLs are produced
+    const FULL_SCAN_QUERY: &str = r#"{
+        fluorescenceScansBySession(sessionIds: [42]) {
+            sessionId
+            scans {
+                id sessionId jpegScanFileFullPath startTime endTime filename exposureTime axisPosition
+                beamTransmission scanFileFullPath energy beamSizeVertical beamSizeHorizontal
+                jpegScanUrl verifiedJpegScanUrl: jpegScanUrl(verify: true)
+                scanFileUrl verifiedScanFileUrl: scanFileUrl(verify: true)
+                snapshots { kind url key }
+            }
+            error { code }
+        }
+    }"#;
+
+    /// A scan with every column recorded
+    fn recorded_scan() -> xfe_fluorescence_spectrum::Model {
+        let start = NaiveDate::from_ymd_opt(2024, 3, 5)
+            .unwrap()
+            .and_hms_opt(9, 30, 0)
+            .unwrap();
+        xfe_fluorescence_spectrum::Model {
+            start_time: Some(start),
+            end_time: Some(start + chrono::Duration::seconds(90)),
+            filename: Some(String::from("scan.dat")),
+            exposure_time: Some(0.5),
+            axis_position: Some(12.25),
+            beam_transmission: Some(0.75),
+            energy: Some(13500.0),
+            beam_size_vertical: Some(20.0),
+            beam_size_horizontal: Some(80.0),
+            ..scan(
+                7,
+                42,
+                Some("/dls/i18/data/2024/cm1-1/scan.dat"),
+                Some("/dls/i18/data/2024/cm1-1/scan.jpg"),
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn deterministic_urls_produce_a_stable_full_scan_response() {
+        let database = FakeDatabase::new(|_| Ok(vec![model_row(&recorded_scan())]));
+        let store = FakeStore::new([]);
+        let service = FluorescenceScanService::builder(database.connect().await)
+            .scan_file_store(store.clone(), ObjectKeyRules::default())
+            .deterministic_urls(true)
+            .build();
+        let response = service.schema().execute(FULL_SCAN_QUERY).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let jpeg = "https://deterministic-urls.invalid/memory%3A%2F%2Ffake//dls/i18/data/2024/cm1-1/scan.jpg";
+        let scan_file = "https://deterministic-urls.invalid/memory%3A%2F%2Ffake//dls/i18/data/2024/cm1-1/scan.dat";
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "fluorescenceScansBySession": [{
+                    "sessionId": 42,
+                    "scans": [{
+                        "id": 7,
+                        "sessionId": 42,
+                        "jpegScanFileFullPath": "/dls/i18/data/2024/cm1-1/scan.jpg",
+                        "startTime": "2024-03-05T09:30:00+00:00",
+                        "endTime": "2024-03-05T09:31:30+00:00",
+                        "filename": "scan.dat",
+                        "exposureTime": 0.5,
+                        "axisPosition": 12.25,
+                        "beamTransmission": 0.75,
+                        "scanFileFullPath": "/dls/i18/data/2024/cm1-1/scan.dat",
+                        "energy": 13500.0,
+                        "beamSizeVertical": 20.0,
+                        "beamSizeHorizontal": 80.0,
+                        "jpegScanUrl": jpeg,
+                        "verifiedJpegScanUrl": jpeg,
+                        "scanFileUrl": scan_file,
+                        "verifiedScanFileUrl": scan_file,
+                        "snapshots": [
+                            {
+                                "kind": "RAW",
+                                "url": jpeg,
+                                "key": "/dls/i18/data/2024/cm1-1/scan.jpg",
+                            },
+                            {
+                                "kind": "ANNOTATED",
+                                "url": "https://deterministic-urls.invalid/memory%3A%2F%2Ffake//dls/i18/data/2024/cm1-1/scan_annotated.jpg",
+                                "key": "/dls/i18/data/2024/cm1-1/scan_annotated.jpg",
+                            },
+                        ],
+                    }],
+                    "error": null,
+                }],
+            })
+        );
+        assert_eq!(store.heads(), 0);
+    }
+
+    /// The stored renderings of the recorded scan, of which the scan file itself is not stored
+    const STORED: [(&str, &[u8]); 2] = [
+        ("/dls/i18/data/2024/cm1-1/scan.jpg", b"raw"),
+        ("/dls/i18/data/2024/cm1-1/scan_annotated.jpg", b"annotated"),
+    ];
+
+    /// The contents served at the URL, fetched through the file proxy of the service unless presigned by the in-memory store
+    async fn download(service: &FluorescenceScanService, url: &Json) -> Option<Vec<u8>> {
+        let url = url.as_str().unwrap();
+        if let Some(key) = url.strip_prefix("https://fake.invalid/") {
+            return STORED
+                .iter()
+                .find(|(stored, _)| *stored == key)
+                .map(|(_, contents)| contents.to_vec());
+        }
+        let response = service
+            .router()
+            .oneshot(axum::http::Request::get(url).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        (response.status() == StatusCode::OK).then_some(
+            to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+                .to_vec(),
+        )
+    }
+
+    #[tokio::test]
+    async fn scan_urls_and_snapshots_are_served_from_every_store() {
+        let (_directory, stores) = every_store(STORED);
+        for store in stores {
+            let location = store.location();
+            let database = FakeDatabase::new(|_| Ok(vec![model_row(&recorded_scan())]));
+            let service = FluorescenceScanService::builder(database.connect().await)
+                .scan_file_store(store, ObjectKeyRules::default())
+                .build();
+            let response = service
+                .schema()
+                .execute(
+                    r#"{
+                        fluorescenceScansBySession(sessionIds: [42]) {
+                            scans {
+                                jpegScanUrl verifiedJpegScanUrl: jpegScanUrl(verify: true)
+                                scanFileUrl verifiedScanFileUrl: scanFileUrl(verify: true)
+                                snapshots { kind key url }
+                            }
+                        }
+                    }"#,
+                )
+                .await;
+            assert!(
+                response.errors.is_empty(),
+                "{location}: {:?}",
+                response.errors
+            );
+            let data = response.data.into_json().unwrap();
+            let scan = &data["fluorescenceScansBySession"][0]["scans"][0];
+
+            for field in ["jpegScanUrl", "verifiedJpegScanUrl"] {
+                assert_eq!(
+                    download(&service, &scan[field]).await.as_deref(),
+                    Some(&b"raw"[..]),
+                    "{location}: {field}"
+                );
+            }
+            assert_eq!(
+                download(&service, &scan["scanFileUrl"]).await,
+                None,
+                "{location}"
+            );
+            assert_eq!(scan["verifiedScanFileUrl"], Json::Null, "{location}");
+
+            let snapshots = scan["snapshots"].as_array().unwrap();
+            assert_eq!(
+                snapshots
+                    .iter()
+                    .map(|snapshot| (snapshot["kind"].clone(), snapshot["key"].clone()))
+                    .collect::<Vec<_>>(),
+                [
+                    (json!("RAW"), json!(STORED[0].0)),
+                    (json!("ANNOTATED"), json!(STORED[1].0)),
+                ],
+                "{location}"
+            );
+            for (snapshot, (_, contents)) in snapshots.iter().zip(STORED) {
+                assert_eq!(
+                    download(&service, &snapshot["url"]).await.as_deref(),
+                    Some(contents),
+                    "{location}"
+                );
+            }
+        }
+    }
+
+    /// The number following the keyword in the SQL, if the keyword is present
+    fn clause(sql: &str, keyword: &str) -> Option<usize> {
+        let (_, rest) = sql.split_once(keyword)?;
+        rest.split_whitespace().next()?.parse().ok()
+    }
+
+    /// A database holding the scans with ids one to the count in session 42, answering both the count and the page of a paginated query
+    fn paginated_database(count: usize) -> FakeDatabase {
+        FakeDatabase::new(move |statement| {
+            let sql = statement.to_string();
+            if sql.contains("COUNT(*)") {
+                return Ok(vec![row([("num_items", Value::from(count as i32))])]);
+            }
+            let offset = clause(&sql, " OFFSET ").unwrap_or(0);
+            let limit = clause(&sql, " LIMIT ").unwrap_or(count);
+            Ok((1..=count as u32)
+                .skip(offset)
+                .take(limit)
+                .map(|id| model_row(&scan(id, 42, None, None)))
+                .collect())
+        })
+    }
+
+    /// Fetches the page of the scans of session 42, returning the response as JSON
+    async fn scan_page(service: &FluorescenceScanService, page: u64, page_size: u64) -> Json {
+        let response = service
+            .schema()
+            .execute(
+                Request::new(
+                    r#"
+                    query ($representations: [_Any!]!, $page: Int!, $pageSize: Int!) {
+                        _entities(representations: $representations) {
+                            ... on Session {
+                                fluorescenceScanPage(page: $page, pageSize: $pageSize) {
+                                    items { id } totalCount totalPages
+                                }
+                            }
+                        }
+                    }
+                "#,
+                )
+                .variables(Variables::from_json(json!({
+                    "representations": [{ "__typename": "Session", "id": 42 }],
+                    "page": page,
+                    "pageSize": page_size,
+                }))),
+            )
+            .await;
+        serde_json::to_value(response).unwrap()
+    }
+
+    /// The ids of the items of the page in the response
+    fn page_ids(response: &Json) -> Json {
+        let items = &response["data"]["_entities"][0]["fluorescenceScanPage"]["items"];
+        Json::Array(
+            items
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["id"].clone())
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn pages_are_offset_by_their_index() {
+        let database = paginated_database(5);
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        for (page, ids) in [(0, json!([1, 2])), (1, json!([3, 4])), (2, json!([5]))] {
+            let response = scan_page(&service, page, 2).await;
+            assert_eq!(response["errors"], Json::Null, "{response}");
+            assert_eq!(page_ids(&response), ids, "page {page}");
+            let totals = &response["data"]["_entities"][0]["fluorescenceScanPage"];
+            assert_eq!(totals["totalCount"], 5);
+            assert_eq!(totals["totalPages"], 3);
+        }
+        assert!(
+            database
+                .queries()
+                .iter()
+                .any(|query| query.contains("LIMIT 2 OFFSET 4")),
+            "{:?}",
+            database.queries()
+        );
+    }
+
+    #[tokio::test]
+    async fn pages_past_the_end_are_empty() {
+        let database = paginated_database(5);
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        let response = scan_page(&service, 3, 2).await;
+        assert_eq!(response["errors"], Json::Null, "{response}");
+        assert_eq!(page_ids(&response), json!([]));
+        let totals = &response["data"]["_entities"][0]["fluorescenceScanPage"];
+        assert_eq!(totals["totalCount"], 5);
+        assert_eq!(totals["totalPages"], 3);
+    }
+
+    #[tokio::test]
+    async fn page_sizes_over_the_maximum_are_refused() {
+        let database = paginated_database(5);
+        let service = FluorescenceScanService::builder(database.connect().await)
+            .query_limits(QueryLimits::new(None, None, 3))
+            .build();
+        assert_eq!(page_ids(&scan_page(&service, 0, 3).await), json!([1, 2, 3]));
+        let response = scan_page(&service, 0, 4).await;
+        assert_eq!(
+            response["errors"][0]["extensions"]["code"],
+            "PAGE_SIZE_EXCEEDED"
+        );
+        assert_eq!(
+            response["errors"][0]["message"],
+            "pageSize must not exceed 3"
+        );
+    }
+
+    #[tokio::test]
+    async fn huge_page_sizes_are_costed_without_overflow() {
+        let database = paginated_database(5);
+        let service = FluorescenceScanService::builder(database.connect().await)
+            .query_limits(QueryLimits::new(None, Some(1000), 1000))
+            .build();
+        let response = service
+            .schema()
+            .execute(format!(
+                r#"{{
+                    _entities(representations: [{{ __typename: "Session", id: 42 }}]) {{
+                        ... on Session {{
+                            fluorescenceScanPage(page: 0, pageSize: {}) {{ items {{ id sessionId }} }}
+                        }}
+                    }}
+                }}"#,
+                i64::MAX
+            ))
+            .await;
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(
+            response["errors"][0]["message"], "Query is too complex.",
+            "{response}"
+        );
+        assert!(database.queries().is_empty(), "{:?}", database.queries());
+    }
 }
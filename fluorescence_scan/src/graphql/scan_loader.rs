@@ -0,0 +1,190 @@
+use async_graphql::dataloader::{DataLoader, Loader};
+use models::xfe_fluorescence_spectrum::{Column, Entity, Model};
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter};
+use std::{collections::HashMap, sync::Arc};
+use tracing::instrument;
+
+use super::lenient_decoding::{decode_scans, LenientDecoding};
+use crate::trace_context::spawn_in_current_span;
+
+/// Loads a batch of scans, such as those of a federated entity request, with a single query
+///
+/// Scans are returned keyed by their id rather than in the order the database produced them, so each representation of a batch receives its own scan, or none if it does not exist.
+#[derive(Debug)]
+pub struct ScanLoader {
+    /// The connection to the ISPyB database
+    database: DatabaseConnection,
+    /// The decoder tolerating rows which do not match the model, if lenient decoding is enabled
+    lenient_decoding: Option<LenientDecoding>,
+}
+
+impl ScanLoader {
+    /// Creates a data loader reading from the supplied database, decoding leniently if a decoder is supplied
+    pub fn data_loader(
+        database: DatabaseConnection,
+        lenient_decoding: Option<LenientDecoding>,
+    ) -> DataLoader<Self> {
+        DataLoader::new(
+            Self {
+                database,
+                lenient_decoding,
+            },
+            spawn_in_current_span,
+        )
+    }
+}
+
+impl Loader<u32> for ScanLoader {
+    type Value = Model;
+    type Error = Arc<DbErr>;
+
+    #[instrument(name = "load_scans", skip_all, fields(scans = keys.len()))]
+    async fn load(&self, keys: &[u32]) -> Result<HashMap<u32, Self::Value>, Self::Error> {
+        let (scans, _) = decode_scans(
+            &self.database,
+            self.lenient_decoding.as_ref(),
+            Entity::find().filter(Column::XfeFluorescenceSpectrumId.is_in(keys.iter().copied())),
+        )
+        .await?;
+        Ok(scans
+            .into_iter()
+            .map(|scan| (scan.xfe_fluorescence_spectrum_id, scan))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        authorization::IspybMembership,
+        fake_database::{model_row, scan, FakeDatabase},
+        FluorescenceScanService,
+    };
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+    use sea_orm::Value;
+    use serde_json::{json, Value as Json};
+    use std::sync::Arc;
+
+    /// The ids of the scans recorded in the database
+    const RECORDED: [u32; 3] = [3, 5, 8];
+
+    /// A database holding the recorded scans, producing those requested in the reverse of the order requested
+    fn database() -> FakeDatabase {
+        FakeDatabase::new(|statement| {
+            let requested = statement
+                .values
+                .iter()
+                .flat_map(|values| values.0.iter())
+                .filter_map(|value| match value {
+                    Value::Unsigned(Some(id)) => Some(*id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            Ok(requested
+                .into_iter()
+                .rev()
+                .filter(|id| RECORDED.contains(id))
+                .map(|id| model_row(&scan(id, 40 + id, None, None)))
+                .collect())
+        })
+    }
+
+    /// The entities produced for the representations by a service permitting every read, in the order produced
+    async fn entities(database: &FakeDatabase, representations: &[Json]) -> Vec<Json> {
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        resolve(&service, representations).await
+    }
+
+    /// The entities produced for the representations by the service, in the order produced
+    async fn resolve(service: &FluorescenceScanService, representations: &[Json]) -> Vec<Json> {
+        let response = service
+            .schema()
+            .execute(
+                async_graphql::Request::new(
+                    "query ($representations: [_Any!]!) { _entities(representations: $representations) { __typename ... on Session { id } ... on FluorescenceScan { id sessionId } } }",
+                )
+                .variables(async_graphql::Variables::from_json(
+                    json!({ "representations": representations }),
+                )),
+            )
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        data["_entities"].as_array().unwrap().clone()
+    }
+
+    /// The entity expected for the representation
+    fn expected(representation: &Json) -> Json {
+        let id = representation["id"].as_u64().unwrap();
+        match representation["__typename"].as_str().unwrap() {
+            "Session" => json!({ "__typename": "Session", "id": id }),
+            _ if RECORDED.contains(&(id as u32)) => {
+                json!({ "__typename": "FluorescenceScan", "id": id, "sessionId": 40 + id })
+            }
+            _ => Json::Null,
+        }
+    }
+
+    /// Interleaved representations of sessions, recorded scans and unknown scans
+    fn representations() -> Vec<Json> {
+        vec![
+            json!({ "__typename": "FluorescenceScan", "id": 8 }),
+            json!({ "__typename": "Session", "id": 1 }),
+            json!({ "__typename": "FluorescenceScan", "id": 4 }),
+            json!({ "__typename": "FluorescenceScan", "id": 3 }),
+            json!({ "__typename": "Session", "id": 7 }),
+            json!({ "__typename": "FluorescenceScan", "id": 9 }),
+            json!({ "__typename": "FluorescenceScan", "id": 5 }),
+            json!({ "__typename": "FluorescenceScan", "id": 8 }),
+        ]
+    }
+
+    #[tokio::test]
+    async fn entities_correspond_to_representations_by_position() {
+        let database = database();
+        let representations = representations();
+        assert_eq!(
+            entities(&database, &representations).await,
+            representations.iter().map(expected).collect::<Vec<_>>()
+        );
+        assert_eq!(database.queries().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn entities_follow_shuffled_representations() {
+        let mut rng = StdRng::seed_from_u64(456);
+        let mut representations = representations();
+        for _ in 0..20 {
+            representations.shuffle(&mut rng);
+            assert_eq!(
+                entities(&database(), &representations).await,
+                representations.iter().map(expected).collect::<Vec<_>>(),
+                "{representations:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_scans_are_null_in_their_position() {
+        let connection = database().connect().await;
+        let service = FluorescenceScanService::builder(connection.clone())
+            .authorization_policy(Arc::new(IspybMembership::new(connection, "admin")))
+            .build();
+        assert_eq!(
+            resolve(
+                &service,
+                &[
+                    json!({ "__typename": "Session", "id": 1 }),
+                    json!({ "__typename": "FluorescenceScan", "id": 3 }),
+                    json!({ "__typename": "Session", "id": 2 }),
+                ]
+            )
+            .await,
+            [
+                json!({ "__typename": "Session", "id": 1 }),
+                Json::Null,
+                json!({ "__typename": "Session", "id": 2 }),
+            ]
+        );
+    }
+}
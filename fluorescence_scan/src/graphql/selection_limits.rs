@@ -0,0 +1,470 @@
+use async_graphql::{
+    async_trait::async_trait,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery},
+    parser::{
+        parse_query,
+        types::{Directive, ExecutableDocument, FragmentDefinition, Selection, SelectionSet},
+    },
+    ErrorExtensions, Name, Pos, Positioned, ServerError, ServerResult, Variables,
+};
+use clap::Parser;
+use std::{collections::HashMap, sync::Arc};
+
+/// The maximum number of aliases in an operation used when none is configured
+const DEFAULT_MAX_ALIASES: usize = 200;
+
+/// The maximum number of selections of the same field in a selection set used when none is configured
+const DEFAULT_MAX_FIELD_REPETITIONS: usize = 50;
+
+/// The maximum number of directives in an operation used when none is configured
+const DEFAULT_MAX_DIRECTIVES: usize = 100;
+
+/// The maximum number of field selections in an operation, once its fragments are expanded, used when none is configured
+const DEFAULT_MAX_SELECTIONS: usize = 5000;
+
+/// The deepest nesting of selection sets and fragment spreads walked, beyond the recursion depth of 32 which the schema accepts, bounding the stack used in counting
+const MAX_NESTING: usize = 64;
+
+/// Limits on the aliases, repeated fields, directives and selections of the operations accepted by the service, which the complexity limit does not account for
+///
+/// Fragments are counted once for every place they are spread, so a fragment cannot be used to multiply selections past the limits. Each fragment is walked only once however often it is spread, so nesting spreads cannot make counting exponentially slow.
+#[derive(Debug, Clone, Copy, Parser)]
+pub struct SelectionLimits {
+    /// The maximum number of aliased fields in an accepted operation
+    #[arg(long, env, default_value_t = DEFAULT_MAX_ALIASES)]
+    max_aliases: usize,
+    /// The maximum number of times the same field may be selected within one selection set of an accepted operation, whether aliased or not
+    #[arg(long, env, default_value_t = DEFAULT_MAX_FIELD_REPETITIONS)]
+    max_field_repetitions: usize,
+    /// The maximum number of directives in an accepted operation
+    #[arg(long, env, default_value_t = DEFAULT_MAX_DIRECTIVES)]
+    max_directives: usize,
+    /// The maximum number of field selections in an accepted operation, counting those of fragments wherever they are spread
+    #[arg(long, env, default_value_t = DEFAULT_MAX_SELECTIONS)]
+    max_selections: usize,
+}
+
+impl Default for SelectionLimits {
+    fn default() -> Self {
+        Self {
+            max_aliases: DEFAULT_MAX_ALIASES,
+            max_field_repetitions: DEFAULT_MAX_FIELD_REPETITIONS,
+            max_directives: DEFAULT_MAX_DIRECTIVES,
+            max_selections: DEFAULT_MAX_SELECTIONS,
+        }
+    }
+}
+
+impl SelectionLimits {
+    /// Creates limits with the supplied maximum aliases, repetitions of a field and directives, and the default maximum selections
+    pub fn new(max_aliases: usize, max_field_repetitions: usize, max_directives: usize) -> Self {
+        Self {
+            max_aliases,
+            max_field_repetitions,
+            max_directives,
+            max_selections: DEFAULT_MAX_SELECTIONS,
+        }
+    }
+
+    /// Replaces the maximum number of field selections in an operation
+    pub fn max_selections(mut self, max_selections: usize) -> Self {
+        self.max_selections = max_selections;
+        self
+    }
+}
+
+impl ExtensionFactory for SelectionLimits {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(SelectionLimitsExtension(*self))
+    }
+}
+
+/// The extension enforcing the [`SelectionLimits`], which rejects offending operations before they are validated or executed
+///
+/// Operations are parsed and checked before being handed on, as the recursion depth check made once they are parsed for execution expands every fragment wherever it is spread, which takes exponentially long for operations nesting spreads.
+struct SelectionLimitsExtension(SelectionLimits);
+
+#[async_trait]
+impl Extension for SelectionLimitsExtension {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        // Unparsable operations are left for the parse made for execution to reject
+        if let Ok(document) = parse_query(query) {
+            for (_, operation) in document.operations.iter() {
+                let mut walk = Walk::new(self.0, &document);
+                walk.directives(&operation.node.directives, operation.pos)?;
+                walk.selection_set(
+                    &operation.node.selection_set.node,
+                    &mut HashMap::new(),
+                    None,
+                    operation.pos,
+                )?;
+            }
+        }
+        next.run(ctx, query, variables).await
+    }
+}
+
+/// Produces a `VALIDATION` error naming the limit which the operation exceeds
+fn exceeded(limit: &'static str, description: &str, maximum: usize, pos: Pos) -> ServerError {
+    async_graphql::Error::new(format!(
+        "Operation exceeds the limit of {maximum} {description}"
+    ))
+    .extend_with(|_, extensions| {
+        extensions.set("code", "VALIDATION");
+        extensions.set("limit", limit);
+    })
+    .into_server_error(pos)
+}
+
+/// Adds to a count, failing if it then exceeds the maximum of the limit
+fn count(
+    count: &mut usize,
+    added: usize,
+    maximum: usize,
+    limit: &'static str,
+    description: &str,
+    pos: Pos,
+) -> ServerResult<()> {
+    *count += added;
+    if *count > maximum {
+        return Err(exceeded(limit, description, maximum, pos));
+    }
+    Ok(())
+}
+
+/// The number of selections of each field of each type condition within a selection set
+type Repetitions<'a> = HashMap<(Option<&'a str>, &'a str), usize>;
+
+/// The counts which a fragment contributes wherever it is spread
+struct Expansion<'a> {
+    /// The number of aliased fields within the fragment
+    aliases: usize,
+    /// The number of directives within the fragment
+    directives: usize,
+    /// The number of field selections within the fragment
+    selections: usize,
+    /// The selections of each field directly within the fragment
+    repetitions: Repetitions<'a>,
+}
+
+/// The selections of an operation counted so far, with fragments expanded where they are spread
+struct Walk<'a> {
+    /// The limits being enforced
+    limits: SelectionLimits,
+    /// The fragments defined by the document
+    fragments: &'a HashMap<Name, Positioned<FragmentDefinition>>,
+    /// The fragments being expanded, outermost first
+    spreading: Vec<&'a Name>,
+    /// The expansion of each fragment counted so far, so that each is walked once however often it is spread
+    expanded: HashMap<&'a Name, Expansion<'a>>,
+    /// The number of aliased fields counted
+    aliases: usize,
+    /// The number of directives counted
+    directives: usize,
+    /// The number of field selections counted
+    selections: usize,
+    /// The number of selection sets and fragments enclosing the selections being counted
+    nesting: usize,
+}
+
+impl<'a> Walk<'a> {
+    /// Creates a walk of the operations of the document
+    fn new(limits: SelectionLimits, document: &'a ExecutableDocument) -> Self {
+        Self {
+            limits,
+            fragments: &document.fragments,
+            spreading: Vec::new(),
+            expanded: HashMap::new(),
+            aliases: 0,
+            directives: 0,
+            selections: 0,
+            nesting: 0,
+        }
+    }
+
+    /// Counts the directives applied at a position
+    fn directives(&mut self, directives: &[Positioned<Directive>], pos: Pos) -> ServerResult<()> {
+        count(
+            &mut self.directives,
+            directives.len(),
+            self.limits.max_directives,
+            "maxDirectives",
+            "directives",
+            pos,
+        )
+    }
+
+    /// Counts the selections of a selection set at a position, where `repetitions` holds the number of selections of each field of each type condition in the set so far
+    fn selection_set(
+        &mut self,
+        selection_set: &'a SelectionSet,
+        repetitions: &mut Repetitions<'a>,
+        type_condition: Option<&'a str>,
+        pos: Pos,
+    ) -> ServerResult<()> {
+        count(
+            &mut self.nesting,
+            1,
+            MAX_NESTING,
+            "maxNesting",
+            "nested selection sets and fragments",
+            pos,
+        )?;
+        let counted = self.selections_of(selection_set, repetitions, type_condition);
+        self.nesting -= 1;
+        counted
+    }
+
+    /// Counts each selection of a selection set
+    fn selections_of(
+        &mut self,
+        selection_set: &'a SelectionSet,
+        repetitions: &mut Repetitions<'a>,
+        type_condition: Option<&'a str>,
+    ) -> ServerResult<()> {
+        for selection in &selection_set.items {
+            match &selection.node {
+                Selection::Field(field) => {
+                    self.directives(&field.node.directives, field.pos)?;
+                    count(
+                        &mut self.selections,
+                        1,
+                        self.limits.max_selections,
+                        "maxSelections",
+                        "field selections",
+                        field.pos,
+                    )?;
+                    if field.node.alias.is_some() {
+                        count(
+                            &mut self.aliases,
+                            1,
+                            self.limits.max_aliases,
+                            "maxAliases",
+                            "aliases",
+                            field.pos,
+                        )?;
+                    }
+                    count(
+                        repetitions
+                            .entry((type_condition, field.node.name.node.as_str()))
+                            .or_default(),
+                        1,
+                        self.limits.max_field_repetitions,
+                        "maxFieldRepetitions",
+                        "selections of the same field",
+                        field.pos,
+                    )?;
+                    self.selection_set(
+                        &field.node.selection_set.node,
+                        &mut HashMap::new(),
+                        None,
+                        field.pos,
+                    )?;
+                }
+                Selection::FragmentSpread(spread) => {
+                    self.directives(&spread.node.directives, spread.pos)?;
+                    let name = &spread.node.fragment_name.node;
+                    // Unknown and cyclic fragments are left for validation to reject
+                    let Some(fragment) = self.fragments.get(name) else {
+                        continue;
+                    };
+                    if self.spreading.contains(&name) {
+                        continue;
+                    }
+                    if !self.expanded.contains_key(name) {
+                        self.expand(name, fragment, spread.pos)?;
+                    }
+                    let expansion = &self.expanded[name];
+                    count(
+                        &mut self.aliases,
+                        expansion.aliases,
+                        self.limits.max_aliases,
+                        "maxAliases",
+                        "aliases",
+                        spread.pos,
+                    )?;
+                    count(
+                        &mut self.directives,
+                        expansion.directives,
+                        self.limits.max_directives,
+                        "maxDirectives",
+                        "directives",
+                        spread.pos,
+                    )?;
+                    count(
+                        &mut self.selections,
+                        expansion.selections,
+                        self.limits.max_selections,
+                        "maxSelections",
+                        "field selections",
+                        spread.pos,
+                    )?;
+                    for (field, repeated) in &expansion.repetitions {
+                        count(
+                            repetitions.entry(*field).or_default(),
+                            *repeated,
+                            self.limits.max_field_repetitions,
+                            "maxFieldRepetitions",
+                            "selections of the same field",
+                            spread.pos,
+                        )?;
+                    }
+                }
+                Selection::InlineFragment(fragment) => {
+                    self.directives(&fragment.node.directives, fragment.pos)?;
+                    self.selection_set(
+                        &fragment.node.selection_set.node,
+                        repetitions,
+                        fragment
+                            .node
+                            .type_condition
+                            .as_ref()
+                            .map(|condition| condition.node.on.node.as_str())
+                            .or(type_condition),
+                        fragment.pos,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts the selections of the fragment spread at a position alone, recording them as its expansion
+    fn expand(
+        &mut self,
+        name: &'a Name,
+        fragment: &'a Positioned<FragmentDefinition>,
+        pos: Pos,
+    ) -> ServerResult<()> {
+        let counted = (self.aliases, self.directives, self.selections);
+        (self.aliases, self.directives, self.selections) = (0, 0, 0);
+        let mut repetitions = HashMap::new();
+        self.spreading.push(name);
+        let walked = self.selection_set(
+            &fragment.node.selection_set.node,
+            &mut repetitions,
+            Some(fragment.node.type_condition.node.on.node.as_str()),
+            pos,
+        );
+        self.spreading.pop();
+        let expansion = Expansion {
+            aliases: self.aliases,
+            directives: self.directives,
+            selections: self.selections,
+            repetitions,
+        };
+        (self.aliases, self.directives, self.selections) = counted;
+        walked?;
+        self.expanded.insert(name, expansion);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SelectionLimits;
+    use crate::graphql::{root_schema_builder, QueryLimits, RootSchema};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    /// The schema with the supplied selection limits and no data
+    fn schema(selection_limits: SelectionLimits) -> RootSchema {
+        QueryLimits::default()
+            .selection_limits(selection_limits)
+            .apply(root_schema_builder(None))
+            .finish()
+    }
+
+    /// Executes the query, failing if it takes longer than a few seconds, producing the response as JSON
+    async fn execute(schema: &RootSchema, query: &str) -> Value {
+        let response = tokio::time::timeout(Duration::from_secs(5), schema.execute(query))
+            .await
+            .expect("query was not rejected promptly");
+        serde_json::to_value(response).unwrap()
+    }
+
+    /// The name of the limit which the response reports as exceeded, if any
+    fn exceeded_limit(response: &Value) -> Option<&str> {
+        response["errors"][0]["extensions"]["limit"].as_str()
+    }
+
+    /// The query from the penetration test report, requesting the change feed of a session under 500 aliases
+    fn alias_bomb() -> String {
+        let aliases = (0..500)
+            .map(|alias| {
+                format!(
+                    "a{alias}: fluorescenceScanChanges(sessionId: 1, limit: 1000) {{ nextCursor }}"
+                )
+            })
+            .collect::<Vec<_>>();
+        format!("{{ {} }}", aliases.join(" "))
+    }
+
+    #[tokio::test]
+    async fn alias_bomb_is_rejected() {
+        let response = execute(&schema(SelectionLimits::default()), &alias_bomb()).await;
+        assert_eq!(response["errors"][0]["extensions"]["code"], "VALIDATION");
+        assert_eq!(exceeded_limit(&response), Some("maxFieldRepetitions"));
+        let response = execute(&schema(SelectionLimits::new(200, 1000, 100)), &alias_bomb()).await;
+        assert_eq!(exceeded_limit(&response), Some("maxAliases"));
+    }
+
+    #[tokio::test]
+    async fn repetitions_are_counted_wherever_a_fragment_is_spread() {
+        let typenames = vec!["__typename"; 30].join(" ");
+        let response = execute(
+            &schema(SelectionLimits::default()),
+            &format!(
+                "{{ ...Typenames ...Typenames }} fragment Typenames on Query {{ {typenames} }}"
+            ),
+        )
+        .await;
+        assert_eq!(exceeded_limit(&response), Some("maxFieldRepetitions"));
+    }
+
+    #[tokio::test]
+    async fn nested_spreads_are_rejected_promptly() {
+        let mut query =
+            String::from("{ __schema { queryType { ...F15 } } } fragment F0 on __Type { name }");
+        for level in 1..=15 {
+            let inner = level - 1;
+            query.push_str(&format!(
+                " fragment F{level} on __Type {{ ofType {{ ...F{inner} }} interfaces {{ ...F{inner} }} possibleTypes {{ ...F{inner} }} }}"
+            ));
+        }
+        let response = execute(&schema(SelectionLimits::default()), &query).await;
+        assert_eq!(exceeded_limit(&response), Some("maxSelections"));
+    }
+
+    #[tokio::test]
+    async fn deeply_chained_fragments_are_rejected() {
+        let mut query = String::from("{ ...F100 } fragment F0 on Query { __typename }");
+        for level in 1..=100 {
+            query.push_str(&format!(
+                " fragment F{level} on Query {{ ...F{} }}",
+                level - 1
+            ));
+        }
+        let response = execute(&schema(SelectionLimits::default()), &query).await;
+        assert_eq!(exceeded_limit(&response), Some("maxNesting"));
+    }
+
+    #[tokio::test]
+    async fn fragments_within_the_limits_are_accepted() {
+        let response = execute(
+            &schema(SelectionLimits::default()),
+            "{ __schema { queryType { ...Type } mutationType { ...Type } } } fragment Type on __Type { name }",
+        )
+        .await;
+        assert_eq!(
+            response["data"],
+            json!({ "__schema": { "queryType": { "name": "Query" }, "mutationType": { "name": "Mutation" } } })
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use async_graphql::{Context, Enum, SimpleObject};
+use derive_more::{Display, Error};
+use futures::{stream, StreamExt, TryStreamExt};
+use std::str::FromStr;
+use tracing::warn;
+
+use super::{object_exists, presigned_url};
+use crate::{object_key::ObjectKey, store::ScanFiles};
+
+/// The maximum number of snapshot variants probed concurrently for a single scan
+pub const MAX_CONCURRENT_PROBES: usize = 4;
+
+/// The extension with which the jpeg rendering of a scan is stored alongside the scan file, by convention
+const JPEG_EXTENSION: &str = "jpg";
+
+/// The variants probed when none are configured, the recorded snapshot and an annotated sibling
+pub const DEFAULT_SNAPSHOT_VARIANTS: &str = "raw=,annotated=_annotated";
+
+/// The rendering captured in a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum SnapshotKind {
+    /// The snapshot as captured by the camera
+    Raw,
+    /// The snapshot overlaid with annotations by the beamline software
+    Annotated,
+}
+
+/// A jpeg snapshot of a scan
+#[derive(Debug, Clone, SimpleObject)]
+pub struct Snapshot {
+    /// The rendering captured in the snapshot
+    #[graphql(tag = "public")]
+    kind: SnapshotKind,
+    /// A presigned URL from which the snapshot can be downloaded
+    #[graphql(tag = "public")]
+    url: String,
+    /// The key of the snapshot within the bucket
+    #[graphql(tag = "public")]
+    key: String,
+}
+
+/// An error produced when a snapshot variant cannot be parsed
+#[derive(Debug, Display, Error)]
+pub enum SnapshotVariantError {
+    /// The variant was not of the form `KIND=SUFFIX`
+    #[display(fmt = "Snapshot variant must be of the form KIND=SUFFIX")]
+    Malformed,
+    /// The kind was not recognised
+    #[display(fmt = "Snapshot kind must be one of raw or annotated")]
+    UnknownKind,
+    /// The suffix would place the variant in another directory
+    #[display(fmt = "Snapshot suffix must not contain a path separator")]
+    Separator,
+}
+
+/// A convention by which a variant of the recorded snapshot is stored, as a suffix inserted before the file extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotVariant {
+    /// The rendering stored under this convention
+    kind: SnapshotKind,
+    /// The suffix appended to the file stem, empty for the recorded snapshot
+    suffix: String,
+}
+
+impl SnapshotVariant {
+    /// Derives the path of this variant from the recorded path
+    fn path(&self, recorded: &str) -> String {
+        let stem_end = stem_end(recorded);
+        format!(
+            "{}{}{}",
+            &recorded[..stem_end],
+            self.suffix,
+            &recorded[stem_end..]
+        )
+    }
+}
+
+/// The index at which the file stem of the path ends, before the extension if there is one
+fn stem_end(path: &str) -> usize {
+    let name_start = path.rfind('/').map_or(0, |separator| separator + 1);
+    path[name_start..]
+        .rfind('.')
+        .filter(|dot| *dot > 0)
+        .map_or(path.len(), |dot| name_start + dot)
+}
+
+impl FromStr for SnapshotVariant {
+    type Err = SnapshotVariantError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, suffix) = s.split_once('=').ok_or(SnapshotVariantError::Malformed)?;
+        let kind = match kind.trim().to_ascii_lowercase().as_str() {
+            "raw" => SnapshotKind::Raw,
+            "annotated" => SnapshotKind::Annotated,
+            _ => return Err(SnapshotVariantError::UnknownKind),
+        };
+        if suffix.contains('/') {
+            return Err(SnapshotVariantError::Separator);
+        }
+        Ok(Self {
+            kind,
+            suffix: suffix.to_string(),
+        })
+    }
+}
+
+/// The conventions probed to discover the snapshots of a scan
+#[derive(Debug, Clone)]
+pub struct SnapshotVariants(Vec<SnapshotVariant>);
+
+impl SnapshotVariants {
+    /// Creates the set of conventions to probe
+    pub fn new(variants: Vec<SnapshotVariant>) -> Self {
+        Self(variants)
+    }
+
+    /// Derives the paths at which, by convention, the recorded jpeg rendering of the scan file would be stored
+    pub fn jpeg_paths(&self, scan_file: &str) -> Vec<String> {
+        let jpeg = format!("{}.{JPEG_EXTENSION}", &scan_file[..stem_end(scan_file)]);
+        self.0
+            .iter()
+            .filter(|variant| variant.kind == SnapshotKind::Raw)
+            .map(|variant| variant.path(&jpeg))
+            .collect()
+    }
+}
+
+impl Default for SnapshotVariants {
+    fn default() -> Self {
+        Self(
+            DEFAULT_SNAPSHOT_VARIANTS
+                .split(',')
+                .map(|variant| variant.parse().unwrap())
+                .collect(),
+        )
+    }
+}
+
+/// Lists the snapshots stored alongside the recorded path, falling back to only the recorded snapshot if probing fails
+pub async fn find_snapshots(
+    ctx: &Context<'_>,
+    recorded: &str,
+) -> async_graphql::Result<Vec<Snapshot>> {
+    let key_rules = &ctx.data::<ScanFiles>()?.key_rules;
+    let variants = ctx.data::<SnapshotVariants>()?;
+    let candidates = variants
+        .0
+        .iter()
+        .map(|variant| {
+            Ok((
+                variant.kind,
+                ObjectKey::scan_jpeg(key_rules, &variant.path(recorded))?,
+            ))
+        })
+        .collect::<async_graphql::Result<Vec<_>>>()?;
+    let probed: async_graphql::Result<Vec<_>> = stream::iter(candidates)
+        .map(|(kind, key)| async move {
+            async_graphql::Result::Ok(object_exists(ctx, &key).await?.then_some((kind, key)))
+        })
+        .buffered(MAX_CONCURRENT_PROBES)
+        .try_collect()
+        .await;
+    let found = match probed {
+        Ok(probed) => probed.into_iter().flatten().collect(),
+        Err(err) => {
+            warn!("Failed to probe snapshot variants: {}", err.message);
+            vec![(
+                SnapshotKind::Raw,
+                ObjectKey::scan_jpeg(key_rules, recorded)?,
+            )]
+        }
+    };
+    let mut snapshots = Vec::with_capacity(found.len());
+    for (kind, key) in found {
+        snapshots.push(Snapshot {
+            kind,
+            url: presigned_url(ctx, &key).await?,
+            key: key.into(),
+        });
+    }
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SnapshotKind, SnapshotVariant, SnapshotVariantError, SnapshotVariants};
+    use crate::{
+        fake_database::{model_row, scan, FakeDatabase},
+        object_key::ObjectKeyRules,
+        store::testing::FakeStore,
+        FluorescenceScanService,
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    /// The variant parsed from the text, which must be valid
+    fn variant(s: &str) -> SnapshotVariant {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn variants_are_parsed_from_kind_and_suffix() {
+        assert_eq!(
+            variant(" Annotated =_annotated"),
+            SnapshotVariant {
+                kind: SnapshotKind::Annotated,
+                suffix: String::from("_annotated"),
+            }
+        );
+        assert_eq!(variant("raw=").suffix, "");
+    }
+
+    #[test]
+    fn malformed_variants_are_rejected() {
+        assert!(matches!(
+            "raw".parse::<SnapshotVariant>(),
+            Err(SnapshotVariantError::Malformed)
+        ));
+        assert!(matches!(
+            "thumbnail=_thumb".parse::<SnapshotVariant>(),
+            Err(SnapshotVariantError::UnknownKind)
+        ));
+        assert!(matches!(
+            "annotated=../_annotated".parse::<SnapshotVariant>(),
+            Err(SnapshotVariantError::Separator)
+        ));
+    }
+
+    #[test]
+    fn suffixes_are_inserted_before_the_extension_of_the_file_name() {
+        let annotated = variant("annotated=_annotated");
+        for (recorded, path) in [
+            ("/dls/i18/scan.jpg", "/dls/i18/scan_annotated.jpg"),
+            ("/dls/i18/scan.tar.gz", "/dls/i18/scan.tar_annotated.gz"),
+            ("/dls/i18/scan", "/dls/i18/scan_annotated"),
+            ("/dls/i18/.scan", "/dls/i18/.scan_annotated"),
+            ("/dls/i18.data/scan", "/dls/i18.data/scan_annotated"),
+            ("/dls/i18.data/scan.jpg", "/dls/i18.data/scan_annotated.jpg"),
+            ("scan.jpg", "scan_annotated.jpg"),
+        ] {
+            assert_eq!(annotated.path(recorded), path, "{recorded}");
+        }
+    }
+
+    #[test]
+    fn jpeg_paths_are_derived_for_raw_variants_only() {
+        let variants = SnapshotVariants::new(vec![
+            variant("raw="),
+            variant("annotated=_annotated"),
+            variant("raw=_full"),
+        ]);
+        assert_eq!(
+            variants.jpeg_paths("/dls/i18.data/scan.dat"),
+            ["/dls/i18.data/scan.jpg", "/dls/i18.data/scan_full.jpg"]
+        );
+        assert_eq!(
+            variants.jpeg_paths("/dls/i18/scan"),
+            ["/dls/i18/scan.jpg", "/dls/i18/scan_full.jpg"]
+        );
+        assert_eq!(
+            variants.jpeg_paths("/dls/i18/.scan"),
+            ["/dls/i18/.scan.jpg", "/dls/i18/.scan_full.jpg"]
+        );
+    }
+
+    /// The kind and key of each snapshot of a scan recording a jpeg, with the default variants probed in the store
+    async fn snapshots(store: Arc<FakeStore>) -> Value {
+        let database = FakeDatabase::new(|_| {
+            Ok(vec![model_row(&scan(
+                7,
+                42,
+                None,
+                Some("/dls/i18/scan.jpg"),
+            ))])
+        });
+        let service = FluorescenceScanService::builder(database.connect().await)
+            .scan_file_store(store, ObjectKeyRules::default())
+            .build();
+        let response = service
+            .schema()
+            .execute("{ fluorescenceScansBySession(sessionIds: [42]) { scans { snapshots { kind key url } } } }")
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        response.data.into_json().unwrap()["fluorescenceScansBySession"][0]["scans"][0]["snapshots"]
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn missing_variants_are_absent() {
+        let store = FakeStore::new([("/dls/i18/scan_annotated.jpg", &b""[..])]);
+        assert_eq!(
+            snapshots(store).await,
+            json!([{
+                "kind": "ANNOTATED",
+                "key": "/dls/i18/scan_annotated.jpg",
+                "url": "https://fake.invalid//dls/i18/scan_annotated.jpg",
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn scans_without_stored_snapshots_have_none() {
+        assert_eq!(snapshots(FakeStore::new([])).await, json!([]));
+    }
+
+    #[tokio::test]
+    async fn the_recorded_snapshot_is_produced_when_probing_fails() {
+        let store = FakeStore::new([]);
+        store.fail(Some("connection reset"));
+        assert_eq!(
+            snapshots(store).await,
+            json!([{
+                "kind": "RAW",
+                "key": "/dls/i18/scan.jpg",
+                "url": "https://fake.invalid//dls/i18/scan.jpg",
+            }])
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use async_graphql::{dataloader::DataLoader, Context};
+use models::{bl_session, proposal};
+use sea_orm::{
+    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, FromQueryResult, QueryFilter, QuerySelect,
+};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tracing::{instrument, warn};
+
+use crate::trace_context::spawn_in_current_span;
+
+/// The proposal and visit number of a session, each of which may be unrecorded
+#[derive(Debug, Clone, PartialEq, Eq, FromQueryResult)]
+pub struct SessionVisit {
+    /// The session described
+    session_id: u32,
+    /// The code of the proposal under which the session was allocated, such as `mx`
+    pub proposal_code: Option<String>,
+    /// The number of the proposal under which the session was allocated
+    pub proposal_number: Option<String>,
+    /// The number of the visit within the proposal
+    pub visit_number: Option<u32>,
+}
+
+impl SessionVisit {
+    /// The conventional name of the visit, such as `mx12345-6`, if the proposal code, proposal number and visit number are all recorded
+    ///
+    /// The proposal code is lowercased and surrounding whitespace is trimmed from the code and number.
+    pub fn visit(&self) -> Option<String> {
+        let code = self.proposal_code.as_deref()?.trim();
+        let number = self.proposal_number.as_deref()?.trim();
+        if code.is_empty() || number.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{}{number}-{}",
+            code.to_lowercase(),
+            self.visit_number?
+        ))
+    }
+}
+
+/// Whether the sessions and proposals tables can be read, as found by probing them on startup
+///
+/// Until the probe succeeds the proposal and visit of sessions are null.
+#[derive(Debug, Clone, Default)]
+pub struct ProposalAccess(Arc<AtomicBool>);
+
+impl ProposalAccess {
+    /// Attempts to read the proposal and visit of a session, recording whether it could be done
+    pub async fn probe(&self, database: &DatabaseConnection) {
+        match visits()
+            .limit(1)
+            .into_model::<SessionVisit>()
+            .all(database)
+            .await
+        {
+            Ok(_) => self.0.store(true, Ordering::Release),
+            Err(err) => warn!(%err, "Proposals of sessions cannot be read, so are not served"),
+        }
+    }
+
+    /// Whether the probe has found that the tables can be read
+    fn available(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Selects the proposal and visit number of each session, whether or not its proposal is recorded, aliased as the fields of [`SessionVisit`]
+fn visits() -> sea_orm::Select<bl_session::Entity> {
+    bl_session::Entity::find()
+        .select_only()
+        .column_as(bl_session::Column::SessionId, "session_id")
+        .column_as(proposal::Column::ProposalCode, "proposal_code")
+        .column_as(proposal::Column::ProposalNumber, "proposal_number")
+        .column_as(bl_session::Column::VisitNumber, "visit_number")
+        .left_join(proposal::Entity)
+}
+
+/// Loads the proposals and visits of a batch of sessions, such as those of a federated entity request, with a single query
+#[derive(Debug)]
+pub struct VisitLoader {
+    /// The connection to the ISPyB database
+    database: DatabaseConnection,
+}
+
+impl VisitLoader {
+    /// Creates a data loader reading from the supplied database
+    pub fn data_loader(database: DatabaseConnection) -> DataLoader<Self> {
+        DataLoader::new(Self { database }, spawn_in_current_span)
+    }
+}
+
+impl async_graphql::dataloader::Loader<u32> for VisitLoader {
+    type Value = SessionVisit;
+    type Error = Arc<DbErr>;
+
+    #[instrument(name = "load_visits", skip_all, fields(sessions = keys.len()))]
+    async fn load(&self, keys: &[u32]) -> Result<HashMap<u32, Self::Value>, Self::Error> {
+        Ok(visits()
+            .filter(bl_session::Column::SessionId.is_in(keys.iter().copied()))
+            .into_model::<SessionVisit>()
+            .all(&self.database)
+            .await?
+            .into_iter()
+            .map(|visit| (visit.session_id, visit))
+            .collect())
+    }
+}
+
+/// The proposal and visit of the session, or [`None`] if the tables cannot be read, the session does not exist or the query fails
+pub async fn session_visit(
+    ctx: &Context<'_>,
+    session_id: u32,
+) -> async_graphql::Result<Option<SessionVisit>> {
+    if !ctx.data::<ProposalAccess>()?.available() {
+        return Ok(None);
+    }
+    match ctx
+        .data::<DataLoader<VisitLoader>>()?
+        .load_one(session_id)
+        .await
+    {
+        Ok(visit) => Ok(visit),
+        Err(err) => {
+            warn!(%err, session_id, "Proposal of session could not be read");
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionVisit;
+
+    /// The visit of a session with the recorded proposal and visit number
+    fn session(code: Option<&str>, number: Option<&str>, visit: Option<u32>) -> SessionVisit {
+        SessionVisit {
+            session_id: 1,
+            proposal_code: code.map(String::from),
+            proposal_number: number.map(String::from),
+            visit_number: visit,
+        }
+    }
+
+    #[test]
+    fn visit_is_named_from_the_proposal_and_visit_number() {
+        assert_eq!(
+            session(Some("mx"), Some("12345"), Some(6))
+                .visit()
+                .as_deref(),
+            Some("mx12345-6")
+        );
+        assert_eq!(
+            session(Some("cm"), Some("1"), Some(0)).visit().as_deref(),
+            Some("cm1-0")
+        );
+    }
+
+    #[test]
+    fn visit_is_missing_without_every_part() {
+        assert_eq!(session(None, Some("12345"), Some(6)).visit(), None);
+        assert_eq!(session(Some("mx"), None, Some(6)).visit(), None);
+        assert_eq!(session(Some("mx"), Some("12345"), None).visit(), None);
+        assert_eq!(session(None, None, None).visit(), None);
+    }
+
+    #[test]
+    fn visit_is_missing_with_a_blank_code_or_number() {
+        assert_eq!(session(Some(""), Some("12345"), Some(6)).visit(), None);
+        assert_eq!(session(Some("  "), Some("12345"), Some(6)).visit(), None);
+        assert_eq!(session(Some("mx"), Some(""), Some(6)).visit(), None);
+        assert_eq!(session(Some("mx"), Some("\t"), Some(6)).visit(), None);
+    }
+
+    #[test]
+    fn visit_code_is_lowercased_and_trimmed() {
+        assert_eq!(
+            session(Some("MX"), Some("12345"), Some(6))
+                .visit()
+                .as_deref(),
+            Some("mx12345-6")
+        );
+        assert_eq!(
+            session(Some(" Nt "), Some(" 37104\n"), Some(2))
+                .visit()
+                .as_deref(),
+            Some("nt37104-2")
+        );
+    }
+}
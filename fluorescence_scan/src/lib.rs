@@ -0,0 +1,71 @@
+#![forbid(unsafe_code)]
+#![doc=include_str!("../../README.md")]
+#![warn(missing_docs)]
+#![warn(clippy::missing_docs_in_private_items)]
+
+/// Decisions on whether clients may access sessions, scans and restricted fields
+mod authorization;
+/// Metadata about the crate, courtesy of [`built`]
+mod built_info;
+/// Statistics served by the debug endpoints
+mod debug_stats;
+/// A database with canned answers for tests
+#[cfg(test)]
+mod fake_database;
+/// Signed URLs under which objects are served by the service itself
+mod file_proxy;
+/// GraphQL resolvers
+mod graphql;
+/// Caching of objects known to be missing from S3
+mod negative_cache;
+/// Construction of the keys under which scan files are stored in S3
+mod object_key;
+/// Degraded mode whilst ISPyB refuses writes during maintenance
+mod read_only;
+/// Redaction of user identifying data from telemetry
+mod redaction;
+/// Content negotiated error responses for the non-GraphQL routes
+mod route_error;
+/// [`axum::handler::Handler`]s for GraphQL and the service status routes
+mod route_handlers;
+/// Security headers applied to the GraphiQL page
+mod security_headers;
+/// Assembly of the GraphQL schema and routes into an embeddable service
+mod service;
+/// Stores from which scan files are read
+mod store;
+/// Minimal ISPyB schema for ephemeral test and development databases
+mod test_schema;
+/// Verification of bearer tokens against the signing keys of their issuer
+mod token_verifier;
+/// Continuation of the traces of callers which reach the service directly
+mod trace_context;
+/// Exercising of the service on startup
+mod warmup;
+
+use derive_more::{Deref, FromStr, Into};
+
+pub use authorization::{
+    Action, AllowAll, AuthorizationPolicy, BeamlineClaims, Claims, Decision, IspybMembership,
+};
+pub use graphql::{
+    root_schema_builder, QueryLimits, RootSchema, SelectionLimits, SnapshotVariant,
+    SnapshotVariants, DEFAULT_BACKFILL_LIMIT, DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL,
+    DEFAULT_PER_CLIENT_CONCURRENCY, DEFAULT_SETTLE_INTERVAL, DEFAULT_SNAPSHOT_VARIANTS,
+};
+pub use object_key::{KeyFamily, ObjectKey, ObjectKeyError, ObjectKeyRules};
+pub use read_only::DEFAULT_READ_ONLY_COOL_DOWN;
+pub use redaction::PathRedaction;
+pub use security_headers::{GraphiQLAccess, GraphiQLPolicy};
+pub use service::{FluorescenceScanService, FluorescenceScanServiceBuilder, S3Facilities};
+pub use store::{
+    FilesystemStore, ObjectInfo, S3Store, ScanFileStore, StoreError, DEFAULT_FALLBACK_COOL_DOWN,
+};
+pub use test_schema::{
+    apply_test_schema, check_test_schema, TestSchemaError, TEST_SCHEMA_DDL, TEST_SCHEMA_VERSION,
+};
+pub use token_verifier::{JwksError, TokenError, TokenVerifier};
+
+/// S3 bucket where the flourescence scan data is stored
+#[derive(Debug, Clone, Deref, FromStr, Into)]
+pub struct S3Bucket(String);
@@ -5,19 +5,25 @@
 
 /// Metadata about the crate, courtesy of [`built`]
 mod built_info;
-/// GraphQL resolvers
-mod graphql;
-/// An [`axum::handler::Handler`] for GraphQL
-mod route_handlers;
 
-use async_graphql::{http::GraphiQLSource, SDLExportOptions};
+use async_graphql::SDLExportOptions;
 use aws_credential_types::{provider::SharedCredentialsProvider, Credentials};
 use aws_sdk_s3::{config::Region, Client};
-use axum::{response::Html, routing::get, Router};
-use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
-use clap::{ArgAction::SetTrue, Parser};
-use derive_more::{Deref, FromStr, Into};
-use graphql::{root_schema_builder, RootSchema};
+use axum::{http::HeaderValue, Router};
+use clap::{
+    error::ErrorKind,
+    ArgAction::{self, SetTrue},
+    CommandFactory, Parser, Subcommand, ValueEnum,
+};
+use fluorescence_scan::{
+    apply_test_schema, check_test_schema, root_schema_builder, AllowAll, AuthorizationPolicy,
+    BeamlineClaims, FilesystemStore, FluorescenceScanService, GraphiQLAccess, GraphiQLPolicy,
+    IspybMembership, ObjectKeyRules, PathRedaction, QueryLimits, S3Bucket, S3Store, ScanFileStore,
+    SnapshotVariant, SnapshotVariants, TokenVerifier, DEFAULT_BACKFILL_LIMIT,
+    DEFAULT_FALLBACK_COOL_DOWN, DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL, DEFAULT_PER_CLIENT_CONCURRENCY,
+    DEFAULT_READ_ONLY_COOL_DOWN, DEFAULT_SETTLE_INTERVAL, DEFAULT_SNAPSHOT_VARIANTS,
+    TEST_SCHEMA_DDL, TEST_SCHEMA_VERSION,
+};
 use opentelemetry_otlp::WithExportConfig;
 use sea_orm::{ConnectOptions, Database, DatabaseConnection, DbErr, TransactionError};
 use std::{
@@ -25,14 +31,21 @@ use std::{
     io::Write,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     path::PathBuf,
+    str::FromStr,
+    sync::Arc,
     time::Duration,
 };
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, signal, sync::watch};
 use tracing::{info, instrument};
-use tracing_subscriber::{filter::FilterFn, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 use url::Url;
 
-use crate::route_handlers::GraphQLHandler;
+/// The log level overrides applied unless configured otherwise, quietening the verbose AWS SDK targets
+const DEFAULT_LOG_TARGET_FILTERS: &str = "aws_smithy_runtime=warn,aws_credential_types=warn";
 
 /// A service providing Beamline ISPyB data collected during sessions
 #[derive(Debug, Parser)]
@@ -43,6 +56,9 @@ enum Cli {
     Serve(ServeArgs),
     /// Produces the GraphQL schema
     Schema(SchemaArgs),
+    /// Manages the minimal ISPyB schema of ephemeral test and development databases
+    #[command(subcommand)]
+    TestSchema(TestSchemaCommand),
 }
 
 /// Arguments for serving the GraphQL API
@@ -51,29 +67,248 @@ struct ServeArgs {
     /// The port to which this application should bind
     #[arg(short, long, env = "PORT", default_value_t = 80)]
     port: u16,
+    /// The port to which the health and readiness routes should bind, if unset they are served on the public port
+    #[arg(long, env = "INTERNAL_PORT")]
+    internal_port: Option<u16>,
     /// The URL of the ISPyB instance which should be connected to
     #[arg(long, env = "DATABASE_URL")]
     database_url: Url,
-    /// The S3 bucket which images are to be stored in.
+    /// The backend from which scan files are read.
+    #[arg(long, env, value_enum, default_value_t = StorageBackend::S3)]
+    storage_backend: StorageBackend,
+    /// The S3 bucket which images are to be stored in, required by the S3 backend.
+    #[arg(long, env)]
+    s3_bucket: Option<S3Bucket>,
+    /// The directory beneath which scan files are read, required by the filesystem backend.
+    #[arg(long, env)]
+    filesystem_root: Option<PathBuf>,
+    /// The key with which URLs served by the file proxy are signed, which must be shared by all replicas, required by the filesystem backend as a key generated on startup would invalidate its URLs on every restart.
     #[arg(long, env)]
-    s3_bucket: S3Bucket,
+    file_proxy_secret: Option<String>,
     /// Configuration argument of the S3 client.
     #[command(flatten)]
     s3_client: S3ClientArgs,
+    /// Limits on the cost of accepted queries.
+    #[command(flatten)]
+    query_limits: QueryLimits,
+    /// Nulls nullable fields and skips rows which cannot be decoded, rather than failing the query.
+    #[arg(long, env, action = SetTrue)]
+    lenient_decoding: bool,
+    /// Executes a canned query and S3 request on startup, before reporting ready.
+    #[arg(long, env, default_value_t = true, action = ArgAction::Set)]
+    warmup: bool,
+    /// Withholds readiness if any step of the startup warm-up fails.
+    #[arg(long, env, action = SetTrue)]
+    strict_warmup: bool,
+    /// Serves the debug statistics on the internal routes and enables the downloadDiagnostics field.
+    #[arg(long, env, action = SetTrue)]
+    debug_endpoints: bool,
+    /// A testing aid, producing fake URLs derived from only the store location and object key, under an .invalid host, and taking every object to exist, so that contract tests see stable responses. Never use in production.
+    #[arg(long, env, action = SetTrue, requires = "allow_deterministic_urls")]
+    deterministic_urls: bool,
+    /// Acknowledges that --deterministic-urls makes every URL field unusable, without which it is refused.
+    #[arg(long, env, action = SetTrue)]
+    allow_deterministic_urls: bool,
+    /// Counts the resolutions of each field, exporting them as metrics and serving them on the internal routes alongside the debug statistics.
+    #[arg(long, env, action = SetTrue)]
+    field_usage_metrics: bool,
+    /// Counts field resolutions for only every Nth request, as counting every field of wide responses has a cost.
+    #[arg(long, env, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    field_usage_sample_every: u64,
+    /// Replaces user identifying segments of paths attached to traces and logs with a hash.
+    #[arg(long, env, default_value_t = true, action = ArgAction::Set)]
+    redact_paths: bool,
+    /// The key with which user identifying segments of paths are hashed, which must be shared by all replicas for their telemetry to agree, otherwise one is generated on startup.
+    #[arg(long, env)]
+    redaction_secret: Option<String>,
+    /// The number of seconds for which S3 objects found to be missing are not looked up again.
+    #[arg(long, env, default_value_t = 60)]
+    s3_negative_cache_ttl: u64,
+    /// The maximum number of S3 objects recorded as missing.
+    #[arg(long, env, default_value_t = 10_000)]
+    s3_negative_cache_capacity: usize,
+    /// The number of seconds for which degraded mode is reported on the capabilities route after ISPyB last refused a write as read-only.
+    #[arg(long, env, default_value_t = DEFAULT_READ_ONLY_COOL_DOWN.as_secs())]
+    read_only_cool_down: u64,
+    /// Path prefixes stripped from recorded file paths to produce object keys, if set every path must begin with one of them.
+    #[arg(long, env, value_delimiter = ',')]
+    s3_path_prefix: Vec<String>,
+    /// Conventions by which snapshot variants are stored alongside the recorded snapshot, as KIND=SUFFIX where the suffix is inserted before the file extension.
+    #[arg(long, env, value_delimiter = ',', default_value = DEFAULT_SNAPSHOT_VARIANTS)]
+    snapshot_variant: Vec<SnapshotVariant>,
+    /// The number of scan listings which may run concurrently for the same principal, or for the same session when unauthenticated, excess work is briefly queued then rejected.
+    #[arg(long, env, default_value_t = DEFAULT_PER_CLIENT_CONCURRENCY as u32, value_parser = clap::value_parser!(u32).range(1..))]
+    per_client_concurrency: u32,
+    /// The maximum number of scans whose jpeg paths are backfilled by a single invocation of the mutation.
+    #[arg(long, env, default_value_t = DEFAULT_BACKFILL_LIMIT, value_parser = clap::value_parser!(u64).range(1..))]
+    backfill_limit: u64,
+    /// The number of seconds for which newly recorded scans are withheld from the change feed, scans recorded by transactions committing later than this may never be delivered.
+    #[arg(long, env, default_value_t = DEFAULT_SETTLE_INTERVAL.as_secs())]
+    change_feed_settle_interval: u64,
+    /// The number of live spectra which may be watched concurrently by the same subject, or over the same connection when unauthenticated, further subscriptions are rejected.
+    #[arg(long, env, default_value_t = DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL as u32, value_parser = clap::value_parser!(u32).range(1..))]
+    live_spectra_per_principal: u32,
+    /// A Content-Security-Policy served with GraphiQL in place of the default, which permits only the CDN assets of the stock build.
+    #[arg(long, env)]
+    graphiql_csp: Option<HeaderValue>,
+    /// Serves GraphiQL without a bearer token when an authorization policy is configured, though the schema may then only be introspected with a token.
+    #[arg(long, env, action = SetTrue)]
+    graphiql_public: bool,
+    /// The URL of the SSO helper to which browsers loading GraphiQL without a token are sent, which returns them with a token in the access_token fragment parameter.
+    #[arg(long, env)]
+    graphiql_sign_in_url: Option<Url>,
+    /// The policy deciding whether clients may access sessions and restricted fields. Policies other than allow-all require bearer tokens to be verified, with --token-jwks-url, --token-issuer and --token-audience.
+    #[arg(long, env, value_enum, default_value_t = AuthPolicy::AllowAll)]
+    auth_policy: AuthPolicy,
+    /// The URL of the JSON Web Key Set of the token issuer, against which the signatures of bearer tokens are verified.
+    #[arg(long, env)]
+    token_jwks_url: Option<Url>,
+    /// The issuer which bearer tokens must name to be accepted.
+    #[arg(long, env)]
+    token_issuer: Option<String>,
+    /// The audience which bearer tokens must name to be accepted.
+    #[arg(long, env)]
+    token_audience: Option<String>,
+    /// The token group whose members may access restricted fields.
+    #[arg(long, env, default_value = "admin")]
+    admin_group: String,
+    /// Serves only the fields tagged for this contract, such as public, so that other fields cannot be selected or introspected.
+    #[arg(long, env)]
+    contract: Option<String>,
     /// The [`tracing::Level`] to log at
     #[arg(long, env = "LOG_LEVEL", default_value_t = tracing::Level::INFO)]
     log_level: tracing::Level,
+    /// Overrides of the log level for individual targets and their descendants, as target=level, applied over the defaults which quieten the AWS SDK. Directives of RUST_LOG, if set, are applied over these and its bare level, if any, replaces the log level.
+    #[arg(long, env, value_delimiter = ',')]
+    log_target_filter: Vec<LogTargetFilter>,
     /// The URL of the OpenTelemetry collector to send traces to
     #[arg(long, env = "OTEL_COLLECTOR_URL")]
     otel_collector_url: Option<Url>,
 }
 
-/// S3 bucket where the flourescence scan data is stored
-#[derive(Debug, Clone, Deref, FromStr, Into)]
-pub struct S3Bucket(String);
+/// A rule over the serve arguments, producing a description of how to resolve the violation if they break it
+type Rule = fn(&ServeArgs) -> Option<&'static str>;
+
+/// The combinations of serve arguments which cannot be honoured, checked before anything is started
+const RULES: &[Rule] = &[
+    distinct_ports,
+    s3_bucket_required,
+    filesystem_root_required,
+    file_proxy_secret_required,
+    paired_s3_credentials,
+    s3_fallback_requires_s3,
+    strict_warmup_requires_warmup,
+    graphiql_public_requires_auth,
+    auth_policy_requires_token_verification,
+    paired_token_verification,
+];
+
+/// The internal routes cannot be bound to the public port
+fn distinct_ports(args: &ServeArgs) -> Option<&'static str> {
+    (args.internal_port == Some(args.port)).then_some(
+        "--internal-port must differ from --port, unset it to serve the health and readiness routes on the public port",
+    )
+}
+
+/// The S3 backend cannot read without a bucket
+fn s3_bucket_required(args: &ServeArgs) -> Option<&'static str> {
+    (args.storage_backend == StorageBackend::S3 && args.s3_bucket.is_none()).then_some(
+        "--s3-bucket is required by the s3 storage backend, set it or select --storage-backend filesystem",
+    )
+}
+
+/// The filesystem backend cannot read without a root directory
+fn filesystem_root_required(args: &ServeArgs) -> Option<&'static str> {
+    (args.storage_backend == StorageBackend::Filesystem && args.filesystem_root.is_none()).then_some(
+        "--filesystem-root is required by the filesystem storage backend, set it or select --storage-backend s3",
+    )
+}
+
+/// URLs of the filesystem backend are signed by the file proxy, so must be verifiable by every replica and after restarts
+fn file_proxy_secret_required(args: &ServeArgs) -> Option<&'static str> {
+    (args.storage_backend == StorageBackend::Filesystem && args.file_proxy_secret.is_none()).then_some(
+        "--file-proxy-secret is required by the filesystem storage backend, set it to a key shared by all replicas",
+    )
+}
+
+/// S3 credentials are unusable without both halves
+fn paired_s3_credentials(args: &ServeArgs) -> Option<&'static str> {
+    (args.s3_client.s3_access_key_id.is_some() != args.s3_client.s3_secret_access_key.is_some())
+        .then_some(
+        "--s3-access-key-id and --s3-secret-access-key must be set together, set both or neither",
+    )
+}
+
+/// The filesystem backend makes no requests which could be routed to an S3 fallback
+fn s3_fallback_requires_s3(args: &ServeArgs) -> Option<&'static str> {
+    (args.storage_backend == StorageBackend::Filesystem
+        && args.s3_client.s3_fallback_endpoint_url.is_some())
+    .then_some(
+        "--s3-fallback-endpoint-url has no effect with the filesystem storage backend, remove it or select --storage-backend s3",
+    )
+}
+
+/// The outcome of the warm-up cannot withhold readiness if it does not run
+fn strict_warmup_requires_warmup(args: &ServeArgs) -> Option<&'static str> {
+    (args.strict_warmup && !args.warmup)
+        .then_some("--strict-warmup requires the warm-up, remove it or set --warmup true")
+}
+
+/// GraphiQL is always served publicly when every request is permitted
+fn graphiql_public_requires_auth(args: &ServeArgs) -> Option<&'static str> {
+    (args.graphiql_public && args.auth_policy == AuthPolicy::AllowAll).then_some(
+        "--graphiql-public has no effect with the allow-all policy, remove it or select another --auth-policy",
+    )
+}
+
+/// Policies deciding on claims cannot trust claims of tokens which are not verified
+fn auth_policy_requires_token_verification(args: &ServeArgs) -> Option<&'static str> {
+    (args.auth_policy != AuthPolicy::AllowAll && args.token_jwks_url.is_none()).then_some(
+        "--auth-policy ispyb and claims require --token-jwks-url, --token-issuer and --token-audience, as the claims of unverified tokens could be forged",
+    )
+}
+
+/// Tokens cannot be verified without the keys, issuer and audience all being known
+fn paired_token_verification(args: &ServeArgs) -> Option<&'static str> {
+    let set = [
+        args.token_jwks_url.is_some(),
+        args.token_issuer.is_some(),
+        args.token_audience.is_some(),
+    ];
+    (set.contains(&true) && set.contains(&false)).then_some(
+        "--token-jwks-url, --token-issuer and --token-audience must be set together, set all or none",
+    )
+}
+
+impl ServeArgs {
+    /// Checks the arguments against every rule, describing how to resolve each violation
+    fn validate(&self) -> Vec<&'static str> {
+        RULES.iter().filter_map(|rule| rule(self)).collect()
+    }
+}
+
+/// A backend from which scan files are read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StorageBackend {
+    /// An S3 bucket, from which files are downloaded by presigned URL
+    S3,
+    /// A local or network filesystem, from which files are served by the service
+    Filesystem,
+}
+
+/// A policy deciding whether clients may access sessions and restricted fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AuthPolicy {
+    /// Permits sessions of which the token subject is a member in ISPyB
+    Ispyb,
+    /// Permits sessions of beamlines whose groups are listed in the token claims
+    Claims,
+    /// Permits every read, but no mutation
+    AllowAll,
+}
 
 /// Arguments for configuring the S3 Client.
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 pub struct S3ClientArgs {
     /// The URL of the S3 endpoint to retrieve images from.
     #[arg(long, env)]
@@ -90,6 +325,12 @@ pub struct S3ClientArgs {
     /// The AWS region of the S3 bucket.
     #[arg(long, env)]
     s3_region: Option<String>,
+    /// The URL of a passive S3 endpoint serving the same bucket, against which a request is retried and to which requests are routed for a cool-down whenever the primary cannot be reached.
+    #[arg(long, env)]
+    s3_fallback_endpoint_url: Option<Url>,
+    /// The number of seconds for which requests are routed to the fallback endpoint before the primary is probed again.
+    #[arg(long, env, default_value_t = DEFAULT_FALLBACK_COOL_DOWN.as_secs())]
+    s3_fallback_cool_down: u64,
 }
 
 /// S3 client argument trait
@@ -129,6 +370,31 @@ struct SchemaArgs {
     /// The URL of the ISPyB instance which should be connected to
     #[arg(long, env = "DATABASE_URL")]
     database_url: Url,
+    /// Produces the schema restricted to the fields tagged for this contract, such as public, rather than the full schema
+    #[arg(long, env)]
+    contract: Option<String>,
+}
+
+/// Commands managing the minimal ISPyB schema of ephemeral test and development databases
+#[derive(Debug, Subcommand)]
+enum TestSchemaCommand {
+    /// Creates the tables of the test schema in an empty database, refusing any database holding other tables, such as a production ISPyB
+    Apply(TestSchemaApplyArgs),
+    /// Checks that the test schema declares exactly the tables and columns of the models
+    Check,
+    /// Prints the DDL of the test schema
+    Print,
+}
+
+/// Arguments for applying the test schema
+#[derive(Debug, Parser)]
+struct TestSchemaApplyArgs {
+    /// The URL of the test database in which the tables should be created
+    #[arg(long, env = "DATABASE_URL")]
+    database_url: Url,
+    /// Drops and recreates tables of the test schema which already exist
+    #[arg(long, action = SetTrue)]
+    force: bool,
 }
 
 /// Creates a connection pool to access the database
@@ -143,42 +409,57 @@ async fn setup_database(database_url: Url) -> Result<DatabaseConnection, Transac
     Ok(connection)
 }
 
-/// Creates an [`axum::Router`] serving GraphiQL, synchronous GraphQL and GraphQL subscriptions
-fn setup_router(schema: RootSchema) -> Router {
-    #[allow(clippy::missing_docs_in_private_items)]
-    const GRAPHQL_ENDPOINT: &str = "/";
-
-    Router::new()
-        .route(
-            GRAPHQL_ENDPOINT,
-            get(Html(
-                GraphiQLSource::build().endpoint(GRAPHQL_ENDPOINT).finish(),
-            ))
-            .post(GraphQLHandler::new(schema)),
-        )
-        .layer(OtelInResponseLayer)
-        .layer(OtelAxumLayer::default())
+/// Completes when the process receives an interrupt or terminate signal
+async fn shutdown_signal() {
+    let interrupt = async {
+        signal::ctrl_c().await.unwrap();
+    };
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received");
 }
 
-/// Serves the endpoints on the specified port forever
-async fn serve(router: Router, port: u16) -> Result<(), std::io::Error> {
+/// Binds a listener to the specified port on all interfaces
+async fn bind(port: u16) -> Result<TcpListener, std::io::Error> {
     let socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
     let listener = TcpListener::bind(socket_addr).await?;
-    println!("Serving API & GraphQL UI at {}", socket_addr);
-    axum::serve(listener, router.into_make_service()).await?;
+    println!("Serving endpoints at {}", socket_addr);
+    Ok(listener)
+}
+
+/// Serves the endpoints on the listener until the shutdown signal is received
+async fn serve(
+    listener: TcpListener,
+    router: Router,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<(), std::io::Error> {
+    axum::serve(listener, router.into_make_service())
+        .with_graceful_shutdown(async move {
+            shutdown.changed().await.ok();
+        })
+        .await?;
     Ok(())
 }
 
 /// Sets up Logging & Tracing using opentelemetry if available
 fn setup_telemetry(
     log_level: tracing::Level,
+    log_target_filters: Vec<LogTargetFilter>,
     otel_collector_url: Option<Url>,
 ) -> Result<(), anyhow::Error> {
-    let custom_filter = FilterFn::new(|metadata| {
-        !metadata.target().contains("aws_smithy_runtime")
-            && !metadata.target().contains("aws_credential_types")
-    });
-    let level_filter = tracing_subscriber::filter::LevelFilter::from_level(log_level);
+    let log_filter = log_filter(
+        log_level,
+        log_target_filters,
+        std::env::var("RUST_LOG").ok(),
+    )?;
     let log_layer = tracing_subscriber::fmt::layer();
     let service_name_resource = opentelemetry_sdk::Resource::new(vec![
         opentelemetry::KeyValue::new(
@@ -190,10 +471,10 @@ fn setup_telemetry(
             built_info::PKG_VERSION,
         ),
     ]);
+    opentelemetry::global::set_text_map_propagator(
+        opentelemetry_sdk::propagation::TraceContextPropagator::default(),
+    );
     let (metrics_layer, tracing_layer) = if let Some(otel_collector_url) = otel_collector_url {
-        opentelemetry::global::set_text_map_propagator(
-            opentelemetry_sdk::propagation::TraceContextPropagator::default(),
-        );
         (
             Some(tracing_opentelemetry::MetricsLayer::new(
                 opentelemetry_otlp::new_pipeline()
@@ -228,8 +509,7 @@ fn setup_telemetry(
     };
 
     tracing_subscriber::Registry::default()
-        .with(custom_filter)
-        .with(level_filter)
+        .with(log_filter)
         .with(log_layer)
         .with(metrics_layer)
         .with(tracing_layer)
@@ -238,6 +518,78 @@ fn setup_telemetry(
     Ok(())
 }
 
+/// A level override for the events of a target and its descendants
+#[derive(Debug, Clone)]
+struct LogTargetFilter {
+    /// The target, such as a crate or module path, to which the level applies
+    target: String,
+    /// The most verbose level at which events of the target are recorded
+    level: LevelFilter,
+}
+
+impl FromStr for LogTargetFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, level) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Log target filter must be of the form target=level"))?;
+        Ok(Self {
+            target: target.trim().to_string(),
+            level: level.trim().parse()?,
+        })
+    }
+}
+
+/// Builds the filter applied to all telemetry, from the log level and the default per-target overrides, then the configured overrides, then `RUST_LOG` if it is set, each replacing the levels of the targets it names
+///
+/// `RUST_LOG` is read as a comma separated list of `target=level` directives and bare default levels, span and field directives are not supported. Targets it does not name keep their levels, so that it cannot silence other targets by omission.
+fn log_filter(
+    log_level: tracing::Level,
+    log_target_filters: Vec<LogTargetFilter>,
+    rust_log: Option<String>,
+) -> Result<Targets, anyhow::Error> {
+    let defaults = DEFAULT_LOG_TARGET_FILTERS
+        .split(',')
+        .map(LogTargetFilter::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut filter = Targets::new().with_default(log_level).with_targets(
+        defaults
+            .into_iter()
+            .chain(log_target_filters)
+            .map(|filter| (filter.target, filter.level)),
+    );
+    if let Some(rust_log) = rust_log.filter(|rust_log| !rust_log.trim().is_empty()) {
+        let rust_log = rust_log.parse::<Targets>()?;
+        if let Some(default_level) = rust_log.default_level() {
+            filter = filter.with_default(default_level);
+        }
+        filter = filter.with_targets(
+            rust_log
+                .iter()
+                .map(|(target, level)| (target.to_string(), level)),
+        );
+    }
+    Ok(filter)
+}
+
+/// Exits, reporting every violation of the rules by the arguments at once
+fn invalid_arguments(violations: &[&str]) -> ! {
+    Cli::command()
+        .error(
+            ErrorKind::ArgumentConflict,
+            format!(
+                "the arguments cannot be used together:\n{}",
+                violations
+                    .iter()
+                    .map(|violation| format!("  - {violation}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        )
+        .exit()
+}
+
 #[tokio::main]
 async fn main() {
     dotenvy::dotenv().ok();
@@ -245,15 +597,134 @@ async fn main() {
 
     match args {
         Cli::Serve(args) => {
-            setup_telemetry(args.log_level, args.otel_collector_url).unwrap();
+            let violations = args.validate();
+            if !violations.is_empty() {
+                invalid_arguments(&violations);
+            }
+            setup_telemetry(
+                args.log_level,
+                args.log_target_filter,
+                args.otel_collector_url,
+            )
+            .unwrap();
+            let store: Arc<dyn ScanFileStore> = match args.storage_backend {
+                StorageBackend::S3 => {
+                    let mut store = S3Store::new(
+                        Client::from_s3_client_args(args.s3_client.clone()),
+                        args.s3_bucket
+                            .expect("Bucket is required by the S3 backend"),
+                    );
+                    if let Some(fallback_endpoint_url) =
+                        args.s3_client.s3_fallback_endpoint_url.clone()
+                    {
+                        let cool_down = Duration::from_secs(args.s3_client.s3_fallback_cool_down);
+                        store = store.fallback(
+                            Client::from_s3_client_args(S3ClientArgs {
+                                s3_endpoint_url: Some(fallback_endpoint_url),
+                                ..args.s3_client
+                            }),
+                            cool_down,
+                            &opentelemetry::global::meter_provider(),
+                        );
+                    }
+                    Arc::new(store)
+                }
+                StorageBackend::Filesystem => Arc::new(
+                    FilesystemStore::new(
+                        args.filesystem_root
+                            .expect("Root is required by the filesystem backend"),
+                    )
+                    .unwrap(),
+                ),
+            };
             let database = setup_database(args.database_url).await.unwrap();
-            let _s3_client = Client::from_s3_client_args(args.s3_client);
-            let schema = root_schema_builder().data(database).finish();
-            let router = setup_router(schema);
-            serve(router, args.port).await.unwrap();
+            let token_verifier = match (args.token_jwks_url, args.token_issuer, args.token_audience)
+            {
+                (Some(jwks_url), Some(issuer), Some(audience)) => Some(Arc::new(
+                    TokenVerifier::fetch(&jwks_url, issuer, audience)
+                        .await
+                        .unwrap(),
+                )),
+                _ => None,
+            };
+            let authorization_policy: Arc<dyn AuthorizationPolicy> = match args.auth_policy {
+                AuthPolicy::Ispyb => {
+                    Arc::new(IspybMembership::new(database.clone(), args.admin_group))
+                }
+                AuthPolicy::Claims => {
+                    Arc::new(BeamlineClaims::new(database.clone(), args.admin_group))
+                }
+                AuthPolicy::AllowAll => Arc::new(AllowAll),
+            };
+            let mut builder = FluorescenceScanService::builder(database)
+                .authorization_policy(authorization_policy)
+                .scan_file_store(store, ObjectKeyRules::new(args.s3_path_prefix));
+            if let Some(token_verifier) = token_verifier {
+                builder = builder.token_verifier(token_verifier);
+            }
+            if let Some(file_proxy_secret) = args.file_proxy_secret {
+                builder = builder.file_proxy_secret(file_proxy_secret);
+            }
+            if let Some(contract) = args.contract {
+                builder = builder.contract(contract);
+            }
+            if args.field_usage_metrics {
+                builder = builder.field_usage_metrics(args.field_usage_sample_every);
+            }
+            let mut path_redaction = PathRedaction::new(args.redact_paths);
+            if let Some(redaction_secret) = args.redaction_secret {
+                path_redaction = path_redaction.secret(redaction_secret.into_bytes());
+            }
+            let service = builder
+                .query_limits(args.query_limits)
+                .lenient_decoding(args.lenient_decoding)
+                .debug_endpoints(args.debug_endpoints)
+                .deterministic_urls(args.deterministic_urls)
+                .path_redaction(path_redaction)
+                .snapshot_variants(SnapshotVariants::new(args.snapshot_variant))
+                .per_client_concurrency(args.per_client_concurrency as usize)
+                .backfill_limit(args.backfill_limit)
+                .change_feed_settle_interval(Duration::from_secs(args.change_feed_settle_interval))
+                .live_spectra_per_principal(args.live_spectra_per_principal as usize)
+                .graphiql_policy(
+                    GraphiQLPolicy::new(args.graphiql_csp)
+                        .access(match args.auth_policy {
+                            AuthPolicy::AllowAll => GraphiQLAccess::Open,
+                            _ if args.graphiql_public => GraphiQLAccess::Public,
+                            _ => GraphiQLAccess::Authenticated,
+                        })
+                        .sign_in_url(args.graphiql_sign_in_url),
+                )
+                .negative_cache(
+                    Duration::from_secs(args.s3_negative_cache_ttl),
+                    args.s3_negative_cache_capacity,
+                )
+                .read_only_cool_down(Duration::from_secs(args.read_only_cool_down))
+                .build();
+            let (shutdown_tx, shutdown_rx) = watch::channel(());
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                shutdown_tx.send(()).ok();
+            });
+            let listener = bind(args.port).await.unwrap();
+            let serving = async {
+                if let Some(internal_port) = args.internal_port {
+                    let internal_listener = bind(internal_port).await?;
+                    tokio::try_join!(
+                        serve(listener, service.public_router(), shutdown_rx.clone()),
+                        serve(internal_listener, service.internal_router(), shutdown_rx),
+                    )?;
+                    Ok(())
+                } else {
+                    serve(listener, service.router(), shutdown_rx).await
+                }
+            };
+            let (served, ()) =
+                tokio::join!(serving, service.start(args.warmup, args.strict_warmup));
+            served.unwrap();
         }
         Cli::Schema(args) => {
-            let schema = root_schema_builder().finish();
+            let schema = root_schema_builder(args.contract.as_deref()).finish();
             let schema_string = schema.sdl_with_options(SDLExportOptions::new().federation());
             if let Some(path) = args.path {
                 let mut file = File::create(path).unwrap();
@@ -262,5 +733,349 @@ async fn main() {
                 println!("{}", schema_string)
             }
         }
+        Cli::TestSchema(TestSchemaCommand::Apply(args)) => {
+            let database = setup_database(args.database_url).await.unwrap();
+            match apply_test_schema(&database, args.force).await {
+                Ok(tables) => info!(
+                    version = TEST_SCHEMA_VERSION,
+                    "Created test schema tables: {}",
+                    tables.join(", ")
+                ),
+                Err(err) => {
+                    eprintln!("{err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Cli::TestSchema(TestSchemaCommand::Check) => {
+            if let Err(err) = check_test_schema() {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+            println!("Test schema version {TEST_SCHEMA_VERSION} matches the models");
+        }
+        Cli::TestSchema(TestSchemaCommand::Print) => print!("{TEST_SCHEMA_DDL}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        auth_policy_requires_token_verification, distinct_ports, file_proxy_secret_required,
+        filesystem_root_required, graphiql_public_requires_auth, log_filter, paired_s3_credentials,
+        paired_token_verification, s3_bucket_required, s3_fallback_requires_s3,
+        strict_warmup_requires_warmup, LogTargetFilter, Rule, ServeArgs, RULES,
+    };
+    use clap::Parser;
+    use tracing::Level;
+    use tracing_subscriber::filter::Targets;
+
+    /// Arguments which break no rule
+    const VALID: &[&str] = &[
+        "fluorescence_scan",
+        "--database-url",
+        "mysql://localhost/ispyb",
+        "--s3-bucket",
+        "scans",
+    ];
+
+    /// Arguments of a service verifying tokens against an issuer
+    const TOKEN_VERIFICATION: &[&str] = &[
+        "--token-jwks-url",
+        "https://issuer.invalid/jwks",
+        "--token-issuer",
+        "https://issuer.invalid",
+        "--token-audience",
+        "fluorescence-scan",
+    ];
+
+    /// Parses the valid arguments followed by the extra arguments
+    fn parse(extra: &[&str]) -> ServeArgs {
+        ServeArgs::try_parse_from(VALID.iter().chain(extra)).unwrap()
+    }
+
+    /// Asserts that the rule is broken by each set of extra arguments, and by nothing else amongst the rules, and that it is kept by each set of extra arguments
+    fn check(rule: Rule, broken: &[&[&str]], kept: &[&[&str]]) {
+        for extra in broken {
+            let args = parse(extra);
+            let violation = rule(&args).unwrap_or_else(|| panic!("{extra:?} kept the rule"));
+            assert_eq!(args.validate(), [violation], "{extra:?}");
+        }
+        for extra in kept {
+            assert_eq!(rule(&parse(extra)), None, "{extra:?} broke the rule");
+        }
+    }
+
+    #[test]
+    fn valid_arguments_break_no_rule() {
+        assert!(parse(&[]).validate().is_empty());
+    }
+
+    #[test]
+    fn every_rule_is_broken_alone_by_an_example() {
+        let filesystem = [
+            "--storage-backend",
+            "filesystem",
+            "--filesystem-root",
+            "/dls",
+            "--file-proxy-secret",
+            "key",
+        ];
+        let examples = [
+            ServeArgs::try_parse_from(&VALID[..3]).unwrap(),
+            parse(&["--port", "8080", "--internal-port", "8080"]),
+            parse(&[&filesystem[..2], &filesystem[4..]].concat()),
+            parse(&filesystem[..4]),
+            parse(&["--s3-access-key-id", "id"]),
+            parse(
+                &[
+                    &filesystem[..],
+                    &["--s3-fallback-endpoint-url", "http://fallback.invalid"],
+                ]
+                .concat(),
+            ),
+            parse(&["--strict-warmup", "--warmup", "false"]),
+            parse(&["--graphiql-public"]),
+            parse(&["--auth-policy", "ispyb"]),
+            parse(&TOKEN_VERIFICATION[..2]),
+        ];
+        for (index, rule) in RULES.iter().enumerate() {
+            assert!(
+                examples
+                    .iter()
+                    .any(|args| rule(args).is_some_and(|violation| args.validate() == [violation])),
+                "No example breaks rule {index} alone"
+            );
+        }
+    }
+
+    #[test]
+    fn ports_must_be_distinct() {
+        check(
+            distinct_ports,
+            &[&["--port", "8080", "--internal-port", "8080"]],
+            &[
+                &["--port", "8080", "--internal-port", "8081"],
+                &["--port", "8080"],
+            ],
+        );
+    }
+
+    #[test]
+    fn s3_backend_requires_a_bucket() {
+        let args = ServeArgs::try_parse_from(&VALID[..3]).unwrap();
+        assert_eq!(args.validate(), [s3_bucket_required(&args).unwrap()]);
+        assert_eq!(s3_bucket_required(&parse(&[])), None);
+    }
+
+    #[test]
+    fn filesystem_backend_requires_a_root_and_a_secret() {
+        check(
+            filesystem_root_required,
+            &[&[
+                "--storage-backend",
+                "filesystem",
+                "--file-proxy-secret",
+                "key",
+            ]],
+            &[
+                &[],
+                &[
+                    "--storage-backend",
+                    "filesystem",
+                    "--filesystem-root",
+                    "/dls",
+                ],
+            ],
+        );
+        check(
+            file_proxy_secret_required,
+            &[&[
+                "--storage-backend",
+                "filesystem",
+                "--filesystem-root",
+                "/dls",
+            ]],
+            &[
+                &[],
+                &[
+                    "--storage-backend",
+                    "filesystem",
+                    "--filesystem-root",
+                    "/dls",
+                    "--file-proxy-secret",
+                    "key",
+                ],
+            ],
+        );
+    }
+
+    #[test]
+    fn s3_credentials_must_be_paired() {
+        check(
+            paired_s3_credentials,
+            &[
+                &["--s3-access-key-id", "id"],
+                &["--s3-secret-access-key", "secret"],
+            ],
+            &[
+                &[],
+                &[
+                    "--s3-access-key-id",
+                    "id",
+                    "--s3-secret-access-key",
+                    "secret",
+                ],
+            ],
+        );
+    }
+
+    #[test]
+    fn s3_fallback_requires_the_s3_backend() {
+        check(
+            s3_fallback_requires_s3,
+            &[&[
+                "--storage-backend",
+                "filesystem",
+                "--filesystem-root",
+                "/dls",
+                "--file-proxy-secret",
+                "key",
+                "--s3-fallback-endpoint-url",
+                "http://fallback.invalid",
+            ]],
+            &[&["--s3-fallback-endpoint-url", "http://fallback.invalid"]],
+        );
+    }
+
+    #[test]
+    fn strict_warmup_requires_the_warmup() {
+        check(
+            strict_warmup_requires_warmup,
+            &[&["--strict-warmup", "--warmup", "false"]],
+            &[&["--strict-warmup"], &["--warmup", "false"]],
+        );
+    }
+
+    #[test]
+    fn public_graphiql_requires_an_authorization_policy() {
+        let claims = [
+            &["--graphiql-public", "--auth-policy", "claims"],
+            TOKEN_VERIFICATION,
+        ]
+        .concat();
+        check(
+            graphiql_public_requires_auth,
+            &[&["--graphiql-public"]],
+            &[&claims, &["--auth-policy", "claims"]],
+        );
+    }
+
+    #[test]
+    fn authorization_policies_require_token_verification() {
+        let ispyb = [&["--auth-policy", "ispyb"], TOKEN_VERIFICATION].concat();
+        let claims = [&["--auth-policy", "claims"], TOKEN_VERIFICATION].concat();
+        check(
+            auth_policy_requires_token_verification,
+            &[&["--auth-policy", "ispyb"], &["--auth-policy", "claims"]],
+            &[&[], &ispyb, &claims],
+        );
+    }
+
+    #[test]
+    fn token_verification_arguments_must_be_set_together() {
+        check(
+            paired_token_verification,
+            &[
+                &TOKEN_VERIFICATION[..2],
+                &TOKEN_VERIFICATION[2..],
+                &TOKEN_VERIFICATION[..4],
+            ],
+            &[&[], TOKEN_VERIFICATION],
+        );
+    }
+
+    /// The level at which the events of the target are recorded by the filter, if any
+    fn level(filter: &Targets, target: &str) -> Option<tracing::Level> {
+        [
+            tracing::Level::TRACE,
+            tracing::Level::DEBUG,
+            tracing::Level::INFO,
+            tracing::Level::WARN,
+            tracing::Level::ERROR,
+        ]
+        .into_iter()
+        .find(|level| filter.would_enable(target, level))
+    }
+
+    /// The filters parsed from the comma separated target=level list
+    fn target_filters(filters: &str) -> Vec<LogTargetFilter> {
+        filters
+            .split(',')
+            .map(|filter| filter.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn default_target_filters_quieten_the_aws_sdk() {
+        let filter = log_filter(Level::INFO, Vec::new(), None).unwrap();
+        assert_eq!(level(&filter, "fluorescence_scan"), Some(Level::INFO));
+        assert_eq!(
+            level(&filter, "aws_smithy_runtime::client"),
+            Some(Level::WARN)
+        );
+        assert_eq!(level(&filter, "aws_credential_types"), Some(Level::WARN));
+    }
+
+    #[test]
+    fn target_filters_are_merged_over_the_defaults() {
+        let filter = log_filter(
+            Level::INFO,
+            target_filters("sea_orm=debug,aws_smithy_runtime=error"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(level(&filter, "sea_orm::driver"), Some(Level::DEBUG));
+        assert_eq!(level(&filter, "aws_smithy_runtime"), Some(Level::ERROR));
+        assert_eq!(level(&filter, "aws_credential_types"), Some(Level::WARN));
+        assert_eq!(level(&filter, "fluorescence_scan"), Some(Level::INFO));
+    }
+
+    #[test]
+    fn rust_log_without_a_bare_level_keeps_other_targets() {
+        let filter = log_filter(
+            Level::INFO,
+            target_filters("sea_orm=debug"),
+            Some(String::from("fluorescence_scan=trace")),
+        )
+        .unwrap();
+        assert_eq!(level(&filter, "fluorescence_scan"), Some(Level::TRACE));
+        assert_eq!(level(&filter, "hyper"), Some(Level::INFO));
+        assert_eq!(level(&filter, "sea_orm"), Some(Level::DEBUG));
+        assert_eq!(level(&filter, "aws_smithy_runtime"), Some(Level::WARN));
+    }
+
+    #[test]
+    fn rust_log_replaces_the_levels_it_names() {
+        let filter = log_filter(
+            Level::INFO,
+            Vec::new(),
+            Some(String::from("warn,aws_smithy_runtime=debug")),
+        )
+        .unwrap();
+        assert_eq!(level(&filter, "fluorescence_scan"), Some(Level::WARN));
+        assert_eq!(level(&filter, "aws_smithy_runtime"), Some(Level::DEBUG));
+        assert_eq!(level(&filter, "aws_credential_types"), Some(Level::WARN));
+        let off = log_filter(Level::INFO, Vec::new(), Some(String::from("off"))).unwrap();
+        assert_eq!(level(&off, "fluorescence_scan"), None);
+        let blank = log_filter(Level::DEBUG, Vec::new(), Some(String::from(" "))).unwrap();
+        assert_eq!(level(&blank, "fluorescence_scan"), Some(Level::DEBUG));
+    }
+
+    #[test]
+    fn malformed_filters_are_rejected() {
+        assert!("sea_orm".parse::<LogTargetFilter>().is_err());
+        assert!("sea_orm=loud".parse::<LogTargetFilter>().is_err());
+        assert!(log_filter(Level::INFO, Vec::new(), Some(String::from("sea_orm=loud"))).is_err());
     }
 }
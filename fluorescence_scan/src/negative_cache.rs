@@ -0,0 +1,258 @@
+use opentelemetry::metrics::{Counter, MeterProvider};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{built_info, object_key::ObjectKey};
+
+/// A bounded record of objects recently found to be missing, so that repeated requests for them need not reach S3
+#[derive(Debug)]
+pub struct NegativeCache {
+    /// The time until which each key is known to be missing
+    entries: Mutex<HashMap<ObjectKey, Instant>>,
+    /// The most recent error with which a lookup of each key failed, and when
+    errors: Mutex<HashMap<ObjectKey, (Instant, String)>>,
+    /// The period for which a key is known to be missing after a failed lookup
+    ttl: Duration,
+    /// The maximum number of keys recorded, both as missing and with errors
+    capacity: usize,
+    /// The number of lookups answered by the cache since startup
+    hit_count: AtomicU64,
+    /// The count of lookups answered by the cache
+    hits: Counter<u64>,
+}
+
+impl NegativeCache {
+    /// Creates an empty cache holding up to `capacity` keys for `ttl` each
+    pub fn new(ttl: Duration, capacity: usize, meter_provider: &impl MeterProvider) -> Self {
+        Self {
+            entries: Mutex::default(),
+            errors: Mutex::default(),
+            ttl,
+            capacity,
+            hit_count: AtomicU64::default(),
+            hits: meter_provider
+                .meter(built_info::PKG_NAME)
+                .u64_counter("s3.negative_cache.hits")
+                .with_description("Object lookups answered by the cache of missing objects")
+                .init(),
+        }
+    }
+
+    /// Checks whether the object is known to be missing
+    pub fn is_missing(&self, key: &ObjectKey) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(expiry) if *expiry > Instant::now() => {
+                self.hit_count.fetch_add(1, Ordering::Relaxed);
+                self.hits.add(1, &[]);
+                true
+            }
+            Some(_) => {
+                entries.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The remaining period for which the object is known to be missing, without counting a hit
+    pub fn remaining(&self, key: &ObjectKey) -> Option<Duration> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|expiry| expiry.checked_duration_since(Instant::now()))
+    }
+
+    /// Records that the object is missing, evicting expired keys, and failing that the soonest to expire, when full
+    pub fn insert(&self, key: ObjectKey) {
+        if self.capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, expiry| *expiry > now);
+            if entries.len() >= self.capacity {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, expiry)| **expiry)
+                    .map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        entries.insert(key, now + self.ttl);
+    }
+
+    /// Records the error with which a lookup of the object failed, evicting the oldest error recorded when full
+    pub fn record_error(&self, key: &ObjectKey, error: &dyn Display) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() >= self.capacity && !errors.contains_key(key) {
+            let oldest = errors
+                .iter()
+                .min_by_key(|(_, (recorded, _))| *recorded)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                errors.remove(&oldest);
+            }
+        }
+        errors.insert(key.clone(), (Instant::now(), error.to_string()));
+    }
+
+    /// The most recent error with which a lookup of the object failed and how long ago it failed, if one is recorded
+    pub fn last_error(&self, key: &ObjectKey) -> Option<(String, Duration)> {
+        self.errors
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|(recorded, error)| (error.clone(), recorded.elapsed()))
+    }
+
+    /// Renders the usage of the cache for the debug statistics
+    pub fn to_json(&self) -> Value {
+        json!({
+            "hits": self.hit_count.load(Ordering::Relaxed),
+            "entries": self.entries.lock().unwrap().len(),
+            "errors": self.errors.lock().unwrap().len(),
+            "capacity": self.capacity,
+            "ttlSecs": self.ttl.as_secs(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NegativeCache;
+    use crate::object_key::{ObjectKey, ObjectKeyRules};
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    /// An empty cache holding up to `capacity` keys for a minute each
+    fn cache(capacity: usize) -> NegativeCache {
+        NegativeCache::new(Duration::from_secs(60), capacity, &NoopMeterProvider::new())
+    }
+
+    /// The key of the jpeg at the path
+    fn key(path: &str) -> ObjectKey {
+        ObjectKey::scan_jpeg(&ObjectKeyRules::default(), path).unwrap()
+    }
+
+    /// Makes the entry of the key expire at the supplied time
+    fn expire(cache: &NegativeCache, key: &ObjectKey, expiry: Instant) {
+        *cache.entries.lock().unwrap().get_mut(key).unwrap() = expiry;
+    }
+
+    #[test]
+    fn recorded_keys_are_missing_until_their_ttl_expires() {
+        let cache = cache(10);
+        assert!(!cache.is_missing(&key("i18/a.jpg")));
+        cache.insert(key("i18/a.jpg"));
+        assert!(cache.is_missing(&key("i18/a.jpg")));
+        assert!(!cache.is_missing(&key("i18/b.jpg")));
+
+        expire(
+            &cache,
+            &key("i18/a.jpg"),
+            Instant::now() - Duration::from_secs(1),
+        );
+        assert!(!cache.is_missing(&key("i18/a.jpg")));
+        assert_eq!(cache.to_json()["entries"], json!(0));
+        assert_eq!(cache.to_json()["hits"], json!(1));
+    }
+
+    #[test]
+    fn remaining_does_not_count_a_hit() {
+        let cache = cache(10);
+        assert_eq!(cache.remaining(&key("i18/a.jpg")), None);
+        cache.insert(key("i18/a.jpg"));
+        let remaining = cache.remaining(&key("i18/a.jpg")).unwrap();
+        assert!(remaining > Duration::from_secs(59) && remaining <= Duration::from_secs(60));
+        assert_eq!(cache.to_json()["hits"], json!(0));
+        assert!(cache.is_missing(&key("i18/a.jpg")));
+        assert_eq!(cache.to_json()["hits"], json!(1));
+
+        expire(
+            &cache,
+            &key("i18/a.jpg"),
+            Instant::now() - Duration::from_secs(1),
+        );
+        assert_eq!(cache.remaining(&key("i18/a.jpg")), None);
+    }
+
+    #[test]
+    fn expired_keys_are_evicted_first_when_full() {
+        let cache = cache(2);
+        cache.insert(key("i18/a.jpg"));
+        cache.insert(key("i18/b.jpg"));
+        expire(
+            &cache,
+            &key("i18/b.jpg"),
+            Instant::now() - Duration::from_secs(1),
+        );
+        cache.insert(key("i18/c.jpg"));
+        assert_eq!(cache.to_json()["entries"], json!(2));
+        assert!(cache.remaining(&key("i18/a.jpg")).is_some());
+        assert!(cache.remaining(&key("i18/c.jpg")).is_some());
+        assert!(!cache
+            .entries
+            .lock()
+            .unwrap()
+            .contains_key(&key("i18/b.jpg")));
+    }
+
+    #[test]
+    fn the_soonest_to_expire_is_evicted_when_full_of_live_keys() {
+        let cache = cache(2);
+        cache.insert(key("i18/a.jpg"));
+        cache.insert(key("i18/b.jpg"));
+        expire(
+            &cache,
+            &key("i18/a.jpg"),
+            Instant::now() + Duration::from_secs(30),
+        );
+        cache.insert(key("i18/c.jpg"));
+        assert_eq!(cache.remaining(&key("i18/a.jpg")), None);
+        assert!(cache.remaining(&key("i18/b.jpg")).is_some());
+        assert!(cache.remaining(&key("i18/c.jpg")).is_some());
+
+        cache.insert(key("i18/c.jpg"));
+        assert_eq!(cache.to_json()["entries"], json!(2));
+        assert!(cache.remaining(&key("i18/b.jpg")).is_some());
+    }
+
+    #[test]
+    fn the_oldest_error_is_evicted_when_full() {
+        let cache = cache(2);
+        cache.record_error(&key("i18/a.jpg"), &"first");
+        cache.record_error(&key("i18/b.jpg"), &"second");
+        cache.record_error(&key("i18/a.jpg"), &"third");
+        assert_eq!(cache.last_error(&key("i18/a.jpg")).unwrap().0, "third");
+        cache.record_error(&key("i18/c.jpg"), &"fourth");
+        assert_eq!(cache.last_error(&key("i18/b.jpg")), None);
+        assert_eq!(cache.last_error(&key("i18/c.jpg")).unwrap().0, "fourth");
+        assert_eq!(cache.to_json()["errors"], json!(2));
+    }
+
+    #[test]
+    fn caches_without_capacity_record_nothing() {
+        let cache = cache(0);
+        cache.insert(key("i18/a.jpg"));
+        cache.record_error(&key("i18/a.jpg"), &"failed");
+        assert!(!cache.is_missing(&key("i18/a.jpg")));
+        assert_eq!(cache.last_error(&key("i18/a.jpg")), None);
+    }
+}
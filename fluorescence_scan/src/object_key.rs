@@ -0,0 +1,385 @@
+use derive_more::{Display, Error};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{fmt, ops::Deref};
+
+/// The maximum length of an S3 object key, in bytes
+const MAX_KEY_LENGTH: usize = 1024;
+
+/// The length of the hash suffix appended to keys which have been truncated
+const HASH_SUFFIX_LENGTH: usize = 17;
+
+/// Characters which are percent-encoded within a key segment, leaving only the S3 safe characters
+pub const SEGMENT_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// A family of objects, those read from where the acquisition software recorded them and those written by the service, each under a distinct namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFamily {
+    /// The jpeg rendering of a fluorescence scan, as recorded
+    ScanJpeg,
+    /// The raw data file of a fluorescence scan, as recorded
+    ScanData,
+    /// An annotated rendering of a scan uploaded through the service
+    AnnotatedUpload,
+    /// A manifest of the files of a session written by the service
+    Manifest,
+}
+
+impl KeyFamily {
+    /// The name of the family, for diagnostics
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ScanJpeg => "scan-jpeg",
+            Self::ScanData => "scan-data",
+            Self::AnnotatedUpload => "annotated-upload",
+            Self::Manifest => "manifest",
+        }
+    }
+
+    /// The namespace under which the service writes keys of this family, or [`None`] if objects of the family are read from the keys at which they were recorded
+    pub fn namespace(self) -> Option<&'static str> {
+        match self {
+            Self::ScanJpeg | Self::ScanData => None,
+            Self::AnnotatedUpload | Self::Manifest => Some(self.name()),
+        }
+    }
+
+    /// The namespaces of every family of objects written by the service, which no recorded key may enter
+    fn written_namespaces() -> impl Iterator<Item = &'static str> {
+        [Self::AnnotatedUpload, Self::Manifest]
+            .into_iter()
+            .filter_map(Self::namespace)
+    }
+}
+
+/// An error produced when an [`ObjectKey`] cannot be constructed from the supplied path
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum ObjectKeyError {
+    /// The path contained no usable segments
+    #[display(fmt = "Path contains no usable segments")]
+    Empty,
+    /// The path contained a `..` segment
+    #[display(fmt = "Path contains a parent directory traversal")]
+    Traversal,
+    /// The path did not begin with any of the configured prefixes
+    #[display(fmt = "Path does not begin with any configured prefix")]
+    UnknownPrefix,
+    /// The recorded path exceeds the length of any key which can be stored
+    #[display(fmt = "Path exceeds the limit of {} bytes", MAX_KEY_LENGTH)]
+    TooLong,
+    /// The recorded path begins with the namespace of the objects written by the service
+    #[display(fmt = "Path begins with the reserved namespace {}", _0)]
+    ReservedNamespace(#[error(not(source))] &'static str),
+}
+
+/// The rules used to derive object keys from the paths recorded in ISPyB
+#[derive(Debug, Clone, Default)]
+pub struct ObjectKeyRules {
+    /// Path prefixes which are stripped from recorded paths, if non-empty every path must begin with one of them
+    path_prefixes: Vec<String>,
+}
+
+impl ObjectKeyRules {
+    /// Creates rules which strip the supplied path prefixes
+    pub fn new(path_prefixes: Vec<String>) -> Self {
+        Self { path_prefixes }
+    }
+
+    /// The first configured prefix with which the path begins
+    pub fn matched_prefix(&self, path: &str) -> Option<&str> {
+        self.path_prefixes
+            .iter()
+            .map(String::as_str)
+            .find(|prefix| path.starts_with(prefix))
+    }
+
+    /// Removes the first matching configured prefix from the path
+    fn strip_prefix<'a>(&self, path: &'a str) -> Result<&'a str, ObjectKeyError> {
+        if self.path_prefixes.is_empty() {
+            return Ok(path);
+        }
+        self.matched_prefix(path)
+            .map(|prefix| &path[prefix.len()..])
+            .ok_or(ObjectKeyError::UnknownPrefix)
+    }
+}
+
+/// A key identifying an object within the S3 bucket
+///
+/// Keys of objects read from where they were recorded are the recorded path, less any configured prefix, byte for byte, and may not begin with the namespace of any written family. Keys of objects written by the service are placed under the namespace of their [`KeyFamily`], with each segment percent-encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectKey(String);
+
+impl ObjectKey {
+    /// Creates the key of the jpeg rendering of a scan from its recorded path
+    pub fn scan_jpeg(rules: &ObjectKeyRules, path: &str) -> Result<Self, ObjectKeyError> {
+        Self::from_path(KeyFamily::ScanJpeg, rules, path)
+    }
+
+    /// Creates the key of the raw data file of a scan from its recorded path
+    pub fn scan_data(rules: &ObjectKeyRules, path: &str) -> Result<Self, ObjectKeyError> {
+        Self::from_path(KeyFamily::ScanData, rules, path)
+    }
+
+    /// Creates the key under which an annotated rendering of a scan is uploaded, from the path of the rendering
+    pub fn annotated_upload(rules: &ObjectKeyRules, path: &str) -> Result<Self, ObjectKeyError> {
+        Self::from_path(KeyFamily::AnnotatedUpload, rules, path)
+    }
+
+    /// Creates the key under which a manifest is written, from the path it describes
+    pub fn manifest(rules: &ObjectKeyRules, path: &str) -> Result<Self, ObjectKeyError> {
+        Self::from_path(KeyFamily::Manifest, rules, path)
+    }
+
+    /// Creates the key of an object of the family from a path, which is the path itself for recorded families, otherwise a key within the family namespace
+    pub fn from_path(
+        family: KeyFamily,
+        rules: &ObjectKeyRules,
+        path: &str,
+    ) -> Result<Self, ObjectKeyError> {
+        let path = rules.strip_prefix(path)?;
+        match family.namespace() {
+            None => Self::recorded(path),
+            Some(namespace) => Self::written(namespace, path),
+        }
+    }
+
+    /// Recovers the key from a URL signed by the file proxy, which only signs keys derived by these rules
+    pub fn signed(key: String) -> Self {
+        Self(key)
+    }
+
+    /// Creates the key of a recorded object, which must be exactly its path, checking only that it could be a key outside the namespaces of written objects
+    fn recorded(path: &str) -> Result<Self, ObjectKeyError> {
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !matches!(*segment, "" | "."));
+        if segments.clone().any(|segment| segment == "..") {
+            return Err(ObjectKeyError::Traversal);
+        }
+        let Some(first) = segments.next() else {
+            return Err(ObjectKeyError::Empty);
+        };
+        if let Some(namespace) =
+            KeyFamily::written_namespaces().find(|namespace| *namespace == first)
+        {
+            return Err(ObjectKeyError::ReservedNamespace(namespace));
+        }
+        if path.len() > MAX_KEY_LENGTH {
+            return Err(ObjectKeyError::TooLong);
+        }
+        Ok(Self(path.to_string()))
+    }
+
+    /// Creates a key to be written within the namespace, encoding each segment of the path
+    fn written(namespace: &str, path: &str) -> Result<Self, ObjectKeyError> {
+        let mut key = String::from(namespace);
+        let mut segments = 0;
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(ObjectKeyError::Traversal),
+                segment => {
+                    key.push('/');
+                    key.extend(utf8_percent_encode(segment, SEGMENT_ENCODE_SET));
+                    segments += 1;
+                }
+            }
+        }
+        if segments == 0 {
+            return Err(ObjectKeyError::Empty);
+        }
+        Ok(Self(truncate(key)))
+    }
+}
+
+impl Deref for ObjectKey {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Display for ObjectKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<ObjectKey> for String {
+    fn from(value: ObjectKey) -> Self {
+        value.0
+    }
+}
+
+/// Shortens keys exceeding the S3 key length limit, appending a hash of the full key so truncated keys remain distinct
+fn truncate(mut key: String) -> String {
+    if key.len() <= MAX_KEY_LENGTH {
+        return key;
+    }
+    let hash = fnv1a(key.as_bytes());
+    let mut end = MAX_KEY_LENGTH - HASH_SUFFIX_LENGTH;
+    // Encoded keys are ASCII, so only avoid splitting a percent-encoded triplet
+    if let Some(escape) = key[end.saturating_sub(2)..end].find('%') {
+        end = end - 2 + escape;
+    }
+    key.truncate(end);
+    key.push_str(&format!("~{hash:016x}"));
+    key
+}
+
+/// Percent-encodes each segment of the key for inclusion in the path of a URL
+pub fn url_path(key: &str) -> String {
+    key.split('/')
+        .map(|segment| utf8_percent_encode(segment, SEGMENT_ENCODE_SET).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Computes the 64 bit FNV-1a hash of the input, which is stable across builds and platforms
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{url_path, ObjectKey, ObjectKeyError, ObjectKeyRules, MAX_KEY_LENGTH};
+
+    /// Rules stripping the root of the beamline data directories
+    fn rules() -> ObjectKeyRules {
+        ObjectKeyRules::new(vec![String::from("/dls/")])
+    }
+
+    #[test]
+    fn recorded_keys_are_the_path_less_its_prefix() {
+        for path in [
+            "/dls/i18/data/2024/cm1-1/scan.dat",
+            "/dls/i18/data/2024/cm1-1/a scan with spaces.dat",
+            "/dls/i18/data/2024/cm1-1/spéctre-ß-光.dat",
+            "/dls/i18/data/2024/cm1-1/100%?#+&.dat",
+            "/dls/i18//data/./2024/scan.dat",
+        ] {
+            let expected = path.strip_prefix("/dls/").unwrap();
+            assert_eq!(&*ObjectKey::scan_jpeg(&rules(), path).unwrap(), expected);
+            assert_eq!(&*ObjectKey::scan_data(&rules(), path).unwrap(), expected);
+        }
+        assert_eq!(
+            &*ObjectKey::scan_data(&ObjectKeyRules::default(), "/dls/i18/scan.dat").unwrap(),
+            "/dls/i18/scan.dat"
+        );
+    }
+
+    #[test]
+    fn traversal_is_rejected() {
+        for path in [
+            "/dls/../etc/passwd",
+            "/dls/i18/data/../../../etc/passwd",
+            "/dls/i18/..",
+        ] {
+            assert_eq!(
+                ObjectKey::scan_jpeg(&rules(), path),
+                Err(ObjectKeyError::Traversal)
+            );
+            assert_eq!(
+                ObjectKey::annotated_upload(&rules(), path),
+                Err(ObjectKeyError::Traversal)
+            );
+        }
+    }
+
+    #[test]
+    fn paths_without_segments_or_prefix_are_rejected() {
+        for path in ["/dls/", "/dls/./", "/dls//"] {
+            assert_eq!(
+                ObjectKey::scan_jpeg(&rules(), path),
+                Err(ObjectKeyError::Empty)
+            );
+        }
+        assert_eq!(
+            ObjectKey::scan_jpeg(&rules(), "/home/abc12345/scan.jpg"),
+            Err(ObjectKeyError::UnknownPrefix)
+        );
+    }
+
+    #[test]
+    fn recorded_paths_longer_than_any_key_are_rejected() {
+        let longest = format!("/dls/{}", "a".repeat(MAX_KEY_LENGTH));
+        assert!(ObjectKey::scan_jpeg(&rules(), &longest).is_ok());
+        let too_long = format!("{longest}a");
+        assert_eq!(
+            ObjectKey::scan_jpeg(&rules(), &too_long),
+            Err(ObjectKeyError::TooLong)
+        );
+    }
+
+    #[test]
+    fn recorded_paths_cannot_enter_written_namespaces() {
+        for (path, namespace) in [
+            ("/dls/manifest/i18/x.jpg", "manifest"),
+            ("/dls/annotated-upload/i18/x.jpg", "annotated-upload"),
+            ("/dls//./manifest/i18/x.jpg", "manifest"),
+            ("/dls/manifest", "manifest"),
+        ] {
+            assert_eq!(
+                ObjectKey::scan_jpeg(&rules(), path),
+                Err(ObjectKeyError::ReservedNamespace(namespace))
+            );
+            assert_eq!(
+                ObjectKey::scan_data(&rules(), path),
+                Err(ObjectKeyError::ReservedNamespace(namespace))
+            );
+        }
+        assert_eq!(
+            ObjectKey::scan_jpeg(&ObjectKeyRules::default(), "/manifest/x.jpg"),
+            Err(ObjectKeyError::ReservedNamespace("manifest"))
+        );
+        for path in [
+            "/dls/i18/manifest/x.jpg",
+            "/dls/manifests/x.jpg",
+            "/dls/Manifest/x.jpg",
+        ] {
+            assert!(ObjectKey::scan_jpeg(&rules(), path).is_ok(), "{path}");
+        }
+        assert_eq!(
+            &*ObjectKey::manifest(&rules(), "/dls/manifest/i18/x.jpg").unwrap(),
+            "manifest/manifest/i18/x.jpg"
+        );
+    }
+
+    #[test]
+    fn written_keys_are_namespaced_and_encoded() {
+        let path = "/dls/i18/data/2024/cm1-1/a scan-ß.jpg";
+        assert_eq!(
+            &*ObjectKey::annotated_upload(&rules(), path).unwrap(),
+            "annotated-upload/i18/data/2024/cm1-1/a%20scan-%C3%9F.jpg"
+        );
+        assert_eq!(
+            &*ObjectKey::manifest(&rules(), path).unwrap(),
+            "manifest/i18/data/2024/cm1-1/a%20scan-%C3%9F.jpg"
+        );
+    }
+
+    #[test]
+    fn long_written_keys_are_truncated_distinctly() {
+        let name = "光".repeat(400);
+        let first = ObjectKey::manifest(&rules(), &format!("/dls/i18/{name}1.dat")).unwrap();
+        let second = ObjectKey::manifest(&rules(), &format!("/dls/i18/{name}2.dat")).unwrap();
+        for key in [&first, &second] {
+            assert!(key.len() <= MAX_KEY_LENGTH);
+            assert!(!key[..key.len() - 17].ends_with('%'));
+            assert!(!key[..key.len() - 18].ends_with('%'));
+        }
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn keys_are_encoded_in_urls() {
+        assert_eq!(url_path("/i18/a scan?#%.jpg"), "/i18/a%20scan%3F%23%25.jpg");
+    }
+}
@@ -0,0 +1,206 @@
+use async_graphql::ErrorExtensions;
+use opentelemetry::metrics::MeterProvider;
+use sea_orm::{DbErr, RuntimeErr};
+use serde_json::{json, Value};
+use sqlx::mysql::MySqlDatabaseError;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::built_info;
+
+/// The period for which the service remains in degraded mode after ISPyB last refused a write as read-only, unless configured otherwise
+pub const DEFAULT_READ_ONLY_COOL_DOWN: Duration = Duration::from_secs(5 * 60);
+
+/// The MySQL error numbers with which writes are refused by a read-only server or transaction, as during ISPyB maintenance
+const READ_ONLY_ERRORS: [u16; 2] = [
+    // ER_OPTION_PREVENTS_STATEMENT, raised by servers running with --read-only
+    1290, // ER_CANT_EXECUTE_IN_READ_ONLY_TRANSACTION
+    1792,
+];
+
+/// The message of the errors produced for writes refused as read-only
+const READ_ONLY_MESSAGE: &str = "ISPyB is in maintenance";
+
+/// Whether the service is in degraded mode, entered whenever ISPyB refuses a write as read-only and left once the cool-down has elapsed without another refusal
+///
+/// Writes refused as read-only are never retried. Queries are unaffected by degraded mode.
+#[derive(Debug, Clone)]
+pub struct ReadOnlyMode {
+    /// The period for which degraded mode lasts after the most recent refusal
+    cool_down: Duration,
+    /// When ISPyB first refused a write as read-only since degraded mode was last entered, and when it most recently did, if it ever has
+    detected: Arc<Mutex<Option<(Instant, Instant)>>>,
+}
+
+impl ReadOnlyMode {
+    /// Creates a record of degraded mode lasting for the cool-down, reporting it as a gauge using the supplied meter provider
+    pub fn new(cool_down: Duration, meter_provider: &impl MeterProvider) -> Self {
+        let read_only = Self {
+            cool_down,
+            detected: Arc::default(),
+        };
+        meter_provider
+            .meter(built_info::PKG_NAME)
+            .u64_observable_gauge("ispyb.read_only")
+            .with_description("Whether ISPyB has recently refused writes as read-only")
+            .with_callback({
+                let read_only = read_only.clone();
+                move |observer| observer.observe(read_only.active().into(), &[])
+            })
+            .init();
+        read_only
+    }
+
+    /// Whether ISPyB has refused a write as read-only within the cool-down
+    pub fn active(&self) -> bool {
+        self.remaining().is_some()
+    }
+
+    /// The remaining period of degraded mode, if it is active
+    fn remaining(&self) -> Option<Duration> {
+        let (_, latest) = (*self.detected.lock().unwrap())?;
+        self.cool_down
+            .checked_sub(latest.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Converts the failure of a write into a GraphQL error, entering degraded mode with the `READ_ONLY` code if the server refused it as read-only
+    pub fn write_error(&self, err: DbErr) -> async_graphql::Error {
+        self.refused(mysql_error_number(&err), err)
+    }
+
+    /// Converts the failure of a write, with which the server reported the MySQL error number if any, into a GraphQL error
+    pub(crate) fn refused(&self, number: Option<u16>, err: DbErr) -> async_graphql::Error {
+        match number {
+            Some(number) if READ_ONLY_ERRORS.contains(&number) => {
+                warn!("ISPyB refused a write as read-only: {err}");
+                let now = Instant::now();
+                let mut detected = self.detected.lock().unwrap();
+                let first = match *detected {
+                    Some((first, latest)) if now.duration_since(latest) < self.cool_down => first,
+                    _ => now,
+                };
+                *detected = Some((first, now));
+                async_graphql::Error::new(READ_ONLY_MESSAGE)
+                    .extend_with(|_, extensions| extensions.set("code", "READ_ONLY"))
+            }
+            _ => err.into(),
+        }
+    }
+
+    /// Renders the degraded mode flags for the capabilities route
+    pub fn to_json(&self) -> Value {
+        let first = self.detected.lock().unwrap().map(|(first, _)| first);
+        let remaining = self.remaining();
+        json!({
+            "readOnly": remaining.is_some(),
+            "readOnlySinceSecs": remaining.and(first).map(|first| first.elapsed().as_secs()),
+            "readOnlyRemainingSecs": remaining.map(|remaining| remaining.as_secs()),
+        })
+    }
+}
+
+/// The MySQL error number with which the server refused a statement, if it did
+fn mysql_error_number(err: &DbErr) -> Option<u16> {
+    match err {
+        DbErr::Exec(RuntimeErr::SqlxError(sqlx::Error::Database(err)))
+        | DbErr::Query(RuntimeErr::SqlxError(sqlx::Error::Database(err))) => err
+            .try_downcast_ref::<MySqlDatabaseError>()
+            .map(MySqlDatabaseError::number),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mysql_error_number, ReadOnlyMode, READ_ONLY_MESSAGE};
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use sea_orm::DbErr;
+    use serde_json::json;
+    use std::time::{Duration, Instant};
+
+    /// Degraded mode lasting for the cool-down
+    fn read_only(cool_down: Duration) -> ReadOnlyMode {
+        ReadOnlyMode::new(cool_down, &NoopMeterProvider::new())
+    }
+
+    /// The code of the error, if it has one
+    fn code(err: &async_graphql::Error) -> Option<String> {
+        err.extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get("code"))
+            .map(|code| code.to_string())
+    }
+
+    #[test]
+    fn read_only_refusals_enter_degraded_mode() {
+        for number in [1290, 1792] {
+            let read_only = read_only(Duration::from_secs(60));
+            let err = read_only.refused(Some(number), DbErr::Custom("refused".into()));
+            assert_eq!(err.message, READ_ONLY_MESSAGE);
+            assert_eq!(code(&err).as_deref(), Some("\"READ_ONLY\""));
+            assert!(read_only.active());
+        }
+    }
+
+    #[test]
+    fn other_write_failures_carry_no_code() {
+        let read_only = read_only(Duration::from_secs(60));
+        let failure = DbErr::Custom("Duplicate entry".into());
+        assert_eq!(mysql_error_number(&failure), None);
+        let err = read_only.write_error(failure);
+        assert_eq!(err.message, "Custom Error: Duplicate entry");
+        assert_eq!(code(&err), None);
+        let err = read_only.refused(Some(1062), DbErr::Custom("Duplicate entry".into()));
+        assert_eq!(code(&err), None);
+        assert!(!read_only.active());
+    }
+
+    #[test]
+    fn degraded_mode_ends_once_the_cool_down_elapses() {
+        let read_only = read_only(Duration::from_secs(60));
+        read_only.refused(Some(1290), DbErr::Custom("refused".into()));
+        let ago = Instant::now() - Duration::from_secs(61);
+        *read_only.detected.lock().unwrap() = Some((ago, ago));
+        assert!(!read_only.active());
+        assert_eq!(
+            read_only.to_json(),
+            json!({ "readOnly": false, "readOnlySinceSecs": null, "readOnlyRemainingSecs": null })
+        );
+    }
+
+    #[test]
+    fn repeated_refusals_extend_degraded_mode_from_the_first() {
+        let read_only = read_only(Duration::from_secs(60));
+        let first = Instant::now() - Duration::from_secs(100);
+        let latest = Instant::now() - Duration::from_secs(30);
+        *read_only.detected.lock().unwrap() = Some((first, latest));
+        read_only.refused(Some(1792), DbErr::Custom("refused".into()));
+        let report = read_only.to_json();
+        assert_eq!(report["readOnly"], json!(true));
+        assert_eq!(report["readOnlySinceSecs"], json!(100));
+        assert_eq!(report["readOnlyRemainingSecs"], json!(59));
+    }
+
+    #[test]
+    fn refusals_after_the_cool_down_restart_degraded_mode() {
+        let read_only = read_only(Duration::from_secs(60));
+        let ago = Instant::now() - Duration::from_secs(100);
+        *read_only.detected.lock().unwrap() = Some((ago, ago));
+        read_only.refused(Some(1290), DbErr::Custom("refused".into()));
+        assert_eq!(read_only.to_json()["readOnlySinceSecs"], json!(0));
+    }
+
+    #[test]
+    fn reports_normal_service_until_a_refusal() {
+        let read_only = read_only(Duration::from_secs(60));
+        assert!(!read_only.active());
+        assert_eq!(
+            read_only.to_json(),
+            json!({ "readOnly": false, "readOnlySinceSecs": null, "readOnlyRemainingSecs": null })
+        );
+    }
+}
@@ -0,0 +1,175 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{borrow::Cow, fmt, sync::Arc};
+use tracing::Span;
+
+/// Directory names which are followed by a segment naming a user, typically their federal ID
+const USER_DIRECTORIES: &[&str] = &["users", "home"];
+
+/// Controls the redaction of user identifying segments from paths attached to telemetry
+///
+/// All path-bearing span attributes should be attached through [`PathRedaction::record`] so that they cannot bypass redaction.
+#[derive(Clone)]
+pub struct PathRedaction {
+    /// Whether paths are redacted
+    enabled: bool,
+    /// The key with which segments naming a user are hashed, so that the hash of a known identifier cannot be looked up without it
+    secret: Arc<[u8]>,
+}
+
+impl fmt::Debug for PathRedaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathRedaction")
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for PathRedaction {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl PathRedaction {
+    /// Creates a redaction policy which redacts paths only when enabled, hashing with a key generated for this process
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            secret: Arc::from(rand::random::<[u8; 32]>()),
+        }
+    }
+
+    /// Sets the key with which segments naming a user are hashed, which must be shared by all replicas for their telemetry to agree
+    pub fn secret(mut self, secret: impl Into<Arc<[u8]>>) -> Self {
+        self.secret = secret.into();
+        self
+    }
+
+    /// Computes the pseudonym of a segment naming a user, from the first 64 bits of its keyed hash
+    fn pseudonym(&self, segment: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(segment.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        format!(
+            "user-{:016x}",
+            u64::from_be_bytes(hash[..8].try_into().expect("SHA-256 produces 32 bytes"))
+        )
+    }
+
+    /// Records the redacted path as the value of a field on the span
+    pub fn record(&self, span: &Span, field: &'static str, path: &str) {
+        span.record(field, &*self.redact(path));
+    }
+
+    /// Replaces each segment naming a user with a keyed hash of its value, which is stable for as long as the key
+    pub fn redact<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if !self.enabled {
+            return Cow::Borrowed(path);
+        }
+        let mut redacted = false;
+        let mut follows_user_directory = false;
+        let segments = path
+            .split('/')
+            .map(|segment| {
+                let segment = if follows_user_directory && !segment.is_empty() {
+                    redacted = true;
+                    Cow::Owned(self.pseudonym(segment))
+                } else {
+                    Cow::Borrowed(segment)
+                };
+                follows_user_directory = USER_DIRECTORIES
+                    .iter()
+                    .any(|directory| directory.eq_ignore_ascii_case(&segment));
+                segment
+            })
+            .collect::<Vec<_>>();
+        if redacted {
+            Cow::Owned(segments.join("/"))
+        } else {
+            Cow::Borrowed(path)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathRedaction;
+
+    /// A redaction policy hashing with a fixed key
+    fn redaction(secret: &[u8]) -> PathRedaction {
+        PathRedaction::new(true).secret(secret.to_vec())
+    }
+
+    /// Whether the segment is a pseudonym produced by redaction
+    fn is_pseudonym(segment: &str) -> bool {
+        segment.strip_prefix("user-").is_some_and(|hash| {
+            hash.len() == 16 && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+        })
+    }
+
+    #[test]
+    fn segments_following_user_directories_are_replaced() {
+        let redaction = redaction(b"key");
+        for (path, redacted_segment) in [
+            ("/home/abc12345/scan.dat", 2),
+            ("/dls/i18/data/users/abc12345/scan.dat", 5),
+            ("/HOME/abc12345", 2),
+            ("relative/Users/abc12345/", 2),
+        ] {
+            let redacted = redaction.redact(path);
+            let segments = redacted.split('/').collect::<Vec<_>>();
+            let original = path.split('/').collect::<Vec<_>>();
+            assert_eq!(segments.len(), original.len(), "{path}");
+            for (index, (segment, original)) in segments.iter().zip(&original).enumerate() {
+                if index == redacted_segment {
+                    assert!(is_pseudonym(segment), "{path} became {redacted}");
+                } else {
+                    assert_eq!(segment, original, "{path} became {redacted}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn paths_without_users_are_untouched() {
+        let redaction = redaction(b"key");
+        for path in [
+            "/dls/i18/data/2024/cm1-1/scan.dat",
+            "/home",
+            "/home/",
+            "/dls/homes/abc12345/scan.dat",
+            "",
+        ] {
+            assert_eq!(redaction.redact(path), path);
+        }
+    }
+
+    #[test]
+    fn pseudonyms_depend_on_the_user_and_the_key() {
+        let path = "/home/abc12345/scan.dat";
+        assert_eq!(
+            redaction(b"key").redact(path),
+            redaction(b"key").redact(path)
+        );
+        assert_ne!(
+            redaction(b"key").redact(path),
+            redaction(b"key").redact("/home/abc12346/scan.dat")
+        );
+        assert_ne!(
+            redaction(b"key").redact(path),
+            redaction(b"another key").redact(path)
+        );
+        assert_ne!(
+            PathRedaction::default().redact(path),
+            PathRedaction::default().redact(path)
+        );
+    }
+
+    #[test]
+    fn disabled_redaction_keeps_paths() {
+        let path = "/home/abc12345/scan.dat";
+        assert_eq!(PathRedaction::new(false).redact(path), path);
+    }
+}
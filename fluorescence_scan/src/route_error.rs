@@ -0,0 +1,267 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+
+/// The header carrying the identifier assigned to each request
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// An error produced by a non-GraphQL route
+///
+/// The response is rendered by [`negotiate_error`], which produces RFC 7807 problem details when the client prefers JSON to plain text and plain text otherwise.
+#[derive(Debug, Clone)]
+pub struct RouteError {
+    /// The HTTP status of the response
+    status: StatusCode,
+    /// A human readable explanation of this occurrence of the problem
+    detail: String,
+}
+
+impl RouteError {
+    /// Creates an error with the supplied status and explanation
+    pub fn new(status: StatusCode, detail: impl ToString) -> Self {
+        Self {
+            status,
+            detail: detail.to_string(),
+        }
+    }
+
+    /// Renders the error as RFC 7807 problem details
+    fn into_problem_details(self, request_id: Option<String>) -> Response {
+        let body = json!({
+            "type": "about:blank",
+            "title": self.status.canonical_reason(),
+            "status": self.status.as_u16(),
+            "detail": self.detail,
+            "requestId": request_id,
+        });
+        (
+            self.status,
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/problem+json"),
+            )],
+            body.to_string(),
+        )
+            .into_response()
+    }
+
+    /// Renders the error as plain text
+    fn into_plain_text(self, request_id: Option<String>) -> Response {
+        let mut body = format!(
+            "{} {}: {}",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or_default(),
+            self.detail
+        );
+        if let Some(request_id) = request_id {
+            body.push_str(&format!("\nRequest ID: {request_id}"));
+        }
+        (self.status, body).into_response()
+    }
+}
+
+impl IntoResponse for RouteError {
+    fn into_response(self) -> Response {
+        let mut response = self.status.into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+/// The media ranges listed by the Accept header, lowercased, with their quality values
+fn media_ranges(headers: &HeaderMap) -> Vec<(String, f32)> {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| {
+            let mut parts = media_range.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let quality = match parts.find_map(|parameter| {
+                let (name, value) = parameter.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then_some(value.trim())
+            }) {
+                Some(quality) => quality.parse().ok().filter(|q| (0.0..=1.0).contains(q))?,
+                None => 1.0,
+            };
+            (!range.is_empty()).then_some((range, quality))
+        })
+        .collect()
+}
+
+/// The quality with which a media type is accepted, taken from the ranges of the most specific level listed, in order of decreasing specificity, which any range matches
+fn quality(ranges: &[(String, f32)], levels: &[&[&str]]) -> Option<f32> {
+    levels.iter().find_map(|level| {
+        ranges
+            .iter()
+            .filter(|(range, _)| level.contains(&range.as_str()))
+            .map(|(_, quality)| *quality)
+            .reduce(f32::max)
+    })
+}
+
+/// Checks whether the Accept header prefers a JSON media type to plain text
+///
+/// JSON must be accepted with a non-zero quality by name or by `application/*`, and at least as highly as plain text. A bare `*/*` accepts plain text but not JSON, so browsers are shown text. Invalid quality values exclude their media range.
+fn accepts_json(headers: &HeaderMap) -> bool {
+    let ranges = media_ranges(headers);
+    let json = quality(
+        &ranges,
+        &[
+            &["application/json", "application/problem+json"],
+            &["application/*"],
+        ],
+    )
+    .unwrap_or_default();
+    let text = quality(&ranges, &[&["text/plain"], &["text/*"], &["*/*"]]).unwrap_or_default();
+    json > 0.0 && json >= text
+}
+
+/// Middleware rendering any [`RouteError`] produced by the inner service according to the Accept header of the request
+pub async fn negotiate_error(request: Request, next: Next) -> Response {
+    let json = accepts_json(request.headers());
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mut response = next.run(request).await;
+    match response.extensions_mut().remove::<RouteError>() {
+        Some(error) if json => error.into_problem_details(request_id),
+        Some(error) => error.into_plain_text(request_id),
+        None => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate_error, RouteError, REQUEST_ID_HEADER};
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    /// Requests the path of a router failing beneath `/missing`, with the Accept header and request id if supplied, producing the status, content type and body of the response
+    async fn fetch(
+        path: &str,
+        accept: Option<&str>,
+        request_id: Option<&str>,
+    ) -> (StatusCode, Option<String>, String) {
+        let router = Router::new()
+            .route(
+                "/missing",
+                get(|| async { RouteError::new(StatusCode::NOT_FOUND, "Object does not exist") }),
+            )
+            .route("/found", get(|| async { "Found" }))
+            .layer(middleware::from_fn(negotiate_error));
+        let mut request = Request::get(path);
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        let response = router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn json_clients_receive_problem_details() {
+        let (status, content_type, body) =
+            fetch("/missing", Some("application/json"), Some("abc-123")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap(),
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Object does not exist",
+                "requestId": "abc-123",
+            })
+        );
+        let (_, _, body) = fetch("/missing", Some("application/problem+json"), None).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["requestId"],
+            Value::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn other_clients_receive_plain_text() {
+        for accept in [None, Some("text/html,*/*;q=0.8"), Some("text/plain")] {
+            let (status, content_type, body) = fetch("/missing", accept, Some("abc-123")).await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert!(
+                content_type.unwrap().starts_with("text/plain"),
+                "{accept:?}"
+            );
+            assert_eq!(
+                body,
+                "404 Not Found: Object does not exist\nRequest ID: abc-123"
+            );
+        }
+        let (_, _, body) = fetch("/missing", None, None).await;
+        assert_eq!(body, "404 Not Found: Object does not exist");
+    }
+
+    #[tokio::test]
+    async fn quality_values_are_honoured() {
+        for (accept, json) in [
+            ("application/json;q=0", false),
+            ("application/json; q=0.0, text/plain", false),
+            ("application/*", true),
+            ("text/plain;q=0.5, application/*;q=0.9", true),
+            ("application/json;q=0.4, text/*;q=0.5", false),
+            ("application/*;q=1, application/json;q=0", false),
+            ("text/plain, application/json", true),
+            ("application/json;q=2", false),
+            ("*/*", false),
+        ] {
+            let (_, content_type, _) = fetch("/missing", Some(accept), None).await;
+            assert_eq!(
+                content_type.as_deref() == Some("application/problem+json"),
+                json,
+                "{accept}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn other_responses_pass_through() {
+        assert_eq!(
+            fetch("/found", Some("application/json"), Some("abc-123")).await,
+            (
+                StatusCode::OK,
+                Some(String::from("text/plain; charset=utf-8")),
+                String::from("Found")
+            )
+        );
+    }
+}
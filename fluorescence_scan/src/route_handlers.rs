@@ -1,29 +1,73 @@
-use async_graphql::Executor;
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use crate::{
+    authorization::Claims,
+    debug_stats::DebugStats,
+    file_proxy::FileProxy,
+    graphql::{ClientName, ConnectionId, FieldUsage, CLIENT_NAME_HEADER, ESTIMATE_COST_EXTENSION},
+    negative_cache::NegativeCache,
+    object_key::ObjectKey,
+    read_only::ReadOnlyMode,
+    route_error::RouteError,
+    store::ScanFiles,
+    token_verifier::TokenVerifier,
+};
+use async_graphql::{http::ALL_WEBSOCKET_PROTOCOLS, Data, Executor, Value};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
 use axum::{
-    extract::Request,
+    extract::{Request, WebSocketUpgrade},
     handler::Handler,
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
     RequestExt,
 };
 use axum_extra::{
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
-use std::{future::Future, pin::Pin};
+use percent_encoding::percent_decode_str;
+use sea_orm::DatabaseConnection;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// The header with which clients request a cost estimate in place of execution
+const ESTIMATE_ONLY_HEADER: &str = "x-graphql-estimate-only";
 
 /// An [`Handler`] which executes an [`Executor`] including the [`Authorization<Bearer>`] in the [`async_graphql::Context`]
 #[derive(Debug, Clone)]
 pub struct GraphQLHandler<E: Executor> {
     /// The GraphQL executor used to process the request
     executor: E,
+    /// Whether requests without a token with a subject are prevented from introspecting the schema
+    authenticated_introspection: bool,
+    /// The verifier of bearer tokens, without which no request has claims
+    token_verifier: Option<Arc<TokenVerifier>>,
 }
 
 impl<E: Executor> GraphQLHandler<E> {
     /// Constructs an instance of the handler with the provided schema.
     pub fn new(executor: E) -> Self {
-        Self { executor }
+        Self {
+            executor,
+            authenticated_introspection: false,
+            token_verifier: None,
+        }
+    }
+
+    /// Verifies bearer tokens with the verifier, producing claims for those which are genuine
+    pub fn token_verifier(mut self, token_verifier: Option<Arc<TokenVerifier>>) -> Self {
+        self.token_verifier = token_verifier;
+        self
+    }
+
+    /// Prevents requests without a token with a subject from introspecting the schema
+    pub fn authenticated_introspection(mut self, authenticated_introspection: bool) -> Self {
+        self.authenticated_introspection = authenticated_introspection;
+        self
     }
 }
 
@@ -40,16 +84,307 @@ where
                 .await
                 .ok()
                 .map(|token| token.0);
+            let estimate_only = req
+                .headers()
+                .get(ESTIMATE_ONLY_HEADER)
+                .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
+            let client_name = req
+                .headers()
+                .get(CLIENT_NAME_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| ClientName(value.to_string()));
             let request = req.extract::<GraphQLRequest, _>().await;
             match request {
-                Ok(request) => GraphQLResponse::from(
-                    self.executor
-                        .execute(request.into_inner().data(token))
-                        .await,
-                )
-                .into_response(),
+                Ok(request) => {
+                    let claims =
+                        Claims::verified(self.token_verifier.as_deref(), token.as_ref()).await;
+                    let mut request = request.into_inner();
+                    if self.authenticated_introspection && claims.subject.is_none() {
+                        request = request.disable_introspection();
+                    }
+                    let mut request = request.data(token).data(claims);
+                    if let Some(client_name) = client_name {
+                        request = request.data(client_name);
+                    }
+                    if estimate_only {
+                        request
+                            .extensions
+                            .insert(ESTIMATE_COST_EXTENSION.to_string(), Value::Boolean(true));
+                    }
+                    GraphQLResponse::from(self.executor.execute(request).await).into_response()
+                }
                 Err(err) => (StatusCode::BAD_REQUEST, err.0.to_string()).into_response(),
             }
         })
     }
 }
+
+/// Reads the bearer token from the `Authorization` entry of a `connection_init` payload, as sent by browser clients which cannot set headers on a websocket
+fn payload_token(payload: &serde_json::Value) -> Option<Authorization<Bearer>> {
+    let value = payload
+        .get("Authorization")
+        .or_else(|| payload.get("authorization"))?
+        .as_str()?;
+    let token = value
+        .strip_prefix("Bearer ")
+        .or_else(|| value.strip_prefix("bearer "))?;
+    Authorization::bearer(token).ok()
+}
+
+/// An [`Handler`] which serves GraphQL subscriptions from an [`Executor`] over a websocket, including the [`Authorization<Bearer>`] in the [`async_graphql::Context`]
+///
+/// The token is taken from the upgrade request, or from the `connection_init` payload in preference where one is presented there.
+#[derive(Debug, Clone)]
+pub struct GraphQLSubscriptionHandler<E: Executor> {
+    /// The GraphQL executor used to process the subscriptions
+    executor: E,
+    /// The verifier of bearer tokens, without which no subscription has claims
+    token_verifier: Option<Arc<TokenVerifier>>,
+}
+
+impl<E: Executor> GraphQLSubscriptionHandler<E> {
+    /// Constructs an instance of the handler with the provided schema.
+    pub fn new(executor: E) -> Self {
+        Self {
+            executor,
+            token_verifier: None,
+        }
+    }
+
+    /// Verifies bearer tokens with the verifier, producing claims for those which are genuine
+    pub fn token_verifier(mut self, token_verifier: Option<Arc<TokenVerifier>>) -> Self {
+        self.token_verifier = token_verifier;
+        self
+    }
+}
+
+impl<S, E> Handler<((),), S> for GraphQLSubscriptionHandler<E>
+where
+    E: Executor,
+{
+    type Future = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+    fn call(self, mut req: Request, _state: S) -> Self::Future {
+        Box::pin(async move {
+            let token = req
+                .extract_parts::<TypedHeader<Authorization<Bearer>>>()
+                .await
+                .ok()
+                .map(|token| token.0);
+            let protocol = match req.extract_parts::<GraphQLProtocol>().await {
+                Ok(protocol) => protocol,
+                Err(err) => return err.into_response(),
+            };
+            let upgrade = match req.extract_parts::<WebSocketUpgrade>().await {
+                Ok(upgrade) => upgrade,
+                Err(err) => return err.into_response(),
+            };
+            let mut data = Data::default();
+            data.insert(Claims::verified(self.token_verifier.as_deref(), token.as_ref()).await);
+            data.insert(token);
+            data.insert(ConnectionId::next());
+            upgrade
+                .protocols(ALL_WEBSOCKET_PROTOCOLS)
+                .on_upgrade(move |stream| {
+                    GraphQLWebSocket::new(stream, self.executor, protocol)
+                        .with_data(data)
+                        .on_connection_init(|payload| async move {
+                            let mut data = Data::default();
+                            if let Some(token) = payload_token(&payload) {
+                                data.insert(
+                                    Claims::verified(self.token_verifier.as_deref(), Some(&token))
+                                        .await,
+                                );
+                                data.insert(Some(token));
+                            }
+                            Ok(data)
+                        })
+                        .serve()
+                })
+        })
+    }
+}
+
+/// Responds to liveness probes, succeeding whenever the service is able to handle requests
+pub async fn health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// An [`Handler`] which responds to readiness probes, succeeding only once startup has completed and while the database is reachable
+#[derive(Debug, Clone)]
+pub struct ReadinessHandler {
+    /// The database connection which must be reachable for the service to be ready
+    database: DatabaseConnection,
+    /// Whether startup, including any warm-up, has completed
+    started: Arc<AtomicBool>,
+}
+
+impl ReadinessHandler {
+    /// Constructs an instance of the handler with the provided database connection and startup flag.
+    pub fn new(database: DatabaseConnection, started: Arc<AtomicBool>) -> Self {
+        Self { database, started }
+    }
+}
+
+impl<S> Handler<((),), S> for ReadinessHandler {
+    type Future = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+    fn call(self, _req: Request, _state: S) -> Self::Future {
+        Box::pin(async move {
+            if !self.started.load(Ordering::Acquire) {
+                return RouteError::new(StatusCode::SERVICE_UNAVAILABLE, "Startup in progress")
+                    .into_response();
+            }
+            match self.database.ping().await {
+                Ok(()) => StatusCode::OK.into_response(),
+                Err(err) => RouteError::new(StatusCode::SERVICE_UNAVAILABLE, err).into_response(),
+            }
+        })
+    }
+}
+
+/// An [`Handler`] which reports the [`DebugStats`] of the service
+#[derive(Debug, Clone)]
+pub struct DebugStatsHandler {
+    /// The statistics to be reported
+    stats: DebugStats,
+}
+
+impl DebugStatsHandler {
+    /// Constructs an instance of the handler reporting the provided statistics.
+    pub fn new(stats: DebugStats) -> Self {
+        Self { stats }
+    }
+}
+
+impl<S> Handler<((),), S> for DebugStatsHandler {
+    type Future = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+    fn call(self, _req: Request, _state: S) -> Self::Future {
+        Box::pin(async move { Json(self.stats.to_json()).into_response() })
+    }
+}
+
+/// An [`Handler`] which reports the degraded modes of the service, so that clients can warn of them
+#[derive(Debug, Clone)]
+pub struct CapabilitiesHandler {
+    /// Whether ISPyB has recently refused writes as read-only
+    read_only: ReadOnlyMode,
+}
+
+impl CapabilitiesHandler {
+    /// Constructs an instance of the handler reporting the provided degraded mode.
+    pub fn new(read_only: ReadOnlyMode) -> Self {
+        Self { read_only }
+    }
+}
+
+impl<S> Handler<((),), S> for CapabilitiesHandler {
+    type Future = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+    fn call(self, _req: Request, _state: S) -> Self::Future {
+        Box::pin(async move { Json(self.read_only.to_json()).into_response() })
+    }
+}
+
+/// An [`Handler`] which reports the [`FieldUsage`] of the schema
+#[derive(Debug, Clone)]
+pub struct FieldUsageHandler {
+    /// The counts to be reported
+    usage: FieldUsage,
+}
+
+impl FieldUsageHandler {
+    /// Constructs an instance of the handler reporting the provided counts.
+    pub fn new(usage: FieldUsage) -> Self {
+        Self { usage }
+    }
+}
+
+impl<S> Handler<((),), S> for FieldUsageHandler {
+    type Future = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+    fn call(self, _req: Request, _state: S) -> Self::Future {
+        Box::pin(async move { Json(self.usage.to_json()).into_response() })
+    }
+}
+
+/// An [`Handler`] which serves objects from the [`ScanFiles`] store under URLs signed by the [`FileProxy`], answering requests for objects recently found to be missing from the [`NegativeCache`]
+#[derive(Debug, Clone)]
+pub struct FileProxyHandler {
+    /// The store from which objects are served
+    files: ScanFiles,
+    /// The signer of the URLs under which objects are served
+    proxy: FileProxy,
+    /// The record of objects recently found to be missing
+    negative_cache: Arc<NegativeCache>,
+}
+
+impl FileProxyHandler {
+    /// Constructs an instance of the handler serving from the provided store.
+    pub fn new(files: ScanFiles, proxy: FileProxy, negative_cache: Arc<NegativeCache>) -> Self {
+        Self {
+            files,
+            proxy,
+            negative_cache,
+        }
+    }
+}
+
+impl<S> Handler<((),), S> for FileProxyHandler {
+    type Future = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+
+    fn call(self, req: Request, _state: S) -> Self::Future {
+        Box::pin(async move {
+            let Some(key) = req
+                .uri()
+                .path()
+                .trim_start_matches('/')
+                .split_once('/')
+                .and_then(|(_, key)| percent_decode_str(key).decode_utf8().ok())
+            else {
+                return RouteError::new(StatusCode::NOT_FOUND, "No object requested")
+                    .into_response();
+            };
+            let mut expires = None;
+            let mut signature = None;
+            for (name, value) in
+                url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            {
+                match name.as_ref() {
+                    "expires" => expires = value.parse::<u64>().ok(),
+                    "signature" => signature = Some(value.into_owned()),
+                    _ => {}
+                }
+            }
+            let (Some(expires), Some(signature)) = (expires, signature) else {
+                return RouteError::new(StatusCode::FORBIDDEN, "URL is not signed").into_response();
+            };
+            if !self.proxy.verify(&key, expires, &signature) {
+                return RouteError::new(
+                    StatusCode::FORBIDDEN,
+                    "URL signature is invalid or expired",
+                )
+                .into_response();
+            }
+            let key = ObjectKey::signed(key.into_owned());
+            if self.negative_cache.is_missing(&key) {
+                return RouteError::new(StatusCode::NOT_FOUND, "Object does not exist")
+                    .into_response();
+            }
+            match self.files.store.get(&key).await {
+                Ok(Some(body)) => {
+                    ([(header::CACHE_CONTROL, "private, no-store")], body).into_response()
+                }
+                Ok(None) => {
+                    self.negative_cache.insert(key);
+                    RouteError::new(StatusCode::NOT_FOUND, "Object does not exist").into_response()
+                }
+                Err(err) => {
+                    self.negative_cache.record_error(&key, &err);
+                    RouteError::new(StatusCode::BAD_GATEWAY, err).into_response()
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,376 @@
+use axum::{
+    handler::Handler,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse},
+    routing::{get, MethodRouter},
+};
+use axum_extra::headers::{authorization::Bearer, Authorization, Cookie, HeaderMapExt};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tower_http::set_header::SetResponseHeaderLayer;
+use url::Url;
+
+use crate::{authorization::Claims, token_verifier::TokenVerifier};
+
+/// The origin from which the embedded GraphiQL build loads its scripts and styles
+const GRAPHIQL_ASSET_ORIGIN: &str = "https://unpkg.com";
+
+/// The origin from which the embedded GraphiQL build loads its favicon
+const GRAPHIQL_ICON_ORIGIN: &str = "https://graphql.org";
+
+/// The cookie in which the browser holds the bearer token with which GraphiQL is loaded and sends requests
+const TOKEN_COOKIE: &str = "graphiql_token";
+
+/// Copies a bearer token passed in the `access_token` fragment parameter, as by the SSO helper, or held in the token cookie into the headers GraphiQL sends, removing it from the address
+///
+/// Placed in the head so that it runs before GraphiQL restores its headers from storage.
+const TOKEN_FRAGMENT_SCRIPT: &str = "<script>(() => {\
+const cookie = document.cookie.split('; ').find((cookie) => cookie.startsWith('graphiql_token='));\
+const token = new URLSearchParams(window.location.hash.slice(1)).get('access_token') || (cookie && decodeURIComponent(cookie.slice(15)));\
+if (token) {\
+localStorage.setItem('graphiql:headers', JSON.stringify({ Authorization: `Bearer ${token}` }, null, 2));\
+history.replaceState(null, '', window.location.pathname + window.location.search);\
+}\
+})();</script>";
+
+/// Served with `401 Unauthorized` in place of GraphiQL to browsers without a verified token, moving a token passed in the `access_token` fragment parameter into the token cookie and reloading, or otherwise sending the browser to sign in
+///
+/// Browsers send no `Authorization` header when navigating, so the cookie carries the token to the page. The sign in URL, a JSON string or `null`, is substituted for `SIGN_IN_URL`.
+const SIGN_IN_SCRIPT: &str = "(() => {\
+const token = new URLSearchParams(window.location.hash.slice(1)).get('access_token');\
+const signIn = SIGN_IN_URL;\
+if (token) {\
+const secure = window.location.protocol === 'https:' ? '; Secure' : '';\
+document.cookie = `graphiql_token=${encodeURIComponent(token)}; Path=${window.location.pathname}; SameSite=Strict${secure}`;\
+window.location.replace(window.location.pathname + window.location.search);\
+} else if (signIn) {\
+const target = new URL(signIn);\
+target.searchParams.set('redirect_uri', window.location.pathname + window.location.search);\
+window.location.assign(target);\
+}\
+})();";
+
+/// Who may load the GraphiQL page
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GraphiQLAccess {
+    /// Served to anyone, for deployments without authorization
+    #[default]
+    Open,
+    /// Served only to clients presenting a verified bearer token with a subject, in the `Authorization` header or the token cookie, others receiving `401 Unauthorized` with a page which signs the browser in
+    Authenticated,
+    /// Served to anyone, but clients presenting no token with a subject may not introspect the schema, leaving the documentation and completion of GraphiQL empty until a token is supplied
+    Public,
+}
+
+/// The security headers applied to the GraphiQL page, and who may load it
+#[derive(Debug, Clone, Default)]
+pub struct GraphiQLPolicy {
+    /// The content security policy, if overridden, otherwise one permitting only the assets of the page is derived
+    content_security_policy: Option<HeaderValue>,
+    /// Who may load the page
+    access: GraphiQLAccess,
+    /// The URL of the SSO helper to which browsers without a token are sent, if any
+    sign_in_url: Option<Url>,
+}
+
+impl GraphiQLPolicy {
+    /// Creates a policy applying the supplied content security policy in place of the derived one
+    pub fn new(content_security_policy: Option<HeaderValue>) -> Self {
+        Self {
+            content_security_policy,
+            access: GraphiQLAccess::Open,
+            sign_in_url: None,
+        }
+    }
+
+    /// Sets who may load the page, which anyone may by default
+    pub fn access(mut self, access: GraphiQLAccess) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Sends browsers without a token to the SSO helper at the URL, with the address of the page in the `redirect_uri` parameter, which is to return them with a token in the `access_token` fragment parameter
+    pub fn sign_in_url(mut self, sign_in_url: Option<Url>) -> Self {
+        self.sign_in_url = sign_in_url;
+        self
+    }
+
+    /// Whether clients must present a token with a subject to introspect the schema
+    pub fn authenticated_introspection(&self) -> bool {
+        self.access == GraphiQLAccess::Public
+    }
+
+    /// Creates a route serving the page with the security headers applied, to those permitted to load it, verifying tokens with the verifier
+    ///
+    /// Unless the page is open, it is served with a script taking a token from the address fragment.
+    pub fn route<S: Clone + Send + Sync + 'static>(
+        &self,
+        page: String,
+        token_verifier: Option<Arc<TokenVerifier>>,
+    ) -> MethodRouter<S> {
+        let page = match self.access {
+            GraphiQLAccess::Open => page,
+            GraphiQLAccess::Authenticated | GraphiQLAccess::Public => {
+                page.replacen("</head>", &format!("{TOKEN_FRAGMENT_SCRIPT}</head>"), 1)
+            }
+        };
+        let content_security_policy = self
+            .content_security_policy
+            .clone()
+            .unwrap_or_else(|| default_content_security_policy(&page));
+        let sign_in_script = SIGN_IN_SCRIPT.replace(
+            "SIGN_IN_URL",
+            &serde_json::to_string(&self.sign_in_url.as_ref().map(Url::as_str))
+                .expect("URLs are serializable")
+                .replace('<', "\\u003c"),
+        );
+        let sign_in_page = format!(
+            "<!DOCTYPE html><html><head><title>Sign in</title><script>{sign_in_script}</script></head><body><p>A bearer token is required to load GraphiQL.</p></body></html>"
+        );
+        let sign_in_content_security_policy = HeaderValue::try_from(format!(
+            "default-src 'none'; script-src 'sha256-{}'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'",
+            STANDARD.encode(Sha256::digest(&sign_in_script))
+        ))
+        .expect("Content security policy contains only visible ASCII");
+        let authenticated = self.access == GraphiQLAccess::Authenticated;
+        get((move |headers: HeaderMap| {
+            let page = page.clone();
+            let content_security_policy = content_security_policy.clone();
+            let sign_in_page = sign_in_page.clone();
+            let sign_in_content_security_policy = sign_in_content_security_policy.clone();
+            let token_verifier = token_verifier.clone();
+            async move {
+                if authenticated {
+                    let token = headers.typed_get::<Authorization<Bearer>>().or_else(|| {
+                        headers
+                            .typed_get::<Cookie>()?
+                            .get(TOKEN_COOKIE)
+                            .and_then(|token| Authorization::bearer(token).ok())
+                    });
+                    if Claims::verified(token_verifier.as_deref(), token.as_ref())
+                        .await
+                        .subject
+                        .is_none()
+                    {
+                        return (
+                            StatusCode::UNAUTHORIZED,
+                            [
+                                (header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer")),
+                                (
+                                    header::CONTENT_SECURITY_POLICY,
+                                    sign_in_content_security_policy,
+                                ),
+                            ],
+                            Html(sign_in_page),
+                        )
+                            .into_response();
+                    }
+                }
+                (
+                    [(header::CONTENT_SECURITY_POLICY, content_security_policy)],
+                    Html(page),
+                )
+                    .into_response()
+            }
+        })
+        .layer(SetResponseHeaderLayer::overriding(
+            header::X_FRAME_OPTIONS,
+            HeaderValue::from_static("DENY"),
+        ))
+        .layer(SetResponseHeaderLayer::overriding(
+            header::REFERRER_POLICY,
+            HeaderValue::from_static("same-origin"),
+        ))
+        .layer(SetResponseHeaderLayer::overriding(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-store"),
+        )))
+    }
+}
+
+/// Derives a content security policy permitting the CDN assets and inline scripts of the stock GraphiQL page, and nothing else
+///
+/// Inline scripts are permitted by hash, as they embed the configured endpoint.
+fn default_content_security_policy(page: &str) -> HeaderValue {
+    let script_hashes = inline_scripts(page)
+        .map(|script| format!(" 'sha256-{}'", STANDARD.encode(Sha256::digest(script))))
+        .collect::<String>();
+    HeaderValue::try_from(format!(
+        "default-src 'none'; \
+         script-src {GRAPHIQL_ASSET_ORIGIN}{script_hashes}; \
+         style-src {GRAPHIQL_ASSET_ORIGIN} 'unsafe-inline'; \
+         font-src {GRAPHIQL_ASSET_ORIGIN} data:; \
+         img-src {GRAPHIQL_ICON_ORIGIN} data:; \
+         connect-src 'self'; \
+         base-uri 'none'; \
+         form-action 'none'; \
+         frame-ancestors 'none'"
+    ))
+    .expect("Content security policy contains only visible ASCII")
+}
+
+/// The contents of each script element without a source in the page
+fn inline_scripts(page: &str) -> impl Iterator<Item = &str> {
+    page.split("<script>")
+        .skip(1)
+        .filter_map(|rest| rest.split_once("</script>"))
+        .map(|(script, _)| script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inline_scripts, GraphiQLAccess, GraphiQLPolicy};
+    use crate::token_verifier::testing::{forged_token, genuine_token, verifier};
+    use async_graphql::http::GraphiQLSource;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request, StatusCode},
+        response::Response,
+        Router,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use sha2::{Digest, Sha256};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+    use url::Url;
+
+    /// Loads the GraphiQL page served under the policy with the supplied request headers
+    async fn load(policy: GraphiQLPolicy, headers: &[(header::HeaderName, String)]) -> Response {
+        let page = GraphiQLSource::build().endpoint("/").finish();
+        let router = Router::new().route("/", policy.route(page, Some(Arc::new(verifier()))));
+        let mut request = Request::get("/");
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        router
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// The body of the response
+    async fn body(response: Response) -> String {
+        String::from_utf8(
+            to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap()
+                .to_vec(),
+        )
+        .unwrap()
+    }
+
+    /// Asserts that every inline script of the page is permitted by its content security policy
+    fn assert_scripts_permitted(content_security_policy: &str, page: &str) {
+        for script in inline_scripts(page) {
+            let hash = format!("'sha256-{}'", STANDARD.encode(Sha256::digest(script)));
+            assert!(
+                content_security_policy.contains(&hash),
+                "{content_security_policy} does not permit {script}"
+            );
+        }
+    }
+
+    /// The policy requiring a verified token to load the page
+    fn authenticated() -> GraphiQLPolicy {
+        GraphiQLPolicy::default().access(GraphiQLAccess::Authenticated)
+    }
+
+    #[tokio::test]
+    async fn open_page_is_served_as_today() {
+        let response = load(GraphiQLPolicy::default(), &[]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::X_FRAME_OPTIONS], "DENY");
+        let page = body(response).await;
+        assert_eq!(page, GraphiQLSource::build().endpoint("/").finish());
+        assert!(!page.contains("graphiql_token"));
+    }
+
+    #[tokio::test]
+    async fn authenticated_page_is_refused_without_a_token() {
+        let response = load(authenticated(), &[]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        let content_security_policy = response.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let page = body(response).await;
+        assert!(!page.contains("unpkg.com"));
+        assert!(page.contains("access_token"));
+        assert_scripts_permitted(&content_security_policy, &page);
+    }
+
+    #[tokio::test]
+    async fn authenticated_page_is_refused_with_a_forged_token() {
+        let forged = forged_token("abc12345", &[]);
+        for headers in [
+            [(header::AUTHORIZATION, format!("Bearer {forged}"))],
+            [(header::COOKIE, format!("graphiql_token={forged}"))],
+        ] {
+            let response = load(authenticated(), &headers).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn authenticated_page_is_served_with_a_verified_header_token() {
+        let token = genuine_token("abc12345", &[]);
+        let response = load(
+            authenticated(),
+            &[(header::AUTHORIZATION, format!("Bearer {token}"))],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn authenticated_page_is_served_on_navigation_with_a_verified_cookie_token() {
+        let token = genuine_token("abc12345", &[]);
+        let response = load(
+            authenticated(),
+            &[(
+                header::COOKIE,
+                format!("theme=dark; graphiql_token={token}"),
+            )],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_security_policy = response.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let page = body(response).await;
+        assert!(page.contains("graphiql:headers"));
+        assert_scripts_permitted(&content_security_policy, &page);
+    }
+
+    #[tokio::test]
+    async fn authenticated_page_sends_browsers_to_sign_in() {
+        let policy = authenticated().sign_in_url(Some(
+            Url::parse("https://sso.invalid/login?client=graphiql").unwrap(),
+        ));
+        let response = load(policy, &[]).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(body(response)
+            .await
+            .contains(r#"const signIn = "https://sso.invalid/login?client=graphiql";"#));
+    }
+
+    #[tokio::test]
+    async fn public_page_is_served_without_a_token() {
+        let response = load(
+            GraphiQLPolicy::default().access(GraphiQLAccess::Public),
+            &[],
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_security_policy = response.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let page = body(response).await;
+        assert!(page.contains("access_token"));
+        assert_scripts_permitted(&content_security_policy, &page);
+    }
+}
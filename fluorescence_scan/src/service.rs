@@ -0,0 +1,904 @@
+use async_graphql::http::GraphiQLSource;
+use aws_sdk_s3::Client;
+use axum::{
+    body::Body,
+    http::{header, HeaderValue},
+    middleware,
+    routing::{get, RouterIntoService},
+    Router,
+};
+use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
+use sea_orm::DatabaseConnection;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    set_header::SetResponseHeaderLayer,
+};
+
+use crate::{
+    authorization::{AllowAll, AuthorizationPolicy},
+    debug_stats::DebugStats,
+    file_proxy::{FileProxy, FILE_PROXY_ROUTE},
+    graphql::{
+        root_schema_builder, BackfillLimit, ConcurrencyLimiter, DeprecationUsage,
+        DeterministicUrls, DownloadDiagnosticsEnabled, FieldUsage, LenientDecoding,
+        LiveSpectrumLimiter, ProposalAccess, QueryLimits, RootSchema, ScanLoader, SettleInterval,
+        SkippedRowsReport, SnapshotVariants, VisitLoader, DEFAULT_BACKFILL_LIMIT,
+        DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL, DEFAULT_PER_CLIENT_CONCURRENCY,
+        DEFAULT_SETTLE_INTERVAL,
+    },
+    negative_cache::NegativeCache,
+    object_key::ObjectKeyRules,
+    read_only::{ReadOnlyMode, DEFAULT_READ_ONLY_COOL_DOWN},
+    redaction::PathRedaction,
+    route_error::{negotiate_error, REQUEST_ID_HEADER},
+    route_handlers::{
+        health, CapabilitiesHandler, DebugStatsHandler, FieldUsageHandler, FileProxyHandler,
+        GraphQLHandler, GraphQLSubscriptionHandler, ReadinessHandler,
+    },
+    security_headers::GraphiQLPolicy,
+    store::{S3Store, ScanFileStore, ScanFiles},
+    token_verifier::TokenVerifier,
+    trace_context::continue_trace,
+    warmup::warm_up,
+    S3Bucket,
+};
+
+/// The period for which objects found to be missing are not looked up again, unless configured otherwise
+const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The maximum number of objects recorded as missing, unless configured otherwise
+const DEFAULT_NEGATIVE_CACHE_CAPACITY: usize = 10_000;
+
+/// The S3 client, bucket and key derivation rules with which scan files are accessed
+#[derive(Debug, Clone)]
+pub struct S3Facilities {
+    /// The client used to access the bucket
+    pub client: Client,
+    /// The bucket in which scan files are stored
+    pub bucket: S3Bucket,
+    /// The rules used to derive object keys from recorded paths
+    pub key_rules: ObjectKeyRules,
+}
+
+/// A builder for a [`FluorescenceScanService`]
+#[derive(Debug)]
+pub struct FluorescenceScanServiceBuilder {
+    /// The connection to the ISPyB database
+    database: DatabaseConnection,
+    /// The store from which scan files are read, if available
+    files: Option<ScanFiles>,
+    /// The key with which file proxy URLs are signed
+    file_proxy_secret: Vec<u8>,
+    /// Limits on the cost of accepted queries
+    query_limits: QueryLimits,
+    /// Whether rows which cannot be fully decoded should be tolerated
+    lenient_decoding: bool,
+    /// Whether the debug statistics and download diagnostics should be served
+    debug_endpoints: bool,
+    /// Whether URLs should be deterministic fakes rather than usable
+    deterministic_urls: bool,
+    /// The interval at which requests are sampled to count field resolutions, if they are counted
+    field_usage_sample_every: Option<u64>,
+    /// The redaction applied to paths attached to telemetry
+    path_redaction: PathRedaction,
+    /// The conventions probed to discover the snapshots of a scan
+    snapshot_variants: SnapshotVariants,
+    /// The number of expensive resolvers which may run concurrently for one client
+    per_client_concurrency: usize,
+    /// The maximum number of scans backfilled by a single invocation
+    backfill_limit: u64,
+    /// The period for which newly recorded scans are withheld from the change feed
+    settle_interval: Duration,
+    /// The number of live spectra which may be watched concurrently by one principal
+    live_spectra_per_principal: usize,
+    /// The period for which objects found to be missing are not looked up again
+    negative_cache_ttl: Duration,
+    /// The maximum number of objects recorded as missing
+    negative_cache_capacity: usize,
+    /// The period for which degraded mode lasts after ISPyB refuses a write as read-only
+    read_only_cool_down: Duration,
+    /// The security headers applied to the GraphiQL page
+    graphiql_policy: GraphiQLPolicy,
+    /// The policy deciding whether clients may access sessions, scans and restricted fields
+    authorization_policy: Arc<dyn AuthorizationPolicy>,
+    /// The path, as seen by the browser, at which GraphQL requests are to be sent
+    graphql_endpoint: String,
+    /// The contract to whose tagged fields the schema is restricted, if any
+    contract: Option<String>,
+    /// The verifier of bearer tokens, without which no request has claims
+    token_verifier: Option<Arc<TokenVerifier>>,
+}
+
+impl FluorescenceScanServiceBuilder {
+    /// Provides access to scan files in S3, without which the URL fields produce errors
+    pub fn s3(self, s3: S3Facilities) -> Self {
+        self.scan_file_store(Arc::new(S3Store::new(s3.client, s3.bucket)), s3.key_rules)
+    }
+
+    /// Provides access to scan files in the supplied store, without which the URL fields produce errors
+    pub fn scan_file_store(
+        mut self,
+        store: Arc<dyn ScanFileStore>,
+        key_rules: ObjectKeyRules,
+    ) -> Self {
+        self.files = Some(ScanFiles { store, key_rules });
+        self
+    }
+
+    /// Sets the key with which URLs served by the file proxy are signed, which must be shared by all replicas, otherwise one is generated
+    pub fn file_proxy_secret(mut self, file_proxy_secret: impl Into<Vec<u8>>) -> Self {
+        self.file_proxy_secret = file_proxy_secret.into();
+        self
+    }
+
+    /// Sets the limits on the cost of accepted queries
+    pub fn query_limits(mut self, query_limits: QueryLimits) -> Self {
+        self.query_limits = query_limits;
+        self
+    }
+
+    /// Nulls nullable fields and skips rows which cannot be decoded, rather than failing the query
+    pub fn lenient_decoding(mut self, lenient_decoding: bool) -> Self {
+        self.lenient_decoding = lenient_decoding;
+        self
+    }
+
+    /// Serves the debug statistics on the internal routes and enables the download diagnostics field
+    pub fn debug_endpoints(mut self, debug_endpoints: bool) -> Self {
+        self.debug_endpoints = debug_endpoints;
+        self
+    }
+
+    /// Sets the redaction applied to paths attached to telemetry, which redacts by default
+    pub fn path_redaction(mut self, path_redaction: PathRedaction) -> Self {
+        self.path_redaction = path_redaction;
+        self
+    }
+
+    /// Sets the conventions probed to discover the snapshots of a scan
+    pub fn snapshot_variants(mut self, snapshot_variants: SnapshotVariants) -> Self {
+        self.snapshot_variants = snapshot_variants;
+        self
+    }
+
+    /// Sets the number of expensive resolvers which may run concurrently for one client
+    pub fn per_client_concurrency(mut self, per_client_concurrency: usize) -> Self {
+        self.per_client_concurrency = per_client_concurrency;
+        self
+    }
+
+    /// Sets the maximum number of scans whose jpeg paths are backfilled by a single invocation
+    pub fn backfill_limit(mut self, backfill_limit: u64) -> Self {
+        self.backfill_limit = backfill_limit;
+        self
+    }
+
+    /// Sets the period for which newly recorded scans are withheld from the change feed, which should exceed the longest transaction recording scans
+    pub fn change_feed_settle_interval(mut self, settle_interval: Duration) -> Self {
+        self.settle_interval = settle_interval;
+        self
+    }
+
+    /// Replaces presigned and file proxy URLs with deterministic fakes under an `.invalid` host, for contract tests requiring stable responses
+    ///
+    /// Every object is taken to exist, so the store is never consulted for verified URLs or snapshots.
+    pub fn deterministic_urls(mut self, deterministic_urls: bool) -> Self {
+        self.deterministic_urls = deterministic_urls;
+        self
+    }
+
+    /// Counts the resolutions of each field by every `sample_every`th request, exporting them as metrics and serving them with the debug endpoints
+    pub fn field_usage_metrics(mut self, sample_every: u64) -> Self {
+        self.field_usage_sample_every = Some(sample_every);
+        self
+    }
+
+    /// Sets the number of live spectra which may be watched concurrently by one principal
+    pub fn live_spectra_per_principal(mut self, live_spectra_per_principal: usize) -> Self {
+        self.live_spectra_per_principal = live_spectra_per_principal;
+        self
+    }
+
+    /// Sets the period for which, and number of, objects found to be missing are not looked up again
+    pub fn negative_cache(mut self, ttl: Duration, capacity: usize) -> Self {
+        self.negative_cache_ttl = ttl;
+        self.negative_cache_capacity = capacity;
+        self
+    }
+
+    /// Sets the period for which degraded mode is reported after ISPyB last refused a write as read-only
+    pub fn read_only_cool_down(mut self, read_only_cool_down: Duration) -> Self {
+        self.read_only_cool_down = read_only_cool_down;
+        self
+    }
+
+    /// Sets the policy deciding whether clients may access sessions, scans and restricted fields, which by default allows every read but no mutation
+    pub fn authorization_policy(
+        mut self,
+        authorization_policy: Arc<dyn AuthorizationPolicy>,
+    ) -> Self {
+        self.authorization_policy = authorization_policy;
+        self
+    }
+
+    /// Verifies bearer tokens with the verifier, trusting the claims of only those which are genuine, without which every request is anonymous
+    pub fn token_verifier(mut self, token_verifier: Arc<TokenVerifier>) -> Self {
+        self.token_verifier = Some(token_verifier);
+        self
+    }
+
+    /// Sets the security headers applied to the GraphiQL page
+    pub fn graphiql_policy(mut self, graphiql_policy: GraphiQLPolicy) -> Self {
+        self.graphiql_policy = graphiql_policy;
+        self
+    }
+
+    /// Restricts the schema to the fields tagged for the contract, such that other fields cannot be selected or introspected
+    pub fn contract(mut self, contract: impl Into<String>) -> Self {
+        self.contract = Some(contract.into());
+        self
+    }
+
+    /// Sets the path, as seen by the browser, to which GraphiQL sends requests, for use when the service is nested
+    pub fn graphql_endpoint(mut self, graphql_endpoint: impl Into<String>) -> Self {
+        self.graphql_endpoint = graphql_endpoint.into();
+        self
+    }
+
+    /// Builds the service, creating all of its state
+    pub fn build(self) -> FluorescenceScanService {
+        let meter_provider = opentelemetry::global::meter_provider();
+        let negative_cache = Arc::new(NegativeCache::new(
+            self.negative_cache_ttl,
+            self.negative_cache_capacity,
+            &meter_provider,
+        ));
+        let deprecation_usage = DeprecationUsage::new(&meter_provider);
+        let read_only = ReadOnlyMode::new(self.read_only_cool_down, &meter_provider);
+        let proposal_access = ProposalAccess::default();
+        let lenient_decoding = self
+            .lenient_decoding
+            .then(|| LenientDecoding::new(&meter_provider));
+        let mut schema_builder = self
+            .query_limits
+            .apply(root_schema_builder(self.contract.as_deref()))
+            .extension(deprecation_usage.clone())
+            .data(self.database.clone())
+            .data(VisitLoader::data_loader(self.database.clone()))
+            .data(ScanLoader::data_loader(
+                self.database.clone(),
+                lenient_decoding.clone(),
+            ))
+            .data(proposal_access.clone())
+            .data(self.path_redaction)
+            .data(self.snapshot_variants)
+            .data(ConcurrencyLimiter::new(
+                self.per_client_concurrency,
+                &meter_provider,
+            ))
+            .data(BackfillLimit(self.backfill_limit))
+            .data(SettleInterval(self.settle_interval))
+            .data(LiveSpectrumLimiter::new(self.live_spectra_per_principal))
+            .data(negative_cache.clone())
+            .data(read_only.clone())
+            .data(self.authorization_policy);
+        let file_proxy = FileProxy::new(self.graphql_endpoint.clone(), self.file_proxy_secret);
+        schema_builder = schema_builder.data(file_proxy.clone());
+        if let Some(files) = self.files.clone() {
+            schema_builder = schema_builder.data(files);
+        }
+        if let Some(lenient_decoding) = lenient_decoding {
+            schema_builder = schema_builder
+                .data(lenient_decoding)
+                .extension(SkippedRowsReport);
+        }
+        if self.debug_endpoints {
+            schema_builder = schema_builder.data(DownloadDiagnosticsEnabled);
+        }
+        if self.deterministic_urls {
+            schema_builder = schema_builder.data(DeterministicUrls);
+        }
+        let field_usage = self
+            .field_usage_sample_every
+            .map(|sample_every| FieldUsage::new(sample_every, &meter_provider));
+        if let Some(field_usage) = &field_usage {
+            schema_builder = schema_builder.extension(field_usage.clone());
+        }
+        let schema = schema_builder.finish();
+        if let Some(field_usage) = &field_usage {
+            field_usage.register_schema(&schema.sdl());
+        }
+        let debug_stats = DebugStats::new(
+            negative_cache.clone(),
+            deprecation_usage,
+            self.files.as_ref().map(|files| files.store.clone()),
+        );
+        FluorescenceScanService {
+            schema,
+            database: self.database,
+            files: self.files,
+            file_proxy,
+            started: Arc::new(AtomicBool::new(false)),
+            proposal_access,
+            read_only,
+            negative_cache,
+            debug_stats,
+            debug_endpoints: self.debug_endpoints,
+            field_usage,
+            graphiql_policy: self.graphiql_policy,
+            graphql_endpoint: self.graphql_endpoint,
+            token_verifier: self.token_verifier,
+        }
+    }
+}
+
+/// The fluorescence scan subgraph, with all of its state, as routes which can be served or nested in another application
+#[derive(Clone)]
+pub struct FluorescenceScanService {
+    /// The GraphQL schema, holding the state used by the resolvers
+    schema: RootSchema,
+    /// The connection to the ISPyB database
+    database: DatabaseConnection,
+    /// The store from which scan files are read, if available
+    files: Option<ScanFiles>,
+    /// The signer of URLs served by the file proxy
+    file_proxy: FileProxy,
+    /// Whether startup, including any warm-up, has completed
+    started: Arc<AtomicBool>,
+    /// Whether the proposals of sessions can be read
+    proposal_access: ProposalAccess,
+    /// Whether ISPyB has recently refused writes as read-only
+    read_only: ReadOnlyMode,
+    /// The record of objects recently found to be missing, shared by the resolvers and the file proxy
+    negative_cache: Arc<NegativeCache>,
+    /// Statistics describing the service
+    debug_stats: DebugStats,
+    /// Whether the debug statistics should be served
+    debug_endpoints: bool,
+    /// The counts of field resolutions, if they are counted
+    field_usage: Option<FieldUsage>,
+    /// The security headers applied to the GraphiQL page
+    graphiql_policy: GraphiQLPolicy,
+    /// The path, as seen by the browser, at which GraphQL requests are to be sent
+    graphql_endpoint: String,
+    /// The verifier of bearer tokens, without which no request has claims
+    token_verifier: Option<Arc<TokenVerifier>>,
+}
+
+impl FluorescenceScanService {
+    /// Creates a builder for a service reading from the supplied database
+    pub fn builder(database: DatabaseConnection) -> FluorescenceScanServiceBuilder {
+        FluorescenceScanServiceBuilder {
+            database,
+            files: None,
+            file_proxy_secret: rand::random::<[u8; 32]>().to_vec(),
+            query_limits: QueryLimits::default(),
+            lenient_decoding: false,
+            debug_endpoints: false,
+            deterministic_urls: false,
+            field_usage_sample_every: None,
+            path_redaction: PathRedaction::default(),
+            snapshot_variants: SnapshotVariants::default(),
+            per_client_concurrency: DEFAULT_PER_CLIENT_CONCURRENCY,
+            backfill_limit: DEFAULT_BACKFILL_LIMIT,
+            settle_interval: DEFAULT_SETTLE_INTERVAL,
+            live_spectra_per_principal: DEFAULT_LIVE_SPECTRA_PER_PRINCIPAL,
+            negative_cache_ttl: DEFAULT_NEGATIVE_CACHE_TTL,
+            negative_cache_capacity: DEFAULT_NEGATIVE_CACHE_CAPACITY,
+            read_only_cool_down: DEFAULT_READ_ONLY_COOL_DOWN,
+            graphiql_policy: GraphiQLPolicy::default(),
+            authorization_policy: Arc::new(AllowAll),
+            graphql_endpoint: String::from("/"),
+            contract: None,
+            token_verifier: None,
+        }
+    }
+
+    /// Creates an [`axum::Router`] serving GraphiQL, synchronous GraphQL, GraphQL subscriptions, the capabilities of the service and the file proxy
+    pub fn public_router(&self) -> Router {
+        let router = Router::new()
+            .route(
+                "/",
+                self.graphiql_policy
+                    .route(
+                        GraphiQLSource::build()
+                            .endpoint(&self.graphql_endpoint)
+                            .subscription_endpoint(&format!(
+                                "{}/ws",
+                                self.graphql_endpoint.trim_end_matches('/')
+                            ))
+                            .finish(),
+                        self.token_verifier.clone(),
+                    )
+                    .post(
+                        GraphQLHandler::new(self.schema.clone())
+                            .authenticated_introspection(
+                                self.graphiql_policy.authenticated_introspection(),
+                            )
+                            .token_verifier(self.token_verifier.clone()),
+                    ),
+            )
+            .route(
+                "/ws",
+                get(GraphQLSubscriptionHandler::new(self.schema.clone())
+                    .token_verifier(self.token_verifier.clone())),
+            )
+            .route(
+                "/capabilities",
+                get(CapabilitiesHandler::new(self.read_only.clone())),
+            )
+            .layer(SetResponseHeaderLayer::overriding(
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            ));
+        let router = match &self.files {
+            Some(files) => router.route(
+                &format!("{FILE_PROXY_ROUTE}/*key"),
+                get(FileProxyHandler::new(
+                    files.clone(),
+                    self.file_proxy.clone(),
+                    self.negative_cache.clone(),
+                )),
+            ),
+            None => router,
+        };
+        with_common_layers(router)
+    }
+
+    /// Creates an [`axum::Router`] serving the health and readiness routes, and the debug statistics if enabled
+    pub fn internal_router(&self) -> Router {
+        let mut router = Router::new().route("/healthz", get(health)).route(
+            "/readyz",
+            get(ReadinessHandler::new(
+                self.database.clone(),
+                self.started.clone(),
+            )),
+        );
+        if self.debug_endpoints {
+            router = router.route(
+                "/debug/stats",
+                get(DebugStatsHandler::new(self.debug_stats.clone())),
+            );
+            if let Some(field_usage) = &self.field_usage {
+                router = router.route(
+                    "/debug/field-usage",
+                    get(FieldUsageHandler::new(field_usage.clone())),
+                );
+            }
+        }
+        with_common_layers(router)
+    }
+
+    /// The GraphQL schema of the service, for executing requests without routing them over HTTP
+    pub fn schema(&self) -> &RootSchema {
+        &self.schema
+    }
+
+    /// Creates an [`axum::Router`] serving both the public and internal routes
+    pub fn router(&self) -> Router {
+        self.public_router().merge(self.internal_router())
+    }
+
+    /// Converts the service into a `tower::Service` serving both the public and internal routes
+    pub fn into_service(self) -> RouterIntoService<Body> {
+        self.router().into_service()
+    }
+
+    /// Completes startup, probing whether the proposals of sessions can be read and reporting ready once any warm-up has completed
+    ///
+    /// If the warm-up is strict and fails the service never reports ready. Until the probe succeeds the proposal and visit of sessions are null.
+    pub async fn start(&self, warmup: bool, strict_warmup: bool) {
+        self.proposal_access.probe(&self.database).await;
+        if warmup {
+            let report = warm_up(&self.schema, self.files.as_ref()).await;
+            let succeeded = report.succeeded();
+            self.debug_stats.record_warmup(report);
+            if !succeeded && strict_warmup {
+                return;
+            }
+        }
+        self.started.store(true, Ordering::Release);
+    }
+}
+
+/// Wraps the routes with request identification, error negotiation and telemetry, continuing the trace of the caller before any other processing
+fn with_common_layers(router: Router) -> Router {
+    router
+        .layer(middleware::from_fn(negotiate_error))
+        .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER))
+        .layer(SetRequestIdLayer::new(REQUEST_ID_HEADER, MakeRequestUuid))
+        .layer(middleware::from_fn(continue_trace))
+        .layer(OtelInResponseLayer)
+        .layer(OtelAxumLayer::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FluorescenceScanService;
+    use crate::{
+        fake_database::{model_row, scan, FakeDatabase},
+        object_key::{ObjectKey, ObjectKeyRules},
+        security_headers::{GraphiQLAccess, GraphiQLPolicy},
+        store::testing::{every_store, FakeStore},
+        token_verifier::testing::{forged_token, genuine_token, verifier},
+        ScanFileStore,
+    };
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, HeaderMap, Request, StatusCode},
+        routing::get,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use std::{sync::Arc, time::Duration};
+    use tower::ServiceExt;
+    use url::Url;
+
+    /// Posts the query to the router, with the token if supplied, producing the response body
+    async fn post(router: Router, query: &str, token: Option<String>) -> Value {
+        let mut request = Request::post("/").header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let response = router
+            .oneshot(
+                request
+                    .body(Body::from(json!({ "query": query }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn public_graphiql_restricts_introspection_to_verified_tokens() {
+        let service =
+            FluorescenceScanService::builder(FakeDatabase::with_results([]).connect().await)
+                .graphiql_policy(GraphiQLPolicy::default().access(GraphiQLAccess::Public))
+                .token_verifier(Arc::new(verifier()))
+                .build();
+        let query = "{ __schema { queryType { name } } }";
+        for token in [None, Some(forged_token("abc12345", &[]))] {
+            let response = post(service.public_router(), query, token).await;
+            assert_eq!(response["data"], json!({ "__schema": null }));
+        }
+        let response = post(
+            service.public_router(),
+            query,
+            Some(genuine_token("abc12345", &[])),
+        )
+        .await;
+        assert_eq!(
+            response["data"],
+            json!({ "__schema": { "queryType": { "name": "Query" } } })
+        );
+    }
+
+    /// The sources permitted by the directive of the content security policy
+    fn sources<'a>(content_security_policy: &'a str, directive: &str) -> Vec<&'a str> {
+        content_security_policy
+            .split(';')
+            .map(str::split_whitespace)
+            .find_map(|mut words| (words.next() == Some(directive)).then(|| words.collect()))
+            .unwrap_or_else(|| panic!("{content_security_policy} has no {directive}"))
+    }
+
+    /// The opening tags of every element with the name in the page
+    fn elements<'a>(page: &'a str, name: &str) -> Vec<&'a str> {
+        page.split(&format!("<{name}"))
+            .skip(1)
+            .filter(|element| element.starts_with(|c: char| c.is_whitespace() || c == '>'))
+            .filter_map(|element| element.split_once('>').map(|(tag, _)| tag))
+            .collect()
+    }
+
+    /// The value of the attribute in the opening tag
+    fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+        let value = tag.split_once(&format!("{name}=\""))?.1;
+        value.split_once('"').map(|(value, _)| value)
+    }
+
+    /// The origin of the URL
+    fn origin(url: &str) -> String {
+        Url::parse(url).unwrap().origin().ascii_serialization()
+    }
+
+    /// Loads the GraphiQL page from the full router of the service, as a browser navigating to it would
+    async fn load_graphiql(service: &FluorescenceScanService) -> (StatusCode, HeaderMap, String) {
+        let response = service
+            .router()
+            .oneshot(
+                Request::get("/")
+                    .header(header::ACCEPT, "text/html")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let page = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, headers, String::from_utf8(page.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn graphiql_is_served_with_security_headers_permitting_its_assets() {
+        let service =
+            FluorescenceScanService::builder(FakeDatabase::with_results([]).connect().await)
+                .build();
+        let (status, headers, page) = load_graphiql(&service).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[header::REFERRER_POLICY], "same-origin");
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        let content_security_policy = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert_eq!(sources(content_security_policy, "default-src"), ["'none'"]);
+        assert_eq!(
+            sources(content_security_policy, "frame-ancestors"),
+            ["'none'"]
+        );
+        assert!(!content_security_policy.contains("'unsafe-eval'"));
+        let script_sources = sources(content_security_policy, "script-src");
+        assert!(!script_sources.contains(&"'unsafe-inline'"));
+        let scripts = elements(&page, "script")
+            .into_iter()
+            .filter_map(|script| attribute(script, "src"))
+            .collect::<Vec<_>>();
+        assert_eq!(scripts.len(), 3, "{page}");
+        for script in scripts {
+            assert!(
+                script_sources.contains(&origin(script).as_str()),
+                "{script}"
+            );
+        }
+        let links = elements(&page, "link");
+        assert_eq!(links.len(), 2, "{page}");
+        for link in links {
+            let directive = match attribute(link, "rel") {
+                Some("stylesheet") => "style-src",
+                Some("icon") => "img-src",
+                rel => panic!("Unexpected link {rel:?}"),
+            };
+            let href = attribute(link, "href").unwrap();
+            assert!(
+                sources(content_security_policy, directive).contains(&origin(href).as_str()),
+                "{href}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn graphiql_needs_nothing_the_policy_forbids() {
+        let service =
+            FluorescenceScanService::builder(FakeDatabase::with_results([]).connect().await)
+                .build();
+        let (_, _, page) = load_graphiql(&service).await;
+        // Without 'unsafe-eval' the browser refuses code compiled from strings, and without 'unsafe-inline' it refuses handler attributes, which no hash can permit
+        for forbidden in [
+            "eval(",
+            "new Function",
+            "Function(",
+            "setTimeout(\"",
+            "setTimeout('",
+            "javascript:",
+        ] {
+            assert!(!page.contains(forbidden), "{forbidden} in {page}");
+        }
+        for element in page.split('<').skip(1) {
+            let tag = &element[..element.find('>').unwrap_or(element.len())];
+            assert!(
+                !tag.split_whitespace()
+                    .any(|attribute| attribute.starts_with("on")),
+                "Handler attribute in <{tag}>"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn graphql_responses_are_not_sniffed() {
+        let service =
+            FluorescenceScanService::builder(FakeDatabase::with_results([]).connect().await)
+                .build();
+        let response = service
+            .router()
+            .oneshot(
+                Request::post("/")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(json!({ "query": "{ __typename }" }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+            "nosniff"
+        );
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("application/json"));
+    }
+
+    /// Sends the request to the app, producing the status and body of the response
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, String) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// The ids of the scans of the session served under the path of the app
+    async fn scan_ids(app: &Router, path: &str, session_id: u32) -> Value {
+        let query = json!({
+            "query": "query ($representations: [_Any!]!) { _entities(representations: $representations) { ... on Session { fluorescenceScan { id } } } }",
+            "variables": { "representations": [{ "__typename": "Session", "id": session_id }] },
+        });
+        let (status, body) = send(
+            app,
+            Request::post(path)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(query.to_string()))
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        serde_json::from_str::<Value>(&body).unwrap()["data"]["_entities"][0]["fluorescenceScan"]
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn embedded_services_serve_independently_within_a_host_app() {
+        let first_database = FakeDatabase::new(|_| Ok(vec![model_row(&scan(3, 1, None, None))]));
+        let second_database = FakeDatabase::new(|_| Ok(vec![model_row(&scan(5, 1, None, None))]));
+        let first = FluorescenceScanService::builder(first_database.connect().await)
+            .graphql_endpoint("/first")
+            .build();
+        let second = FluorescenceScanService::builder(second_database.connect().await)
+            .graphql_endpoint("/second")
+            .build();
+        first.start(false, false).await;
+        let app = Router::new()
+            .route("/", get(|| async { "Host application" }))
+            .nest("/first", first.router())
+            .nest_service("/second", second.into_service());
+
+        assert_eq!(
+            send(&app, Request::get("/").body(Body::empty()).unwrap()).await,
+            (StatusCode::OK, String::from("Host application"))
+        );
+        assert_eq!(scan_ids(&app, "/first", 1).await, json!([{ "id": 3 }]));
+        assert_eq!(scan_ids(&app, "/second", 1).await, json!([{ "id": 5 }]));
+        for database in [&first_database, &second_database] {
+            let scan_queries = database
+                .queries()
+                .into_iter()
+                .filter(|query| query.contains("FROM `XFEFluorescenceSpectrum`"))
+                .count();
+            assert_eq!(scan_queries, 1);
+        }
+
+        let (status, page) = send(
+            &app,
+            Request::get("/second")
+                .header(header::ACCEPT, "text/html")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(page.contains("createUrl('/second')"), "{page}");
+        assert!(page.contains("createUrl('/second/ws', true)"), "{page}");
+
+        let ready = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+        assert_eq!(send(&app, ready("/first/readyz")).await.0, StatusCode::OK);
+        assert_eq!(
+            send(&app, ready("/second/readyz")).await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn capabilities_report_degraded_mode_whilst_queries_continue() {
+        let database = FakeDatabase::new(|_| Ok(vec![model_row(&scan(3, 1, None, None))]));
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        let app = service.router();
+        let capabilities = || Request::get("/capabilities").body(Body::empty()).unwrap();
+
+        let (status, body) = send(&app, capabilities()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["readOnly"],
+            json!(false)
+        );
+
+        service
+            .read_only
+            .refused(Some(1290), sea_orm::DbErr::Custom("refused".into()));
+        let (_, body) = send(&app, capabilities()).await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&body).unwrap()["readOnly"],
+            json!(true)
+        );
+        assert_eq!(scan_ids(&app, "/", 1).await, json!([{ "id": 3 }]));
+    }
+
+    /// Serves the store through the file proxy of a service, producing the service and a request for each key under a signed URL
+    async fn proxied(
+        store: Arc<dyn ScanFileStore>,
+    ) -> (FluorescenceScanService, impl Fn(&str) -> Request<Body>) {
+        let service =
+            FluorescenceScanService::builder(FakeDatabase::with_results([]).connect().await)
+                .scan_file_store(store, ObjectKeyRules::default())
+                .build();
+        let proxy = service.file_proxy.clone();
+        let fetch = move |key: &str| {
+            Request::get(proxy.url(key, Duration::from_secs(60)))
+                .body(Body::empty())
+                .unwrap()
+        };
+        (service, fetch)
+    }
+
+    #[tokio::test]
+    async fn file_proxy_answers_missing_objects_from_the_negative_cache() {
+        let (_directory, stores) = every_store([("i18/scan.jpg", &b"jpeg"[..])]);
+        for store in stores {
+            let location = store.location();
+            let (service, fetch) = proxied(store).await;
+            let app = service.router();
+
+            assert_eq!(
+                send(&app, fetch("i18/scan.jpg")).await,
+                (StatusCode::OK, String::from("jpeg")),
+                "{location}"
+            );
+            for _ in 0..2 {
+                assert_eq!(
+                    send(&app, fetch("i18/missing.jpg")).await.0,
+                    StatusCode::NOT_FOUND,
+                    "{location}"
+                );
+            }
+            assert_eq!(
+                service.negative_cache.to_json()["hits"],
+                json!(1),
+                "{location}"
+            );
+            assert_eq!(
+                service.negative_cache.to_json()["entries"],
+                json!(1),
+                "{location}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn file_proxy_records_failed_reads() {
+        let store = FakeStore::new([("i18/scan.jpg", &b"jpeg"[..])]);
+        let (service, fetch) = proxied(store.clone()).await;
+        store.fail(Some("connection reset"));
+        assert_eq!(
+            send(&service.router(), fetch("i18/scan.jpg")).await.0,
+            StatusCode::BAD_GATEWAY
+        );
+        let key = ObjectKey::signed(String::from("i18/scan.jpg"));
+        assert_eq!(
+            service.negative_cache.last_error(&key).unwrap().0,
+            "connection reset"
+        );
+        assert_eq!(service.negative_cache.remaining(&key), None);
+    }
+}
@@ -0,0 +1,218 @@
+use aws_sdk_s3::error::SdkError;
+use opentelemetry::metrics::{Counter, MeterProvider};
+use serde_json::{json, Value};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::built_info;
+
+/// The cool-down after which the primary endpoint is probed, unless configured otherwise
+pub const DEFAULT_FALLBACK_COOL_DOWN: Duration = Duration::from_secs(30);
+
+/// An endpoint to which operations may be routed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    /// The endpoint used whilst it is reachable
+    Primary,
+    /// The endpoint used whilst the primary is unreachable
+    Fallback,
+}
+
+/// Whether the error was a failure to reach the endpoint, rather than a response from it
+pub fn is_connection_failure<E, R>(err: &SdkError<E, R>) -> bool {
+    matches!(
+        err,
+        SdkError::DispatchFailure(_) | SdkError::TimeoutError(_)
+    )
+}
+
+/// A circuit breaker routing operations to the fallback endpoint for a cool-down period once the primary cannot be reached
+///
+/// Once the cool-down has elapsed a single probe of the primary is made, whilst other operations continue to use the fallback, and operations return to the primary only if it succeeds.
+#[derive(Debug)]
+pub struct Breaker {
+    /// The period for which operations are routed to the fallback before the primary is probed
+    cool_down: Duration,
+    /// When the primary was last found to be unreachable, if operations are routed to the fallback
+    tripped: Arc<Mutex<Option<Instant>>>,
+    /// Whether a probe of the primary is in progress
+    probing: AtomicBool,
+    /// The count of switches to the fallback
+    failovers: Counter<u64>,
+}
+
+impl Breaker {
+    /// Creates a closed breaker, recording metrics using the supplied meter provider
+    pub fn new(cool_down: Duration, meter_provider: &impl MeterProvider) -> Self {
+        let meter = meter_provider.meter(built_info::PKG_NAME);
+        let tripped = Arc::<Mutex<Option<Instant>>>::default();
+        meter
+            .u64_observable_gauge("s3.fallback_active")
+            .with_description("Whether S3 operations are routed to the fallback endpoint")
+            .with_callback({
+                let tripped = tripped.clone();
+                move |observer| observer.observe(tripped.lock().unwrap().is_some().into(), &[])
+            })
+            .init();
+        Self {
+            cool_down,
+            tripped,
+            probing: AtomicBool::new(false),
+            failovers: meter
+                .u64_counter("s3.failovers")
+                .with_description("Switches of S3 operations to the fallback endpoint")
+                .init(),
+        }
+    }
+
+    /// The endpoint to which operations are currently routed
+    pub fn endpoint(&self) -> Endpoint {
+        match *self.tripped.lock().unwrap() {
+            Some(_) => Endpoint::Fallback,
+            None => Endpoint::Primary,
+        }
+    }
+
+    /// Claims the probe of the primary, if the cool-down has elapsed and no other probe is in progress
+    ///
+    /// The claim is released when the probe is dropped, so that a probe abandoned before completing, such as by its request being cancelled, does not prevent further probes.
+    pub fn begin_probe(&self) -> Option<Probe<'_>> {
+        let due = matches!(*self.tripped.lock().unwrap(), Some(tripped) if tripped.elapsed() >= self.cool_down);
+        (due && self
+            .probing
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok())
+        .then_some(Probe { breaker: self })
+    }
+
+    /// Records the outcome of an operation against an endpoint, routing to the fallback if the primary could not be reached
+    pub fn record<T, E, R>(&self, endpoint: Endpoint, result: &Result<T, SdkError<E, R>>) {
+        if endpoint != Endpoint::Primary {
+            return;
+        }
+        if matches!(result, Err(err) if is_connection_failure(err)) {
+            let mut tripped = self.tripped.lock().unwrap();
+            if tripped.is_none() {
+                self.failovers.add(1, &[]);
+            }
+            *tripped = Some(Instant::now());
+        }
+    }
+
+    /// Renders the state of the breaker as JSON
+    pub fn to_json(&self) -> Value {
+        let tripped = *self.tripped.lock().unwrap();
+        json!({
+            "activeEndpoint": match tripped {
+                Some(_) => "fallback",
+                None => "primary",
+            },
+            "secondsUntilProbe": tripped.map(|tripped| self.cool_down.saturating_sub(tripped.elapsed()).as_secs()),
+        })
+    }
+}
+
+/// The claim of a breaker's single probe of the primary, released when dropped
+#[derive(Debug)]
+pub struct Probe<'a> {
+    /// The breaker whose probe is claimed
+    breaker: &'a Breaker,
+}
+
+impl Probe<'_> {
+    /// Completes the probe of the primary, returning operations to it if it could be reached and restarting the cool-down otherwise
+    pub fn end(self, reachable: bool) {
+        *self.breaker.tripped.lock().unwrap() = (!reachable).then(Instant::now);
+    }
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        self.breaker.probing.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breaker, Endpoint};
+    use aws_sdk_s3::error::SdkError;
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+
+    /// A breaker with the cool-down which was tripped the supplied time ago
+    fn tripped(cool_down: Duration, ago: Duration) -> Breaker {
+        let breaker = Breaker::new(cool_down, &NoopMeterProvider::new());
+        *breaker.tripped.lock().unwrap() = Some(Instant::now() - ago);
+        breaker
+    }
+
+    /// The outcome of an operation which could not reach its endpoint
+    fn unreachable() -> Result<(), SdkError<(), ()>> {
+        Err(SdkError::timeout_error(io::Error::from(
+            io::ErrorKind::TimedOut,
+        )))
+    }
+
+    #[test]
+    fn connection_failures_of_the_primary_trip_the_breaker() {
+        let breaker = Breaker::new(Duration::from_secs(30), &NoopMeterProvider::new());
+        assert_eq!(breaker.endpoint(), Endpoint::Primary);
+        breaker.record(Endpoint::Fallback, &unreachable());
+        assert_eq!(breaker.endpoint(), Endpoint::Primary);
+        breaker.record(Endpoint::Primary, &Ok::<_, SdkError<(), ()>>(()));
+        assert_eq!(breaker.endpoint(), Endpoint::Primary);
+        breaker.record(Endpoint::Primary, &unreachable());
+        assert_eq!(breaker.endpoint(), Endpoint::Fallback);
+    }
+
+    #[test]
+    fn the_primary_is_not_probed_during_the_cool_down() {
+        let breaker = Breaker::new(Duration::from_secs(30), &NoopMeterProvider::new());
+        assert!(breaker.begin_probe().is_none());
+        let breaker = tripped(Duration::from_secs(30), Duration::from_secs(10));
+        assert!(breaker.begin_probe().is_none());
+        assert_eq!(breaker.endpoint(), Endpoint::Fallback);
+    }
+
+    #[test]
+    fn a_single_probe_is_made_once_the_cool_down_has_elapsed() {
+        let breaker = tripped(Duration::from_secs(30), Duration::from_secs(31));
+        let probe = breaker.begin_probe().expect("probe is due");
+        assert!(breaker.begin_probe().is_none());
+        assert_eq!(breaker.endpoint(), Endpoint::Fallback);
+        drop(probe);
+    }
+
+    #[test]
+    fn a_failed_probe_trips_the_breaker_again() {
+        let breaker = tripped(Duration::from_secs(30), Duration::from_secs(31));
+        breaker.begin_probe().unwrap().end(false);
+        assert_eq!(breaker.endpoint(), Endpoint::Fallback);
+        assert!(breaker.begin_probe().is_none());
+        assert!(breaker.to_json()["secondsUntilProbe"].as_u64().unwrap() >= 29);
+    }
+
+    #[test]
+    fn a_successful_probe_returns_operations_to_the_primary() {
+        let breaker = tripped(Duration::from_secs(30), Duration::from_secs(31));
+        breaker.begin_probe().unwrap().end(true);
+        assert_eq!(breaker.endpoint(), Endpoint::Primary);
+        assert_eq!(breaker.to_json()["activeEndpoint"], "primary");
+    }
+
+    #[test]
+    fn an_abandoned_probe_releases_its_claim() {
+        let breaker = tripped(Duration::from_secs(30), Duration::from_secs(31));
+        drop(breaker.begin_probe().unwrap());
+        assert_eq!(breaker.endpoint(), Endpoint::Fallback);
+        assert!(breaker.begin_probe().is_some());
+    }
+}
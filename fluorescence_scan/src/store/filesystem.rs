@@ -0,0 +1,254 @@
+use async_graphql::async_trait::async_trait;
+use axum::body::Body;
+use std::{
+    io::{self, ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{ObjectInfo, ScanFileStore, StoreError};
+
+/// A store reading files from beneath a root directory, such as a network mount, which are served through the file proxy
+///
+/// Object keys are mapped to paths beneath the root segment by segment, ignoring empty segments. Keys which would resolve outside of the root, including through symbolic links, are treated as missing.
+#[derive(Debug, Clone)]
+pub struct FilesystemStore {
+    /// The canonical path of the directory beneath which files are read
+    root: PathBuf,
+}
+
+impl FilesystemStore {
+    /// Creates a store reading from beneath the root directory
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            root: std::fs::canonicalize(root)?,
+        })
+    }
+
+    /// Maps the key to a path beneath the root, if the key has a valid form
+    fn candidate(&self, key: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for segment in key.split('/').filter(|segment| !segment.is_empty()) {
+            if matches!(segment, "." | "..") || segment.contains(['\\', '\0']) {
+                return None;
+            }
+            path.push(segment);
+        }
+        (path != self.root).then_some(path)
+    }
+
+    /// Resolves the key to the canonical path of an existing file beneath the root
+    async fn resolve(&self, key: &str) -> io::Result<Option<PathBuf>> {
+        let Some(candidate) = self.candidate(key) else {
+            return Ok(None);
+        };
+        match fs::canonicalize(candidate).await {
+            Ok(path) if path.starts_with(&self.root) => Ok(Some(path)),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl ScanFileStore for FilesystemStore {
+    fn location(&self) -> String {
+        format!("file://{}", self.root.display())
+    }
+
+    async fn presigned_url(
+        &self,
+        _key: &str,
+        _expiry: Duration,
+    ) -> Result<Option<String>, StoreError> {
+        Ok(None)
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+        let Some(path) = self.resolve(key).await? else {
+            return Ok(None);
+        };
+        let metadata = fs::metadata(path).await?;
+        Ok(metadata.is_file().then_some(ObjectInfo {
+            size: metadata.len(),
+        }))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Body>, StoreError> {
+        let Some(path) = self.resolve(key).await? else {
+            return Ok(None);
+        };
+        if !fs::metadata(&path).await?.is_file() {
+            return Ok(None);
+        }
+        let file = File::open(path).await?;
+        Ok(Some(Body::from_stream(ReaderStream::new(file))))
+    }
+
+    async fn get_from(&self, key: &str, offset: u64) -> Result<Option<Vec<u8>>, StoreError> {
+        let Some(path) = self.resolve(key).await? else {
+            return Ok(None);
+        };
+        if !fs::metadata(&path).await?.is_file() {
+            return Ok(None);
+        }
+        let mut file = File::open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await?;
+        Ok(Some(contents))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        let directory_key = &prefix[..prefix.rfind('/').map_or(0, |separator| separator + 1)];
+        let directory = if directory_key.split('/').all(str::is_empty) {
+            Some(self.root.clone())
+        } else {
+            self.resolve(directory_key).await?
+        };
+        let Some(directory) = directory else {
+            return Ok(Vec::new());
+        };
+        let mut entries = match fs::read_dir(directory).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let (Ok(file_type), Some(name)) = (
+                entry.file_type().await,
+                entry.file_name().to_str().map(String::from),
+            ) else {
+                continue;
+            };
+            if !file_type.is_file() {
+                continue;
+            }
+            let key = format!("{directory_key}{name}");
+            if key.starts_with(prefix) {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn check(&self) -> Result<(), StoreError> {
+        if fs::metadata(&self.root).await?.is_dir() {
+            Ok(())
+        } else {
+            Err(format!("{} is not a directory", self.root.display()).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilesystemStore;
+    use crate::{store::testing::TempDir, ScanFileStore};
+
+    /// A directory holding a store root with scan files, and a secret file outside of the root
+    fn layout() -> (TempDir, FilesystemStore) {
+        let directory = TempDir::new();
+        directory.write("root/i18/data/scan.dat", b"1 10\n");
+        directory.write("root/i18/data/a scan ß.dat", b"2 20\n");
+        directory.write("secret.txt", b"secret");
+        let store = FilesystemStore::new(directory.0.join("root")).unwrap();
+        (directory, store)
+    }
+
+    #[tokio::test]
+    async fn keys_are_read_beneath_the_root() {
+        let (_directory, store) = layout();
+        assert_eq!(
+            store.get_from("i18/data/scan.dat", 0).await.unwrap(),
+            Some(b"1 10\n".to_vec())
+        );
+        assert_eq!(
+            store.get_from("/i18//data/scan.dat", 2).await.unwrap(),
+            Some(b"10\n".to_vec())
+        );
+        assert_eq!(
+            store
+                .head("i18/data/a scan ß.dat")
+                .await
+                .unwrap()
+                .unwrap()
+                .size,
+            5
+        );
+        assert!(store.head("i18/data/missing.dat").await.unwrap().is_none());
+        assert!(store.head("i18/data").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn traversal_is_treated_as_missing() {
+        let (_directory, store) = layout();
+        for key in [
+            "../secret.txt",
+            "i18/../../secret.txt",
+            "i18/./data/scan.dat",
+            "i18\\..\\..\\secret.txt",
+            "i18/data/scan.dat\0",
+            "",
+            "/",
+        ] {
+            assert!(store.head(key).await.unwrap().is_none(), "{key:?}");
+            assert!(store.get_from(key, 0).await.unwrap().is_none(), "{key:?}");
+        }
+        assert!(store.list("../").await.unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symbolic_links_out_of_the_root_are_treated_as_missing() {
+        let (directory, store) = layout();
+        let root = directory.0.join("root");
+        std::os::unix::fs::symlink(directory.0.join("secret.txt"), root.join("leak.txt")).unwrap();
+        std::os::unix::fs::symlink(&directory.0, root.join("parent")).unwrap();
+        std::os::unix::fs::symlink(root.join("i18/data/scan.dat"), root.join("inside.dat"))
+            .unwrap();
+        assert!(store.head("leak.txt").await.unwrap().is_none());
+        assert!(store
+            .get_from("parent/secret.txt", 0)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store.list("parent/").await.unwrap().is_empty());
+        assert_eq!(
+            store.get_from("inside.dat", 0).await.unwrap(),
+            Some(b"1 10\n".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn listing_produces_keys_of_files_with_the_prefix() {
+        let (_directory, store) = layout();
+        assert_eq!(
+            store.list("i18/data/a").await.unwrap(),
+            ["i18/data/a scan ß.dat"]
+        );
+        assert_eq!(
+            store.list("i18/data/").await.unwrap(),
+            ["i18/data/a scan ß.dat", "i18/data/scan.dat"]
+        );
+        assert!(store.list("i18/").await.unwrap().is_empty());
+        assert!(store.list("i18/missing/").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn root_must_be_a_directory() {
+        let (directory, store) = layout();
+        store.check().await.unwrap();
+        assert!(FilesystemStore::new(directory.0.join("missing")).is_err());
+        let file = FilesystemStore::new(directory.0.join("secret.txt")).unwrap();
+        assert!(file.check().await.is_err());
+    }
+}
@@ -0,0 +1,237 @@
+/// Routing of S3 operations to a fallback endpoint whilst the primary is unreachable
+mod failover;
+/// Storage of scan files on a local or network filesystem
+mod filesystem;
+/// Storage of scan files in an S3 bucket
+mod s3;
+
+use async_graphql::async_trait::async_trait;
+use axum::body::Body;
+use serde_json::Value;
+use std::{error::Error, fmt::Debug, sync::Arc, time::Duration};
+
+pub use failover::DEFAULT_FALLBACK_COOL_DOWN;
+pub use filesystem::FilesystemStore;
+pub use s3::S3Store;
+
+use crate::object_key::ObjectKeyRules;
+
+/// An error produced by a [`ScanFileStore`]
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+/// Metadata describing a stored object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// The size of the object in bytes
+    pub size: u64,
+}
+
+/// A store from which scan files are read, addressed by object key
+#[async_trait]
+pub trait ScanFileStore: Debug + Send + Sync {
+    /// A description of where objects are stored, for diagnostics
+    fn location(&self) -> String;
+
+    /// A URL granting temporary read access to the object, or [`None`] if the store cannot presign and the object must be served through the file proxy
+    async fn presigned_url(
+        &self,
+        key: &str,
+        expiry: Duration,
+    ) -> Result<Option<String>, StoreError>;
+
+    /// Metadata describing the object, or [`None`] if it does not exist
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError>;
+
+    /// A stream of the contents of the object, or [`None`] if it does not exist
+    async fn get(&self, key: &str) -> Result<Option<Body>, StoreError>;
+
+    /// The contents of the object from the byte offset to its end, which are empty if the offset is beyond the end, or [`None`] if it does not exist
+    async fn get_from(&self, key: &str, offset: u64) -> Result<Option<Vec<u8>>, StoreError>;
+
+    /// The keys of the objects directly within the directory denoted by the prefix which begin with it
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError>;
+
+    /// Checks that the store can be reached
+    async fn check(&self) -> Result<(), StoreError>;
+
+    /// A description of the state of the store, for the debug statistics, if it has any
+    fn status(&self) -> Option<Value> {
+        None
+    }
+}
+
+/// The store from which scan files are read and the rules with which their keys are derived
+#[derive(Debug, Clone)]
+pub struct ScanFiles {
+    /// The store from which scan files are read
+    pub store: Arc<dyn ScanFileStore>,
+    /// The rules used to derive object keys from recorded paths
+    pub key_rules: ObjectKeyRules,
+}
+
+/// Stores holding objects in memory and in temporary directories for tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{FilesystemStore, ObjectInfo, ScanFileStore, StoreError};
+    use async_graphql::async_trait::async_trait;
+    use axum::body::Body;
+    use std::{
+        collections::BTreeMap,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    /// A directory beneath the system temporary directory, removed when dropped
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        /// Creates an empty directory distinct from that of every other test
+        pub fn new() -> Self {
+            /// The number of directories created by this process
+            static CREATED: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "fluorescence-scan-{}-{}",
+                std::process::id(),
+                CREATED.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// Writes the file at the path relative to the directory, creating its parents
+        pub fn write(&self, path: &str, contents: &[u8]) {
+            let path = self.0.join(path.trim_start_matches('/'));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Each kind of store holding the objects, against which tests of store independent behaviour are run: one in memory which presigns, and one in a temporary directory, kept until dropped, which is served through the file proxy
+    pub fn every_store<'a>(
+        objects: impl IntoIterator<Item = (&'a str, &'a [u8])> + Clone,
+    ) -> (TempDir, [Arc<dyn ScanFileStore>; 2]) {
+        let directory = TempDir::new();
+        for (key, contents) in objects.clone() {
+            directory.write(key, contents);
+        }
+        let filesystem = FilesystemStore::new(&directory.0).unwrap();
+        (directory, [FakeStore::new(objects), Arc::new(filesystem)])
+    }
+
+    /// A store holding the supplied objects, counting the requests for their metadata
+    #[derive(Debug, Default)]
+    pub struct FakeStore {
+        /// The contents of each object, by key
+        objects: Mutex<BTreeMap<String, Vec<u8>>>,
+        /// The message of the error with which every operation fails, if the store is failing
+        failure: Mutex<Option<String>>,
+        /// The number of requests for object metadata received
+        heads: AtomicUsize,
+    }
+
+    impl FakeStore {
+        /// Creates a store holding the objects
+        pub fn new<'a>(objects: impl IntoIterator<Item = (&'a str, &'a [u8])>) -> Arc<Self> {
+            Arc::new(Self {
+                objects: Mutex::new(
+                    objects
+                        .into_iter()
+                        .map(|(key, contents)| (key.to_string(), contents.to_vec()))
+                        .collect(),
+                ),
+                failure: Mutex::default(),
+                heads: AtomicUsize::new(0),
+            })
+        }
+
+        /// Replaces the contents of the object, creating it if it does not exist
+        pub fn put(&self, key: &str, contents: &[u8]) {
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), contents.to_vec());
+        }
+
+        /// Fails every later operation with the message, or if there is none succeeds again
+        pub fn fail(&self, message: Option<&str>) {
+            *self.failure.lock().unwrap() = message.map(String::from);
+        }
+
+        /// The number of requests for object metadata received so far
+        pub fn heads(&self) -> usize {
+            self.heads.load(Ordering::SeqCst)
+        }
+
+        /// The contents of the object, or [`None`] if it does not exist, unless the store is failing
+        fn contents(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+            if let Some(message) = &*self.failure.lock().unwrap() {
+                return Err(message.clone().into());
+            }
+            Ok(self.objects.lock().unwrap().get(key).cloned())
+        }
+    }
+
+    #[async_trait]
+    impl ScanFileStore for FakeStore {
+        fn location(&self) -> String {
+            String::from("memory://fake")
+        }
+
+        async fn presigned_url(
+            &self,
+            key: &str,
+            _expiry: Duration,
+        ) -> Result<Option<String>, StoreError> {
+            Ok(Some(format!("https://fake.invalid/{key}")))
+        }
+
+        async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+            self.heads.fetch_add(1, Ordering::SeqCst);
+            Ok(self.contents(key)?.map(|contents| ObjectInfo {
+                size: contents.len() as u64,
+            }))
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Body>, StoreError> {
+            Ok(self.contents(key)?.map(Body::from))
+        }
+
+        async fn get_from(&self, key: &str, offset: u64) -> Result<Option<Vec<u8>>, StoreError> {
+            Ok(self
+                .contents(key)?
+                .map(|contents| contents.get(offset as usize..).unwrap_or_default().to_vec()))
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+            self.contents(prefix)?;
+            let directory = prefix
+                .rsplit_once('/')
+                .map_or("", |(directory, _)| directory);
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| {
+                    key.starts_with(prefix)
+                        && key.rsplit_once('/').map_or("", |(directory, _)| directory) == directory
+                })
+                .cloned()
+                .collect())
+        }
+
+        async fn check(&self) -> Result<(), StoreError> {
+            self.contents("").map(|_| ())
+        }
+    }
+}
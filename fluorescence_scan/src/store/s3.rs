@@ -0,0 +1,314 @@
+use async_graphql::async_trait::async_trait;
+use aws_sdk_s3::{
+    error::{ProvideErrorMetadata, SdkError},
+    presigning::PresigningConfig,
+    Client,
+};
+use axum::body::Body;
+use opentelemetry::metrics::MeterProvider;
+use serde_json::Value;
+use std::{future::Future, sync::Arc, time::Duration};
+
+use super::{
+    failover::{is_connection_failure, Breaker, Endpoint},
+    ObjectInfo, ScanFileStore, StoreError,
+};
+use crate::S3Bucket;
+
+/// A store reading objects from an S3 bucket, granting access by presigned URL
+#[derive(Debug, Clone)]
+pub struct S3Store {
+    /// The client with which the bucket is accessed
+    client: Client,
+    /// The client of the fallback endpoint and the breaker routing operations to it, if a fallback is configured
+    fallback: Option<(Client, Arc<Breaker>)>,
+    /// The bucket in which objects are stored
+    bucket: S3Bucket,
+}
+
+impl S3Store {
+    /// Creates a store reading from the bucket with the supplied client
+    pub fn new(client: Client, bucket: S3Bucket) -> Self {
+        Self {
+            client,
+            fallback: None,
+            bucket,
+        }
+    }
+
+    /// Routes operations to the endpoint of the fallback client for the cool-down whenever the primary cannot be reached, recording metrics using the supplied meter provider
+    pub fn fallback(
+        mut self,
+        client: Client,
+        cool_down: Duration,
+        meter_provider: &impl MeterProvider,
+    ) -> Self {
+        self.fallback = Some((client, Arc::new(Breaker::new(cool_down, meter_provider))));
+        self
+    }
+
+    /// The endpoint to which the next operation should be routed, and its client
+    ///
+    /// If the cool-down has elapsed the primary is probed first, unless another operation is already probing it. Should this operation be cancelled mid-probe, the probe is abandoned and the next operation probes again.
+    async fn route(&self) -> (Endpoint, &Client) {
+        let Some((fallback, breaker)) = &self.fallback else {
+            return (Endpoint::Primary, &self.client);
+        };
+        if let Some(probe) = breaker.begin_probe() {
+            let result = self
+                .client
+                .head_bucket()
+                .bucket(self.bucket.clone())
+                .send()
+                .await;
+            probe.end(!matches!(&result, Err(err) if is_connection_failure(err)));
+        }
+        match breaker.endpoint() {
+            Endpoint::Primary => (Endpoint::Primary, &self.client),
+            Endpoint::Fallback => (Endpoint::Fallback, fallback),
+        }
+    }
+
+    /// Records the outcome of an operation routed to the endpoint
+    fn record<T, E, R>(&self, endpoint: Endpoint, result: &Result<T, SdkError<E, R>>) {
+        if let Some((_, breaker)) = &self.fallback {
+            breaker.record(endpoint, result);
+        }
+    }
+
+    /// Sends the operation to the endpoint to which operations are routed, retrying it once against the fallback if the primary could not be reached, so that the operation which trips the breaker does not itself fail
+    async fn send<T, E, R, F>(&self, operation: impl Fn(&Client) -> F) -> Result<T, SdkError<E, R>>
+    where
+        F: Future<Output = Result<T, SdkError<E, R>>>,
+    {
+        let (endpoint, client) = self.route().await;
+        let result = operation(client).await;
+        self.record(endpoint, &result);
+        match &self.fallback {
+            Some((fallback, _))
+                if endpoint == Endpoint::Primary
+                    && matches!(&result, Err(err) if is_connection_failure(err)) =>
+            {
+                operation(fallback).await
+            }
+            _ => result,
+        }
+    }
+}
+
+#[async_trait]
+impl ScanFileStore for S3Store {
+    fn location(&self) -> String {
+        format!("s3://{}", *self.bucket)
+    }
+
+    async fn presigned_url(
+        &self,
+        key: &str,
+        expiry: Duration,
+    ) -> Result<Option<String>, StoreError> {
+        // Presigned against the active endpoint, so that clients are not sent to one which is unreachable
+        let (_, client) = self.route().await;
+        let request = client
+            .get_object()
+            .bucket(self.bucket.clone())
+            .key(key)
+            .presigned(PresigningConfig::expires_in(expiry)?)
+            .await?;
+        Ok(Some(request.uri().to_string()))
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<ObjectInfo>, StoreError> {
+        let result = self
+            .send(|client| {
+                client
+                    .head_object()
+                    .bucket(self.bucket.clone())
+                    .key(key)
+                    .send()
+            })
+            .await;
+        match result {
+            Ok(head) => Ok(Some(ObjectInfo {
+                size: head.content_length().try_into().unwrap_or_default(),
+            })),
+            Err(SdkError::ServiceError(err)) if err.err().is_not_found() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Body>, StoreError> {
+        let result = self
+            .send(|client| {
+                client
+                    .get_object()
+                    .bucket(self.bucket.clone())
+                    .key(key)
+                    .send()
+            })
+            .await;
+        match result {
+            Ok(object) => Ok(Some(Body::from_stream(object.body))),
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn get_from(&self, key: &str, offset: u64) -> Result<Option<Vec<u8>>, StoreError> {
+        let result = self
+            .send(|client| {
+                client
+                    .get_object()
+                    .bucket(self.bucket.clone())
+                    .key(key)
+                    .range(format!("bytes={offset}-"))
+                    .send()
+            })
+            .await;
+        match result {
+            Ok(object) => Ok(Some(object.body.collect().await?.into_bytes().to_vec())),
+            Err(SdkError::ServiceError(err)) if err.err().is_no_such_key() => Ok(None),
+            // Produced when the offset is at or beyond the end of the object
+            Err(SdkError::ServiceError(err)) if err.err().code() == Some("InvalidRange") => {
+                Ok(Some(Vec::new()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, StoreError> {
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let page = self
+                .send(|client| {
+                    client
+                        .list_objects_v2()
+                        .bucket(self.bucket.clone())
+                        .prefix(prefix)
+                        .delimiter("/")
+                        .set_continuation_token(continuation_token.clone())
+                        .send()
+                })
+                .await?;
+            keys.extend(
+                page.contents()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|object| object.key().map(String::from)),
+            );
+            match page.next_continuation_token() {
+                Some(token) if page.is_truncated() => continuation_token = Some(token.to_string()),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    async fn check(&self) -> Result<(), StoreError> {
+        self.send(|client| client.head_bucket().bucket(self.bucket.clone()).send())
+            .await?;
+        Ok(())
+    }
+
+    fn status(&self) -> Option<Value> {
+        self.fallback.as_ref().map(|(_, breaker)| breaker.to_json())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::S3Store;
+    use crate::{S3Bucket, ScanFileStore};
+    use aws_sdk_s3::{
+        config::{retry::RetryConfig, Credentials, Region},
+        Client,
+    };
+    use axum::{http::StatusCode, Router};
+    use opentelemetry::metrics::noop::NoopMeterProvider;
+    use std::{
+        net::{Ipv4Addr, SocketAddr, TcpListener},
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// A client of the endpoint which makes no attempt beyond the first
+    fn client(endpoint: SocketAddr) -> Client {
+        Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .region(Region::new("undefined"))
+                .credentials_provider(Credentials::new("id", "secret", None, None, "test"))
+                .endpoint_url(format!("http://{endpoint}"))
+                .force_path_style(true)
+                .retry_config(RetryConfig::disabled())
+                .build(),
+        )
+    }
+
+    /// Serves every request with the status, returning the address served and the count of requests received
+    async fn endpoint(status: StatusCode) -> (SocketAddr, Arc<AtomicUsize>) {
+        let requests = Arc::<AtomicUsize>::default();
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().fallback({
+            let requests = requests.clone();
+            move || async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                status
+            }
+        });
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        (address, requests)
+    }
+
+    /// An address at which nothing is listening
+    fn unreachable() -> SocketAddr {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// A store of the primary endpoint with a fallback endpoint
+    fn store(primary: SocketAddr, fallback: SocketAddr) -> S3Store {
+        S3Store::new(client(primary), S3Bucket::from_str("scans").unwrap()).fallback(
+            client(fallback),
+            Duration::from_secs(60),
+            &NoopMeterProvider::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn unreachable_primary_is_retried_against_the_fallback() {
+        let (fallback, requests) = endpoint(StatusCode::OK).await;
+        let store = store(unreachable(), fallback);
+        store.check().await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(store.status().unwrap()["activeEndpoint"], "fallback");
+        assert!(store.head("scan.dat").await.unwrap().is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn responses_of_the_primary_are_not_retried() {
+        let (primary, primary_requests) = endpoint(StatusCode::NOT_FOUND).await;
+        let (fallback, fallback_requests) = endpoint(StatusCode::OK).await;
+        let store = store(primary, fallback);
+        assert!(store.head("scan.dat").await.unwrap().is_none());
+        assert!(store.check().await.is_err());
+        assert_eq!(primary_requests.load(Ordering::SeqCst), 2);
+        assert_eq!(fallback_requests.load(Ordering::SeqCst), 0);
+        assert_eq!(store.status().unwrap()["activeEndpoint"], "primary");
+    }
+
+    #[tokio::test]
+    async fn unreachable_fallback_fails_the_operation() {
+        let store = store(unreachable(), unreachable());
+        assert!(store.check().await.is_err());
+    }
+}
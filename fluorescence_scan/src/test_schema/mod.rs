@@ -0,0 +1,261 @@
+use derive_more::{Display, Error, From};
+use models::{bl_session, person, proposal, session_has_person, xfe_fluorescence_spectrum};
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, IdenStatic, Iterable, Statement,
+};
+
+/// The version of the embedded test schema, incremented whenever its tables or columns change
+pub const TEST_SCHEMA_VERSION: u32 = 1;
+
+/// The DDL of the tables and columns of ISPyB read by the service, for creating ephemeral test and development databases
+pub const TEST_SCHEMA_DDL: &str = include_str!("v1.sql");
+
+/// An error produced when checking or applying the test schema
+#[derive(Debug, Display, Error, From)]
+pub enum TestSchemaError {
+    /// The embedded DDL and the generated models do not declare the same tables and columns
+    #[display(fmt = "Test schema disagrees with the models: {}", "_0.join(\"; \")")]
+    #[from(ignore)]
+    Disagreement(#[error(not(source))] Vec<String>),
+    /// The database holds tables outside of the test schema, so may be a production ISPyB
+    #[display(
+        fmt = "Database holds tables outside of the test schema, so may be a production ISPyB: {}",
+        "_0.join(\", \")"
+    )]
+    #[from(ignore)]
+    ForeignTables(#[error(not(source))] Vec<String>),
+    /// The database already holds tables of the test schema, which were not to be replaced
+    #[display(
+        fmt = "Database already holds tables of the test schema: {}",
+        "_0.join(\", \")"
+    )]
+    #[from(ignore)]
+    NotEmpty(#[error(not(source))] Vec<String>),
+    /// The database could not be read or altered
+    #[display(fmt = "{}", _0)]
+    Database(DbErr),
+}
+
+/// The statements of the embedded DDL, without comments
+fn statements() -> impl Iterator<Item = String> {
+    TEST_SCHEMA_DDL
+        .split(';')
+        .map(|statement| {
+            statement
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("--"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .filter(|statement| !statement.trim().is_empty())
+}
+
+/// The name of each table declared by the embedded DDL, in the order they are created, with the names of its columns
+fn declared_tables() -> Vec<(String, Vec<String>)> {
+    statements()
+        .filter_map(|statement| {
+            let (header, body) = statement.split_once('(')?;
+            let table = header.trim().strip_prefix("CREATE TABLE")?.trim();
+            let columns = body
+                .lines()
+                .filter_map(|line| line.trim().strip_prefix('`')?.split_once('`'))
+                .map(|(column, _)| column.to_string())
+                .collect();
+            Some((table.trim_matches('`').to_string(), columns))
+        })
+        .collect()
+}
+
+/// The name of the table of the entity with the names of its columns
+fn model_table<E: EntityTrait>() -> (String, Vec<String>) {
+    (
+        E::default().table_name().to_string(),
+        E::Column::iter()
+            .map(|column| column.as_str().to_string())
+            .collect(),
+    )
+}
+
+/// The name of each table of the generated models with the names of its columns
+fn model_tables() -> Vec<(String, Vec<String>)> {
+    vec![
+        model_table::<bl_session::Entity>(),
+        model_table::<person::Entity>(),
+        model_table::<proposal::Entity>(),
+        model_table::<session_has_person::Entity>(),
+        model_table::<xfe_fluorescence_spectrum::Entity>(),
+    ]
+}
+
+/// Lists the tables and columns present in one set but not the other, qualified by the name of the set in which they are present
+fn differences(
+    present: &[(String, Vec<String>)],
+    present_in: &str,
+    other: &[(String, Vec<String>)],
+) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (table, columns) in present {
+        match other.iter().find(|(other_table, _)| other_table == table) {
+            None => differences.push(format!("{table} only in the {present_in}")),
+            Some((_, other_columns)) => differences.extend(
+                columns
+                    .iter()
+                    .filter(|column| !other_columns.contains(column))
+                    .map(|column| format!("{table}.{column} only in the {present_in}")),
+            ),
+        }
+    }
+    differences
+}
+
+/// Checks that the embedded DDL declares exactly the tables and columns of the generated models
+pub fn check_test_schema() -> Result<(), TestSchemaError> {
+    let declared = declared_tables();
+    let models = model_tables();
+    let mut disagreements = differences(&declared, "test schema", &models);
+    disagreements.extend(differences(&models, "models", &declared));
+    if disagreements.is_empty() {
+        Ok(())
+    } else {
+        Err(TestSchemaError::Disagreement(disagreements))
+    }
+}
+
+/// Creates the tables of the embedded DDL in the selected database, returning their names
+///
+/// The database must hold no tables other than those of the test schema, so that a production ISPyB, which holds many more, is never altered. Tables of the test schema which already exist are dropped and recreated if `replace` is set, otherwise nothing is done.
+pub async fn apply_test_schema(
+    database: &DatabaseConnection,
+    replace: bool,
+) -> Result<Vec<String>, TestSchemaError> {
+    check_test_schema()?;
+    let backend = database.get_database_backend();
+    let declared = declared_tables();
+    let mut existing = Vec::new();
+    for row in database
+        .query_all(Statement::from_string(
+            backend,
+            "SELECT TABLE_NAME AS table_name FROM information_schema.TABLES WHERE TABLE_SCHEMA = DATABASE()",
+        ))
+        .await?
+    {
+        existing.push(row.try_get::<String>("", "table_name")?);
+    }
+    let foreign = existing
+        .iter()
+        .filter(|table| !declared.iter().any(|(declared, _)| declared == *table))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !foreign.is_empty() {
+        return Err(TestSchemaError::ForeignTables(foreign));
+    }
+    if !existing.is_empty() {
+        if !replace {
+            return Err(TestSchemaError::NotEmpty(existing));
+        }
+        // Dropped in the reverse of the order of creation, so that no table is dropped whilst referenced
+        for (table, _) in declared.iter().rev() {
+            database
+                .execute(Statement::from_string(
+                    backend,
+                    format!("DROP TABLE IF EXISTS `{table}`"),
+                ))
+                .await?;
+        }
+    }
+    for statement in statements() {
+        database
+            .execute(Statement::from_string(backend, statement))
+            .await?;
+    }
+    Ok(declared.into_iter().map(|(table, _)| table).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_test_schema, check_test_schema, declared_tables, statements, TestSchemaError,
+    };
+    use crate::fake_database::{row, FakeDatabase};
+    use sea_orm::Value;
+
+    /// A database already holding the tables
+    fn holding(tables: &'static [&'static str]) -> FakeDatabase {
+        FakeDatabase::new(move |statement| {
+            Ok(if statement.sql.contains("information_schema") {
+                tables
+                    .iter()
+                    .map(|table| {
+                        row([(
+                            "table_name",
+                            Value::String(Some(Box::new(table.to_string()))),
+                        )])
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            })
+        })
+    }
+
+    #[test]
+    fn test_schema_matches_the_models() {
+        check_test_schema().unwrap();
+    }
+
+    #[tokio::test]
+    async fn apply_refuses_databases_with_foreign_tables() {
+        let database = holding(&["XFEFluorescenceSpectrum", "DataCollection", "Shipping"]);
+        let err = apply_test_schema(&database.connect().await, true)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, TestSchemaError::ForeignTables(foreign) if foreign == &["DataCollection", "Shipping"]),
+            "{err}"
+        );
+        assert_eq!(database.queries().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn apply_refuses_to_replace_existing_tables_unless_forced() {
+        let database = holding(&["BLSession"]);
+        let err = apply_test_schema(&database.connect().await, false)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, TestSchemaError::NotEmpty(existing) if existing == &["BLSession"]),
+            "{err}"
+        );
+        assert_eq!(database.queries().len(), 1);
+        let tables = apply_test_schema(&database.connect().await, true)
+            .await
+            .unwrap();
+        let queries = database.queries();
+        let drops = queries
+            .iter()
+            .filter(|query| query.starts_with("DROP TABLE"))
+            .count();
+        assert_eq!(drops, tables.len());
+        assert_eq!(queries.len(), 2 + drops + statements().count());
+    }
+
+    #[tokio::test]
+    async fn apply_creates_every_table_in_an_empty_database() {
+        let database = holding(&[]);
+        let tables = apply_test_schema(&database.connect().await, false)
+            .await
+            .unwrap();
+        assert_eq!(
+            tables,
+            declared_tables()
+                .into_iter()
+                .map(|(table, _)| table)
+                .collect::<Vec<_>>()
+        );
+        let queries = database.queries();
+        assert_eq!(queries.len(), 1 + statements().count());
+        assert!(queries[1..]
+            .iter()
+            .all(|query| query.trim_start().starts_with("CREATE TABLE")));
+    }
+}
@@ -0,0 +1,57 @@
+-- Minimal ISPyB schema for ephemeral test and development databases, version 1
+--
+-- Declares only the tables and columns read by the service, as they are defined by ispyb-database v3.0.0.
+-- Every column listed here must appear in the generated models, and every column of the models must appear here.
+
+CREATE TABLE `Proposal` (
+  `proposalId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `proposalCode` varchar(45) DEFAULT NULL,
+  `proposalNumber` varchar(45) DEFAULT NULL,
+  PRIMARY KEY (`proposalId`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE `Person` (
+  `personId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `login` varchar(45) DEFAULT NULL,
+  PRIMARY KEY (`personId`),
+  UNIQUE KEY `Person_login` (`login`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE `BLSession` (
+  `sessionId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `beamLineName` varchar(45) DEFAULT NULL,
+  `proposalId` int(10) unsigned NOT NULL DEFAULT 0,
+  `visit_number` int(10) unsigned DEFAULT 0,
+  PRIMARY KEY (`sessionId`),
+  KEY `BLSession_FKIndexProposalId` (`proposalId`),
+  CONSTRAINT `BLSession_ibfk_1` FOREIGN KEY (`proposalId`) REFERENCES `Proposal` (`proposalId`) ON DELETE CASCADE ON UPDATE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE `Session_has_Person` (
+  `sessionId` int(10) unsigned NOT NULL DEFAULT 0,
+  `personId` int(10) unsigned NOT NULL DEFAULT 0,
+  PRIMARY KEY (`sessionId`, `personId`),
+  KEY `Session_has_Person_FKIndex2` (`personId`),
+  CONSTRAINT `Session_has_Person_ibfk_1` FOREIGN KEY (`sessionId`) REFERENCES `BLSession` (`sessionId`) ON DELETE CASCADE ON UPDATE CASCADE,
+  CONSTRAINT `Session_has_Person_ibfk_2` FOREIGN KEY (`personId`) REFERENCES `Person` (`personId`) ON DELETE CASCADE ON UPDATE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
+
+CREATE TABLE `XFEFluorescenceSpectrum` (
+  `xfeFluorescenceSpectrumId` int(10) unsigned NOT NULL AUTO_INCREMENT,
+  `sessionId` int(10) unsigned NOT NULL,
+  `jpegScanFileFullPath` varchar(255) DEFAULT NULL,
+  `startTime` datetime DEFAULT NULL,
+  `endTime` datetime DEFAULT NULL,
+  `filename` varchar(255) DEFAULT NULL,
+  `exposureTime` float DEFAULT NULL,
+  `axisPosition` float DEFAULT NULL,
+  `beamTransmission` float DEFAULT NULL,
+  `energy` float DEFAULT NULL,
+  `beamSizeVertical` float DEFAULT NULL,
+  `beamSizeHorizontal` float DEFAULT NULL,
+  `scanFileFullPath` varchar(255) DEFAULT NULL,
+  `recordTimeStamp` timestamp NOT NULL DEFAULT current_timestamp(),
+  PRIMARY KEY (`xfeFluorescenceSpectrumId`),
+  KEY `XFEFluorescnceSpectrum_FKIndex1` (`sessionId`),
+  CONSTRAINT `XFE_ibfk_1` FOREIGN KEY (`sessionId`) REFERENCES `BLSession` (`sessionId`) ON DELETE CASCADE ON UPDATE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb4;
@@ -0,0 +1,326 @@
+use crate::authorization::Claims;
+use derive_more::{Display, Error, From};
+use hyper::{client::HttpConnector, http::uri::InvalidUri, Client, StatusCode, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::Value;
+use std::{
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::warn;
+use url::Url;
+
+/// The minimum period between fetches of the signing keys prompted by tokens signed with an unknown key, so that forged key identifiers cannot flood the issuer
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An error produced when fetching the signing keys of the issuer
+#[derive(Debug, Display, Error, From)]
+pub enum JwksError {
+    /// The URL of the key set is not a valid URI
+    #[display(fmt = "Signing key set URL is invalid: {}", _0)]
+    Uri(InvalidUri),
+    /// The key set could not be requested
+    #[display(fmt = "Signing keys could not be fetched: {}", _0)]
+    Http(hyper::Error),
+    /// The issuer responded with an unsuccessful status
+    #[display(fmt = "Signing keys could not be fetched, the issuer responded {}", _0)]
+    #[from(ignore)]
+    Status(#[error(not(source))] StatusCode),
+    /// The response was not a JSON Web Key Set
+    #[display(fmt = "Signing keys could not be read: {}", _0)]
+    Json(serde_json::Error),
+}
+
+/// An error produced when a bearer token cannot be verified
+#[derive(Debug, Display, Error, From)]
+pub enum TokenError {
+    /// The token is not signed by any key of the issuer
+    #[display(fmt = "Token is not signed by a known key")]
+    UnknownKey,
+    /// The token is signed with an algorithm other than that of its key
+    #[display(fmt = "Token is not signed with the algorithm of its key")]
+    AlgorithmMismatch,
+    /// The token is malformed, its signature is invalid, it has expired or it was issued by or for another party
+    #[display(fmt = "{}", _0)]
+    Invalid(jsonwebtoken::errors::Error),
+}
+
+/// The location from which the signing keys of the issuer are refreshed
+#[derive(Debug)]
+struct KeySource {
+    /// The URL of the JSON Web Key Set
+    uri: Uri,
+    /// The client used to fetch the key set
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// When the key set was last fetched
+    fetched: Mutex<Instant>,
+}
+
+impl KeySource {
+    /// Fetches the key set
+    async fn fetch(&self) -> Result<JwkSet, JwksError> {
+        let response = self.client.get(self.uri.clone()).await?;
+        if !response.status().is_success() {
+            return Err(JwksError::Status(response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Verifies the signature, issuer, audience and expiry of bearer tokens against the signing keys published by their issuer, so that only the claims of genuine tokens are trusted
+#[derive(Debug)]
+pub struct TokenVerifier {
+    /// The issuer whose tokens are accepted
+    issuer: String,
+    /// The audience for which accepted tokens must be issued
+    audience: String,
+    /// The signing keys of the issuer
+    keys: RwLock<JwkSet>,
+    /// The location from which the keys are refreshed, if they are not fixed
+    source: Option<KeySource>,
+}
+
+impl TokenVerifier {
+    /// Creates a verifier accepting tokens signed by the supplied keys, which are never refreshed
+    pub fn new(issuer: impl Into<String>, audience: impl Into<String>, keys: JwkSet) -> Self {
+        Self {
+            issuer: issuer.into(),
+            audience: audience.into(),
+            keys: RwLock::new(keys),
+            source: None,
+        }
+    }
+
+    /// Creates a verifier accepting tokens signed by the keys published at the JSON Web Key Set URL, fetching them immediately
+    ///
+    /// The keys are fetched again when a token names a key which is not known, as when the issuer rotates its keys, at most once a minute.
+    pub async fn fetch(
+        jwks_url: &Url,
+        issuer: impl Into<String>,
+        audience: impl Into<String>,
+    ) -> Result<Self, JwksError> {
+        let source = KeySource {
+            uri: Uri::from_str(jwks_url.as_str())?,
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_native_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build(),
+            ),
+            fetched: Mutex::new(Instant::now()),
+        };
+        let keys = source.fetch().await?;
+        Ok(Self {
+            issuer: issuer.into(),
+            audience: audience.into(),
+            keys: RwLock::new(keys),
+            source: Some(source),
+        })
+    }
+
+    /// Verifies the token, producing its claims if it is genuine
+    pub async fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let header = decode_header(token)?;
+        let jwk = match self.key(header.kid.as_deref()) {
+            Some(jwk) => jwk,
+            None => {
+                self.refresh().await;
+                self.key(header.kid.as_deref())
+                    .ok_or(TokenError::UnknownKey)?
+            }
+        };
+        if let Some(key_algorithm) = jwk.common.key_algorithm {
+            if Algorithm::from_str(&key_algorithm.to_string()).ok() != Some(header.alg) {
+                return Err(TokenError::AlgorithmMismatch);
+            }
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let payload = decode::<Value>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+        Ok(Claims::from_payload(&payload.claims))
+    }
+
+    /// The key with the identifier, or the only key if there is one and no identifier is given
+    fn key(&self, kid: Option<&str>) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap();
+        match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None if keys.keys.len() == 1 => keys.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    /// Fetches the keys again, unless they were fetched within the minimum refresh interval
+    async fn refresh(&self) {
+        let Some(source) = &self.source else {
+            return;
+        };
+        let mut fetched = source.fetched.lock().await;
+        if fetched.elapsed() < MIN_REFRESH_INTERVAL {
+            return;
+        }
+        *fetched = Instant::now();
+        match source.fetch().await {
+            Ok(keys) => *self.keys.write().unwrap() = keys,
+            Err(err) => warn!("Signing keys could not be refreshed: {err}"),
+        }
+    }
+}
+
+/// Signing and verification of tokens for tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::TokenVerifier;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, jwk::JwkSet, Algorithm, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    /// The secret of the signing key of the issuer in tests
+    pub const SECRET: &[u8] = b"a secret of the issuer used only in tests";
+
+    /// A verifier accepting tokens signed with [`SECRET`]
+    pub fn verifier() -> TokenVerifier {
+        let keys = serde_json::from_value::<JwkSet>(json!({
+            "keys": [{ "kty": "oct", "kid": "test", "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(SECRET) }]
+        }))
+        .unwrap();
+        TokenVerifier::new("https://issuer.invalid", "fluorescence-scan", keys)
+    }
+
+    /// A token with the claims signed with the secret
+    pub fn token(claims: Value, secret: &[u8]) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(String::from("test"));
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    /// Claims of a token for the subject which is valid for an hour
+    pub fn valid_claims(subject: &str, groups: &[&str]) -> Value {
+        json!({
+            "sub": subject,
+            "groups": groups,
+            "iss": "https://issuer.invalid",
+            "aud": "fluorescence-scan",
+            "exp": chrono::Utc::now().timestamp() + 3600,
+        })
+    }
+
+    /// A genuine token for the subject, which is a member of the groups
+    pub fn genuine_token(subject: &str, groups: &[&str]) -> String {
+        token(valid_claims(subject, groups), SECRET)
+    }
+
+    /// A token for the subject signed with a key not known to the verifier
+    pub fn forged_token(subject: &str, groups: &[&str]) -> String {
+        token(
+            valid_claims(subject, groups),
+            b"a secret guessed by an attacker",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        testing::{forged_token, genuine_token, token, verifier, SECRET},
+        TokenError,
+    };
+    use crate::authorization::Claims;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    /// Claims of a token which is valid for an hour
+    fn valid_claims() -> Value {
+        super::testing::valid_claims("abc12345", &["/i18", "admin"])
+    }
+
+    #[tokio::test]
+    async fn genuine_token_produces_claims() {
+        let claims = verifier()
+            .verify(&genuine_token("abc12345", &["/i18", "admin"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            claims,
+            Claims {
+                subject: Some(String::from("abc12345")),
+                groups: vec![String::from("i18"), String::from("admin")],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn token_signed_by_another_key_is_rejected() {
+        let forged = forged_token("abc12345", &["/i18", "admin"]);
+        assert!(verifier().verify(&forged).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn token_with_tampered_payload_is_rejected() {
+        let genuine = genuine_token("abc12345", &["/i18", "admin"]);
+        let mut parts = genuine.split('.').map(String::from).collect::<Vec<_>>();
+        let mut claims = valid_claims();
+        claims["sub"] = json!("someone-else");
+        parts[1] = URL_SAFE_NO_PAD.encode(claims.to_string());
+        assert!(verifier().verify(&parts.join(".")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsigned_token_is_rejected() {
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(valid_claims().to_string())
+        );
+        assert!(verifier().verify(&unsigned).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn token_of_another_issuer_or_audience_is_rejected() {
+        let mut other_issuer = valid_claims();
+        other_issuer["iss"] = json!("https://elsewhere.invalid");
+        assert!(verifier()
+            .verify(&token(other_issuer, SECRET))
+            .await
+            .is_err());
+        let mut other_audience = valid_claims();
+        other_audience["aud"] = json!("another-service");
+        assert!(verifier()
+            .verify(&token(other_audience, SECRET))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_rejected() {
+        let mut expired = valid_claims();
+        expired["exp"] = json!(chrono::Utc::now().timestamp() - 3600);
+        assert!(verifier().verify(&token(expired, SECRET)).await.is_err());
+        let mut unexpiring = valid_claims();
+        unexpiring.as_object_mut().unwrap().remove("exp");
+        assert!(verifier().verify(&token(unexpiring, SECRET)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn token_naming_an_unknown_key_is_rejected() {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(String::from("unknown"));
+        let token = encode(&header, &valid_claims(), &EncodingKey::from_secret(SECRET)).unwrap();
+        assert!(matches!(
+            verifier().verify(&token).await,
+            Err(TokenError::UnknownKey)
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+use axum::{extract::Request, http::HeaderMap, middleware::Next, response::Response};
+use opentelemetry::{
+    propagation::{Extractor, TextMapPropagator},
+    trace::TraceContextExt,
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use std::future::Future;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Reads propagation fields, such as `traceparent` and `tracestate`, from request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Middleware parenting the span of the request on the W3C trace context, including any `tracestate`, supplied by the caller
+///
+/// This does not depend on a propagator having been installed globally, so callers which reach the service directly rather than through the router join their own trace, and as it runs ahead of every handler this holds for rejected requests too.
+pub async fn continue_trace(request: Request, next: Next) -> Response {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(request.headers()));
+    if context.span().span_context().is_remote() {
+        Span::current().set_parent(context);
+    }
+    next.run(request).await
+}
+
+/// Spawns background work on behalf of the current request, such as a batch of a data loader, within the span of the request
+///
+/// A spawned task does not otherwise inherit the span of the task spawning it, so the spans of the work would begin traces of their own.
+pub fn spawn_in_current_span<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.in_current_span())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        fake_database::{model_row, scan, FakeDatabase},
+        security_headers::{GraphiQLAccess, GraphiQLPolicy},
+        token_verifier::testing::verifier,
+        FluorescenceScanService,
+    };
+    use axum::{
+        body::Body,
+        http::{header, request::Builder, Request, StatusCode},
+        Router,
+    };
+    use futures::future::BoxFuture;
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider as _};
+    use opentelemetry_sdk::{
+        export::trace::{ExportResult, SpanData, SpanExporter},
+        trace::TracerProvider,
+    };
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    /// The trace of the caller
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    /// The span of the caller from which requests are sent
+    const CALLER_SPAN_ID: &str = "00f067aa0ba902b7";
+
+    /// An exporter retaining the spans it is given in memory
+    #[derive(Debug, Clone, Default)]
+    struct InMemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for InMemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// Sends the request to the router from within the trace of the caller, producing the status of the response and every span exported whilst handling it
+    async fn traced(router: Router, request: Builder, body: Body) -> (StatusCode, Vec<SpanData>) {
+        let exporter = InMemoryExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let guard = tracing::subscriber::set_default(subscriber);
+        let status = router
+            .oneshot(
+                request
+                    .header("traceparent", format!("00-{TRACE_ID}-{CALLER_SPAN_ID}-01"))
+                    .header("tracestate", "caller=abc")
+                    .body(body)
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status();
+        drop(guard);
+        provider.force_flush();
+        let spans = exporter.0.lock().unwrap().clone();
+        (status, spans)
+    }
+
+    /// The span of the request, which must be the only child of the caller's span
+    fn request_span(spans: &[SpanData]) -> &SpanData {
+        let caller = SpanId::from_hex(CALLER_SPAN_ID).unwrap();
+        let children = spans
+            .iter()
+            .filter(|span| span.parent_span_id == caller)
+            .collect::<Vec<_>>();
+        assert_eq!(children.len(), 1, "{spans:#?}");
+        children[0]
+    }
+
+    /// The spans enclosing the span, innermost first, ending with the first parent which was not exported
+    fn ancestors(spans: &[SpanData], span: &SpanData) -> Vec<SpanId> {
+        let mut ancestors = vec![span.parent_span_id];
+        while let Some(parent) = spans
+            .iter()
+            .find(|candidate| candidate.span_context.span_id() == *ancestors.last().unwrap())
+        {
+            ancestors.push(parent.parent_span_id);
+        }
+        ancestors
+    }
+
+    #[tokio::test]
+    async fn rejected_request_joins_the_trace_of_the_caller() {
+        let service =
+            FluorescenceScanService::builder(FakeDatabase::with_results([]).connect().await)
+                .graphiql_policy(GraphiQLPolicy::default().access(GraphiQLAccess::Authenticated))
+                .token_verifier(Arc::new(verifier()))
+                .build();
+        let (status, spans) = traced(service.router(), Request::get("/"), Body::empty()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let request = request_span(&spans);
+        assert_eq!(
+            request.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        assert_eq!(request.span_context.trace_state().header(), "caller=abc");
+    }
+
+    #[tokio::test]
+    async fn background_batch_is_a_descendant_of_the_request() {
+        let database = FakeDatabase::new(|_| Ok(vec![model_row(&scan(3, 43, None, None))]));
+        let service = FluorescenceScanService::builder(database.connect().await).build();
+        let body = json!({
+            "query": "query ($representations: [_Any!]!) { _entities(representations: $representations) { ... on FluorescenceScan { id } } }",
+            "variables": { "representations": [{ "__typename": "FluorescenceScan", "id": 3 }] },
+        });
+        let (status, spans) = traced(
+            service.router(),
+            Request::post("/").header(header::CONTENT_TYPE, "application/json"),
+            Body::from(body.to_string()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let request = request_span(&spans);
+        let batch = spans
+            .iter()
+            .find(|span| span.name == "load_scans")
+            .expect("Batch span exported");
+        assert_eq!(
+            batch.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID).unwrap()
+        );
+        let ancestors = ancestors(&spans, batch);
+        assert!(
+            ancestors.contains(&request.span_context.span_id()),
+            "{ancestors:?}"
+        );
+        assert_eq!(
+            ancestors.last(),
+            Some(&SpanId::from_hex(CALLER_SPAN_ID).unwrap())
+        );
+    }
+}
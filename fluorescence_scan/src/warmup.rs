@@ -0,0 +1,71 @@
+use async_graphql::Request;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::{authorization::InternalRequest, graphql::RootSchema, store::ScanFiles};
+
+/// The query executed to warm the schema and the database connection pool
+const WARMUP_QUERY: &str =
+    "{ _service { sdl } fluorescenceScanChanges(sessionId: 0, limit: 1) { hasMore } }";
+
+/// The outcome of the warm-up performed on startup
+#[derive(Debug, Clone)]
+pub struct WarmupReport {
+    /// The time taken to execute the warm-up GraphQL query
+    graphql: Duration,
+    /// The time taken to check the scan file store
+    storage: Duration,
+    /// Descriptions of the failures encountered during warm-up
+    errors: Vec<String>,
+}
+
+impl WarmupReport {
+    /// Whether every warm-up step succeeded
+    pub fn succeeded(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Renders the report for the debug statistics
+    pub fn to_json(&self) -> Value {
+        json!({
+            "graphqlMs": self.graphql.as_secs_f64() * 1000.0,
+            "storageMs": self.storage.as_secs_f64() * 1000.0,
+            "errors": self.errors,
+        })
+    }
+}
+
+/// Exercises the GraphQL schema, database and scan file store, if available, through their normal code paths, so the first request need not initialise them
+pub async fn warm_up(schema: &RootSchema, files: Option<&ScanFiles>) -> WarmupReport {
+    let mut errors = Vec::new();
+
+    let start = Instant::now();
+    let response = schema
+        .execute(Request::new(WARMUP_QUERY).data(InternalRequest))
+        .await;
+    let graphql = start.elapsed();
+    errors.extend(response.errors.into_iter().map(|err| err.message));
+
+    let start = Instant::now();
+    if let Some(files) = files {
+        if let Err(err) = files.store.check().await {
+            errors.push(err.to_string());
+        }
+    }
+    let storage = start.elapsed();
+
+    let report = WarmupReport {
+        graphql,
+        storage,
+        errors,
+    };
+    let graphql_ms = report.graphql.as_millis() as u64;
+    let storage_ms = report.storage.as_millis() as u64;
+    if report.succeeded() {
+        info!(graphql_ms, storage_ms, "Warm-up completed");
+    } else {
+        warn!(graphql_ms, storage_ms, errors = ?report.errors, "Warm-up failed");
+    }
+    report
+}
@@ -15,24 +15,43 @@ struct Table<'a> {
     columns: &'a [&'a str],
 }
 
-const TABLES_SPECS: &[&Table] = &[&Table {
-    name: "XFEFluorescenceSpectrum",
-    columns: &[
-        "xfeFluorescenceSpectrumId",
-        "sessionId",
-        "jpegScanFileFullPath",
-        "startTime",
-        "endTime",
-        "filename",
-        "exposureTime",
-        "axisPosition",
-        "beamTransmission",
-        "energy",
-        "beamSizeVertical",
-        "beamSizeHorizontal",
-        "scanFileFullPath",
-    ],
-}];
+const TABLES_SPECS: &[&Table] = &[
+    &Table {
+        name: "BLSession",
+        columns: &["sessionId", "beamLineName", "proposalId", "visit_number"],
+    },
+    &Table {
+        name: "Person",
+        columns: &["personId", "login"],
+    },
+    &Table {
+        name: "Proposal",
+        columns: &["proposalId", "proposalCode", "proposalNumber"],
+    },
+    &Table {
+        name: "Session_has_Person",
+        columns: &["sessionId", "personId"],
+    },
+    &Table {
+        name: "XFEFluorescenceSpectrum",
+        columns: &[
+            "xfeFluorescenceSpectrumId",
+            "sessionId",
+            "jpegScanFileFullPath",
+            "startTime",
+            "endTime",
+            "filename",
+            "exposureTime",
+            "axisPosition",
+            "beamTransmission",
+            "energy",
+            "beamSizeVertical",
+            "beamSizeHorizontal",
+            "scanFileFullPath",
+            "recordTimeStamp",
+        ],
+    },
+];
 
 fn main() {
     tokio::runtime::Builder::new_current_thread()